@@ -1,5 +1,7 @@
 use crate::dispatch::*;
 use coreaudio_sys::*;
+use std::mem;
+use std::os::raw::{c_int, c_ulong, c_void};
 
 // See https://opensource.apple.com/source/WebCore/WebCore-7604.5.6/platform/spi/cf/CoreAudioSPI.h.auto.html
 // Per https://github.com/WebKit/WebKit/commit/7c4c851bc80f14b4cf907f76d65baee013a45eea,
@@ -24,3 +26,79 @@ pub fn audio_device_duck(
     debug_assert_running_serially();
     unsafe { AudioDeviceDuck(in_device, in_ducked_level, in_start_time, in_ramp_duration) }
 }
+
+// See AUVoiceIOOtherAudioDuckingConfiguration in AudioUnitProperties.h. This first appeared in
+// MacOS 14.0, so the bindings may be generated from an SDK without it.
+pub const VPIO_PROPERTY_OTHER_AUDIO_DUCKING_CONFIGURATION: AudioUnitPropertyID = 2108;
+
+pub const VPIO_OTHER_AUDIO_DUCKING_LEVEL_DEFAULT: u32 = 0;
+pub const VPIO_OTHER_AUDIO_DUCKING_LEVEL_MIN: u32 = 10;
+pub const VPIO_OTHER_AUDIO_DUCKING_LEVEL_MID: u32 = 20;
+pub const VPIO_OTHER_AUDIO_DUCKING_LEVEL_MAX: u32 = 30;
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct VpioOtherAudioDuckingConfiguration {
+    pub enable_advanced_ducking: Boolean,
+    pub ducking_level: u32,
+}
+
+// See AUVoiceIOMutedSpeechActivityEventListener in AudioUnitProperties.h. This first appeared in
+// MacOS 14.0 too. The listener is called when the user starts or stops talking while the input of
+// the VoiceProcessingIO unit is muted.
+pub const VPIO_PROPERTY_MUTED_SPEECH_ACTIVITY_EVENT_LISTENER: AudioUnitPropertyID = 2106;
+
+// AUVoiceIOSpeechActivityEvent is an NSInteger.
+pub const VPIO_SPEECH_ACTIVITY_HAS_STARTED: isize = 0;
+pub const VPIO_SPEECH_ACTIVITY_HAS_ENDED: isize = 1;
+
+extern "C" {
+    static _NSConcreteStackBlock: [*const c_void; 32];
+}
+
+#[repr(C)]
+struct BlockDescriptor {
+    reserved: c_ulong,
+    size: c_ulong,
+}
+
+// A block taking a speech activity event, per https://clang.llvm.org/docs/Block-ABI-Apple.html,
+// calling `callback` with `user_ptr`. It captures no object, so it can be copied as is by the
+// AudioUnit.
+#[repr(C)]
+pub struct SpeechActivityListenerBlock {
+    isa: *const c_void,
+    flags: c_int,
+    reserved: c_int,
+    invoke: unsafe extern "C" fn(*mut SpeechActivityListenerBlock, isize),
+    descriptor: *const BlockDescriptor,
+    callback: extern "C" fn(*mut c_void, isize),
+    user_ptr: *mut c_void,
+}
+
+static SPEECH_ACTIVITY_LISTENER_BLOCK_DESCRIPTOR: BlockDescriptor = BlockDescriptor {
+    reserved: 0,
+    size: mem::size_of::<SpeechActivityListenerBlock>() as c_ulong,
+};
+
+unsafe extern "C" fn invoke_speech_activity_listener_block(
+    block: *mut SpeechActivityListenerBlock,
+    event: isize,
+) {
+    let block = &*block;
+    (block.callback)(block.user_ptr, event);
+}
+
+impl SpeechActivityListenerBlock {
+    pub fn new(callback: extern "C" fn(*mut c_void, isize), user_ptr: *mut c_void) -> Self {
+        Self {
+            isa: unsafe { &_NSConcreteStackBlock as *const _ as *const c_void },
+            flags: 0,
+            reserved: 0,
+            invoke: invoke_speech_activity_listener_block,
+            descriptor: &SPEECH_ACTIVITY_LISTENER_BLOCK_DESCRIPTOR,
+            callback,
+            user_ptr,
+        }
+    }
+}
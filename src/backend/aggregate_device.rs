@@ -1,8 +1,17 @@
 use super::*;
+use std::fs::OpenOptions;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 pub const DRIFT_COMPENSATION: u32 = 1;
 
+const AGGREGATE_DEVICE_UID_PREFIX: &str = "org.mozilla.";
+// The aggregate devices are private to the process creating them, so other processes can't
+// enumerate the ones a crashed process leaked. Their uids are kept in this file, in the temporary
+// directory of the user, until they are destroyed.
+const AGGREGATE_DEVICE_REGISTRY_FILE_NAME: &str = "org.mozilla.cubeb.aggregate-devices";
+
 #[derive(Debug)]
 pub struct AggregateDevice {
     plugin_id: AudioObjectID,
@@ -12,6 +21,15 @@ pub struct AggregateDevice {
     output_id: AudioObjectID,
 }
 
+// The clock setup of an aggregate device, as reported by the HAL.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AggregateClockInfo {
+    // The UID of the sub-device driving the clock.
+    pub master_uid: String,
+    // The UIDs of the other sub-devices, and whether drift compensation is on for them.
+    pub drift_compensation: Vec<(String, bool)>,
+}
+
 #[derive(Debug)]
 pub enum Error {
     OS(OSStatus),
@@ -59,7 +77,7 @@ impl AggregateDevice {
     //    (don't add sub-devices in that step, prone to fail [0])
     // 3. Ask the base plug-in to create the aggregate device (blank)
     // 4. Add the array of sub-devices.
-    // 5. Set the master device (1st output device in our case)
+    // 5. Set the master device (see `select_master_device`)
     // 6. Enable drift compensation for the non-master devices
     //
     // [0] https://lists.apple.com/archives/coreaudio-api/2006/Apr/msg00092.html
@@ -68,6 +86,16 @@ impl AggregateDevice {
     pub fn new(
         input_id: AudioObjectID,
         output_id: AudioObjectID,
+    ) -> std::result::Result<Self, Error> {
+        Self::new_with_master_preference(input_id, output_id, None)
+    }
+
+    // Like `new`, but the device with `preferred_master_uid`, if it's the input or the output
+    // device, becomes the master device.
+    pub fn new_with_master_preference(
+        input_id: AudioObjectID,
+        output_id: AudioObjectID,
+        preferred_master_uid: Option<&str>,
     ) -> std::result::Result<Self, Error> {
         debug_assert_running_serially();
         let plugin_id = Self::get_system_plugin_id()?;
@@ -79,7 +107,8 @@ impl AggregateDevice {
         });
 
         Self::set_sub_devices_sync(device_id, input_id, output_id)?;
-        Self::set_master_device(device_id, output_id)?;
+        let master_id = Self::select_master_device(input_id, output_id, preferred_master_uid);
+        Self::set_master_device(device_id, master_id)?;
         Self::activate_clock_drift_compensation(device_id)?;
         Self::workaround_for_airpod(device_id, input_id, output_id)?;
 
@@ -103,6 +132,39 @@ impl AggregateDevice {
         self.device_id
     }
 
+    pub fn get_clock_info(
+        device_id: AudioObjectID,
+    ) -> std::result::Result<AggregateClockInfo, Error> {
+        debug_assert_running_serially();
+        let master_uid = Self::get_master_device_uid(device_id)?;
+        let address = AudioObjectPropertyAddress {
+            mSelector: kAudioSubDevicePropertyDriftCompensation,
+            mScope: kAudioObjectPropertyScopeGlobal,
+            mElement: kAudioObjectPropertyElementMaster,
+        };
+        let mut drift_compensation = Vec::new();
+        for device in Self::get_owned_sub_devices(device_id)? {
+            let uid = get_device_global_uid(device)
+                .map(|sr| sr.into_string())
+                .unwrap_or_default();
+            if uid == master_uid {
+                continue;
+            }
+            let mut size = mem::size_of::<u32>();
+            let mut compensation: u32 = 0;
+            let status =
+                audio_object_get_property_data(device, &address, &mut size, &mut compensation);
+            if status != NO_ERR {
+                return Err(Error::from(status));
+            }
+            drift_compensation.push((uid, compensation != 0));
+        }
+        Ok(AggregateClockInfo {
+            master_uid,
+            drift_compensation,
+        })
+    }
+
     // The following APIs are set to `pub` for testing purpose.
     pub fn get_system_plugin_id() -> std::result::Result<AudioObjectID, Error> {
         let address = AudioObjectPropertyAddress {
@@ -233,8 +295,14 @@ impl AggregateDevice {
 
         let sys_time = SystemTime::now();
         let time_id = sys_time.duration_since(UNIX_EPOCH).unwrap().as_nanos();
-        let device_name = format!("{}_{}", PRIVATE_AGGREGATE_DEVICE_NAME, time_id);
-        let device_uid = format!("org.mozilla.{}", device_name);
+        // The pid of the owner lets `destroy_stale_devices` find the devices leaked by a crash.
+        let device_name = format!(
+            "{}_{}_{}",
+            PRIVATE_AGGREGATE_DEVICE_NAME,
+            std::process::id(),
+            time_id
+        );
+        let device_uid = format!("{}{}", AGGREGATE_DEVICE_UID_PREFIX, device_name);
 
         let mut device_id = kAudioObjectUnknown;
         let status = unsafe {
@@ -294,6 +362,13 @@ impl AggregateDevice {
         };
         if status == NO_ERR {
             assert_ne!(device_id, kAudioObjectUnknown);
+            if let Err(e) = update_device_registry(|uids| uids.push(device_uid)) {
+                cubeb_log!(
+                    "Cannot register aggregate device {}. Error: {}",
+                    device_id,
+                    e
+                );
+            }
             Ok(device_id)
         } else {
             Err(Error::from(status))
@@ -394,19 +469,22 @@ impl AggregateDevice {
         assert_ne!(input_id, output_id);
         debug_assert_running_serially();
 
-        let output_sub_devices = Self::get_sub_devices_or_self(output_id)?;
-        let input_sub_devices = Self::get_sub_devices_or_self(input_id)?;
+        // An aggregate device can't contain another aggregate device, so use the devices the
+        // aggregate devices the user made in Audio MIDI Setup are made of instead.
+        let output_sub_devices = Self::get_leaf_sub_devices(output_id)?;
+        let input_sub_devices = Self::get_leaf_sub_devices(input_id)?;
+        let sub_device_ids = Self::merge_sub_devices(input_sub_devices, output_sub_devices);
+        cubeb_log!(
+            "Set sub devices of the aggregate device {} to {:?}",
+            device_id,
+            sub_device_ids
+        );
 
         unsafe {
             let sub_devices = CFArrayCreateMutable(ptr::null(), 0, &kCFTypeArrayCallBacks);
             // The order of the items in the array is significant and is used to determine the order of the streams
             // of the AudioAggregateDevice.
-            for device in input_sub_devices {
-                let uid = get_device_global_uid(device)?;
-                CFArrayAppendValue(sub_devices, uid.get_raw() as *const c_void);
-            }
-
-            for device in output_sub_devices {
+            for device in sub_device_ids {
                 let uid = get_device_global_uid(device)?;
                 CFArrayAppendValue(sub_devices, uid.get_raw() as *const c_void);
             }
@@ -474,6 +552,54 @@ impl AggregateDevice {
         })
     }
 
+    // The devices that aren't aggregate devices themselves, found by flattening the nested
+    // aggregate devices of `device_id`, or `device_id` itself if it's not an aggregate device.
+    pub fn get_leaf_sub_devices(
+        device_id: AudioDeviceID,
+    ) -> std::result::Result<Vec<AudioObjectID>, Error> {
+        fn flatten(
+            device_id: AudioDeviceID,
+            visited: &mut Vec<AudioObjectID>,
+            leaves: &mut Vec<AudioObjectID>,
+        ) -> std::result::Result<(), Error> {
+            if visited.contains(&device_id) {
+                return Ok(());
+            }
+            visited.push(device_id);
+            let sub_devices = AggregateDevice::get_sub_devices_or_self(device_id)?;
+            if sub_devices == [device_id] {
+                leaves.push(device_id);
+                return Ok(());
+            }
+            for sub_device in sub_devices {
+                flatten(sub_device, visited, leaves)?;
+            }
+            Ok(())
+        }
+
+        debug_assert_running_serially();
+        let mut visited = Vec::new();
+        let mut leaves = Vec::new();
+        flatten(device_id, &mut visited, &mut leaves)?;
+        Ok(leaves)
+    }
+
+    // The input sub devices followed by the output sub devices, without the devices that are on
+    // both sides, e.g. a headset in a user aggregate device used as input, and as output.
+    pub fn merge_sub_devices(
+        input_sub_devices: Vec<AudioObjectID>,
+        output_sub_devices: Vec<AudioObjectID>,
+    ) -> Vec<AudioObjectID> {
+        let mut sub_devices =
+            Vec::with_capacity(input_sub_devices.len() + output_sub_devices.len());
+        for device in input_sub_devices.into_iter().chain(output_sub_devices) {
+            if !sub_devices.contains(&device) {
+                sub_devices.push(device);
+            }
+        }
+        sub_devices
+    }
+
     pub fn get_master_device_uid(device_id: AudioDeviceID) -> std::result::Result<String, Error> {
         debug_assert_running_serially();
         let address = AudioObjectPropertyAddress {
@@ -497,6 +623,37 @@ impl AggregateDevice {
         Ok(master.into_string())
     }
 
+    pub fn select_master_device(
+        input_id: AudioDeviceID,
+        output_id: AudioDeviceID,
+        preferred_master_uid: Option<&str>,
+    ) -> AudioDeviceID {
+        debug_assert_running_serially();
+        let facts = |id, devtype| ClockDeviceFacts {
+            uid: get_device_global_uid(id)
+                .map(|sr| sr.into_string())
+                .unwrap_or_default(),
+            is_bluetooth: matches!(
+                get_device_transport_type(id, devtype),
+                Ok(kAudioDeviceTransportTypeBluetooth) | Ok(kAudioDeviceTransportTypeBluetoothLE)
+            ),
+            clock_domain: get_clock_domain(id, devtype).ok(),
+        };
+        let input = facts(input_id, DeviceType::INPUT);
+        let output = facts(output_id, DeviceType::OUTPUT);
+        let master = select_master_clock(&input, &output, preferred_master_uid);
+        cubeb_log!(
+            "Master clock for input {:?} and output {:?}: {:?}",
+            input,
+            output,
+            master
+        );
+        match master {
+            MasterClock::Input => input_id,
+            MasterClock::Output => output_id,
+        }
+    }
+
     pub fn set_master_device(
         device_id: AudioDeviceID,
         primary_id: AudioDeviceID,
@@ -518,7 +675,7 @@ impl AggregateDevice {
         };
 
         // The master device will be the 1st sub device of the primary device.
-        let output_sub_devices = Self::get_sub_devices_or_self(primary_id)?;
+        let output_sub_devices = Self::get_leaf_sub_devices(primary_id)?;
         assert!(!output_sub_devices.is_empty());
         let master_sub_device_uid = get_device_global_uid(output_sub_devices[0]).unwrap();
         let master_sub_device = master_sub_device_uid.get_raw();
@@ -536,29 +693,9 @@ impl AggregateDevice {
     ) -> std::result::Result<(), Error> {
         assert_ne!(device_id, kAudioObjectUnknown);
         debug_assert_running_serially();
-        let address = AudioObjectPropertyAddress {
-            mSelector: kAudioObjectPropertyOwnedObjects,
-            mScope: kAudioObjectPropertyScopeGlobal,
-            mElement: kAudioObjectPropertyElementMaster,
-        };
-
-        let qualifier_data_size = mem::size_of::<AudioObjectID>();
-        let class_id: AudioClassID = kAudioSubDeviceClassID;
-        let qualifier_data = &class_id;
-
-        let mut size: usize = 0;
-        let status = audio_object_get_property_data_size_with_qualifier(
-            device_id,
-            &address,
-            qualifier_data_size,
-            qualifier_data,
-            &mut size,
-        );
-        if status != NO_ERR {
-            return Err(Error::from(status));
-        }
-        assert!(size > 0);
-        let subdevices_num = size / mem::size_of::<AudioObjectID>();
+        let sub_devices = Self::get_owned_sub_devices(device_id)?;
+        assert!(!sub_devices.is_empty());
+        let subdevices_num = sub_devices.len();
         if subdevices_num < 2 {
             cubeb_log!(
                 "Aggregate-device {} contains {} sub-devices only.\
@@ -568,18 +705,6 @@ impl AggregateDevice {
             );
             return Err(Error::LessThan2Devices(subdevices_num));
         }
-        let mut sub_devices: Vec<AudioObjectID> = allocate_array(subdevices_num);
-        let status = audio_object_get_property_data_with_qualifier(
-            device_id,
-            &address,
-            qualifier_data_size,
-            qualifier_data,
-            &mut size,
-            sub_devices.as_mut_ptr(),
-        );
-        if status != NO_ERR {
-            return Err(Error::from(status));
-        }
 
         let master_sub_device_uid = Self::get_master_device_uid(device_id)?;
 
@@ -613,6 +738,50 @@ impl AggregateDevice {
         Ok(())
     }
 
+    fn get_owned_sub_devices(
+        device_id: AudioObjectID,
+    ) -> std::result::Result<Vec<AudioObjectID>, Error> {
+        debug_assert_running_serially();
+        let address = AudioObjectPropertyAddress {
+            mSelector: kAudioObjectPropertyOwnedObjects,
+            mScope: kAudioObjectPropertyScopeGlobal,
+            mElement: kAudioObjectPropertyElementMaster,
+        };
+
+        let qualifier_data_size = mem::size_of::<AudioObjectID>();
+        let class_id: AudioClassID = kAudioSubDeviceClassID;
+        let qualifier_data = &class_id;
+
+        let mut size: usize = 0;
+        let status = audio_object_get_property_data_size_with_qualifier(
+            device_id,
+            &address,
+            qualifier_data_size,
+            qualifier_data,
+            &mut size,
+        );
+        if status != NO_ERR {
+            return Err(Error::from(status));
+        }
+        let mut sub_devices: Vec<AudioObjectID> =
+            allocate_array(size / mem::size_of::<AudioObjectID>());
+        if sub_devices.is_empty() {
+            return Ok(sub_devices);
+        }
+        let status = audio_object_get_property_data_with_qualifier(
+            device_id,
+            &address,
+            qualifier_data_size,
+            qualifier_data,
+            &mut size,
+            sub_devices.as_mut_ptr(),
+        );
+        if status != NO_ERR {
+            return Err(Error::from(status));
+        }
+        Ok(sub_devices)
+    }
+
     pub fn destroy_device(
         plugin_id: AudioObjectID,
         mut device_id: AudioDeviceID,
@@ -634,12 +803,106 @@ impl AggregateDevice {
         }
         assert!(size > 0);
 
+        let uid = get_device_global_uid(device_id).map(|uid| uid.into_string());
         let status = audio_object_get_property_data(plugin_id, &address, &mut size, &mut device_id);
-        if status == NO_ERR {
-            Ok(())
-        } else {
-            Err(Error::from(status))
+        if status != NO_ERR {
+            return Err(Error::from(status));
         }
+        if let Ok(uid) = uid {
+            if let Err(e) = update_device_registry(|uids| uids.retain(|u| *u != uid)) {
+                cubeb_log!(
+                    "Cannot unregister aggregate device {}. Error: {}",
+                    device_id,
+                    e
+                );
+            }
+        }
+        Ok(())
+    }
+
+    // The pid of the process that created the aggregate device with `uid`, or None if `uid` isn't
+    // one of ours, or was created before the pid was part of it.
+    pub fn get_owner_pid(uid: &str) -> Option<u32> {
+        let suffix = uid
+            .strip_prefix(AGGREGATE_DEVICE_UID_PREFIX)?
+            .strip_prefix(PRIVATE_AGGREGATE_DEVICE_NAME)?
+            .strip_prefix('_')?;
+        let mut parts = suffix.split('_');
+        let pid = parts.next()?.parse().ok()?;
+        let _time_id: u128 = parts.next()?.parse().ok()?;
+        if parts.next().is_some() {
+            return None;
+        }
+        Some(pid)
+    }
+
+    // Destroy the aggregate devices created by processes that are gone, e.g. after a crash. They
+    // stay around until coreaudiod restarts otherwise. The devices registered by the processes of
+    // the user are checked, as well as the ones `get_devices` lists. Returns the number of
+    // destroyed devices.
+    pub fn destroy_stale_devices() -> std::result::Result<usize, Error> {
+        debug_assert_running_serially();
+        let plugin_id = Self::get_system_plugin_id()?;
+        let mut uids = update_device_registry(|uids| uids.clone()).unwrap_or_else(|e| {
+            cubeb_log!("Cannot read the aggregate device registry. Error: {}", e);
+            Vec::new()
+        });
+        for device_id in get_devices() {
+            if let Ok(uid) = get_device_global_uid(device_id) {
+                let uid = uid.into_string();
+                if !uids.contains(&uid) {
+                    uids.push(uid);
+                }
+            }
+        }
+        let mut destroyed = 0;
+        let mut gone = Vec::new();
+        for uid in uids {
+            let pid = match Self::get_owner_pid(&uid) {
+                Some(pid) if pid != std::process::id() && !process_exists(pid) => pid,
+                _ => continue,
+            };
+            let device_id = match get_device_by_uid(&uid) {
+                Some(device_id) => device_id,
+                None => {
+                    // Already removed, e.g. by the HAL when the process exited.
+                    gone.push(uid);
+                    continue;
+                }
+            };
+            match Self::destroy_device(plugin_id, device_id) {
+                Ok(()) => {
+                    cubeb_log!(
+                        "Destroyed stale aggregate device {} ({}) of process {}",
+                        device_id,
+                        uid,
+                        pid
+                    );
+                    destroyed += 1;
+                }
+                Err(e) => cubeb_log!(
+                    "Failed to destroy stale aggregate device {} ({}). Error: {}",
+                    device_id,
+                    uid,
+                    e
+                ),
+            }
+        }
+        if !gone.is_empty() {
+            if let Err(e) = update_device_registry(|uids| uids.retain(|u| !gone.contains(u))) {
+                cubeb_log!(
+                    "Cannot unregister the removed aggregate devices. Error: {}",
+                    e
+                );
+            }
+        }
+        Ok(destroyed)
+    }
+
+    // The uids of the aggregate devices the processes of the user created and haven't destroyed.
+    #[cfg(test)]
+    pub fn get_registered_device_uids() -> Vec<String> {
+        update_device_registry(|uids| uids.clone()).unwrap()
     }
 
     pub fn workaround_for_airpod(
@@ -653,13 +916,12 @@ impl AggregateDevice {
         assert_ne!(input_id, output_id);
         debug_assert_running_serially();
 
-        let label = get_device_label(input_id, DeviceType::INPUT)?;
-        let input_label = label.into_string();
+        let input_quirks = get_device_quirks(input_id, DeviceType::INPUT);
+        let output_quirks = get_device_quirks(output_id, DeviceType::OUTPUT);
 
-        let label = get_device_label(output_id, DeviceType::OUTPUT)?;
-        let output_label = label.into_string();
-
-        if input_label.contains("AirPods") && output_label.contains("AirPods") {
+        if input_quirks.contains(Quirks::AGGREGATE_INPUT_RATE_FOR_PAIR)
+            && output_quirks.contains(Quirks::AGGREGATE_INPUT_RATE_FOR_PAIR)
+        {
             let input_rate =
                 get_device_sample_rate(input_id, DeviceType::INPUT | DeviceType::OUTPUT)?;
             cubeb_log!(
@@ -700,6 +962,41 @@ impl AggregateDevice {
     }
 }
 
+// Run `update` on the uids of the aggregate device registry, with the registry locked against the
+// other processes, and save them.
+fn update_device_registry<F, R>(update: F) -> std::io::Result<R>
+where
+    F: FnOnce(&mut Vec<String>) -> R,
+{
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(std::env::temp_dir().join(AGGREGATE_DEVICE_REGISTRY_FILE_NAME))?;
+    // The lock is released when the file is closed.
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let mut content = String::new();
+    file.read_to_string(&mut content)?;
+    let mut uids = content.lines().map(String::from).collect::<Vec<_>>();
+    let result = update(&mut uids);
+    file.set_len(0)?;
+    file.seek(SeekFrom::Start(0))?;
+    for uid in &uids {
+        writeln!(file, "{}", uid)?;
+    }
+    Ok(result)
+}
+
+fn process_exists(pid: u32) -> bool {
+    // Signal 0 only checks whether the process can be signaled. EPERM means it exists, but is
+    // owned by another user.
+    let r = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    r == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
 impl Default for AggregateDevice {
     fn default() -> Self {
         Self {
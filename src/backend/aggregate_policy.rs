@@ -0,0 +1,256 @@
+// When to combine the input and output devices of a duplex stream into an aggregate device.
+//
+// An aggregate device lets one AudioUnit run the input and the output on a single clock, with
+// drift compensation, but it takes seconds to create, and it changes how the devices behave for
+// the other clients. Without it, the stream uses one AudioUnit per device and reclocks the input
+// when the devices are in different clock domains.
+//
+// One of the sub-devices of an aggregate device is its master clock, and the others are resampled
+// to follow it when drift compensation is on. The master should be the device with the steadier
+// clock.
+
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AggregateDevicePolicy {
+    // Never create an aggregate device, and accept the drift between the devices.
+    Never,
+    // Only when the devices don't share a clock domain, so they would drift apart.
+    WhenClockDomainsDiffer,
+    // Only for a mic-only input device and a speaker-only output device (BMO 1563475).
+    InputOnlyPlusOutputOnly,
+    // Whenever the devices can be aggregated. This is the historical behavior.
+    #[default]
+    Always,
+}
+
+impl FromStr for AggregateDevicePolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "never" => Ok(AggregateDevicePolicy::Never),
+            "when-clock-domains-differ" => Ok(AggregateDevicePolicy::WhenClockDomainsDiffer),
+            "input-only-plus-output-only" => Ok(AggregateDevicePolicy::InputOnlyPlusOutputOnly),
+            "always" => Ok(AggregateDevicePolicy::Always),
+            _ => Err(()),
+        }
+    }
+}
+
+// What the policy needs to know about the input and output devices of a duplex stream.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DuplexDeviceFacts {
+    pub same_device: bool,
+    // All the sub-devices of one device are part of the other device too, e.g. the output device
+    // is an aggregate device of the input device and some speakers. An aggregate device of both
+    // would add nothing.
+    pub nested_devices: bool,
+    pub same_clock_domain: bool,
+    // The input device has output channels too, e.g. a USB headset.
+    pub input_has_output: bool,
+    // The output device has input channels too.
+    pub output_has_input: bool,
+}
+
+pub fn should_use_aggregate_device(
+    policy: AggregateDevicePolicy,
+    facts: &DuplexDeviceFacts,
+) -> bool {
+    // It's unnecessary to create an aggregate device when opening the same device input/output,
+    // or a device that already contains the other one.
+    if facts.same_device || facts.nested_devices {
+        return false;
+    }
+    match policy {
+        AggregateDevicePolicy::Never => false,
+        AggregateDevicePolicy::WhenClockDomainsDiffer => !facts.same_clock_domain,
+        AggregateDevicePolicy::InputOnlyPlusOutputOnly => {
+            !facts.input_has_output && !facts.output_has_input
+        }
+        AggregateDevicePolicy::Always => true,
+    }
+}
+
+// What the master clock selection needs to know about a device.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ClockDeviceFacts {
+    pub uid: String,
+    // Bluetooth clocks follow the radio link, and are a poor reference for a wired device.
+    pub is_bluetooth: bool,
+    pub clock_domain: Option<u32>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MasterClock {
+    Input,
+    Output,
+}
+
+// Pick the device whose clock drives the aggregate device: the one with `preferred_uid` if any,
+// otherwise the output device, unless it's a Bluetooth device paired with a wired input device in
+// another clock domain.
+pub fn select_master_clock(
+    input: &ClockDeviceFacts,
+    output: &ClockDeviceFacts,
+    preferred_uid: Option<&str>,
+) -> MasterClock {
+    if let Some(uid) = preferred_uid {
+        if uid == output.uid {
+            return MasterClock::Output;
+        }
+        if uid == input.uid {
+            return MasterClock::Input;
+        }
+    }
+    let same_clock_domain =
+        input.clock_domain.is_some() && input.clock_domain == output.clock_domain;
+    if output.is_bluetooth && !input.is_bluetooth && !same_clock_domain {
+        return MasterClock::Input;
+    }
+    MasterClock::Output
+}
+
+#[cfg(test)]
+const POLICIES: [AggregateDevicePolicy; 4] = [
+    AggregateDevicePolicy::Never,
+    AggregateDevicePolicy::WhenClockDomainsDiffer,
+    AggregateDevicePolicy::InputOnlyPlusOutputOnly,
+    AggregateDevicePolicy::Always,
+];
+
+#[test]
+fn test_aggregate_device_policy_impossible_pairs() {
+    let impossible = [
+        DuplexDeviceFacts {
+            same_device: true,
+            ..Default::default()
+        },
+        DuplexDeviceFacts {
+            nested_devices: true,
+            ..Default::default()
+        },
+    ];
+    for policy in POLICIES {
+        for facts in &impossible {
+            assert!(!should_use_aggregate_device(policy, facts));
+        }
+    }
+}
+
+#[test]
+fn test_aggregate_device_policy() {
+    // A mic-only and a speaker-only device in different clock domains, e.g. the builtin ones.
+    let separate = DuplexDeviceFacts::default();
+    // A USB headset with a mic, used with the builtin speakers.
+    let headset_mic = DuplexDeviceFacts {
+        input_has_output: true,
+        ..Default::default()
+    };
+    // Two devices on the same clock.
+    let same_clock = DuplexDeviceFacts {
+        same_clock_domain: true,
+        ..Default::default()
+    };
+
+    let expectations = [
+        (AggregateDevicePolicy::Never, [false, false, false]),
+        (
+            AggregateDevicePolicy::WhenClockDomainsDiffer,
+            [true, true, false],
+        ),
+        (
+            AggregateDevicePolicy::InputOnlyPlusOutputOnly,
+            [true, false, true],
+        ),
+        (AggregateDevicePolicy::Always, [true, true, true]),
+    ];
+    for (policy, expected) in &expectations {
+        let results = [
+            should_use_aggregate_device(*policy, &separate),
+            should_use_aggregate_device(*policy, &headset_mic),
+            should_use_aggregate_device(*policy, &same_clock),
+        ];
+        assert_eq!(&results, expected, "{:?}", policy);
+    }
+}
+
+#[test]
+fn test_aggregate_device_policy_from_str() {
+    for (s, policy) in [
+        "never",
+        "when-clock-domains-differ",
+        "input-only-plus-output-only",
+        "always",
+    ]
+    .iter()
+    .zip(POLICIES.iter())
+    {
+        assert_eq!(s.parse::<AggregateDevicePolicy>(), Ok(*policy));
+    }
+    assert!("sometimes".parse::<AggregateDevicePolicy>().is_err());
+    assert_eq!(
+        AggregateDevicePolicy::default(),
+        AggregateDevicePolicy::Always
+    );
+}
+
+#[test]
+fn test_select_master_clock() {
+    let usb = ClockDeviceFacts {
+        uid: String::from("usb"),
+        is_bluetooth: false,
+        clock_domain: Some(1),
+    };
+    let builtin = ClockDeviceFacts {
+        uid: String::from("builtin"),
+        is_bluetooth: false,
+        clock_domain: Some(2),
+    };
+    let bluetooth = ClockDeviceFacts {
+        uid: String::from("bluetooth"),
+        is_bluetooth: true,
+        clock_domain: Some(3),
+    };
+
+    // The output device by default.
+    assert_eq!(
+        select_master_clock(&usb, &builtin, None),
+        MasterClock::Output
+    );
+    assert_eq!(
+        select_master_clock(&bluetooth, &usb, None),
+        MasterClock::Output
+    );
+    // A wired input device rather than a Bluetooth output device.
+    assert_eq!(
+        select_master_clock(&usb, &bluetooth, None),
+        MasterClock::Input
+    );
+    assert_eq!(
+        select_master_clock(&bluetooth, &bluetooth, None),
+        MasterClock::Output
+    );
+    // Unless they share a clock anyway.
+    let bluetooth_in_usb_domain = ClockDeviceFacts {
+        clock_domain: Some(1),
+        ..bluetooth.clone()
+    };
+    assert_eq!(
+        select_master_clock(&usb, &bluetooth_in_usb_domain, None),
+        MasterClock::Output
+    );
+    // The user knows best.
+    assert_eq!(
+        select_master_clock(&usb, &builtin, Some("usb")),
+        MasterClock::Input
+    );
+    assert_eq!(
+        select_master_clock(&usb, &bluetooth, Some("bluetooth")),
+        MasterClock::Output
+    );
+    assert_eq!(
+        select_master_clock(&usb, &bluetooth, Some("unknown")),
+        MasterClock::Input
+    );
+}
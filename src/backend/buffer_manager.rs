@@ -3,10 +3,13 @@ use std::fmt;
 use std::os::raw::c_void;
 use std::slice;
 
-use cubeb_backend::SampleFormat;
+use cubeb_backend::{InputProcessingParams, SampleFormat};
 use num::cast::AsPrimitive;
 
+use super::dither::{Dither, DitherMode};
+use super::input_processing::{EchoReferenceConsumer, InputProcessor};
 use super::ringbuf::RingBuffer;
+use super::voice_activity::{VoiceActivityDetector, VoiceActivityEvent};
 
 use self::LinearBuffer::*;
 use self::RingBufferConsumer::*;
@@ -37,16 +40,24 @@ fn drop_first_n_channels_in_place<T: Copy>(
     }
 }
 
-trait DataType: AsPrimitive<f32> {
-    fn from_f32(v: f32) -> Self;
+trait DataType: AsPrimitive<f32> + Default {
+    // The magnitude of a full scale sample.
+    const FULL_SCALE: f32;
+    // `v` is in the sample scale of `Self`. Conversions losing precision go through `dither`.
+    fn from_f32(v: f32, dither: &mut Dither, channel: usize) -> Self;
 }
 
-impl<T: AsPrimitive<f32>> DataType for T
-where
-    f32: AsPrimitive<T>,
-{
-    fn from_f32(v: f32) -> T {
-        v.as_()
+impl DataType for i16 {
+    const FULL_SCALE: f32 = 32768.0;
+    fn from_f32(v: f32, dither: &mut Dither, channel: usize) -> i16 {
+        dither.quantize(v, channel)
+    }
+}
+
+impl DataType for f32 {
+    const FULL_SCALE: f32 = 1.0;
+    fn from_f32(v: f32, _: &mut Dither, _: usize) -> f32 {
+        v
     }
 }
 
@@ -60,6 +71,7 @@ fn remix_or_drop_channels<T: DataType>(
     output_channels: usize,
     data: &mut [T],
     frame_count: usize,
+    dither: &mut Dither,
 ) -> usize {
     assert!(input_channels >= output_channels);
     // Nothing to do, just return
@@ -71,7 +83,7 @@ fn remix_or_drop_channels<T: DataType>(
         let mut read_idx = 0;
         for (write_idx, _) in (0..frame_count).enumerate() {
             let avg = (data[read_idx].as_() + data[read_idx + 1].as_()) / 2.0;
-            data[write_idx] = DataType::from_f32(avg);
+            data[write_idx] = DataType::from_f32(avg, dither, 0);
             read_idx += 2;
         }
         return output_channels * frame_count;
@@ -97,7 +109,8 @@ fn process_data<T: DataType>(
     input_channel_count: usize,
     input_channels_to_ignore: usize,
     output_channel_count: usize,
-) -> &'static [T] {
+    dither: &mut Dither,
+) -> &'static mut [T] {
     assert!(
         input_channels_to_ignore == 0
             || input_channel_count >= input_channels_to_ignore + output_channel_count
@@ -124,6 +137,7 @@ fn process_data<T: DataType>(
                 output_channel_count,
                 input_slice,
                 frame_count,
+                dither,
             );
             unsafe { slice::from_raw_parts_mut::<T>(data as *mut T, new_count_remixed) }
         }
@@ -135,6 +149,37 @@ fn process_data<T: DataType>(
     }
 }
 
+// Silence the input, after listening for voice activity in it.
+fn mute_data<T: DataType>(data: &mut [T], voice_activity: Option<&mut VoiceActivityDetector>) {
+    if let Some(detector) = voice_activity {
+        detector.process(data.iter().map(|s| s.as_() / T::FULL_SCALE));
+    }
+    for sample in data.iter_mut() {
+        *sample = T::default();
+    }
+}
+
+// Run the software input processing on `data`, holding interleaved frames of `channels` channels.
+fn process_input<T: DataType>(
+    data: &mut [T],
+    channels: usize,
+    processor: &mut InputProcessor,
+    scratch: &mut Vec<f32>,
+    dither: &mut Dither,
+) {
+    // Not even converted, so that 16-bit input isn't dithered for nothing.
+    if processor.params().is_empty() {
+        processor.skip(data.len() / channels);
+        return;
+    }
+    scratch.clear();
+    scratch.extend(data.iter().map(|s| s.as_() / T::FULL_SCALE));
+    processor.process(scratch);
+    for (i, (sample, processed)) in data.iter_mut().zip(scratch.iter()).enumerate() {
+        *sample = DataType::from_f32(processed * T::FULL_SCALE, dither, i % channels);
+    }
+}
+
 pub enum RingBufferConsumer {
     IntegerRingBufferConsumer(ringbuf::Consumer<i16>),
     FloatRingBufferConsumer(ringbuf::Consumer<f32>),
@@ -161,12 +206,22 @@ pub struct BufferManager {
     // The number of channels we actually needs, which is also the channel count of the
     // processed data stored in the internal ring buffer.
     output_channel_count: usize,
+    // Used when 16-bit input is downmixed in floating point.
+    dither: Dither,
+    // Set when the input is muted here rather than by the AudioUnit, so the voice activity detector
+    // can listen to it.
+    muted: bool,
+    voice_activity: Option<VoiceActivityDetector>,
+    // Used when the input can't be processed by VoiceProcessingIO.
+    input_processor: Option<InputProcessor>,
+    processing_buffer: Vec<f32>,
 }
 
 impl BufferManager {
     pub fn new(
         format: SampleFormat,
         buffer_size_frames: usize,
+        buffer_callbacks: usize,
         input_channel_count: usize,
         input_channels_to_ignore: usize,
         output_channel_count: usize,
@@ -175,9 +230,9 @@ impl BufferManager {
             (input_channels_to_ignore == 0 && input_channel_count == 1)
                 || input_channel_count >= input_channels_to_ignore + output_channel_count
         );
-        // 8 times the expected callback size, to handle the input callback being caled multiple
-        //   times in a row correctly.
-        let buffer_element_count = output_channel_count * buffer_size_frames * 8;
+        // `buffer_callbacks` times the expected callback size, to handle the input callback being
+        //   called multiple times in a row correctly.
+        let buffer_element_count = output_channel_count * buffer_size_frames * buffer_callbacks;
         match format {
             SampleFormat::S16LE | SampleFormat::S16BE | SampleFormat::S16NE => {
                 let ring = RingBuffer::<i16>::new(buffer_element_count);
@@ -191,6 +246,11 @@ impl BufferManager {
                     input_channel_count,
                     input_channels_to_ignore,
                     output_channel_count,
+                    dither: Dither::new(output_channel_count),
+                    muted: false,
+                    voice_activity: None,
+                    input_processor: None,
+                    processing_buffer: Vec::new(),
                 }
             }
             SampleFormat::Float32LE | SampleFormat::Float32BE | SampleFormat::Float32NE => {
@@ -205,6 +265,11 @@ impl BufferManager {
                     input_channel_count,
                     input_channels_to_ignore,
                     output_channel_count,
+                    dither: Dither::new(output_channel_count),
+                    muted: false,
+                    voice_activity: None,
+                    input_processor: None,
+                    processing_buffer: Vec::new(),
                 }
             }
         }
@@ -227,11 +292,56 @@ impl BufferManager {
     fn output_channel_count(&self) -> usize {
         self.output_channel_count
     }
+    pub fn set_dither_mode(&mut self, mode: DitherMode) {
+        self.dither.set_mode(mode);
+    }
+    pub fn enable_voice_activity_detection(&mut self, rate: u32) {
+        self.voice_activity = Some(VoiceActivityDetector::new(
+            rate,
+            self.stored_channel_count(),
+        ));
+    }
+    // Without `echo_reference`, the input can't be echo cancelled.
+    pub fn enable_input_processing(
+        &mut self,
+        rate: u32,
+        echo_reference: Option<EchoReferenceConsumer>,
+    ) {
+        let capacity = match &self.producer {
+            IntegerRingBufferProducer(p) => p.capacity(),
+            FloatRingBufferProducer(p) => p.capacity(),
+        };
+        self.processing_buffer = Vec::with_capacity(capacity);
+        self.input_processor = Some(InputProcessor::new(
+            rate,
+            self.stored_channel_count(),
+            echo_reference,
+        ));
+    }
+    pub fn set_input_processing_params(&mut self, params: InputProcessingParams) {
+        if let Some(processor) = self.input_processor.as_mut() {
+            processor.set_params(params);
+        }
+    }
+    pub fn set_muted(&mut self, muted: bool) {
+        if self.muted && !muted {
+            if let Some(detector) = self.voice_activity.as_mut() {
+                detector.reset();
+            }
+        }
+        self.muted = muted;
+    }
+    pub fn take_voice_activity_event(&mut self) -> Option<VoiceActivityEvent> {
+        self.voice_activity
+            .as_mut()
+            .and_then(VoiceActivityDetector::take_event)
+    }
     pub fn push_data(&mut self, data: *mut c_void, frame_count: usize) {
         let to_push = frame_count * self.stored_channel_count();
         let input_channel_count = self.input_channel_count();
         let input_channels_to_ignore = self.input_channels_to_ignore();
         let output_channel_count = self.output_channel_count();
+        let stored_channel_count = self.stored_channel_count();
         let pushed = match &mut self.producer {
             RingBufferProducer::FloatRingBufferProducer(p) => {
                 let processed_input = process_data(
@@ -240,7 +350,20 @@ impl BufferManager {
                     input_channel_count,
                     input_channels_to_ignore,
                     output_channel_count,
+                    &mut self.dither,
                 );
+                if let Some(processor) = self.input_processor.as_mut() {
+                    process_input(
+                        processed_input,
+                        stored_channel_count,
+                        processor,
+                        &mut self.processing_buffer,
+                        &mut self.dither,
+                    );
+                }
+                if self.muted {
+                    mute_data(processed_input, self.voice_activity.as_mut());
+                }
                 p.push_slice(processed_input)
             }
             RingBufferProducer::IntegerRingBufferProducer(p) => {
@@ -250,7 +373,20 @@ impl BufferManager {
                     input_channel_count,
                     input_channels_to_ignore,
                     output_channel_count,
+                    &mut self.dither,
                 );
+                if let Some(processor) = self.input_processor.as_mut() {
+                    process_input(
+                        processed_input,
+                        stored_channel_count,
+                        processor,
+                        &mut self.processing_buffer,
+                        &mut self.dither,
+                    );
+                }
+                if self.muted {
+                    mute_data(processed_input, self.voice_activity.as_mut());
+                }
                 p.push_slice(processed_input)
             }
         };
@@ -372,9 +508,84 @@ impl fmt::Debug for BufferManager {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::ptr;
     #[test]
     fn remix_stereo_ints() {
         let mut data = [i16::MAX / 2 + 1, i16::MAX / 2 + 1];
-        assert_eq!(remix_or_drop_channels(2, 1, &mut data, 1), 1);
+        assert_eq!(
+            remix_or_drop_channels(2, 1, &mut data, 1, &mut Dither::new(1)),
+            1
+        );
+    }
+    #[test]
+    fn remix_stereo_ints_dithered() {
+        // The average of 3 and 4 is 3.5, which truncation always turns into 3.
+        let mut data = [3, 4].repeat(1000);
+        let mut dither = Dither::new(1);
+        dither.set_mode(DitherMode::Tpdf);
+        assert_eq!(
+            remix_or_drop_channels(2, 1, &mut data, 1000, &mut dither),
+            1000
+        );
+        assert!(data[..1000].iter().all(|&v| (2..=5).contains(&v)));
+        let sum: i32 = data[..1000].iter().map(|&v| i32::from(v)).sum();
+        assert!((sum - 3500).abs() < 100);
+    }
+    #[test]
+    fn muted_with_voice_activity_detection() {
+        let mut manager = BufferManager::new(SampleFormat::S16NE, 4800, 2, 1, 0, 1);
+        manager.enable_voice_activity_detection(48000);
+        manager.set_muted(true);
+        // 100ms of talking. The data is muted in place.
+        let talking = || -> Vec<i16> {
+            (0..4800)
+                .map(|i| if i % 2 == 0 { 4000 } else { -4000 })
+                .collect()
+        };
+        let mut data = talking();
+        manager.push_data(data.as_mut_ptr() as *mut c_void, 4800);
+        assert_eq!(
+            manager.take_voice_activity_event(),
+            Some(VoiceActivityEvent::SpeechStarted)
+        );
+        let pulled = manager.get_linear_data(4800) as *const i16;
+        let pulled = unsafe { slice::from_raw_parts(pulled, 4800) };
+        assert!(pulled.iter().all(|&v| v == 0));
+
+        manager.set_muted(false);
+        assert_eq!(
+            manager.take_voice_activity_event(),
+            Some(VoiceActivityEvent::SpeechEnded)
+        );
+        let mut data = talking();
+        manager.push_data(data.as_mut_ptr() as *mut c_void, 4800);
+        assert_eq!(manager.take_voice_activity_event(), None);
+        let pulled = manager.get_linear_data(4800) as *const i16;
+        let pulled = unsafe { slice::from_raw_parts(pulled, 4800) };
+        assert_eq!(pulled[0], 4000);
+    }
+    #[test]
+    fn input_processing() {
+        let mut manager = BufferManager::new(SampleFormat::S16NE, 4800, 2, 1, 0, 1);
+        manager.enable_input_processing(48000, None);
+        let noise = || -> Vec<i16> { (0..4800).map(|i| if i % 2 == 0 { 8 } else { -8 }).collect() };
+
+        // Without params, the input is untouched.
+        let mut data = noise();
+        manager.push_data(data.as_mut_ptr() as *mut c_void, 4800);
+        let pulled = manager.get_linear_data(4800) as *const i16;
+        let pulled = unsafe { slice::from_raw_parts(pulled, 4800) };
+        assert_eq!(pulled, &noise()[..]);
+
+        // The noise suppression gates the quiet noise, after half a second.
+        manager.set_input_processing_params(InputProcessingParams::NOISE_SUPPRESSION);
+        let mut pulled = ptr::null();
+        for _ in 0..5 {
+            let mut data = noise();
+            manager.push_data(data.as_mut_ptr() as *mut c_void, 4800);
+            pulled = manager.get_linear_data(4800) as *const i16;
+        }
+        let pulled = unsafe { slice::from_raw_parts(pulled, 4800) };
+        assert!(pulled.iter().all(|&v| v.abs() <= 1));
     }
 }
@@ -0,0 +1,322 @@
+// Views of the device collection built on top of the `cubeb_device_info`s.
+//
+// The device-collection-changed callback carries no payload. The backend keeps the device infos it
+// saw last time, and tells which devices appeared, disappeared, or changed since then, so the
+// consumers don't have to enumerate the devices and diff them by themselves.
+//
+// `enumerate_devices` reports the input and output scopes of a device as separate entries. The
+// duplex view pairs them back into one entry per physical device.
+
+use cubeb_backend::ffi;
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DeviceCollectionChanges {
+    pub added: Vec<ffi::cubeb_devid>,
+    pub removed: Vec<ffi::cubeb_devid>,
+    // Devices present in both snapshots, whose default status, default rate or channel count
+    // changed.
+    pub changed: Vec<ffi::cubeb_devid>,
+}
+
+impl DeviceCollectionChanges {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+pub fn diff_device_collections(
+    old: &[ffi::cubeb_device_info],
+    new: &[ffi::cubeb_device_info],
+) -> DeviceCollectionChanges {
+    let mut changes = DeviceCollectionChanges::default();
+    for info in new {
+        match old.iter().find(|o| o.devid == info.devid) {
+            None => changes.added.push(info.devid),
+            Some(o) if device_info_changed(o, info) => changes.changed.push(info.devid),
+            Some(_) => {}
+        }
+    }
+    for info in old {
+        if !new.iter().any(|n| n.devid == info.devid) {
+            changes.removed.push(info.devid);
+        }
+    }
+    changes
+}
+
+fn device_info_changed(old: &ffi::cubeb_device_info, new: &ffi::cubeb_device_info) -> bool {
+    old.preferred != new.preferred
+        || old.default_rate != new.default_rate
+        || old.max_channels != new.max_channels
+}
+
+// One direction of a device, as reported by `create_cubeb_device_info`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeviceDirectionInfo {
+    pub devid: ffi::cubeb_devid,
+    pub device_id: Option<String>,
+    pub group_id: Option<String>,
+    pub friendly_name: String,
+    pub vendor_name: Option<String>,
+    pub device_type: ffi::cubeb_device_type,
+    pub state: ffi::cubeb_device_state,
+    pub preferred: ffi::cubeb_device_pref,
+    pub format: ffi::cubeb_device_fmt,
+    pub default_format: ffi::cubeb_device_fmt,
+    pub max_channels: u32,
+    pub default_rate: u32,
+    pub min_rate: u32,
+    pub max_rate: u32,
+    pub latency_lo: u32,
+    pub latency_hi: u32,
+}
+
+impl DeviceDirectionInfo {
+    pub fn new(info: &ffi::cubeb_device_info) -> Self {
+        Self {
+            devid: info.devid,
+            device_id: string_from_ptr(info.device_id),
+            group_id: string_from_ptr(info.group_id),
+            friendly_name: string_from_ptr(info.friendly_name).unwrap_or_default(),
+            vendor_name: string_from_ptr(info.vendor_name),
+            device_type: info.device_type,
+            state: info.state,
+            preferred: info.preferred,
+            format: info.format,
+            default_format: info.default_format,
+            max_channels: info.max_channels,
+            default_rate: info.default_rate,
+            min_rate: info.min_rate,
+            max_rate: info.max_rate,
+            latency_lo: info.latency_lo,
+            latency_hi: info.latency_hi,
+        }
+    }
+
+    // The returned strings are allocated like the ones of `create_cubeb_device_info`, and must be
+    // released the same way.
+    pub fn to_device_info(&self) -> ffi::cubeb_device_info {
+        ffi::cubeb_device_info {
+            devid: self.devid,
+            device_id: string_into_ptr(self.device_id.as_deref()),
+            group_id: string_into_ptr(self.group_id.as_deref()),
+            friendly_name: string_into_ptr(Some(&self.friendly_name)),
+            vendor_name: string_into_ptr(self.vendor_name.as_deref()),
+            device_type: self.device_type,
+            state: self.state,
+            preferred: self.preferred,
+            format: self.format,
+            default_format: self.default_format,
+            max_channels: self.max_channels,
+            default_rate: self.default_rate,
+            min_rate: self.min_rate,
+            max_rate: self.max_rate,
+            latency_lo: self.latency_lo,
+            latency_hi: self.latency_hi,
+        }
+    }
+}
+
+// Release the strings of an info returned by `DeviceDirectionInfo::to_device_info`.
+pub fn release_device_info(info: &mut ffi::cubeb_device_info) {
+    for s in [
+        &mut info.device_id,
+        &mut info.group_id,
+        &mut info.friendly_name,
+        &mut info.vendor_name,
+    ] {
+        if !s.is_null() {
+            drop(unsafe { CString::from_raw(*s as *mut c_char) });
+            *s = std::ptr::null();
+        }
+    }
+}
+
+fn string_from_ptr(s: *const c_char) -> Option<String> {
+    if s.is_null() {
+        None
+    } else {
+        Some(unsafe { CStr::from_ptr(s) }.to_string_lossy().into_owned())
+    }
+}
+
+fn string_into_ptr(s: Option<&str>) -> *const c_char {
+    s.map_or(std::ptr::null(), |s| {
+        // The strings come from C strings, so they have no interior nul.
+        CString::new(s).unwrap_or_default().into_raw()
+    })
+}
+
+// A physical device with its input and output directions, which may come from different
+// AudioObjectIDs sharing a group id (e.g. the builtin microphone and speakers).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DuplexDeviceInfo {
+    pub input: Option<DeviceDirectionInfo>,
+    pub output: Option<DeviceDirectionInfo>,
+}
+
+impl DuplexDeviceInfo {
+    pub fn group_id(&self) -> Option<&str> {
+        self.input
+            .as_ref()
+            .or(self.output.as_ref())
+            .and_then(|d| d.group_id.as_deref())
+    }
+}
+
+// Pair the output directions with the input directions of the same device first, then with the
+// ones of the same group.
+pub fn merge_duplex_devices(
+    inputs: Vec<DeviceDirectionInfo>,
+    outputs: Vec<DeviceDirectionInfo>,
+) -> Vec<DuplexDeviceInfo> {
+    let mut devices: Vec<DuplexDeviceInfo> = inputs
+        .into_iter()
+        .map(|input| DuplexDeviceInfo {
+            input: Some(input),
+            output: None,
+        })
+        .collect();
+    let mut unpaired = Vec::new();
+    for output in outputs {
+        let same_device = devices.iter_mut().find(|d| {
+            d.output.is_none() && d.input.as_ref().map(|i| i.devid) == Some(output.devid)
+        });
+        match same_device {
+            Some(device) => device.output = Some(output),
+            None => unpaired.push(output),
+        }
+    }
+    for output in unpaired {
+        let same_group = devices.iter_mut().find(|d| {
+            d.output.is_none()
+                && output.group_id.is_some()
+                && d.input.as_ref().and_then(|i| i.group_id.as_ref()) == output.group_id.as_ref()
+        });
+        match same_group {
+            Some(device) => device.output = Some(output),
+            None => devices.push(DuplexDeviceInfo {
+                input: None,
+                output: Some(output),
+            }),
+        }
+    }
+    devices
+}
+
+#[cfg(test)]
+fn test_device_info(devid: usize, rate: u32, channels: u32) -> ffi::cubeb_device_info {
+    ffi::cubeb_device_info {
+        devid: devid as ffi::cubeb_devid,
+        default_rate: rate,
+        max_channels: channels,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_diff_device_collections_unchanged() {
+    let devices = [test_device_info(1, 48000, 2), test_device_info(2, 44100, 1)];
+    let changes = diff_device_collections(&devices, &devices);
+    assert!(changes.is_empty());
+    // The order of the devices doesn't matter.
+    let reversed = [test_device_info(2, 44100, 1), test_device_info(1, 48000, 2)];
+    assert!(diff_device_collections(&devices, &reversed).is_empty());
+}
+
+#[test]
+fn test_diff_device_collections() {
+    let old = [
+        test_device_info(1, 48000, 2),
+        test_device_info(2, 44100, 1),
+        test_device_info(3, 48000, 2),
+    ];
+    let mut preferred = test_device_info(3, 48000, 2);
+    preferred.preferred = ffi::CUBEB_DEVICE_PREF_ALL;
+    let new = [
+        test_device_info(2, 48000, 1),
+        preferred,
+        test_device_info(4, 96000, 8),
+    ];
+    let changes = diff_device_collections(&old, &new);
+    assert_eq!(changes.added, vec![4 as ffi::cubeb_devid]);
+    assert_eq!(changes.removed, vec![1 as ffi::cubeb_devid]);
+    assert_eq!(
+        changes.changed,
+        vec![2 as ffi::cubeb_devid, 3 as ffi::cubeb_devid]
+    );
+    assert!(!changes.is_empty());
+}
+
+#[cfg(test)]
+fn test_direction_info(devid: usize, group_id: Option<&str>) -> DeviceDirectionInfo {
+    let mut info = DeviceDirectionInfo::new(&test_device_info(devid, 48000, 2));
+    info.group_id = group_id.map(String::from);
+    info
+}
+
+#[test]
+fn test_device_direction_info() {
+    let uid = std::ffi::CString::new("uid").unwrap();
+    let name = std::ffi::CString::new("name").unwrap();
+    let mut info = test_device_info(1, 44100, 2);
+    info.device_id = uid.as_ptr();
+    info.friendly_name = name.as_ptr();
+    let direction = DeviceDirectionInfo::new(&info);
+    assert_eq!(direction.devid, 1 as ffi::cubeb_devid);
+    assert_eq!(direction.device_id.as_deref(), Some("uid"));
+    assert_eq!(direction.group_id, None);
+    assert_eq!(direction.friendly_name, "name");
+    assert_eq!(direction.default_rate, 44100);
+    assert_eq!(direction.max_channels, 2);
+
+    let copy = direction.to_device_info();
+    assert_ne!(copy.device_id, info.device_id);
+    assert!(copy.group_id.is_null());
+    assert!(copy.vendor_name.is_null());
+    assert_eq!(DeviceDirectionInfo::new(&copy), direction);
+    unsafe {
+        let _ = CString::from_raw(copy.device_id as *mut _);
+        let _ = CString::from_raw(copy.friendly_name as *mut _);
+    }
+}
+
+#[test]
+fn test_merge_duplex_devices() {
+    let inputs = vec![
+        test_direction_info(1, Some("headset")),
+        test_direction_info(2, Some("builtin")),
+        test_direction_info(3, None),
+    ];
+    let outputs = vec![
+        test_direction_info(4, Some("builtin")),
+        test_direction_info(1, Some("headset")),
+        test_direction_info(5, None),
+        test_direction_info(6, Some("builtin")),
+    ];
+    let devices = merge_duplex_devices(inputs, outputs);
+    let ids: Vec<(Option<usize>, Option<usize>)> = devices
+        .iter()
+        .map(|d| {
+            (
+                d.input.as_ref().map(|i| i.devid as usize),
+                d.output.as_ref().map(|o| o.devid as usize),
+            )
+        })
+        .collect();
+    // The same device first, then the same group, and no group never matches.
+    assert_eq!(
+        ids,
+        vec![
+            (Some(1), Some(1)),
+            (Some(2), Some(4)),
+            (Some(3), None),
+            (None, Some(5)),
+            (None, Some(6)),
+        ]
+    );
+    assert_eq!(devices[1].group_id(), Some("builtin"));
+    assert_eq!(devices[3].group_id(), None);
+}
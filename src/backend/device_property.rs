@@ -44,6 +44,33 @@ pub fn get_devices() -> Vec<AudioObjectID> {
     devices
 }
 
+// The device whose uid is `uid`, or None if there is none. Unlike `get_devices`, this finds the
+// devices private to other processes too.
+pub fn get_device_by_uid(uid: &str) -> Option<AudioObjectID> {
+    debug_assert_running_serially();
+    let address = get_property_address(
+        Property::HardwareTranslateUIDToDevice,
+        DeviceType::INPUT | DeviceType::OUTPUT,
+    );
+    let uid = cfstringref_from_string(uid);
+    let mut size = mem::size_of::<AudioObjectID>();
+    let mut id = kAudioObjectUnknown;
+    let err = audio_object_get_property_data_with_qualifier(
+        kAudioObjectSystemObject,
+        &address,
+        mem::size_of::<CFStringRef>(),
+        &uid,
+        &mut size,
+        &mut id,
+    );
+    unsafe { CFRelease(uid as *const c_void) };
+    if err == NO_ERR && id != kAudioObjectUnknown {
+        Some(id)
+    } else {
+        None
+    }
+}
+
 pub fn get_device_model_uid(
     id: AudioDeviceID,
     devtype: DeviceType,
@@ -98,6 +125,48 @@ pub fn get_device_source(
     }
 }
 
+pub fn get_device_sources(
+    id: AudioDeviceID,
+    devtype: DeviceType,
+) -> std::result::Result<Vec<u32>, OSStatus> {
+    assert_ne!(id, kAudioObjectUnknown);
+    debug_assert_running_serially();
+
+    let address = get_property_address(Property::DeviceSources, devtype);
+
+    let mut size: usize = 0;
+    let err = audio_object_get_property_data_size(id, &address, &mut size);
+    if err != NO_ERR {
+        return Err(err);
+    }
+
+    let mut sources: Vec<u32> = allocate_array_by_size(size);
+    let err = audio_object_get_property_data(id, &address, &mut size, sources.as_mut_ptr());
+    if err == NO_ERR {
+        Ok(sources)
+    } else {
+        Err(err)
+    }
+}
+
+pub fn set_device_source(
+    id: AudioDeviceID,
+    devtype: DeviceType,
+    source: u32,
+) -> std::result::Result<(), OSStatus> {
+    assert_ne!(id, kAudioObjectUnknown);
+    debug_assert_running_serially();
+
+    let address = get_property_address(Property::DeviceSource, devtype);
+    let size = mem::size_of::<u32>();
+    let err = audio_object_set_property_data(id, &address, size, &source);
+    if err == NO_ERR {
+        Ok(())
+    } else {
+        Err(err)
+    }
+}
+
 pub fn get_device_source_name(
     id: AudioDeviceID,
     devtype: DeviceType,
@@ -105,7 +174,18 @@ pub fn get_device_source_name(
     assert_ne!(id, kAudioObjectUnknown);
     debug_assert_running_serially();
 
-    let mut source: u32 = get_device_source(id, devtype)?;
+    let source: u32 = get_device_source(id, devtype)?;
+    get_device_source_name_of(id, devtype, source)
+}
+
+pub fn get_device_source_name_of(
+    id: AudioDeviceID,
+    devtype: DeviceType,
+    mut source: u32,
+) -> std::result::Result<StringRef, OSStatus> {
+    assert_ne!(id, kAudioObjectUnknown);
+    debug_assert_running_serially();
+
     let address = get_property_address(Property::DeviceSourceName, devtype);
     let mut size = mem::size_of::<AudioValueTranslation>();
     let mut name: CFStringRef = ptr::null();
@@ -195,6 +275,78 @@ pub fn get_device_latency(
     }
 }
 
+pub fn get_device_safety_offset(
+    id: AudioDeviceID,
+    devtype: DeviceType,
+) -> std::result::Result<u32, OSStatus> {
+    assert_ne!(id, kAudioObjectUnknown);
+    debug_assert_running_serially();
+
+    let address = get_property_address(Property::DeviceSafetyOffset, devtype);
+    let mut size = mem::size_of::<u32>();
+    let mut offset: u32 = 0;
+    let err = audio_object_get_property_data(id, &address, &mut size, &mut offset);
+    if err == NO_ERR {
+        Ok(offset)
+    } else {
+        Err(err)
+    }
+}
+
+pub fn get_device_is_alive(
+    id: AudioDeviceID,
+    devtype: DeviceType,
+) -> std::result::Result<bool, OSStatus> {
+    assert_ne!(id, kAudioObjectUnknown);
+    debug_assert_running_serially();
+
+    let address = get_property_address(Property::DeviceIsAlive, devtype);
+    let mut size = mem::size_of::<u32>();
+    let mut alive: u32 = 0;
+    let err = audio_object_get_property_data(id, &address, &mut size, &mut alive);
+    if err == NO_ERR {
+        Ok(alive != 0)
+    } else {
+        Err(err)
+    }
+}
+
+pub fn get_device_is_hidden(
+    id: AudioDeviceID,
+    devtype: DeviceType,
+) -> std::result::Result<bool, OSStatus> {
+    assert_ne!(id, kAudioObjectUnknown);
+    debug_assert_running_serially();
+
+    let address = get_property_address(Property::DeviceIsHidden, devtype);
+    let mut size = mem::size_of::<u32>();
+    let mut hidden: u32 = 0;
+    let err = audio_object_get_property_data(id, &address, &mut size, &mut hidden);
+    if err == NO_ERR {
+        Ok(hidden != 0)
+    } else {
+        Err(err)
+    }
+}
+
+pub fn get_device_jack_is_connected(
+    id: AudioDeviceID,
+    devtype: DeviceType,
+) -> std::result::Result<bool, OSStatus> {
+    assert_ne!(id, kAudioObjectUnknown);
+    debug_assert_running_serially();
+
+    let address = get_property_address(Property::DeviceJackIsConnected, devtype);
+    let mut size = mem::size_of::<u32>();
+    let mut connected: u32 = 0;
+    let err = audio_object_get_property_data(id, &address, &mut size, &mut connected);
+    if err == NO_ERR {
+        Ok(connected != 0)
+    } else {
+        Err(err)
+    }
+}
+
 #[derive(Debug)]
 pub struct DeviceStream {
     pub device: AudioDeviceID,
@@ -393,18 +545,23 @@ pub fn get_clock_domain(
 pub enum Property {
     DeviceBufferFrameSizeRange,
     DeviceIsAlive,
+    DeviceIsHidden,
+    DeviceJackIsConnected,
     DeviceLatency,
     DeviceManufacturer,
     DeviceName,
+    DeviceSafetyOffset,
     DeviceSampleRate,
     DeviceSampleRates,
     DeviceSource,
     DeviceSourceName,
+    DeviceSources,
     DeviceStreams,
     DeviceUID,
     HardwareDefaultInputDevice,
     HardwareDefaultOutputDevice,
     HardwareDevices,
+    HardwareTranslateUIDToDevice,
     ModelUID,
     StreamLatency,
     StreamTerminalType,
@@ -418,18 +575,23 @@ impl From<Property> for AudioObjectPropertySelector {
         match p {
             Property::DeviceBufferFrameSizeRange => kAudioDevicePropertyBufferFrameSizeRange,
             Property::DeviceIsAlive => kAudioDevicePropertyDeviceIsAlive,
+            Property::DeviceIsHidden => kAudioDevicePropertyIsHidden,
+            Property::DeviceJackIsConnected => kAudioDevicePropertyJackIsConnected,
             Property::DeviceLatency => kAudioDevicePropertyLatency,
             Property::DeviceManufacturer => kAudioObjectPropertyManufacturer,
             Property::DeviceName => kAudioObjectPropertyName,
+            Property::DeviceSafetyOffset => kAudioDevicePropertySafetyOffset,
             Property::DeviceSampleRate => kAudioDevicePropertyNominalSampleRate,
             Property::DeviceSampleRates => kAudioDevicePropertyAvailableNominalSampleRates,
             Property::DeviceSource => kAudioDevicePropertyDataSource,
             Property::DeviceSourceName => kAudioDevicePropertyDataSourceNameForIDCFString,
+            Property::DeviceSources => kAudioDevicePropertyDataSources,
             Property::DeviceStreams => kAudioDevicePropertyStreams,
             Property::DeviceUID => kAudioDevicePropertyDeviceUID,
             Property::HardwareDefaultInputDevice => kAudioHardwarePropertyDefaultInputDevice,
             Property::HardwareDefaultOutputDevice => kAudioHardwarePropertyDefaultOutputDevice,
             Property::HardwareDevices => kAudioHardwarePropertyDevices,
+            Property::HardwareTranslateUIDToDevice => kAudioHardwarePropertyTranslateUIDToDevice,
             Property::ModelUID => kAudioDevicePropertyModelUID,
             Property::StreamLatency => kAudioStreamPropertyLatency,
             Property::StreamTerminalType => kAudioStreamPropertyTerminalType,
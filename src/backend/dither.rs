@@ -0,0 +1,179 @@
+// Dither applied when floating-point audio is quantized to 16-bit samples.
+//
+// Plain truncation (or rounding) to 16 bits produces quantization error that is correlated with
+// the signal, which is audible as distortion on quiet material. Adding triangular (TPDF) noise of
+// 2 LSB peak-to-peak before rounding decorrelates the error from the signal. Optional first-order
+// error-feedback noise shaping then pushes that noise floor towards high frequencies.
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DitherMode {
+    // Convert without dither (truncation towards zero). This is the historical behavior.
+    #[default]
+    Disabled,
+    // Triangular probability density function dither, then round to nearest.
+    Tpdf,
+    // TPDF dither with first-order error-feedback noise shaping.
+    TpdfNoiseShaped,
+}
+
+// Per-stream dither state. Must only be used from one thread (the audio callback thread) since
+// it carries the random generator state and the noise shaping error of each channel.
+#[derive(Debug)]
+pub struct Dither {
+    mode: DitherMode,
+    // xorshift32 state. A cheap, allocation-free generator is enough for dither noise.
+    seed: u32,
+    // The quantization error of the previous sample of each channel, in LSB.
+    errors: Vec<f32>,
+}
+
+impl Dither {
+    // `channels` is the channel count of the stream the dither is created with, so that the
+    // render callback never has to grow `errors`.
+    pub fn new(channels: usize) -> Self {
+        Self {
+            mode: DitherMode::default(),
+            seed: 0x9E37_79B9,
+            errors: vec![0.0; channels],
+        }
+    }
+
+    pub fn mode(&self) -> DitherMode {
+        self.mode
+    }
+
+    pub fn set_mode(&mut self, mode: DitherMode) {
+        if self.mode != mode {
+            self.mode = mode;
+            self.reset();
+        }
+    }
+
+    pub fn reset(&mut self) {
+        for error in self.errors.iter_mut() {
+            *error = 0.0;
+        }
+    }
+
+    // Quantize `value`, expressed in 16-bit LSB units (i.e. a float sample scaled by 32768 or a
+    // 16-bit sample processed in floating point), to an i16 sample of `channel`.
+    pub fn quantize(&mut self, value: f32, channel: usize) -> i16 {
+        match self.mode {
+            DitherMode::Disabled => value as i16,
+            DitherMode::Tpdf => {
+                let noise = self.triangular_noise();
+                round_to_i16(value + noise)
+            }
+            DitherMode::TpdfNoiseShaped => {
+                // This runs on the audio thread, so a channel out of range is only caught in
+                // debug builds, and gets plain TPDF dither otherwise.
+                debug_assert!(channel < self.errors.len());
+                let noise = self.triangular_noise();
+                let error = match self.errors.get_mut(channel) {
+                    Some(error) => error,
+                    None => return round_to_i16(value + noise),
+                };
+                let target = value - *error;
+                let output = round_to_i16(target + noise);
+                // Bound the error so a clipped sample doesn't make the feedback run away.
+                *error = (f32::from(output) - target).clamp(-MAX_SHAPED_ERROR, MAX_SHAPED_ERROR);
+                output
+            }
+        }
+    }
+
+    // Sum of two independent uniform values in [-0.5, 0.5), giving a triangular distribution
+    // over (-1, 1) LSB.
+    fn triangular_noise(&mut self) -> f32 {
+        self.uniform_noise() + self.uniform_noise()
+    }
+
+    fn uniform_noise(&mut self) -> f32 {
+        let mut x = self.seed;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.seed = x;
+        // Keep the 24 most significant bits, which a f32 can represent exactly.
+        (x >> 8) as f32 / (1 << 24) as f32 - 0.5
+    }
+}
+
+// The largest error an unclipped sample can produce: 0.5 LSB of rounding plus 1 LSB of dither.
+const MAX_SHAPED_ERROR: f32 = 1.5;
+
+fn round_to_i16(value: f32) -> i16 {
+    value
+        .round()
+        .clamp(f32::from(i16::MIN), f32::from(i16::MAX)) as i16
+}
+
+#[test]
+fn test_dither_disabled_truncates() {
+    let mut dither = Dither::new(1);
+    assert_eq!(dither.mode(), DitherMode::Disabled);
+    assert_eq!(dither.quantize(1.9, 0), 1);
+    assert_eq!(dither.quantize(-1.9, 0), -1);
+    assert_eq!(dither.quantize(40000.0, 0), i16::MAX);
+    assert_eq!(dither.quantize(-40000.0, 0), i16::MIN);
+}
+
+#[test]
+fn test_dither_tpdf_is_bounded_and_unbiased() {
+    let mut dither = Dither::new(1);
+    dither.set_mode(DitherMode::Tpdf);
+    let value = 100.25;
+    let rounds = 10000;
+    let mut sum = 0.0;
+    for _ in 0..rounds {
+        let output = dither.quantize(value, 0);
+        // TPDF noise is within (-1, 1) LSB, so rounding lands within 1.5 LSB of the value.
+        assert!((f32::from(output) - value).abs() <= 1.5);
+        sum += f64::from(output);
+    }
+    // Dither makes the quantized signal average out to the original value.
+    let mean = sum / f64::from(rounds);
+    assert!((mean - f64::from(value)).abs() < 0.05);
+}
+
+#[test]
+fn test_dither_clamps_to_full_scale() {
+    for mode in &[DitherMode::Tpdf, DitherMode::TpdfNoiseShaped] {
+        let mut dither = Dither::new(1);
+        dither.set_mode(*mode);
+        for _ in 0..100 {
+            assert_eq!(dither.quantize(40000.0, 0), i16::MAX);
+        }
+        for _ in 0..100 {
+            assert_eq!(dither.quantize(-40000.0, 0), i16::MIN);
+        }
+    }
+}
+
+#[test]
+fn test_dither_noise_shaping_moves_error_to_high_frequencies() {
+    // First-order shaping makes the total error E(z)(1 - z^-1), so the sum of the errors over a
+    // block telescopes to the last error, while plain TPDF errors accumulate like a random walk.
+    let block = 4096;
+    let value = 12.3;
+
+    let mut shaped = Dither::new(2);
+    shaped.set_mode(DitherMode::TpdfNoiseShaped);
+    let mut shaped_sum = 0.0;
+    for _ in 0..block {
+        // Interleaved stereo: each channel keeps its own error.
+        shaped_sum += f32::from(shaped.quantize(value, 0)) - value;
+        let _ = shaped.quantize(-value, 1);
+    }
+    assert!(shaped_sum.abs() <= MAX_SHAPED_ERROR + 0.01);
+
+    let mut flat = Dither::new(1);
+    flat.set_mode(DitherMode::Tpdf);
+    let mut flat_energy = 0.0;
+    for _ in 0..block {
+        let error = f32::from(flat.quantize(value, 0)) - value;
+        flat_energy += error * error;
+    }
+    // Sanity check: the unshaped error has a non-trivial noise floor.
+    assert!(flat_energy / block as f32 > 0.1);
+}
@@ -0,0 +1,292 @@
+// Software gain stage used for the stream volume.
+//
+// Changing the gain of a playing stream in one step produces an audible click. The gain moves
+// towards its target one frame at a time instead, either in a straight line, or exponentially,
+// which sounds linear in loudness.
+
+use std::cmp;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum VolumeRamp {
+    #[default]
+    Linear,
+    Exponential,
+}
+
+// An exponential ramp can't start from or reach 0. Ramp from or to -60 dB instead, and jump the
+// rest of the way.
+const EXPONENTIAL_RAMP_FLOOR: f32 = 0.001;
+
+pub trait GainSample: Copy {
+    fn apply_gain(self, gain: f32) -> Self;
+}
+
+impl GainSample for f32 {
+    fn apply_gain(self, gain: f32) -> f32 {
+        self * gain
+    }
+}
+
+impl GainSample for i16 {
+    fn apply_gain(self, gain: f32) -> i16 {
+        (f32::from(self) * gain)
+            .round()
+            .clamp(f32::from(i16::MIN), f32::from(i16::MAX)) as i16
+    }
+}
+
+#[derive(Debug)]
+pub struct GainRamp {
+    gain: f32,
+    target: f32,
+    shape: VolumeRamp,
+    // Increment (linear) or factor (exponential) applied to the gain on every frame.
+    step: f32,
+    remaining_frames: usize,
+}
+
+impl GainRamp {
+    pub fn new(gain: f32) -> Self {
+        Self {
+            gain,
+            target: gain,
+            shape: VolumeRamp::default(),
+            step: 0.0,
+            remaining_frames: 0,
+        }
+    }
+
+    pub fn gain(&self) -> f32 {
+        self.gain
+    }
+
+    pub fn is_ramping(&self) -> bool {
+        self.remaining_frames > 0
+    }
+
+    // Start moving from the current gain to `target` over `frames` frames. Does nothing if
+    // `target` is already the destination, so it is fine to call this on every callback.
+    pub fn set_target(&mut self, target: f32, shape: VolumeRamp, frames: usize) {
+        if target == self.target {
+            return;
+        }
+        self.target = target;
+        self.shape = shape;
+        if frames == 0 || target == self.gain {
+            self.gain = target;
+            self.remaining_frames = 0;
+            return;
+        }
+        self.remaining_frames = frames;
+        match shape {
+            VolumeRamp::Linear => {
+                self.step = (target - self.gain) / frames as f32;
+            }
+            VolumeRamp::Exponential => {
+                self.gain = self.gain.max(EXPONENTIAL_RAMP_FLOOR);
+                let end = target.max(EXPONENTIAL_RAMP_FLOOR);
+                self.step = (end / self.gain).powf(1.0 / frames as f32);
+            }
+        }
+    }
+
+    // Apply the gain to `buffer`, holding interleaved frames of `channels` channels.
+    pub fn process<T: GainSample>(&mut self, buffer: &mut [T], channels: usize) {
+        assert!(channels > 0);
+        if !self.is_ramping() {
+            if self.gain != 1.0 {
+                for sample in buffer.iter_mut() {
+                    *sample = sample.apply_gain(self.gain);
+                }
+            }
+            return;
+        }
+        for frame in buffer.chunks_mut(channels) {
+            let gain = self.next_gain();
+            for sample in frame.iter_mut() {
+                *sample = sample.apply_gain(gain);
+            }
+        }
+    }
+
+    fn next_gain(&mut self) -> f32 {
+        if self.remaining_frames == 0 {
+            return self.gain;
+        }
+        self.remaining_frames -= 1;
+        if self.remaining_frames == 0 {
+            // Land exactly on the target, whatever the accumulated rounding.
+            self.gain = self.target;
+        } else {
+            match self.shape {
+                VolumeRamp::Linear => self.gain += self.step,
+                VolumeRamp::Exponential => self.gain *= self.step,
+            }
+        }
+        self.gain
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FadeRequest {
+    None,
+    // Start from silence and fade in.
+    In,
+    // Fade out, and stay silent until the next fade in.
+    Out,
+}
+
+// Fade-in on start and fade-out on stop or drain, applied on top of the volume.
+#[derive(Debug)]
+pub struct Fader {
+    gain: GainRamp,
+}
+
+impl Default for Fader {
+    fn default() -> Self {
+        Self {
+            gain: GainRamp::new(1.0),
+        }
+    }
+}
+
+impl Fader {
+    pub fn request(&mut self, request: FadeRequest, frames: usize) {
+        match request {
+            FadeRequest::None => {}
+            FadeRequest::In => {
+                self.gain = GainRamp::new(0.0);
+                self.gain.set_target(1.0, VolumeRamp::Linear, frames);
+            }
+            FadeRequest::Out => self.gain.set_target(0.0, VolumeRamp::Linear, frames),
+        }
+    }
+
+    // True once a fade-out has completed.
+    pub fn is_silent(&self) -> bool {
+        self.gain.gain() == 0.0 && !self.gain.is_ramping()
+    }
+
+    // Apply the fade to `buffer`. When the stream is draining, `buffer` is the last audio of the
+    // stream, and the last `drain_fade_frames` frames are faded out. The drain is only known once
+    // the data callback returns short, when the audio before this buffer has already been played,
+    // so the fade is cut to the frames of `buffer` if it has fewer than `drain_fade_frames`.
+    pub fn process<T: GainSample>(
+        &mut self,
+        buffer: &mut [T],
+        channels: usize,
+        drain_fade_frames: usize,
+    ) {
+        let fade_start = buffer.len() - cmp::min(drain_fade_frames * channels, buffer.len());
+        let (head, tail) = buffer.split_at_mut(fade_start);
+        self.gain.process(head, channels);
+        if !tail.is_empty() {
+            self.gain
+                .set_target(0.0, VolumeRamp::Linear, tail.len() / channels);
+            self.gain.process(tail, channels);
+        }
+    }
+}
+
+#[test]
+fn test_gain_ramp_unity_is_transparent() {
+    let mut ramp = GainRamp::new(1.0);
+    let mut buffer = [0.5_f32, -0.25, 1.0];
+    ramp.process(&mut buffer, 1);
+    assert_eq!(buffer, [0.5, -0.25, 1.0]);
+}
+
+#[test]
+fn test_gain_ramp_immediate() {
+    let mut ramp = GainRamp::new(1.0);
+    ramp.set_target(0.5, VolumeRamp::Linear, 0);
+    assert!(!ramp.is_ramping());
+    let mut buffer = [1000_i16, -1000, 3];
+    ramp.process(&mut buffer, 1);
+    assert_eq!(buffer, [500, -500, 2]);
+}
+
+#[test]
+fn test_gain_ramp_linear() {
+    let mut ramp = GainRamp::new(0.0);
+    ramp.set_target(1.0, VolumeRamp::Linear, 4);
+    // Stereo: both channels of a frame get the same gain.
+    let mut buffer = [1.0_f32; 12];
+    ramp.process(&mut buffer, 2);
+    let expected = [
+        0.25, 0.25, 0.5, 0.5, 0.75, 0.75, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0,
+    ];
+    for (value, expected) in buffer.iter().zip(expected.iter()) {
+        assert!(approx_eq!(f32, *value, *expected, epsilon = 1e-6));
+    }
+    assert!(!ramp.is_ramping());
+    assert_eq!(ramp.gain(), 1.0);
+}
+
+#[test]
+fn test_gain_ramp_exponential() {
+    let mut ramp = GainRamp::new(1.0);
+    ramp.set_target(0.0, VolumeRamp::Exponential, 3);
+    let mut buffer = [1.0_f32; 4];
+    ramp.process(&mut buffer, 1);
+    // From 0 dB to the -60 dB floor in three steps of -20 dB, landing on the target.
+    assert!(approx_eq!(f32, buffer[0], 0.1, epsilon = 1e-6));
+    assert!(approx_eq!(f32, buffer[1], 0.01, epsilon = 1e-6));
+    assert_eq!(buffer[2], 0.0);
+    assert_eq!(buffer[3], 0.0);
+}
+
+#[test]
+fn test_gain_ramp_retarget_is_continuous() {
+    let mut ramp = GainRamp::new(1.0);
+    ramp.set_target(0.0, VolumeRamp::Linear, 10);
+    let mut buffer = [1.0_f32; 5];
+    ramp.process(&mut buffer, 1);
+    assert!(approx_eq!(f32, ramp.gain(), 0.5, epsilon = 1e-6));
+    // Setting the same target again doesn't restart the ramp.
+    ramp.set_target(0.0, VolumeRamp::Linear, 10);
+    assert!(approx_eq!(f32, ramp.gain(), 0.5, epsilon = 1e-6));
+    // A new target starts from the current gain.
+    ramp.set_target(1.0, VolumeRamp::Linear, 5);
+    let mut buffer = [1.0_f32; 1];
+    ramp.process(&mut buffer, 1);
+    assert!(approx_eq!(f32, buffer[0], 0.6, epsilon = 1e-6));
+}
+
+#[test]
+fn test_fader_in_and_out() {
+    let mut fader = Fader::default();
+    fader.request(FadeRequest::In, 4);
+    let mut buffer = [1.0_f32; 6];
+    fader.process(&mut buffer, 1, 0);
+    assert_eq!(buffer, [0.25, 0.5, 0.75, 1.0, 1.0, 1.0]);
+    assert!(!fader.is_silent());
+
+    fader.request(FadeRequest::Out, 2);
+    let mut buffer = [1.0_f32; 3];
+    fader.process(&mut buffer, 1, 0);
+    assert_eq!(buffer, [0.5, 0.0, 0.0]);
+    assert!(fader.is_silent());
+
+    // Without a duration, fading in restores the gain right away.
+    fader.request(FadeRequest::In, 0);
+    let mut buffer = [1.0_f32; 2];
+    fader.process(&mut buffer, 1, 0);
+    assert_eq!(buffer, [1.0, 1.0]);
+}
+
+#[test]
+fn test_fader_drain() {
+    let mut fader = Fader::default();
+    // Stereo, fade out the last two frames.
+    let mut buffer = [1.0_f32; 8];
+    fader.process(&mut buffer, 2, 2);
+    assert_eq!(buffer, [1.0, 1.0, 1.0, 1.0, 0.5, 0.5, 0.0, 0.0]);
+    assert!(fader.is_silent());
+
+    // The fade is limited to the frames of the buffer.
+    let mut fader = Fader::default();
+    let mut buffer = [1.0_f32; 2];
+    fader.process(&mut buffer, 1, 100);
+    assert_eq!(buffer, [0.5, 0.0]);
+}
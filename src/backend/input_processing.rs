@@ -0,0 +1,726 @@
+// Input processing done in software, for the streams that can't use VoiceProcessingIO, e.g. on
+// Monterey, or with the device pairs it is blocked for.
+//
+// The chain runs in the input callback, on interleaved samples in [-1.0, 1.0]:
+// - A high-pass filter removes the rumble and hum below the voice. Part of the noise suppression.
+// - An echo canceller subtracts from the input the echo of what the same duplex stream plays,
+//   estimated by an NLMS adaptive filter over the recent output, the echo reference. The reference
+//   is delayed by the latency of the echo path first, so the filter only covers what's left of it.
+// - A noise gate attenuates the input when it is too quiet to be the voice. Part of the noise
+//   suppression too.
+// - An automatic gain control brings the level of the voice towards a target, without boosting
+//   the silence between words.
+//
+// This is much simpler than what VoiceProcessingIO does, but it is better than nothing.
+
+use cubeb_backend::InputProcessingParams;
+use std::f32::consts::PI;
+use std::fmt;
+
+const HIGH_PASS_CUTOFF_HZ: f32 = 80.0;
+
+// How much output the echo canceller remembers, after the delay of the echo path. Covers the
+// timing of the callbacks, which the delay doesn't account for, plus the early reflections of a
+// small room.
+//
+// The filter runs in the input callback and costs two multiply-adds per tap for each input sample:
+// 768 taps at 48 kHz are about 74 million multiply-adds per second and per input channel, which
+// the compiler vectorizes. Keep it short, as it grows with the rate and the channels of the input.
+const ECHO_FILTER_DURATION_MS: u32 = 16;
+const ECHO_STEP_SIZE: f32 = 0.1;
+// Added to the power of the reference so the filter doesn't blow up when the output is quiet.
+const ECHO_REGULARIZATION: f32 = 1e-3;
+// The echo reference older than this when it is read is dropped, e.g. when the output keeps playing
+// while the input is stalled. The delay of the echo path is capped to it too.
+const ECHO_REFERENCE_MAX_DELAY_MS: u32 = 200;
+
+const GATE_THRESHOLD_DBFS: f32 = -50.0;
+// Attenuation of the input while the gate is closed, -30 dB. Not silence, so the gate doesn't pump.
+const GATE_FLOOR: f32 = 0.031_622_8;
+const GATE_ATTACK_MS: f32 = 5.0;
+const GATE_RELEASE_MS: f32 = 150.0;
+
+const AGC_TARGET_DBFS: f32 = -18.0;
+// 24 dB up, and 18 dB down.
+const AGC_MAX_GAIN: f32 = 15.848_932;
+const AGC_MIN_GAIN: f32 = 0.125_892_5;
+// The level of the voice is only measured while it is louder than this.
+const AGC_ACTIVITY_DBFS: f32 = -45.0;
+const AGC_LEVEL_MS: f32 = 300.0;
+// The gain goes down faster than it goes up, so loud speech doesn't clip for long.
+const AGC_ATTACK_MS: f32 = 200.0;
+const AGC_RELEASE_MS: f32 = 2000.0;
+
+fn from_dbfs(dbfs: f32) -> f32 {
+    10.0_f32.powf(dbfs / 20.0)
+}
+
+// Per-sample smoothing factor of a one-pole filter with the time constant `ms`.
+fn smoothing_coefficient(ms: f32, rate: u32) -> f32 {
+    1.0 - (-1000.0 / (ms * rate as f32)).exp()
+}
+
+// The mono mix of what a duplex stream plays, passed from the output callback to the input
+// callback.
+pub struct EchoReferenceProducer {
+    producer: ringbuf::Producer<f32>,
+}
+
+pub struct EchoReferenceConsumer {
+    consumer: ringbuf::Consumer<f32>,
+    // Frames between the output callback playing the reference and the input callback getting its
+    // echo.
+    delay: usize,
+}
+
+impl fmt::Debug for EchoReferenceConsumer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("EchoReferenceConsumer")
+            .field("len", &self.consumer.len())
+            .field("delay", &self.delay)
+            .finish()
+    }
+}
+
+// `delay` is the latency of the echo path, in frames, from the output callback to the input
+// callback.
+pub fn echo_reference(rate: u32, delay: u32) -> (EchoReferenceProducer, EchoReferenceConsumer) {
+    let max_delay = rate * ECHO_REFERENCE_MAX_DELAY_MS / 1000;
+    // Twice the delay that is kept, so the output callback doesn't have to wait for the input one.
+    let capacity = (2 * max_delay) as usize;
+    let (producer, consumer) = ringbuf::RingBuffer::<f32>::new(capacity).split();
+    (
+        EchoReferenceProducer { producer },
+        EchoReferenceConsumer {
+            consumer,
+            delay: delay.min(max_delay) as usize,
+        },
+    )
+}
+
+impl EchoReferenceProducer {
+    // `samples` are interleaved frames of `channels` channels.
+    pub fn push<I: IntoIterator<Item = f32>>(&mut self, samples: I, channels: usize) {
+        assert!(channels > 0);
+        let mut sum = 0.0;
+        for (i, sample) in samples.into_iter().enumerate() {
+            sum += sample;
+            if (i + 1) % channels == 0 {
+                // Dropped when the input callback is late. It catches up on the delay anyway.
+                let _ = self.producer.push(sum / channels as f32);
+                sum = 0.0;
+            }
+        }
+    }
+}
+
+// Second order Butterworth high-pass filter, in transposed direct form II.
+#[derive(Clone, Debug)]
+struct HighPassFilter {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+    z1: f32,
+    z2: f32,
+}
+
+impl HighPassFilter {
+    fn new(cutoff: f32, rate: u32) -> Self {
+        let w0 = 2.0 * PI * cutoff / rate as f32;
+        let alpha = w0.sin() / 2.0_f32.sqrt();
+        let cos_w0 = w0.cos();
+        let a0 = 1.0 + alpha;
+        Self {
+            b0: (1.0 + cos_w0) / 2.0 / a0,
+            b1: -(1.0 + cos_w0) / a0,
+            b2: (1.0 + cos_w0) / 2.0 / a0,
+            a1: -2.0 * cos_w0 / a0,
+            a2: (1.0 - alpha) / a0,
+            z1: 0.0,
+            z2: 0.0,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.z1 = 0.0;
+        self.z2 = 0.0;
+    }
+
+    fn process(&mut self, x: f32) -> f32 {
+        let y = self.b0 * x + self.z1;
+        self.z1 = self.b1 * x - self.a1 * y + self.z2;
+        self.z2 = self.b2 * x - self.a2 * y;
+        y
+    }
+}
+
+// NLMS adaptive filter estimating the echo of the reference in one channel of the input.
+#[derive(Debug)]
+struct EchoFilter {
+    weights: Vec<f32>,
+}
+
+impl EchoFilter {
+    fn new(length: usize) -> Self {
+        Self {
+            weights: vec![0.0; length],
+        }
+    }
+
+    fn reset(&mut self) {
+        self.weights.iter_mut().for_each(|w| *w = 0.0);
+    }
+
+    // `history` is the reference, oldest first, and `power` its sum of squares.
+    fn process(&mut self, x: f32, history: &[f32], power: f32) -> f32 {
+        assert_eq!(history.len(), self.weights.len());
+        let estimate: f32 = self
+            .weights
+            .iter()
+            .zip(history.iter())
+            .map(|(w, h)| w * h)
+            .sum();
+        let error = x - estimate;
+        let step = ECHO_STEP_SIZE * error / (power + ECHO_REGULARIZATION);
+        for (w, h) in self.weights.iter_mut().zip(history.iter()) {
+            *w += step * h;
+        }
+        error
+    }
+}
+
+// Delays the echo reference by the latency of the echo path.
+#[derive(Debug)]
+struct DelayLine {
+    samples: Vec<f32>,
+    position: usize,
+}
+
+impl DelayLine {
+    fn new(delay: usize) -> Self {
+        Self {
+            samples: vec![0.0; delay],
+            position: 0,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.samples.iter_mut().for_each(|s| *s = 0.0);
+    }
+
+    fn process(&mut self, x: f32) -> f32 {
+        if self.samples.is_empty() {
+            return x;
+        }
+        let y = std::mem::replace(&mut self.samples[self.position], x);
+        self.position = (self.position + 1) % self.samples.len();
+        y
+    }
+}
+
+#[derive(Debug)]
+struct EchoCanceller {
+    reference: EchoReferenceConsumer,
+    max_delay: usize,
+    delay_line: DelayLine,
+    // The last `length` reference samples, stored twice so that they can always be read as one
+    // slice, `history[position + 1..position + 1 + length]`.
+    history: Vec<f32>,
+    position: usize,
+    power: f32,
+    filters: Vec<EchoFilter>,
+}
+
+impl EchoCanceller {
+    fn new(reference: EchoReferenceConsumer, rate: u32, channels: usize) -> Self {
+        let length = (rate * ECHO_FILTER_DURATION_MS / 1000).max(1) as usize;
+        let delay = reference.delay;
+        Self {
+            reference,
+            max_delay: (rate * ECHO_REFERENCE_MAX_DELAY_MS / 1000) as usize,
+            delay_line: DelayLine::new(delay),
+            history: vec![0.0; 2 * length],
+            position: length - 1,
+            power: 0.0,
+            filters: (0..channels).map(|_| EchoFilter::new(length)).collect(),
+        }
+    }
+
+    fn length(&self) -> usize {
+        self.filters[0].weights.len()
+    }
+
+    fn reset(&mut self) {
+        self.delay_line.reset();
+        self.history.iter_mut().for_each(|h| *h = 0.0);
+        self.power = 0.0;
+        self.filters.iter_mut().for_each(EchoFilter::reset);
+    }
+
+    // Drop the reference that is too old to be in the input.
+    fn catch_up(&mut self) {
+        let len = self.reference.consumer.len();
+        if len > self.max_delay {
+            self.reference.consumer.discard(len - self.max_delay);
+        }
+    }
+
+    fn push_reference(&mut self, sample: f32) {
+        let length = self.length();
+        self.position = (self.position + 1) % length;
+        let oldest = self.history[self.position];
+        self.power = (self.power + sample * sample - oldest * oldest).max(0.0);
+        self.history[self.position] = sample;
+        self.history[self.position + length] = sample;
+    }
+
+    fn process_frame(&mut self, frame: &mut [f32]) {
+        // Without reference, e.g. before the output starts, the output is silent.
+        let reference = self.reference.consumer.pop().unwrap_or(0.0);
+        let reference = self.delay_line.process(reference);
+        self.push_reference(reference);
+        let length = self.length();
+        let history = &self.history[self.position + 1..self.position + 1 + length];
+        for (sample, filter) in frame.iter_mut().zip(self.filters.iter_mut()) {
+            *sample = filter.process(*sample, history, self.power);
+        }
+    }
+}
+
+#[derive(Debug)]
+struct NoiseGate {
+    threshold: f32,
+    attack: f32,
+    release: f32,
+    gain: f32,
+}
+
+impl NoiseGate {
+    fn new(rate: u32) -> Self {
+        Self {
+            threshold: from_dbfs(GATE_THRESHOLD_DBFS),
+            attack: smoothing_coefficient(GATE_ATTACK_MS, rate),
+            release: smoothing_coefficient(GATE_RELEASE_MS, rate),
+            gain: 1.0,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.gain = 1.0;
+    }
+
+    fn process_frame(&mut self, frame: &mut [f32], level: f32) {
+        let (target, coefficient) = if level > self.threshold {
+            (1.0, self.attack)
+        } else {
+            (GATE_FLOOR, self.release)
+        };
+        self.gain += (target - self.gain) * coefficient;
+        frame.iter_mut().for_each(|s| *s *= self.gain);
+    }
+}
+
+#[derive(Debug)]
+struct AutomaticGainControl {
+    target: f32,
+    activity: f32,
+    level_coefficient: f32,
+    attack: f32,
+    release: f32,
+    // Mean square of the voice.
+    level: f32,
+    gain: f32,
+}
+
+impl AutomaticGainControl {
+    fn new(rate: u32) -> Self {
+        let target = from_dbfs(AGC_TARGET_DBFS);
+        Self {
+            target,
+            activity: from_dbfs(AGC_ACTIVITY_DBFS),
+            level_coefficient: smoothing_coefficient(AGC_LEVEL_MS, rate),
+            attack: smoothing_coefficient(AGC_ATTACK_MS, rate),
+            release: smoothing_coefficient(AGC_RELEASE_MS, rate),
+            level: target * target,
+            gain: 1.0,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.level = self.target * self.target;
+        self.gain = 1.0;
+    }
+
+    fn process_frame(&mut self, frame: &mut [f32], level: f32) {
+        if level > self.activity {
+            let power = frame.iter().map(|s| s * s).sum::<f32>() / frame.len() as f32;
+            self.level += (power - self.level) * self.level_coefficient;
+        }
+        let target = (self.target / self.level.sqrt()).clamp(AGC_MIN_GAIN, AGC_MAX_GAIN);
+        let coefficient = if target < self.gain {
+            self.attack
+        } else {
+            self.release
+        };
+        self.gain += (target - self.gain) * coefficient;
+        frame
+            .iter_mut()
+            .for_each(|s| *s = (*s * self.gain).clamp(-1.0, 1.0));
+    }
+}
+
+#[derive(Debug)]
+pub struct InputProcessor {
+    channels: usize,
+    params: InputProcessingParams,
+    high_pass: Vec<HighPassFilter>,
+    echo_canceller: Option<EchoCanceller>,
+    gate: NoiseGate,
+    agc: AutomaticGainControl,
+    // Smoothed absolute value of the input, to detect the voice.
+    envelope: f32,
+    envelope_coefficient: f32,
+}
+
+impl InputProcessor {
+    // Without `echo_reference`, e.g. for an input-only stream, there is no echo cancellation.
+    pub fn new(rate: u32, channels: usize, echo_reference: Option<EchoReferenceConsumer>) -> Self {
+        assert!(rate > 0);
+        assert!(channels > 0);
+        Self {
+            channels,
+            params: InputProcessingParams::NONE,
+            high_pass: vec![HighPassFilter::new(HIGH_PASS_CUTOFF_HZ, rate); channels],
+            echo_canceller: echo_reference.map(|r| EchoCanceller::new(r, rate, channels)),
+            gate: NoiseGate::new(rate),
+            agc: AutomaticGainControl::new(rate),
+            envelope: 0.0,
+            envelope_coefficient: smoothing_coefficient(GATE_ATTACK_MS, rate),
+        }
+    }
+
+    pub fn supported_params(&self) -> InputProcessingParams {
+        let mut params = InputProcessingParams::NOISE_SUPPRESSION
+            | InputProcessingParams::AUTOMATIC_GAIN_CONTROL;
+        if self.echo_canceller.is_some() {
+            params |= InputProcessingParams::ECHO_CANCELLATION;
+        }
+        params
+    }
+
+    pub fn params(&self) -> InputProcessingParams {
+        self.params
+    }
+
+    // The unsupported params are ignored. The stages being enabled start over.
+    pub fn set_params(&mut self, params: InputProcessingParams) {
+        let params = params & self.supported_params();
+        let enabled = params - self.params;
+        if enabled.contains(InputProcessingParams::NOISE_SUPPRESSION) {
+            self.high_pass.iter_mut().for_each(HighPassFilter::reset);
+            self.gate.reset();
+        }
+        if enabled.contains(InputProcessingParams::ECHO_CANCELLATION) {
+            if let Some(echo_canceller) = self.echo_canceller.as_mut() {
+                echo_canceller.reset();
+            }
+        }
+        if enabled.contains(InputProcessingParams::AUTOMATIC_GAIN_CONTROL) {
+            self.agc.reset();
+        }
+        self.params = params;
+    }
+
+    // Keep the echo reference in sync with `frames` frames of input that aren't echo cancelled.
+    pub fn skip(&mut self, frames: usize) {
+        if let Some(echo_canceller) = self.echo_canceller.as_mut() {
+            echo_canceller.catch_up();
+            echo_canceller.reference.consumer.discard(frames);
+        }
+    }
+
+    // `data` holds interleaved frames of `channels` channels.
+    pub fn process(&mut self, data: &mut [f32]) {
+        let aec = self
+            .params
+            .contains(InputProcessingParams::ECHO_CANCELLATION);
+        let ns = self
+            .params
+            .contains(InputProcessingParams::NOISE_SUPPRESSION);
+        let agc = self
+            .params
+            .contains(InputProcessingParams::AUTOMATIC_GAIN_CONTROL);
+        if aec {
+            self.echo_canceller.as_mut().unwrap().catch_up();
+        } else {
+            self.skip(data.len() / self.channels);
+        }
+        if self.params.is_empty() {
+            return;
+        }
+        for frame in data.chunks_mut(self.channels) {
+            if ns {
+                for (sample, filter) in frame.iter_mut().zip(self.high_pass.iter_mut()) {
+                    *sample = filter.process(*sample);
+                }
+            }
+            if aec {
+                self.echo_canceller.as_mut().unwrap().process_frame(frame);
+            }
+            let peak = frame.iter().fold(0.0_f32, |peak, s| peak.max(s.abs()));
+            self.envelope += (peak - self.envelope) * self.envelope_coefficient;
+            if ns {
+                self.gate.process_frame(frame, self.envelope);
+            }
+            if agc {
+                self.agc.process_frame(frame, self.envelope);
+            }
+        }
+    }
+}
+
+// VoiceProcessingIO cancels echo and suppresses noise together, or does neither when bypassed: it
+// has no property to control them separately on macOS. Returns the params to apply with
+// VoiceProcessingIO and the ones to apply in software for `params`, so that the other combinations
+// are bypassed and done in software instead, when `software` supports them. That includes echo
+// cancellation without noise suppression, when there is an echo reference to cancel it with.
+pub fn split_voice_processing_params(
+    params: InputProcessingParams,
+    software: InputProcessingParams,
+) -> Option<(InputProcessingParams, InputProcessingParams)> {
+    let aec_and_ns =
+        InputProcessingParams::ECHO_CANCELLATION | InputProcessingParams::NOISE_SUPPRESSION;
+    if params.contains(aec_and_ns) {
+        Some((params, InputProcessingParams::NONE))
+    } else if software.contains(params) {
+        Some((InputProcessingParams::NONE, params))
+    } else {
+        None
+    }
+}
+
+// All the combinations of params that can be applied to a stream.
+pub fn supported_param_combinations<F: Fn(InputProcessingParams) -> bool>(
+    can_apply: F,
+) -> Vec<InputProcessingParams> {
+    let all = InputProcessingParams::ECHO_CANCELLATION
+        | InputProcessingParams::NOISE_SUPPRESSION
+        | InputProcessingParams::AUTOMATIC_GAIN_CONTROL;
+    (0..=all.bits())
+        .filter(|bits| bits & !all.bits() == 0)
+        .map(InputProcessingParams::from_bits_truncate)
+        .filter(|params| can_apply(*params))
+        .collect()
+}
+
+#[cfg(test)]
+fn test_sine(rate: u32, frequency: f32, amplitude: f32, frames: usize, offset: usize) -> Vec<f32> {
+    (offset..offset + frames)
+        .map(|i| amplitude * (2.0 * PI * frequency * i as f32 / rate as f32).sin())
+        .collect()
+}
+
+#[cfg(test)]
+fn test_rms(data: &[f32]) -> f32 {
+    (data.iter().map(|s| s * s).sum::<f32>() / data.len() as f32).sqrt()
+}
+
+#[test]
+fn test_input_processor_disabled_is_transparent() {
+    let mut processor = InputProcessor::new(48000, 1, None);
+    let input = test_sine(48000, 440.0, 0.5, 480, 0);
+    let mut data = input.clone();
+    processor.process(&mut data);
+    assert_eq!(data, input);
+}
+
+#[test]
+fn test_input_processor_supported_params() {
+    let mut processor = InputProcessor::new(48000, 1, None);
+    assert_eq!(
+        processor.supported_params(),
+        InputProcessingParams::NOISE_SUPPRESSION | InputProcessingParams::AUTOMATIC_GAIN_CONTROL
+    );
+    processor.set_params(
+        InputProcessingParams::ECHO_CANCELLATION | InputProcessingParams::NOISE_SUPPRESSION,
+    );
+    assert_eq!(processor.params(), InputProcessingParams::NOISE_SUPPRESSION);
+
+    let (_, consumer) = echo_reference(48000, 0);
+    let processor = InputProcessor::new(48000, 1, Some(consumer));
+    assert!(processor
+        .supported_params()
+        .contains(InputProcessingParams::ECHO_CANCELLATION));
+}
+
+#[test]
+fn test_input_processor_noise_suppression() {
+    let rate = 48000;
+    let mut processor = InputProcessor::new(rate, 2, None);
+    processor.set_params(InputProcessingParams::NOISE_SUPPRESSION);
+
+    // Rumble is filtered out.
+    let mut data: Vec<f32> = test_sine(rate, 10.0, 0.5, rate as usize, 0);
+    data = data.iter().flat_map(|s| vec![*s, *s]).collect();
+    processor.process(&mut data);
+    assert!(test_rms(&data[data.len() / 2..]) < 0.05 * 0.5 / 2.0_f32.sqrt());
+
+    // Quiet noise is attenuated by the gate.
+    let mut data = test_sine(rate, 1000.0, 0.001, rate as usize, 0);
+    data = data.iter().flat_map(|s| vec![*s, *s]).collect();
+    processor.process(&mut data);
+    assert!(test_rms(&data[data.len() / 2..]) < 0.1 * 0.001);
+
+    // The voice goes through.
+    let mut data = test_sine(rate, 1000.0, 0.1, rate as usize / 2, 0);
+    data = data.iter().flat_map(|s| vec![*s, *s]).collect();
+    processor.process(&mut data);
+    let rms = test_rms(&data[data.len() / 2..]);
+    assert!(rms > 0.9 * 0.1 / 2.0_f32.sqrt());
+}
+
+#[test]
+fn test_input_processor_automatic_gain_control() {
+    let rate = 48000;
+    let mut processor = InputProcessor::new(rate, 1, None);
+    processor.set_params(InputProcessingParams::AUTOMATIC_GAIN_CONTROL);
+    let target = from_dbfs(AGC_TARGET_DBFS);
+
+    // A quiet voice is brought up to the target.
+    let mut data = test_sine(rate, 300.0, 0.02, 10 * rate as usize, 0);
+    processor.process(&mut data);
+    let rms = test_rms(&data[data.len() - rate as usize..]);
+    assert!((rms - target).abs() < 0.1 * target);
+
+    // A loud one is brought down.
+    let mut data = test_sine(rate, 300.0, 0.9, 4 * rate as usize, 0);
+    processor.process(&mut data);
+    let rms = test_rms(&data[data.len() - rate as usize..]);
+    assert!((rms - target).abs() < 0.1 * target);
+
+    // Silence isn't boosted.
+    let mut data = vec![0.0001; rate as usize];
+    processor.process(&mut data);
+    assert!(data.iter().all(|s| s.abs() < 0.001));
+}
+
+// Play white noise through an echo path of `delay` frames, of which the echo canceller is told
+// `estimated_delay`, and return the residual and the echo power over the last half second.
+#[cfg(test)]
+fn test_echo_cancellation(rate: u32, delay: usize, estimated_delay: u32) -> (f32, f32) {
+    let (mut producer, consumer) = echo_reference(rate, estimated_delay);
+    let mut processor = InputProcessor::new(rate, 1, Some(consumer));
+    processor.set_params(InputProcessingParams::ECHO_CANCELLATION);
+
+    // The echo is the output, delayed and attenuated.
+    let block = rate as usize / 100;
+    let mut output = vec![0.0_f32; delay];
+    let mut seed: u32 = 1;
+    let mut residual = 0.0;
+    let mut echo = 0.0;
+    for n in 0..300 {
+        // White noise in [-0.25, 0.25].
+        let played: Vec<f32> = (0..block)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                ((seed >> 16) as f32 / 32768.0 - 1.0) / 4.0
+            })
+            .collect();
+        producer.push(played.iter().copied(), 1);
+        output.extend_from_slice(&played);
+        let mut input: Vec<f32> = output.drain(..block).map(|s| 0.5 * s).collect();
+        let captured = test_rms(&input);
+        processor.process(&mut input);
+        if n >= 250 {
+            residual += test_rms(&input);
+            echo += captured;
+        }
+    }
+    (residual, echo)
+}
+
+#[test]
+fn test_input_processor_echo_cancellation() {
+    // An echo path within the filter.
+    let (residual, echo) = test_echo_cancellation(16000, 160, 0);
+    // At least 20 dB of echo return loss enhancement.
+    assert!(residual < 0.1 * echo);
+}
+
+#[test]
+fn test_input_processor_echo_cancellation_with_device_latency() {
+    // The echo paths of USB and Bluetooth devices are longer than the filter. They are still
+    // cancelled once the reference is delayed by the estimated latency, even when the estimate is
+    // off by a few milliseconds.
+    let rate = 16000;
+    for delay_ms in [20, 40, 60] {
+        let delay = rate as usize * delay_ms / 1000;
+        let (residual, echo) = test_echo_cancellation(rate, delay, delay as u32 - rate / 200);
+        assert!(residual < 0.1 * echo, "delay: {}ms", delay_ms);
+        // Without the delay, the filter misses the echo.
+        let (residual, echo) = test_echo_cancellation(rate, delay, 0);
+        assert!(residual > 0.5 * echo, "delay: {}ms", delay_ms);
+    }
+}
+
+#[test]
+fn test_split_voice_processing_params() {
+    let aec = InputProcessingParams::ECHO_CANCELLATION;
+    let ns = InputProcessingParams::NOISE_SUPPRESSION;
+    let agc = InputProcessingParams::AUTOMATIC_GAIN_CONTROL;
+    let none = InputProcessingParams::NONE;
+    let software = aec | ns | agc;
+
+    // VoiceProcessingIO does what it can on its own.
+    assert_eq!(
+        split_voice_processing_params(aec | ns, software),
+        Some((aec | ns, none))
+    );
+    assert_eq!(
+        split_voice_processing_params(aec | ns | agc, software),
+        Some((aec | ns | agc, none))
+    );
+    assert_eq!(
+        split_voice_processing_params(none, software),
+        Some((none, none))
+    );
+
+    // Bypassed for the rest, including echo cancellation without noise suppression.
+    assert_eq!(
+        split_voice_processing_params(aec, software),
+        Some((none, aec))
+    );
+    assert_eq!(
+        split_voice_processing_params(aec | agc, software),
+        Some((none, aec | agc))
+    );
+    assert_eq!(
+        split_voice_processing_params(ns | agc, software),
+        Some((none, ns | agc))
+    );
+    assert_eq!(
+        split_voice_processing_params(agc, software),
+        Some((none, agc))
+    );
+
+    // Without echo reference, e.g. for an input-only stream.
+    assert_eq!(split_voice_processing_params(aec, ns | agc), None);
+    assert_eq!(
+        split_voice_processing_params(aec | ns, ns | agc),
+        Some((aec | ns, none))
+    );
+}
+
+#[test]
+fn test_supported_param_combinations() {
+    let aec = InputProcessingParams::ECHO_CANCELLATION;
+    let ns = InputProcessingParams::NOISE_SUPPRESSION;
+    let agc = InputProcessingParams::AUTOMATIC_GAIN_CONTROL;
+    assert_eq!(supported_param_combinations(|_| true).len(), 8);
+    assert_eq!(
+        supported_param_combinations(|params| !params.contains(aec)),
+        vec![InputProcessingParams::NONE, ns, agc, ns | agc]
+    );
+}
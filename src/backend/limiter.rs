@@ -0,0 +1,169 @@
+// Peak control applied to the output of the mixer.
+//
+// Downmixing sums several channels into one, and the floating-point mixer doesn't normalize its
+// coefficients, so a loud multichannel stream can go over full scale once mixed. The limiter keeps
+// the mixed signal under a ceiling, either with a gain envelope shared by all the channels of a
+// frame (so the stereo image doesn't shift), or with a per-sample soft clipping curve.
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LimiterMode {
+    #[default]
+    Disabled,
+    // Instant attack, exponential release gain envelope. No lookahead, so no added latency.
+    Limiter,
+    // Memoryless saturation curve, linear below the knee.
+    SoftClip,
+}
+
+// -1 dBFS, leaving some headroom for the inter-sample peaks.
+const CEILING: f32 = 0.891_250_9;
+// The soft clipping curve starts bending at -6 dBFS.
+const SOFT_CLIP_KNEE: f32 = 0.501_187_2;
+const RELEASE_TIME_SECONDS: f32 = 0.05;
+// Below this distance (about 0.001 dB), the envelope jumps to its target. Otherwise the release
+// stalls short of unity gain because of the f32 precision.
+const ENVELOPE_SNAP: f32 = 1e-4;
+
+#[derive(Debug)]
+pub struct Limiter {
+    mode: LimiterMode,
+    // Per-sample smoothing factor of the envelope release.
+    release_coefficient: f32,
+    // The gain currently applied by the limiter.
+    envelope: f32,
+    // The lowest gain applied since the last call to `take_gain_reduction`.
+    min_gain: f32,
+}
+
+impl Limiter {
+    pub fn new(sample_rate: u32) -> Self {
+        assert!(sample_rate > 0);
+        Self {
+            mode: LimiterMode::default(),
+            release_coefficient: 1.0 - (-1.0 / (RELEASE_TIME_SECONDS * sample_rate as f32)).exp(),
+            envelope: 1.0,
+            min_gain: 1.0,
+        }
+    }
+
+    pub fn mode(&self) -> LimiterMode {
+        self.mode
+    }
+
+    pub fn set_mode(&mut self, mode: LimiterMode) {
+        if self.mode != mode {
+            self.mode = mode;
+            self.envelope = 1.0;
+        }
+    }
+
+    // `frame` holds one sample per channel, with full scale being 1.0.
+    pub fn process_frame(&mut self, frame: &mut [f32]) {
+        match self.mode {
+            LimiterMode::Disabled => {}
+            LimiterMode::Limiter => {
+                let peak = frame.iter().fold(0.0_f32, |peak, s| peak.max(s.abs()));
+                let target = if peak > CEILING { CEILING / peak } else { 1.0 };
+                if target < self.envelope || target - self.envelope < ENVELOPE_SNAP {
+                    self.envelope = target;
+                } else {
+                    self.envelope += (target - self.envelope) * self.release_coefficient;
+                }
+                for sample in frame.iter_mut() {
+                    // Clamping only catches the rounding of the gain computation.
+                    *sample = (*sample * self.envelope).clamp(-CEILING, CEILING);
+                }
+                self.min_gain = self.min_gain.min(self.envelope);
+            }
+            LimiterMode::SoftClip => {
+                for sample in frame.iter_mut() {
+                    let magnitude = sample.abs();
+                    if magnitude > SOFT_CLIP_KNEE {
+                        let clipped = soft_clip(magnitude);
+                        self.min_gain = self.min_gain.min(clipped / magnitude);
+                        *sample = clipped.copysign(*sample);
+                    }
+                }
+            }
+        }
+    }
+
+    // The largest gain reduction applied since the previous call, in dB (0 means untouched).
+    pub fn take_gain_reduction(&mut self) -> f32 {
+        let reduction = -20.0 * self.min_gain.log10();
+        self.min_gain = 1.0;
+        reduction
+    }
+}
+
+// Linear up to the knee, then a tanh curve tangent to the linear part that tends to the ceiling.
+fn soft_clip(magnitude: f32) -> f32 {
+    let range = CEILING - SOFT_CLIP_KNEE;
+    SOFT_CLIP_KNEE + range * ((magnitude - SOFT_CLIP_KNEE) / range).tanh()
+}
+
+#[test]
+fn test_limiter_disabled_is_transparent() {
+    let mut limiter = Limiter::new(48000);
+    assert_eq!(limiter.mode(), LimiterMode::Disabled);
+    let mut frame = [2.0, -3.0];
+    limiter.process_frame(&mut frame);
+    assert_eq!(frame, [2.0, -3.0]);
+    assert_eq!(limiter.take_gain_reduction(), 0.0);
+}
+
+#[test]
+fn test_limiter_keeps_peaks_under_ceiling() {
+    for mode in &[LimiterMode::Limiter, LimiterMode::SoftClip] {
+        let mut limiter = Limiter::new(48000);
+        limiter.set_mode(*mode);
+        for i in 0..4800 {
+            let value = 4.0 * (i as f32 * 0.05).sin();
+            let mut frame = [value, -value * 0.5];
+            limiter.process_frame(&mut frame);
+            assert!(frame.iter().all(|s| s.abs() <= CEILING));
+        }
+        // 4.0 down to at most the ceiling is at least 13 dB of reduction.
+        assert!(limiter.take_gain_reduction() > 13.0);
+        assert_eq!(limiter.take_gain_reduction(), 0.0);
+    }
+}
+
+#[test]
+fn test_limiter_is_linked_and_releases() {
+    let mut limiter = Limiter::new(48000);
+    limiter.set_mode(LimiterMode::Limiter);
+    let mut frame = [2.0 * CEILING, 0.5];
+    limiter.process_frame(&mut frame);
+    // The same gain is applied to every channel of the frame.
+    assert!(approx_eq!(f32, frame[0], CEILING, epsilon = 1e-6));
+    assert!(approx_eq!(f32, frame[1], 0.25, epsilon = 1e-6));
+    assert!(approx_eq!(
+        f32,
+        limiter.take_gain_reduction(),
+        -20.0 * 0.5_f32.log10(),
+        epsilon = 1e-3
+    ));
+
+    // After a second of quiet signal the envelope is back to unity.
+    for _ in 0..48000 {
+        let mut frame = [0.1, 0.1];
+        limiter.process_frame(&mut frame);
+    }
+    let mut frame = [0.1, 0.1];
+    limiter.process_frame(&mut frame);
+    assert!(approx_eq!(f32, frame[0], 0.1, epsilon = 1e-6));
+}
+
+#[test]
+fn test_soft_clip_is_linear_below_knee() {
+    let mut limiter = Limiter::new(44100);
+    limiter.set_mode(LimiterMode::SoftClip);
+    let mut frame = [SOFT_CLIP_KNEE, -0.25];
+    limiter.process_frame(&mut frame);
+    assert_eq!(frame, [SOFT_CLIP_KNEE, -0.25]);
+    assert_eq!(limiter.take_gain_reduction(), 0.0);
+    // Monotonic above the knee.
+    assert!(soft_clip(0.6) < soft_clip(0.7));
+    assert!(soft_clip(10.0) <= CEILING);
+}
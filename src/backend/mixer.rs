@@ -1,3 +1,5 @@
+use super::dither::{Dither, DitherMode};
+use super::limiter::{Limiter, LimiterMode};
 use cubeb_backend::{ChannelLayout, SampleFormat};
 use std::mem;
 use std::os::raw::{c_int, c_void};
@@ -59,10 +61,150 @@ fn get_default_channel_order(channel_count: usize) -> Vec<audio_mixer::Channel>
     channels
 }
 
+// A user-provided mixing matrix: `output_channels` rows of `input_channels` coefficients, the
+// coefficient at row o and column i being the gain from input channel i to output channel o.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MixingMatrix {
+    input_channels: usize,
+    output_channels: usize,
+    coefficients: Vec<f32>,
+}
+
+impl MixingMatrix {
+    pub fn new(
+        input_channels: usize,
+        output_channels: usize,
+        coefficients: Vec<f32>,
+    ) -> Option<Self> {
+        if input_channels == 0
+            || output_channels == 0
+            || coefficients.len() != input_channels * output_channels
+            || coefficients.iter().any(|c| !c.is_finite())
+        {
+            return None;
+        }
+        Some(Self {
+            input_channels,
+            output_channels,
+            coefficients,
+        })
+    }
+
+    // Extract the coefficients of a `audio_mixer` mixer by mixing a unit impulse on each input
+    // channel.
+    fn from_mixer(mixer: &audio_mixer::Mixer<f32>) -> Self {
+        let input_channels = mixer.input_channels().len();
+        let output_channels = mixer.output_channels().len();
+        let mut coefficients = vec![0.0; input_channels * output_channels];
+        let mut input_frame = vec![0.0; input_channels];
+        let mut output_frame = vec![0.0; output_channels];
+        for i in 0..input_channels {
+            input_frame[i] = 1.0;
+            mixer.mix(&input_frame, &mut output_frame);
+            input_frame[i] = 0.0;
+            for (o, coefficient) in output_frame.iter().enumerate() {
+                coefficients[o * input_channels + i] = *coefficient;
+            }
+        }
+        Self {
+            input_channels,
+            output_channels,
+            coefficients,
+        }
+    }
+
+    pub fn input_channels(&self) -> usize {
+        self.input_channels
+    }
+
+    pub fn output_channels(&self) -> usize {
+        self.output_channels
+    }
+
+    // Scale the coefficients so that no output channel can exceed full scale, which is what
+    // `audio_mixer` does for its integer mixer.
+    fn normalize(&mut self) {
+        let max_sum = self
+            .coefficients
+            .chunks(self.input_channels)
+            .map(|row| row.iter().sum::<f32>())
+            .fold(0.0, f32::max);
+        if max_sum > 1.0 {
+            for coefficient in self.coefficients.iter_mut() {
+                *coefficient /= max_sum;
+            }
+        }
+    }
+
+    fn mix_frame<T: Copy + Into<f32>>(&self, input: &[T], output: &mut [f32]) {
+        for (out, row) in output
+            .iter_mut()
+            .zip(self.coefficients.chunks(self.input_channels))
+        {
+            *out = Self::mix_row(row, input);
+        }
+    }
+
+    fn mix_frame_to_i16(&self, input: &[i16], output: &mut [i16]) {
+        for (out, row) in output
+            .iter_mut()
+            .zip(self.coefficients.chunks(self.input_channels))
+        {
+            *out = Self::mix_row(row, input)
+                .round()
+                .clamp(f32::from(i16::MIN), f32::from(i16::MAX)) as i16;
+        }
+    }
+
+    fn mix_row<T: Copy + Into<f32>>(row: &[f32], input: &[T]) -> f32 {
+        row.iter()
+            .zip(input)
+            .map(|(coefficient, sample)| coefficient * (*sample).into())
+            .sum()
+    }
+}
+
+// The channels of the data given to the mixer: the stream layout, or a default order if the
+// layout doesn't match the channel count.
+pub fn get_input_channel_order(
+    layout: ChannelLayout,
+    channel_count: usize,
+) -> Vec<audio_mixer::Channel> {
+    if channel_count as u32 != layout.bits().count_ones() {
+        cubeb_log!("Mismatch between input channels and layout. Applying default layout instead");
+        get_default_channel_order(channel_count)
+    } else {
+        get_channel_order(layout)
+    }
+}
+
+// The channels of the device as the mixer uses them: the device layout, or a default order if the
+// layout can't be used. Devices misreporting a usable layout must be handled before this, since it
+// is taken as is.
+pub fn get_output_channel_order(
+    channel_count: usize,
+    channels: Vec<audio_mixer::Channel>,
+) -> Vec<audio_mixer::Channel> {
+    let all_silence = vec![audio_mixer::Channel::Silence; channel_count];
+    if channels.is_empty()
+        || channel_count != channels.len()
+        || all_silence == channels
+        || Mixer::duplicate_channel_present(&channels)
+    {
+        cubeb_log!("Use invalid layout. Apply default layout instead");
+        get_default_channel_order(channel_count)
+    } else {
+        channels
+    }
+}
+
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug)]
 enum MixerType {
     IntegerMixer(audio_mixer::Mixer<i16>),
     FloatMixer(audio_mixer::Mixer<f32>),
+    IntegerMatrixMixer(MixingMatrix),
+    FloatMatrixMixer(MixingMatrix),
 }
 
 impl MixerType {
@@ -91,22 +233,26 @@ impl MixerType {
 
     fn sample_size(&self) -> usize {
         match self {
-            MixerType::IntegerMixer(_) => mem::size_of::<i16>(),
-            MixerType::FloatMixer(_) => mem::size_of::<f32>(),
+            MixerType::IntegerMixer(_) | MixerType::IntegerMatrixMixer(_) => mem::size_of::<i16>(),
+            MixerType::FloatMixer(_) | MixerType::FloatMatrixMixer(_) => mem::size_of::<f32>(),
         }
     }
 
-    fn input_channels(&self) -> &[Channel] {
+    fn input_channel_count(&self) -> usize {
         match self {
-            MixerType::IntegerMixer(m) => m.input_channels(),
-            MixerType::FloatMixer(m) => m.input_channels(),
+            MixerType::IntegerMixer(m) => m.input_channels().len(),
+            MixerType::FloatMixer(m) => m.input_channels().len(),
+            MixerType::IntegerMatrixMixer(m) | MixerType::FloatMatrixMixer(m) => m.input_channels(),
         }
     }
 
-    fn output_channels(&self) -> &[Channel] {
+    fn output_channel_count(&self) -> usize {
         match self {
-            MixerType::IntegerMixer(m) => m.output_channels(),
-            MixerType::FloatMixer(m) => m.output_channels(),
+            MixerType::IntegerMixer(m) => m.output_channels().len(),
+            MixerType::FloatMixer(m) => m.output_channels().len(),
+            MixerType::IntegerMatrixMixer(m) | MixerType::FloatMatrixMixer(m) => {
+                m.output_channels()
+            }
         }
     }
 
@@ -121,10 +267,10 @@ impl MixerType {
         use std::slice;
 
         // Check input buffer size.
-        let size_needed = frames * self.input_channels().len() * self.sample_size();
+        let size_needed = frames * self.input_channel_count() * self.sample_size();
         assert!(input_buffer_size >= size_needed);
         // Check output buffer size.
-        let size_needed = frames * self.output_channels().len() * self.sample_size();
+        let size_needed = frames * self.output_channel_count() * self.sample_size();
         assert!(output_buffer_size >= size_needed);
 
         match self {
@@ -132,13 +278,13 @@ impl MixerType {
                 let in_buf_ptr = input_buffer_ptr as *const i16;
                 let out_buf_ptr = output_buffer_ptr as *mut i16;
                 let input_buffer = unsafe {
-                    slice::from_raw_parts(in_buf_ptr, frames * self.input_channels().len())
+                    slice::from_raw_parts(in_buf_ptr, frames * self.input_channel_count())
                 };
                 let output_buffer = unsafe {
-                    slice::from_raw_parts_mut(out_buf_ptr, frames * self.output_channels().len())
+                    slice::from_raw_parts_mut(out_buf_ptr, frames * self.output_channel_count())
                 };
-                let mut in_buf = input_buffer.chunks(self.input_channels().len());
-                let mut out_buf = output_buffer.chunks_mut(self.output_channels().len());
+                let mut in_buf = input_buffer.chunks(self.input_channel_count());
+                let mut out_buf = output_buffer.chunks_mut(self.output_channel_count());
                 for _ in 0..frames {
                     m.mix(in_buf.next().unwrap(), out_buf.next().unwrap());
                 }
@@ -147,24 +293,104 @@ impl MixerType {
                 let in_buf_ptr = input_buffer_ptr as *const f32;
                 let out_buf_ptr = output_buffer_ptr as *mut f32;
                 let input_buffer = unsafe {
-                    slice::from_raw_parts(in_buf_ptr, frames * self.input_channels().len())
+                    slice::from_raw_parts(in_buf_ptr, frames * self.input_channel_count())
                 };
                 let output_buffer = unsafe {
-                    slice::from_raw_parts_mut(out_buf_ptr, frames * self.output_channels().len())
+                    slice::from_raw_parts_mut(out_buf_ptr, frames * self.output_channel_count())
                 };
-                let mut in_buf = input_buffer.chunks(self.input_channels().len());
-                let mut out_buf = output_buffer.chunks_mut(self.output_channels().len());
+                let mut in_buf = input_buffer.chunks(self.input_channel_count());
+                let mut out_buf = output_buffer.chunks_mut(self.output_channel_count());
                 for _ in 0..frames {
                     m.mix(in_buf.next().unwrap(), out_buf.next().unwrap());
                 }
             }
+            MixerType::IntegerMatrixMixer(m) => {
+                let in_buf_ptr = input_buffer_ptr as *const i16;
+                let out_buf_ptr = output_buffer_ptr as *mut i16;
+                let input_buffer = unsafe {
+                    slice::from_raw_parts(in_buf_ptr, frames * self.input_channel_count())
+                };
+                let output_buffer = unsafe {
+                    slice::from_raw_parts_mut(out_buf_ptr, frames * self.output_channel_count())
+                };
+                let mut in_buf = input_buffer.chunks(self.input_channel_count());
+                let mut out_buf = output_buffer.chunks_mut(self.output_channel_count());
+                for _ in 0..frames {
+                    m.mix_frame_to_i16(in_buf.next().unwrap(), out_buf.next().unwrap());
+                }
+            }
+            MixerType::FloatMatrixMixer(m) => {
+                let in_buf_ptr = input_buffer_ptr as *const f32;
+                let out_buf_ptr = output_buffer_ptr as *mut f32;
+                let input_buffer = unsafe {
+                    slice::from_raw_parts(in_buf_ptr, frames * self.input_channel_count())
+                };
+                let output_buffer = unsafe {
+                    slice::from_raw_parts_mut(out_buf_ptr, frames * self.output_channel_count())
+                };
+                let mut in_buf = input_buffer.chunks(self.input_channel_count());
+                let mut out_buf = output_buffer.chunks_mut(self.output_channel_count());
+                for _ in 0..frames {
+                    m.mix_frame(in_buf.next().unwrap(), out_buf.next().unwrap());
+                }
+            }
         };
     }
 }
 
+// Mixes 16-bit data in floating point, so the mix can be limited, and dithered back to 16 bits
+// instead of being truncated by the integer mixer. This is also how a user-provided matrix is
+// applied to 16-bit data.
+#[derive(Debug)]
+struct FloatIntegerMixer {
+    matrix: MixingMatrix,
+    output_frame: Vec<f32>,
+}
+
+impl FloatIntegerMixer {
+    fn new(matrix: MixingMatrix) -> Self {
+        let output_frame = vec![0.0; matrix.output_channels()];
+        Self {
+            matrix,
+            output_frame,
+        }
+    }
+
+    fn mix(
+        &mut self,
+        input_buffer: &[i16],
+        output_buffer: &mut [i16],
+        limiter: &mut Limiter,
+        dither: &mut Dither,
+    ) {
+        const SCALE: f32 = 32768.0;
+        let input_channels = self.matrix.input_channels();
+        let output_channels = self.matrix.output_channels();
+        for (in_frame, out_frame) in input_buffer
+            .chunks(input_channels)
+            .zip(output_buffer.chunks_mut(output_channels))
+        {
+            self.matrix.mix_frame(in_frame, &mut self.output_frame);
+            for sample in self.output_frame.iter_mut() {
+                *sample /= SCALE;
+            }
+            limiter.process_frame(&mut self.output_frame);
+            for (channel, (dst, src)) in out_frame.iter_mut().zip(&self.output_frame).enumerate() {
+                *dst = dither.quantize(src * SCALE, channel);
+            }
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Mixer {
     mixer: MixerType,
+    // Only set for 16-bit formats. Used instead of `mixer` when limiting, dithering, or when
+    // using a user-provided matrix.
+    float_integer_mixer: Option<FloatIntegerMixer>,
+    // Applied after downmixing.
+    limiter: Limiter,
+    dither: Dither,
     // Only accessed from callback thread.
     buffer: Vec<u8>,
 }
@@ -172,10 +398,11 @@ pub struct Mixer {
 impl Mixer {
     pub fn new(
         format: SampleFormat,
+        rate: u32,
         in_channel_count: usize,
         input_layout: ChannelLayout,
         out_channel_count: usize,
-        mut output_channels: Vec<audio_mixer::Channel>,
+        output_channels: Vec<audio_mixer::Channel>,
     ) -> Self {
         assert!(in_channel_count > 0);
         assert!(out_channel_count > 0);
@@ -189,46 +416,69 @@ impl Mixer {
             output_channels
         );
 
-        let input_channels = if in_channel_count as u32 != input_layout.bits().count_ones() {
-            cubeb_log!(
-                "Mismatch between input channels and layout. Applying default layout instead"
-            );
-            get_default_channel_order(in_channel_count)
-        } else {
-            get_channel_order(input_layout)
+        let input_channels = get_input_channel_order(input_layout, in_channel_count);
+        let output_channels = get_output_channel_order(out_channel_count, output_channels);
+
+        let mixer = MixerType::new(format, &input_channels, &output_channels);
+        let float_integer_mixer = match mixer {
+            MixerType::IntegerMixer(_) => {
+                let mut matrix = MixingMatrix::from_mixer(&audio_mixer::Mixer::<f32>::new(
+                    &input_channels,
+                    &output_channels,
+                ));
+                // Sound the same as the integer mixer.
+                matrix.normalize();
+                Some(FloatIntegerMixer::new(matrix))
+            }
+            _ => None,
         };
 
-        // When having one or two channel, force mono or stereo. Some devices (namely,
-        // Bose QC35, mark 1 and 2), expose a single channel mapped to the right for
-        // some reason. Some devices (e.g., builtin speaker on MacBook Pro 2018) map
-        // the channel layout to the undefined channels.
-        if out_channel_count == 1 {
-            output_channels = vec![audio_mixer::Channel::FrontCenter];
-        } else if out_channel_count == 2 {
-            output_channels = vec![
-                audio_mixer::Channel::FrontLeft,
-                audio_mixer::Channel::FrontRight,
-            ];
-        }
-
-        let all_silence = vec![audio_mixer::Channel::Silence; out_channel_count];
-        if output_channels.is_empty()
-            || out_channel_count != output_channels.len()
-            || all_silence == output_channels
-            || Self::duplicate_channel_present(&output_channels)
-        {
-            cubeb_log!("Use invalid layout. Apply default layout instead");
-            output_channels = get_default_channel_order(out_channel_count);
+        Self {
+            mixer,
+            float_integer_mixer,
+            limiter: Limiter::new(rate),
+            dither: Dither::new(out_channel_count),
+            buffer: Vec::new(),
         }
+    }
 
+    // Mix with the coefficients of `matrix` instead of the ones derived from the channel layouts.
+    pub fn with_matrix(format: SampleFormat, rate: u32, matrix: MixingMatrix) -> Self {
+        cubeb_log!("Creating a mixer with a custom matrix: {:?}", matrix);
+        let output_channel_count = matrix.output_channels();
+        let (mixer, float_integer_mixer) = match format {
+            SampleFormat::S16LE | SampleFormat::S16BE | SampleFormat::S16NE => (
+                MixerType::IntegerMatrixMixer(matrix.clone()),
+                Some(FloatIntegerMixer::new(matrix)),
+            ),
+            SampleFormat::Float32LE | SampleFormat::Float32BE | SampleFormat::Float32NE => {
+                (MixerType::FloatMatrixMixer(matrix), None)
+            }
+        };
         Self {
-            mixer: MixerType::new(format, &input_channels, &output_channels),
+            mixer,
+            float_integer_mixer,
+            limiter: Limiter::new(rate),
+            dither: Dither::new(output_channel_count),
             buffer: Vec::new(),
         }
     }
 
+    pub fn set_dither_mode(&mut self, mode: DitherMode) {
+        self.dither.set_mode(mode);
+    }
+
+    pub fn set_limiter_mode(&mut self, mode: LimiterMode) {
+        self.limiter.set_mode(mode);
+    }
+
+    // The largest gain reduction applied by the limiter since the previous call, in dB.
+    pub fn take_limiter_gain_reduction(&mut self) -> f32 {
+        self.limiter.take_gain_reduction()
+    }
+
     pub fn update_buffer_size(&mut self, frames: usize) -> bool {
-        let size_needed = frames * self.mixer.input_channels().len() * self.mixer.sample_size();
+        let size_needed = frames * self.mixer.input_channel_count() * self.mixer.sample_size();
         let elements_needed = size_needed / mem::size_of::<u8>();
         if self.buffer.len() < elements_needed {
             self.buffer.resize(elements_needed, 0);
@@ -243,8 +493,38 @@ impl Mixer {
     }
 
     // `update_buffer_size` must be called before this.
-    pub fn mix(&self, frames: usize, dest_buffer: *mut c_void, dest_buffer_size: usize) -> c_int {
+    pub fn mix(
+        &mut self,
+        frames: usize,
+        dest_buffer: *mut c_void,
+        dest_buffer_size: usize,
+    ) -> c_int {
         let (src_buffer_ptr, src_buffer_size) = self.get_buffer_info();
+        let post_processing = self.dither.mode() != DitherMode::Disabled
+            || self.limiter.mode() != LimiterMode::Disabled;
+        let float_integer_mixer = if post_processing {
+            self.float_integer_mixer.as_mut()
+        } else {
+            None
+        };
+        if let Some(m) = float_integer_mixer {
+            use std::slice;
+            let input_samples = frames * self.mixer.input_channel_count();
+            let output_samples = frames * self.mixer.output_channel_count();
+            assert!(src_buffer_size >= input_samples * mem::size_of::<i16>());
+            assert!(dest_buffer_size >= output_samples * mem::size_of::<i16>());
+            let input_buffer =
+                unsafe { slice::from_raw_parts(src_buffer_ptr as *const i16, input_samples) };
+            let output_buffer =
+                unsafe { slice::from_raw_parts_mut(dest_buffer as *mut i16, output_samples) };
+            m.mix(
+                input_buffer,
+                output_buffer,
+                &mut self.limiter,
+                &mut self.dither,
+            );
+            return 0;
+        }
         self.mixer.mix(
             src_buffer_ptr as *const (),
             src_buffer_size,
@@ -252,6 +532,17 @@ impl Mixer {
             dest_buffer_size,
             frames,
         );
+        if let MixerType::FloatMixer(_) | MixerType::FloatMatrixMixer(_) = self.mixer {
+            if self.limiter.mode() != LimiterMode::Disabled {
+                let channels = self.mixer.output_channel_count();
+                let output_buffer = unsafe {
+                    std::slice::from_raw_parts_mut(dest_buffer as *mut f32, frames * channels)
+                };
+                for frame in output_buffer.chunks_mut(channels) {
+                    self.limiter.process_frame(frame);
+                }
+            }
+        }
         0
     }
 
@@ -511,3 +802,206 @@ fn test_non_silent_duplicate_channels() {
     ];
     assert!(!Mixer::duplicate_channel_present(&non_duplicate));
 }
+
+#[test]
+fn test_dithered_integer_mix_matches_integer_mix() {
+    let frames = 256;
+    let input: Vec<i16> = (0..frames * 6)
+        .map(|i| ((i * 997) % 20000) as i16 - 10000)
+        .collect();
+    let input_bytes = input.len() * mem::size_of::<i16>();
+    let output_samples = frames * 2;
+
+    let mut outputs = Vec::new();
+    for mode in &[DitherMode::Disabled, DitherMode::TpdfNoiseShaped] {
+        let mut mixer = Mixer::new(
+            SampleFormat::S16NE,
+            48000,
+            6,
+            ChannelLayout::_3F2_LFE,
+            2,
+            vec![Channel::FrontLeft, Channel::FrontRight],
+        );
+        mixer.set_dither_mode(*mode);
+        mixer.update_buffer_size(frames);
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                input.as_ptr() as *const u8,
+                mixer.get_buffer_mut_ptr(),
+                input_bytes,
+            );
+        }
+        let mut output = vec![0_i16; output_samples];
+        assert_eq!(
+            mixer.mix(
+                frames,
+                output.as_mut_ptr() as *mut c_void,
+                output_samples * mem::size_of::<i16>()
+            ),
+            0
+        );
+        outputs.push(output);
+    }
+
+    // Both paths apply the same normalized downmix; they only differ by the quantization noise.
+    for (integer, dithered) in outputs[0].iter().zip(&outputs[1]) {
+        assert!((i32::from(*integer) - i32::from(*dithered)).abs() <= 4);
+    }
+}
+
+#[test]
+fn test_float_mix_is_limited() {
+    let frames = 128;
+    // A full scale 5.1 signal downmixed to stereo goes well over full scale.
+    let input = vec![1.0_f32; frames * 6];
+    let output_samples = frames * 2;
+
+    let mut mixer = Mixer::new(
+        SampleFormat::Float32NE,
+        48000,
+        6,
+        ChannelLayout::_3F2_LFE,
+        2,
+        vec![Channel::FrontLeft, Channel::FrontRight],
+    );
+    let mix = |mixer: &mut Mixer| {
+        mixer.update_buffer_size(frames);
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                input.as_ptr() as *const u8,
+                mixer.get_buffer_mut_ptr(),
+                input.len() * mem::size_of::<f32>(),
+            );
+        }
+        let mut output = vec![0.0_f32; output_samples];
+        mixer.mix(
+            frames,
+            output.as_mut_ptr() as *mut c_void,
+            output_samples * mem::size_of::<f32>(),
+        );
+        output
+    };
+
+    let output = mix(&mut mixer);
+    assert!(output.iter().any(|s| s.abs() > 1.0));
+    assert_eq!(mixer.take_limiter_gain_reduction(), 0.0);
+
+    mixer.set_limiter_mode(LimiterMode::Limiter);
+    let output = mix(&mut mixer);
+    assert!(output.iter().all(|s| s.abs() < 1.0));
+    assert!(mixer.take_limiter_gain_reduction() > 0.0);
+}
+
+#[test]
+fn test_mixing_matrix_validation() {
+    assert!(MixingMatrix::new(2, 1, vec![0.5, 0.5]).is_some());
+    assert!(MixingMatrix::new(2, 1, vec![0.5]).is_none());
+    assert!(MixingMatrix::new(0, 0, vec![]).is_none());
+    assert!(MixingMatrix::new(1, 1, vec![f32::NAN]).is_none());
+}
+
+#[test]
+fn test_mixing_matrix_from_mixer() {
+    let mixer = audio_mixer::Mixer::<f32>::new(
+        &[Channel::FrontLeft, Channel::FrontRight],
+        &[Channel::FrontLeft, Channel::FrontRight],
+    );
+    let matrix = MixingMatrix::from_mixer(&mixer);
+    assert_eq!(
+        matrix,
+        MixingMatrix::new(2, 2, vec![1.0, 0.0, 0.0, 1.0]).unwrap()
+    );
+}
+
+#[test]
+fn test_custom_matrix_mix() {
+    // Stereo to three channels: left, right, and the difference of both.
+    let matrix = MixingMatrix::new(2, 3, vec![1.0, 0.0, 0.0, 1.0, 0.5, -0.5]).unwrap();
+    let frames = 2;
+
+    let mut mixer = Mixer::with_matrix(SampleFormat::Float32NE, 48000, matrix.clone());
+    let input = [0.5_f32, 0.25, -1.0, 1.0];
+    mixer.update_buffer_size(frames);
+    unsafe {
+        std::ptr::copy_nonoverlapping(
+            input.as_ptr() as *const u8,
+            mixer.get_buffer_mut_ptr(),
+            input.len() * mem::size_of::<f32>(),
+        );
+    }
+    let mut output = [0.0_f32; 6];
+    mixer.mix(
+        frames,
+        output.as_mut_ptr() as *mut c_void,
+        output.len() * mem::size_of::<f32>(),
+    );
+    assert_eq!(output, [0.5, 0.25, 0.125, -1.0, 1.0, -1.0]);
+
+    let mut mixer = Mixer::with_matrix(SampleFormat::S16NE, 48000, matrix);
+    let input = [1000_i16, 501, -32768, 32767];
+    mixer.update_buffer_size(frames);
+    unsafe {
+        std::ptr::copy_nonoverlapping(
+            input.as_ptr() as *const u8,
+            mixer.get_buffer_mut_ptr(),
+            input.len() * mem::size_of::<i16>(),
+        );
+    }
+    let mut output = [0_i16; 6];
+    mixer.mix(
+        frames,
+        output.as_mut_ptr() as *mut c_void,
+        output.len() * mem::size_of::<i16>(),
+    );
+    assert_eq!(output, [1000, 501, 250, -32768, 32767, -32768]);
+}
+
+#[test]
+fn test_get_output_channel_order() {
+    // A valid layout is used as is, even for one or two channels.
+    assert_eq!(
+        get_output_channel_order(1, vec![Channel::FrontRight]),
+        [Channel::FrontRight]
+    );
+    assert_eq!(
+        get_output_channel_order(2, vec![Channel::FrontCenter, Channel::LowFrequency]),
+        [Channel::FrontCenter, Channel::LowFrequency]
+    );
+    // Invalid layouts fall back to the default order.
+    assert_eq!(
+        get_output_channel_order(2, vec![]),
+        [Channel::FrontLeft, Channel::FrontRight]
+    );
+    assert_eq!(
+        get_output_channel_order(2, vec![Channel::Silence, Channel::Silence]),
+        [Channel::FrontLeft, Channel::FrontRight]
+    );
+    assert_eq!(
+        get_output_channel_order(2, vec![Channel::FrontLeft, Channel::FrontLeft]),
+        [Channel::FrontLeft, Channel::FrontRight]
+    );
+    assert_eq!(
+        get_output_channel_order(3, vec![Channel::FrontLeft, Channel::FrontRight]),
+        [
+            Channel::FrontLeft,
+            Channel::FrontRight,
+            Channel::FrontCenter
+        ]
+    );
+}
+
+#[test]
+fn test_get_input_channel_order() {
+    assert_eq!(
+        get_input_channel_order(ChannelLayout::STEREO, 2),
+        [Channel::FrontLeft, Channel::FrontRight]
+    );
+    assert_eq!(
+        get_input_channel_order(ChannelLayout::UNDEFINED, 2),
+        [Channel::FrontLeft, Channel::FrontRight]
+    );
+    assert_eq!(
+        get_input_channel_order(ChannelLayout::MONO, 2),
+        [Channel::FrontLeft, Channel::FrontRight]
+    );
+}
@@ -9,14 +9,26 @@ extern crate libc;
 extern crate ringbuf;
 
 mod aggregate_device;
+mod aggregate_policy;
 mod auto_release;
 mod buffer_manager;
+mod device_collection;
 mod device_property;
+mod dither;
+mod gain;
+mod input_processing;
+mod limiter;
 mod mixer;
+mod quirks;
 mod resampler;
+mod settings;
 mod utils;
+mod voice_activity;
 
+pub use self::aggregate_device::AggregateClockInfo;
 use self::aggregate_device::*;
+pub use self::aggregate_policy::AggregateDevicePolicy;
+use self::aggregate_policy::*;
 use self::auto_release::*;
 use self::buffer_manager::*;
 use self::coreaudio_sys_utils::aggregate_device::*;
@@ -27,10 +39,28 @@ use self::coreaudio_sys_utils::cf_mutable_dict::*;
 use self::coreaudio_sys_utils::dispatch::*;
 use self::coreaudio_sys_utils::string::*;
 use self::coreaudio_sys_utils::sys::*;
+use self::device_collection::*;
+pub use self::device_collection::{
+    release_device_info, DeviceCollectionChanges, DeviceDirectionInfo, DuplexDeviceInfo,
+};
 use self::device_property::*;
+pub use self::dither::DitherMode;
+use self::dither::*;
+pub use self::gain::VolumeRamp;
+use self::gain::*;
+use self::input_processing::*;
+pub use self::limiter::LimiterMode;
+use self::limiter::*;
+pub use self::mixer::MixingMatrix;
 use self::mixer::*;
+use self::quirks::*;
+pub use self::quirks::{QuirkRule, QuirkScope, Quirks};
 use self::resampler::*;
+pub use self::settings::BackendSettings;
+use self::settings::*;
 use self::utils::*;
+pub use self::voice_activity::VoiceActivityEvent;
+use atomic::Atomic;
 use backend::ringbuf::RingBuffer;
 #[cfg(feature = "audio-dump")]
 use cubeb_backend::ffi::cubeb_audio_dump_stream_t;
@@ -50,6 +80,7 @@ use std::slice;
 use std::str::FromStr;
 use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
 use std::sync::{Arc, Condvar, Mutex, MutexGuard, Weak};
+use std::thread;
 use std::time::{Duration, Instant};
 const NO_ERR: OSStatus = 0;
 
@@ -59,16 +90,14 @@ const AU_IN_BUS: AudioUnitElement = 1;
 const PRIVATE_AGGREGATE_DEVICE_NAME: &str = "CubebAggregateDevice";
 const VOICEPROCESSING_AGGREGATE_DEVICE_NAME: &str = "VPAUAggregateAudioDevice";
 
-const APPLE_STUDIO_DISPLAY_USB_ID: &str = "05AC:1114";
-
-// Testing empirically, some headsets report a minimal latency that is very low,
-// but this does not work in practice. Lie and say the minimum is 128 frames.
-const SAFE_MIN_LATENCY_FRAMES: u32 = 128;
-const SAFE_MAX_LATENCY_FRAMES: u32 = 512;
-
-const VPIO_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
-
-const MACOS_KERNEL_MAJOR_VERSION_MONTEREY: u32 = 21;
+// How much longer than the fade itself `stop` waits for the output callback to render a fade-out.
+const FADE_OUT_TIMEOUT_MARGIN: Duration = Duration::from_millis(200);
+// How often `stop` checks whether the output callback has rendered the fade-out. The callback
+// can't take a lock to wake it up.
+const FADE_OUT_POLL_INTERVAL: Duration = Duration::from_millis(2);
+// How long the device collection has to stay quiet before the device-collection-changed callbacks
+// get called.
+const DEVICE_COLLECTION_SETTLE_TIME: Duration = Duration::from_millis(100);
 
 #[derive(Debug, PartialEq)]
 enum ParseMacOSKernelVersionError {
@@ -242,6 +271,26 @@ fn create_device_info(devid: AudioDeviceID, devtype: DeviceType) -> Option<devic
     }
 }
 
+// The device `devid` refers to, or the default device of `devtype` if `devid` is null.
+fn get_device_id(devid: DeviceId, devtype: DeviceType) -> Result<AudioDeviceID> {
+    debug_assert_running_serially();
+    create_device_info(devid as AudioDeviceID, devtype)
+        .map(|device| device.id)
+        .ok_or_else(|| {
+            cubeb_log!("Could not get default {:?} device", devtype);
+            Error::error()
+        })
+}
+
+// Convert a duration of `frames` frames at `from_rate`, rounding up so the converted duration is
+// never shorter.
+fn convert_frames_to_rate(frames: u32, from_rate: f64, to_rate: u32) -> u32 {
+    if from_rate <= 0.0 {
+        return frames;
+    }
+    (f64::from(frames) * f64::from(to_rate) / from_rate).ceil() as u32
+}
+
 fn create_stream_description(stream_params: &StreamParams) -> Result<AudioStreamBasicDescription> {
     assert!(stream_params.rate() > 0);
     assert!(stream_params.channels() > 0);
@@ -284,42 +333,6 @@ fn create_stream_description(stream_params: &StreamParams) -> Result<AudioStream
     Ok(desc)
 }
 
-fn set_volume(unit: AudioUnit, volume: f32) -> Result<()> {
-    assert!(!unit.is_null());
-    let r = audio_unit_set_parameter(
-        unit,
-        kHALOutputParam_Volume,
-        kAudioUnitScope_Global,
-        0,
-        volume,
-        0,
-    );
-    if r == NO_ERR {
-        Ok(())
-    } else {
-        cubeb_log!("AudioUnitSetParameter/kHALOutputParam_Volume rv={}", r);
-        Err(Error::error())
-    }
-}
-
-fn get_volume(unit: AudioUnit) -> Result<f32> {
-    assert!(!unit.is_null());
-    let mut volume: f32 = 0.0;
-    let r = audio_unit_get_parameter(
-        unit,
-        kHALOutputParam_Volume,
-        kAudioUnitScope_Global,
-        0,
-        &mut volume,
-    );
-    if r == NO_ERR {
-        Ok(volume)
-    } else {
-        cubeb_log!("AudioUnitGetParameter/kHALOutputParam_Volume rv={}", r);
-        Err(Error::error())
-    }
-}
-
 fn set_input_mute(unit: AudioUnit, mute: bool) -> Result<()> {
     assert!(!unit.is_null());
     let mute: u32 = mute.into();
@@ -361,6 +374,156 @@ fn set_input_mute(unit: AudioUnit, mute: bool) -> Result<()> {
     }
 }
 
+// How much a VoiceProcessingIO unit lowers the volume of the other audio playing on its output
+// device.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DuckingLevel {
+    // Leave the other audio at its volume. This is the default, as the ducking of
+    // VoiceProcessingIO is too strong for most users.
+    #[default]
+    None,
+    Min,
+    Mid,
+    Max,
+}
+
+impl DuckingLevel {
+    // The volume of the other audio, for AudioDeviceDuck.
+    fn volume(&self) -> f32 {
+        match self {
+            DuckingLevel::None => 1.0,
+            DuckingLevel::Min => 0.5,
+            DuckingLevel::Mid => 0.25,
+            DuckingLevel::Max => 0.1,
+        }
+    }
+
+    // The level of the VoiceProcessingIO ducking configuration, which can't leave the other audio
+    // alone.
+    fn vpio_level(&self) -> Option<u32> {
+        match self {
+            DuckingLevel::None => None,
+            DuckingLevel::Min => Some(VPIO_OTHER_AUDIO_DUCKING_LEVEL_MIN),
+            DuckingLevel::Mid => Some(VPIO_OTHER_AUDIO_DUCKING_LEVEL_MID),
+            DuckingLevel::Max => Some(VPIO_OTHER_AUDIO_DUCKING_LEVEL_MAX),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct VpioDucking {
+    pub level: DuckingLevel,
+    // Only applies when the VoiceProcessingIO ducking configuration is unavailable, as it has its
+    // own ramp.
+    pub ramp_duration: Duration,
+}
+
+impl Default for VpioDucking {
+    fn default() -> Self {
+        // The ramp duration of VoiceProcessingIO when it ducks.
+        Self {
+            level: DuckingLevel::None,
+            ramp_duration: Duration::from_millis(500),
+        }
+    }
+}
+
+// Duck the other audio on `output_device` as `ducking` says, while the VoiceProcessingIO `unit`
+// plays to it. Uses the ducking configuration of VoiceProcessingIO if available, and AudioDeviceDuck
+// otherwise.
+fn set_vpio_ducking(
+    unit: AudioUnit,
+    output_device: Option<AudioDeviceID>,
+    ducking: VpioDucking,
+) -> Result<()> {
+    assert!(!unit.is_null());
+    if let Some(level) = ducking.level.vpio_level() {
+        let config = VpioOtherAudioDuckingConfiguration {
+            enable_advanced_ducking: 0,
+            ducking_level: level,
+        };
+        let r = audio_unit_set_property(
+            unit,
+            VPIO_PROPERTY_OTHER_AUDIO_DUCKING_CONFIGURATION,
+            kAudioUnitScope_Global,
+            AU_OUT_BUS,
+            &config,
+            mem::size_of::<VpioOtherAudioDuckingConfiguration>(),
+        );
+        if r == NO_ERR {
+            return Ok(());
+        }
+        cubeb_log!(
+            "AudioUnitSetProperty/VPIO_PROPERTY_OTHER_AUDIO_DUCKING_CONFIGURATION rv={}. Falling back to AudioDeviceDuck.",
+            r
+        );
+    }
+
+    let id = match output_device {
+        Some(id) => id,
+        None => {
+            cubeb_log!("No output device to set vpio ducking on");
+            return Err(Error::error());
+        }
+    };
+    let r = audio_device_duck(
+        id,
+        ducking.level.volume(),
+        ptr::null_mut(),
+        ducking.ramp_duration.as_secs_f32(),
+    );
+    if r == NO_ERR {
+        Ok(())
+    } else {
+        cubeb_log!(
+            "AudioDeviceDuck on output device {} to {:?} rv={}",
+            id,
+            ducking,
+            r
+        );
+        Err(Error::error())
+    }
+}
+
+// Called with the user pointer of the stream when the user starts or stops talking while the input
+// is muted.
+pub type VoiceActivityCallback =
+    unsafe extern "C" fn(user_ptr: *mut c_void, event: VoiceActivityEvent);
+
+fn set_speech_activity_listener(
+    unit: AudioUnit,
+    listener: Option<&SpeechActivityListenerBlock>,
+) -> std::result::Result<(), OSStatus> {
+    assert!(!unit.is_null());
+    let block: *const SpeechActivityListenerBlock = listener.map_or(ptr::null(), |l| l as *const _);
+    let r = audio_unit_set_property(
+        unit,
+        VPIO_PROPERTY_MUTED_SPEECH_ACTIVITY_EVENT_LISTENER,
+        kAudioUnitScope_Global,
+        AU_IN_BUS,
+        &block,
+        mem::size_of::<*const SpeechActivityListenerBlock>(),
+    );
+    if r == NO_ERR {
+        Ok(())
+    } else {
+        Err(r)
+    }
+}
+
+extern "C" fn speech_activity_listener(user_ptr: *mut c_void, event: isize) {
+    let stm = unsafe { &*(user_ptr as *const AudioUnitStream) };
+    let event = match event {
+        VPIO_SPEECH_ACTIVITY_HAS_STARTED => VoiceActivityEvent::SpeechStarted,
+        VPIO_SPEECH_ACTIVITY_HAS_ENDED => VoiceActivityEvent::SpeechEnded,
+        _ => {
+            cubeb_alog!("({:p}) Unknown speech activity event {}", stm, event);
+            return;
+        }
+    };
+    stm.notify_voice_activity(event);
+}
+
 fn set_input_processing_params(unit: AudioUnit, params: InputProcessingParams) -> Result<()> {
     assert!(!unit.is_null());
     let aec = params.contains(InputProcessingParams::ECHO_CANCELLATION);
@@ -571,8 +734,16 @@ extern "C" fn audiounit_input_callback(
                 );
             }
 
+            input_buffer_manager.set_dither_mode(stm.dither_mode.load(Ordering::SeqCst));
+            input_buffer_manager.set_input_processing_params(
+                stm.software_input_processing_params.load(Ordering::SeqCst),
+            );
+            input_buffer_manager.set_muted(stm.software_input_mute.load(Ordering::SeqCst));
             input_buffer_manager
                 .push_data(input_buffer_list.mBuffers[0].mData, input_frames as usize);
+            if let Some(event) = input_buffer_manager.take_voice_activity_event() {
+                stm.notify_voice_activity(event);
+            }
             ErrorHandle::Return(status)
         };
 
@@ -941,17 +1112,77 @@ extern "C" fn audiounit_output_callback(
         }
     }
 
+    // Volume and fades. This is applied to the data in the stream's own layout, before any
+    // mixing, so it behaves the same with or without a mixer in the path.
+    {
+        let rate = stm.core_stream_data.output_dev_desc.mSampleRate as f32;
+        let ramp_frames = stm.volume_ramp_duration.load(Ordering::SeqCst) * rate;
+        stm.volume_gain.set_target(
+            stm.volume.load(Ordering::SeqCst),
+            stm.volume_ramp.load(Ordering::SeqCst),
+            ramp_frames as usize,
+        );
+        let fade_frames = (stm.fade_duration.load(Ordering::SeqCst) * rate) as usize;
+        stm.fader.request(
+            stm.fade_request.swap(FadeRequest::None, Ordering::SeqCst),
+            fade_frames,
+        );
+        let drain_fade_frames = if stm.draining.load(Ordering::SeqCst) {
+            fade_frames
+        } else {
+            0
+        };
+        let channel_count = stm.core_stream_data.output_stream_params.channels() as usize;
+        let samples = outframes as usize * channel_count;
+        match stm.core_stream_data.output_stream_params.format() {
+            SampleFormat::S16LE | SampleFormat::S16BE | SampleFormat::S16NE => {
+                let data = unsafe { slice::from_raw_parts_mut(output_buffer as *mut i16, samples) };
+                stm.volume_gain.process(data, channel_count);
+                stm.fader.process(data, channel_count, drain_fade_frames);
+            }
+            SampleFormat::Float32LE | SampleFormat::Float32BE | SampleFormat::Float32NE => {
+                let data = unsafe { slice::from_raw_parts_mut(output_buffer as *mut f32, samples) };
+                stm.volume_gain.process(data, channel_count);
+                stm.fader.process(data, channel_count, drain_fade_frames);
+            }
+        }
+        if stm.fader.is_silent() {
+            stm.fade_out_done.store(true, Ordering::SeqCst);
+        }
+    }
+
+    // The echo canceller of the software input processing listens to what the stream plays.
+    if let Some(echo_reference) = stm.core_stream_data.echo_reference.as_mut() {
+        let channel_count = stm.core_stream_data.output_stream_params.channels() as usize;
+        let samples = output_frames as usize * channel_count;
+        match stm.core_stream_data.output_stream_params.format() {
+            SampleFormat::S16LE | SampleFormat::S16BE | SampleFormat::S16NE => {
+                let data = unsafe { slice::from_raw_parts(output_buffer as *const i16, samples) };
+                echo_reference.push(data.iter().map(|s| f32::from(*s) / 32768.0), channel_count);
+            }
+            SampleFormat::Float32LE | SampleFormat::Float32BE | SampleFormat::Float32NE => {
+                let data = unsafe { slice::from_raw_parts(output_buffer as *const f32, samples) };
+                echo_reference.push(data.iter().copied(), channel_count);
+            }
+        }
+    }
+
     // Mixing
     if stm.core_stream_data.mixer.is_some() {
         assert!(
             buffers[0].mDataByteSize
                 >= stm.core_stream_data.output_dev_desc.mBytesPerFrame * output_frames
         );
-        stm.core_stream_data.mixer.as_mut().unwrap().mix(
+        let mixer = stm.core_stream_data.mixer.as_mut().unwrap();
+        mixer.set_dither_mode(stm.dither_mode.load(Ordering::SeqCst));
+        mixer.set_limiter_mode(stm.limiter_mode.load(Ordering::SeqCst));
+        mixer.mix(
             output_frames as usize,
             buffers[0].mData,
             buffers[0].mDataByteSize as usize,
         );
+        stm.limiter_gain_reduction
+            .store(mixer.take_limiter_gain_reduction(), Ordering::SeqCst);
     }
 
     #[cfg(feature = "audio-dump")]
@@ -1193,6 +1424,23 @@ fn get_channel_layout(output_unit: AudioUnit) -> Result<Vec<mixer::Channel>> {
         })
 }
 
+// Hook for devices reporting an output channel layout that doesn't describe how they render
+// audio. Returns the layout to use instead of `layout` for those.
+fn get_output_channel_layout_quirk(
+    device_id: AudioDeviceID,
+    layout: &[mixer::Channel],
+) -> Option<Vec<mixer::Channel>> {
+    let quirks = get_device_quirks(device_id, DeviceType::OUTPUT);
+    let fixed = fix_output_channel_layout(quirks, layout)?;
+    cubeb_log!(
+        "Device {} reports output channel layout {:?}. Using {:?} instead.",
+        device_id,
+        layout,
+        fixed
+    );
+    Some(fixed)
+}
+
 fn start_audiounit(unit: AudioUnit) -> Result<()> {
     let status = audio_output_unit_start(unit);
     if status == NO_ERR {
@@ -1792,11 +2040,135 @@ fn get_device_label(
     get_device_source_name(id, devtype).or_else(|_| get_device_name(id, devtype))
 }
 
+fn get_device_quirks(id: AudioDeviceID, devtype: DeviceType) -> Quirks {
+    debug_assert_running_serially();
+    let into_string = |s: std::result::Result<StringRef, OSStatus>| {
+        s.map(|s| s.into_string()).unwrap_or_default()
+    };
+    let facts = QuirkDeviceFacts {
+        is_input: devtype == DeviceType::INPUT,
+        model_uid: into_string(get_device_model_uid(id, devtype)),
+        label: into_string(get_device_label(id, devtype)),
+        manufacturer: into_string(get_device_manufacturer(id, devtype)),
+        transport_type: get_device_transport_type(id, devtype).unwrap_or(0),
+        kernel_major_version: macos_kernel_major_version().ok(),
+    };
+    get_quirks(&facts)
+}
+
+// A data source of a device, e.g. the internal speakers or the headphones of the builtin output.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DataSource {
+    pub id: u32,
+    pub name: String,
+}
+
+fn create_data_source(id: AudioDeviceID, devtype: DeviceType, source: u32) -> DataSource {
+    debug_assert_running_serially();
+    let name = match get_device_source_name_of(id, devtype, source) {
+        Ok(name) => name.into_string(),
+        Err(e) => {
+            cubeb_log!(
+                "Cannot get the name of data source {:?} for device {} in {:?} scope. Error: {}",
+                convert_uint32_into_string(source),
+                id,
+                devtype,
+                e
+            );
+            convert_uint32_into_string(source)
+                .into_string()
+                .unwrap_or_default()
+        }
+    };
+    DataSource { id: source, name }
+}
+
+fn get_device_data_sources(
+    id: AudioDeviceID,
+    devtype: DeviceType,
+) -> std::result::Result<Vec<DataSource>, OSStatus> {
+    debug_assert_running_serially();
+    Ok(get_device_sources(id, devtype)?
+        .into_iter()
+        .map(|source| create_data_source(id, devtype, source))
+        .collect())
+}
+
 fn get_device_global_uid(id: AudioDeviceID) -> std::result::Result<StringRef, OSStatus> {
     debug_assert_running_serially();
     get_device_uid(id, DeviceType::INPUT | DeviceType::OUTPUT)
 }
 
+// The cubeb format matching the native format of a device stream, if any.
+fn get_cubeb_device_fmt(desc: &AudioStreamBasicDescription) -> Option<ffi::cubeb_device_fmt> {
+    if desc.mFormatID != kAudioFormatLinearPCM {
+        return None;
+    }
+    let big_endian = desc.mFormatFlags & kAudioFormatFlagIsBigEndian != 0;
+    if desc.mFormatFlags & kAudioFormatFlagIsFloat != 0 && desc.mBitsPerChannel == 32 {
+        Some(if big_endian {
+            ffi::CUBEB_DEVICE_FMT_F32BE
+        } else {
+            ffi::CUBEB_DEVICE_FMT_F32LE
+        })
+    } else if desc.mFormatFlags & kAudioFormatFlagIsSignedInteger != 0 && desc.mBitsPerChannel == 16
+    {
+        Some(if big_endian {
+            ffi::CUBEB_DEVICE_FMT_S16BE
+        } else {
+            ffi::CUBEB_DEVICE_FMT_S16LE
+        })
+    } else {
+        None
+    }
+}
+
+// The native format of the first stream of a device, as the default_format of
+// `cubeb_device_info`. The AudioUnit converts from and to any format, so all the formats are
+// supported whatever the native ones are, and a device whose native format has no cubeb
+// equivalent (e.g. 24-bit integers) uses float by default.
+fn get_cubeb_device_default_format(
+    devid: AudioObjectID,
+    devtype: DeviceType,
+) -> ffi::cubeb_device_fmt {
+    debug_assert_running_serially();
+    get_device_streams(devid, devtype)
+        .map(|streams| {
+            streams
+                .iter()
+                .filter_map(|s| get_stream_virtual_format(s.stream).ok())
+                .find_map(|desc| get_cubeb_device_fmt(&desc))
+        })
+        .unwrap_or_else(|e| {
+            cubeb_log!(
+                "Cannot get the streams for device {} in {:?} scope. Error: {}",
+                devid,
+                devtype,
+                e
+            );
+            None
+        })
+        .unwrap_or(ffi::CUBEB_DEVICE_FMT_F32NE)
+}
+
+fn get_cubeb_device_state(devid: AudioObjectID, devtype: DeviceType) -> ffi::cubeb_device_state {
+    debug_assert_running_serially();
+    if let Ok(false) = get_device_is_alive(devid, devtype) {
+        return ffi::CUBEB_DEVICE_STATE_UNPLUGGED;
+    }
+    if let Ok(true) = get_device_is_hidden(devid, devtype) {
+        return ffi::CUBEB_DEVICE_STATE_DISABLED;
+    }
+    // Only devices with a jack have this property. The builtin devices (speakers, microphone)
+    // work with nothing plugged into their jack, so it doesn't tell whether they are usable.
+    if get_device_transport_type(devid, devtype) != Ok(kAudioDeviceTransportTypeBuiltIn)
+        && get_device_jack_is_connected(devid, devtype) == Ok(false)
+    {
+        return ffi::CUBEB_DEVICE_STATE_UNPLUGGED;
+    }
+    ffi::CUBEB_DEVICE_STATE_ENABLED
+}
+
 #[allow(clippy::cognitive_complexity)]
 fn create_cubeb_device_info(
     devid: AudioObjectID,
@@ -1889,14 +2261,14 @@ fn create_cubeb_device_info(
         _ => panic!("invalid type"),
     };
 
-    dev_info.state = ffi::CUBEB_DEVICE_STATE_ENABLED;
+    dev_info.state = get_cubeb_device_state(devid, devtype);
     dev_info.preferred = match get_default_device(devtype) {
         Some(id) if id == devid => ffi::CUBEB_DEVICE_PREF_ALL,
         _ => ffi::CUBEB_DEVICE_PREF_NONE,
     };
 
     dev_info.format = ffi::CUBEB_DEVICE_FMT_ALL;
-    dev_info.default_format = ffi::CUBEB_DEVICE_FMT_F32NE;
+    dev_info.default_format = get_cubeb_device_default_format(devid, devtype);
 
     match get_device_sample_rate(devid, devtype) {
         Ok(rate) => {
@@ -2023,6 +2395,26 @@ fn audiounit_get_devices_of_type(devtype: DeviceType) -> Vec<AudioObjectID> {
     devices_in_scope
 }
 
+fn audiounit_get_device_infos_of_type(devtype: DeviceType) -> Vec<ffi::cubeb_device_info> {
+    debug_assert_running_serially();
+    let mut dev_types = vec![DeviceType::INPUT, DeviceType::OUTPUT];
+    dev_types.retain(|&dt| devtype.contains(dt));
+    let device_ids: Vec<(DeviceType, Vec<AudioObjectID>)> = dev_types
+        .iter()
+        .map(|&dt| (dt, audiounit_get_devices_of_type(dt)))
+        .collect();
+    let count = device_ids.iter().map(|(_dt, ids)| ids.len()).sum();
+    let mut device_infos = Vec::with_capacity(count);
+    for (dt, dev_ids) in device_ids {
+        for dev_id in dev_ids {
+            if let Ok(info) = create_cubeb_device_info(dev_id, dt) {
+                device_infos.push(info);
+            }
+        }
+    }
+    device_infos
+}
+
 extern "C" fn audiounit_collection_changed_callback(
     _in_object_id: AudioObjectID,
     _in_number_addresses: u32,
@@ -2033,35 +2425,42 @@ extern "C" fn audiounit_collection_changed_callback(
 
     let queue = context.serial_queue.clone();
 
+    // Plugging a device (or a hub) fires a burst of notifications. Wait for the HAL to settle, and
+    // only handle the last notification of the burst.
+    let generation = {
+        let mut devices = context.devices.lock().unwrap();
+        devices.generation += 1;
+        devices.generation
+    };
+
     // This can be called from inside an AudioUnit function, dispatch to another queue.
-    queue.run_async(move || {
+    queue.run_after(Instant::now() + DEVICE_COLLECTION_SETTLE_TIME, move || {
         let ctx_ptr = context as *const AudioUnitContext;
 
-        let mut devices = context.devices.lock().unwrap();
-
-        if devices.input.changed_callback.is_none() && devices.output.changed_callback.is_none() {
-            return;
-        }
-        if devices.input.changed_callback.is_some() {
-            let input_devices = audiounit_get_devices_of_type(DeviceType::INPUT);
-            if devices.input.update_devices(input_devices) {
-                unsafe {
-                    devices.input.changed_callback.unwrap()(
-                        ctx_ptr as *mut ffi::cubeb,
-                        devices.input.callback_user_ptr,
-                    );
+        let mut callbacks = Vec::new();
+        {
+            let mut guard = context.devices.lock().unwrap();
+            let devices = &mut *guard;
+            if generation != devices.generation {
+                return;
+            }
+            for (devtype, data) in [
+                (DeviceType::INPUT, &mut devices.input),
+                (DeviceType::OUTPUT, &mut devices.output),
+            ] {
+                if data.changed_callback.is_none() {
+                    continue;
+                }
+                if data.update_devices(audiounit_get_device_infos_of_type(devtype)) {
+                    callbacks.push((data.changed_callback.unwrap(), data.callback_user_ptr));
                 }
             }
         }
-        if devices.output.changed_callback.is_some() {
-            let output_devices = audiounit_get_devices_of_type(DeviceType::OUTPUT);
-            if devices.output.update_devices(output_devices) {
-                unsafe {
-                    devices.output.changed_callback.unwrap()(
-                        ctx_ptr as *mut ffi::cubeb,
-                        devices.output.callback_user_ptr,
-                    );
-                }
+
+        // Call the callbacks without holding the lock, so they can query the changes.
+        for (callback, user_ptr) in callbacks {
+            unsafe {
+                callback(ctx_ptr as *mut ffi::cubeb, user_ptr);
             }
         }
     });
@@ -2073,7 +2472,10 @@ extern "C" fn audiounit_collection_changed_callback(
 struct DevicesData {
     changed_callback: ffi::cubeb_device_collection_changed_callback,
     callback_user_ptr: *mut c_void,
-    devices: Vec<AudioObjectID>,
+    // The device infos seen by the latest notification, owned by this struct.
+    devices: Vec<ffi::cubeb_device_info>,
+    // The changes between the latest two snapshots.
+    changes: DeviceCollectionChanges,
 }
 
 impl DevicesData {
@@ -2081,32 +2483,46 @@ impl DevicesData {
         &mut self,
         changed_callback: ffi::cubeb_device_collection_changed_callback,
         callback_user_ptr: *mut c_void,
-        devices: Vec<AudioObjectID>,
+        devices: Vec<ffi::cubeb_device_info>,
     ) {
         self.changed_callback = changed_callback;
         self.callback_user_ptr = callback_user_ptr;
-        self.devices = devices;
+        self.replace_devices(devices);
+        self.changes = DeviceCollectionChanges::default();
     }
 
-    fn update_devices(&mut self, devices: Vec<AudioObjectID>) -> bool {
-        // Elements in the vector expected sorted.
-        if self.devices == devices {
+    fn update_devices(&mut self, mut devices: Vec<ffi::cubeb_device_info>) -> bool {
+        let changes = diff_device_collections(&self.devices, &devices);
+        if changes.is_empty() {
+            for device in devices.iter_mut() {
+                destroy_cubeb_device_info(device);
+            }
             return false;
         }
-        self.devices = devices;
+        self.replace_devices(devices);
+        self.changes = changes;
         true
     }
 
+    fn replace_devices(&mut self, devices: Vec<ffi::cubeb_device_info>) {
+        for device in self.devices.iter_mut() {
+            destroy_cubeb_device_info(device);
+        }
+        self.devices = devices;
+    }
+
     fn clear(&mut self) {
         self.changed_callback = None;
         self.callback_user_ptr = ptr::null_mut();
-        self.devices.clear();
+        self.replace_devices(Vec::new());
+        self.changes = DeviceCollectionChanges::default();
     }
 
     fn is_empty(&self) -> bool {
         self.changed_callback.is_none()
             && self.callback_user_ptr.is_null()
             && self.devices.is_empty()
+            && self.changes.is_empty()
     }
 }
 
@@ -2116,65 +2532,294 @@ impl Default for DevicesData {
             changed_callback: None,
             callback_user_ptr: ptr::null_mut(),
             devices: Vec::new(),
+            changes: DeviceCollectionChanges::default(),
         }
     }
 }
 
+impl Drop for DevicesData {
+    fn drop(&mut self) {
+        self.replace_devices(Vec::new());
+    }
+}
+
 #[derive(Debug, Default)]
 struct SharedDevices {
     input: DevicesData,
     output: DevicesData,
+    // Incremented on every HAL notification, to coalesce bursts of them.
+    generation: usize,
+}
+
+extern "C" fn audiounit_device_cache_listener(
+    _in_object_id: AudioObjectID,
+    _in_number_addresses: u32,
+    _in_addresses: *const AudioObjectPropertyAddress,
+    in_client_data: *mut c_void,
+) -> OSStatus {
+    let generation = unsafe { &*(in_client_data as *const AtomicUsize) };
+    generation.fetch_add(1, Ordering::SeqCst);
+    NO_ERR
 }
 
+// The device infos reported by `enumerate_devices`. Must be refreshed on the context's serial
+// queue.
+// The infos are refreshed lazily, the next time they are needed after a HAL notification that may
+// change them: a device appearing or disappearing, the default devices, or a property of one of
+// the cached devices.
 #[derive(Debug, Default)]
-struct LatencyController {
-    streams: u32,
-    latency: Option<u32>,
+struct DeviceCache {
+    // The value of the listeners' generation counter when the infos were queried.
+    generation: Option<usize>,
+    input: Vec<DeviceDirectionInfo>,
+    output: Vec<DeviceDirectionInfo>,
+    listeners: Vec<(AudioObjectID, AudioObjectPropertyAddress)>,
 }
 
-impl LatencyController {
-    fn add_stream(&mut self, latency: u32) -> u32 {
-        self.streams += 1;
-        // For the 1st stream set anything within safe min-max
-        if self.streams == 1 {
-            assert!(self.latency.is_none());
-            // Silently clamp the latency down to the platform default, because we
-            // synthetize the clock from the callbacks, and we want the clock to update often.
-            self.latency = Some(latency.clamp(SAFE_MIN_LATENCY_FRAMES, SAFE_MAX_LATENCY_FRAMES));
+impl DeviceCache {
+    fn get(&mut self, generation: &AtomicUsize, devtype: DeviceType) -> &[DeviceDirectionInfo] {
+        debug_assert_running_serially();
+        assert!(devtype == DeviceType::INPUT || devtype == DeviceType::OUTPUT);
+        let current = generation.load(Ordering::SeqCst);
+        if self.generation != Some(current) {
+            self.refresh(generation, current);
+        }
+        if devtype == DeviceType::INPUT {
+            &self.input
+        } else {
+            &self.output
         }
-        self.latency.unwrap_or(latency)
     }
 
-    fn subtract_stream(&mut self) {
-        self.streams -= 1;
-        if self.streams == 0 {
-            assert!(self.latency.is_some());
-            self.latency = None;
+    // The infos of `devtype` if no notification came since they were queried. Unlike `get`, this
+    // doesn't need the serial queue.
+    fn get_if_fresh(
+        &self,
+        generation: &AtomicUsize,
+        devtype: DeviceType,
+    ) -> Option<&[DeviceDirectionInfo]> {
+        assert!(devtype == DeviceType::INPUT || devtype == DeviceType::OUTPUT);
+        if self.generation != Some(generation.load(Ordering::SeqCst)) {
+            return None;
         }
+        Some(if devtype == DeviceType::INPUT {
+            &self.input
+        } else {
+            &self.output
+        })
     }
-}
 
-// SharedStorage<T> below looks generic but has evolved to be pretty tailored
-// the observed behavior of VoiceProcessingIO audio units on macOS 14.
-// Some key points are:
-// - Creating the first VoiceProcessingIO unit in a process takes a long time, often > 3s.
-// - Creating a second VoiceProcessingIO unit in a process is significantly faster, < 1s.
-// - Disposing of a VoiceProcessingIO unit when all other VoiceProcessingIO units are
-//   uninitialized will take significantly longer than disposing the remaining
-//   VoiceProcessingIO units, and will have other side effects: starting another
-//   VoiceProcessingIO unit after this is on par with creating the first one in the
-//   process, bluetooth devices will move away from the handsfree profile, etc.
-// The takeaway is that there is something internal to the VoiceProcessingIO audio unit
-// that is costly to create and dispose of and its creation is triggered by creation of
-// the first VoiceProcessingIO unit, and its disposal is triggered by the disposal of
-// the first VoiceProcessingIO unit when no other VoiceProcessingIO units are initialized.
-//
-// The intended behavior of SharedStorage<T> and SharedVoiceProcessingUnitManager is therefore:
-// - Retain ideally just one VoiceProcessingIO unit after stream destruction, so device
-//   switching is fast. The benefit of retaining more than one is unclear.
-// - Dispose of either all VoiceProcessingIO units, or none at all, such that the retained
-//   VoiceProcessingIO unit really helps speed up creating and starting the next. In practice
-//   this means we retain all VoiceProcessingIO units until they can all be disposed of.
+    fn refresh(&mut self, generation: &AtomicUsize, current: usize) {
+        cubeb_log!("Refreshing the device cache at generation {}", current);
+        // The listeners follow the devices, and the devices may have changed.
+        self.remove_listeners(generation);
+        let to_directions = |mut infos: Vec<ffi::cubeb_device_info>| {
+            infos
+                .iter_mut()
+                .map(|info| {
+                    let direction = DeviceDirectionInfo::new(info);
+                    destroy_cubeb_device_info(info);
+                    direction
+                })
+                .collect::<Vec<_>>()
+        };
+        self.input = to_directions(audiounit_get_device_infos_of_type(DeviceType::INPUT));
+        self.output = to_directions(audiounit_get_device_infos_of_type(DeviceType::OUTPUT));
+        self.add_listeners(generation);
+        self.generation = Some(current);
+    }
+
+    fn add_listeners(&mut self, generation: &AtomicUsize) {
+        let global = DeviceType::INPUT | DeviceType::OUTPUT;
+        let mut properties = vec![
+            (kAudioObjectSystemObject, Property::HardwareDevices, global),
+            (
+                kAudioObjectSystemObject,
+                Property::HardwareDefaultInputDevice,
+                global,
+            ),
+            (
+                kAudioObjectSystemObject,
+                Property::HardwareDefaultOutputDevice,
+                global,
+            ),
+        ];
+        for (devtype, devices) in [
+            (DeviceType::INPUT, &self.input),
+            (DeviceType::OUTPUT, &self.output),
+        ] {
+            for device in devices {
+                let id = device.devid as AudioObjectID;
+                properties.push((id, Property::DeviceIsAlive, global));
+                properties.push((id, Property::DeviceSampleRate, devtype));
+                properties.push((id, Property::DeviceSource, devtype));
+                properties.push((id, Property::DeviceStreams, devtype));
+                // For the sample rates and latencies the device supports.
+                properties.push((id, Property::DeviceSampleRates, devtype));
+                properties.push((id, Property::DeviceBufferFrameSizeRange, devtype));
+                // For the state of the device.
+                properties.push((id, Property::DeviceIsHidden, devtype));
+                properties.push((id, Property::DeviceJackIsConnected, devtype));
+            }
+        }
+        for (id, property, devtype) in properties {
+            let address = get_property_address(property, devtype);
+            // A device with input and output channels is listed twice.
+            if self.listeners.iter().any(|(i, a)| {
+                *i == id && a.mSelector == address.mSelector && a.mScope == address.mScope
+            }) {
+                continue;
+            }
+            let rv = audio_object_add_property_listener(
+                id,
+                &address,
+                audiounit_device_cache_listener,
+                generation as *const AtomicUsize as *mut AtomicUsize,
+            );
+            if rv == NO_ERR {
+                self.listeners.push((id, address));
+            } else {
+                cubeb_log!(
+                    "Cannot add device cache listener for {:?} of device {}, Error: {}",
+                    convert_uint32_into_string(address.mSelector),
+                    id,
+                    rv
+                );
+            }
+        }
+    }
+
+    fn remove_listeners(&mut self, generation: &AtomicUsize) {
+        for (id, address) in self.listeners.drain(..) {
+            // Removing the listeners of a device that went away fails, which is fine.
+            let _ = audio_object_remove_property_listener(
+                id,
+                &address,
+                audiounit_device_cache_listener,
+                generation as *const AtomicUsize as *mut AtomicUsize,
+            );
+        }
+    }
+
+    fn clear(&mut self, generation: &AtomicUsize) {
+        self.remove_listeners(generation);
+        self.generation = None;
+        self.input.clear();
+        self.output.clear();
+    }
+}
+
+// A device in one direction. CoreAudio has a single buffer size per device and direction, shared
+// by all the units of the process using it.
+type LatencyDevice = (AudioDeviceID, DeviceType);
+
+#[derive(Debug)]
+struct DeviceLatency {
+    device: LatencyDevice,
+    streams: u32,
+    latency: u32,
+}
+
+// The latency of the streams is negotiated per device: the first stream on a device sets its
+// latency, within safe min-max, and the following streams on that device use it. Streams on
+// unrelated devices don't affect each other. A device keeps its latency until its last stream is
+// removed, since changing it would change the buffer size under the streams running on it.
+#[derive(Debug)]
+struct LatencyController {
+    streams: u32,
+    devices: Vec<DeviceLatency>,
+    min_latency_frames: u32,
+    max_latency_frames: u32,
+}
+
+impl LatencyController {
+    fn new(settings: &BackendSettings) -> Self {
+        Self {
+            streams: 0,
+            devices: Vec::new(),
+            min_latency_frames: settings.min_latency_frames,
+            max_latency_frames: settings.max_latency_frames,
+        }
+    }
+
+    fn add_stream(&mut self, devices: &[LatencyDevice], latency: u32) -> Result<u32> {
+        let mut established = devices.iter().filter_map(|d| self.device_latency(d));
+        let latency = match established.next() {
+            Some(established_latency) => {
+                // A duplex stream can't use two devices that already run at different latencies:
+                // its units share one buffer size.
+                if established.any(|l| l != established_latency) {
+                    cubeb_log!(
+                        "The devices {:?} already run at different latencies.",
+                        devices
+                    );
+                    return Err(Error::error());
+                }
+                established_latency
+            }
+            // Silently clamp the latency down to the platform default, because we
+            // synthetize the clock from the callbacks, and we want the clock to update often.
+            None => latency.clamp(self.min_latency_frames, self.max_latency_frames),
+        };
+        self.streams += 1;
+        for device in devices {
+            match self.devices.iter_mut().find(|d| d.device == *device) {
+                Some(d) => d.streams += 1,
+                None => self.devices.push(DeviceLatency {
+                    device: *device,
+                    streams: 1,
+                    latency,
+                }),
+            }
+        }
+        Ok(latency)
+    }
+
+    fn subtract_stream(&mut self, devices: &[LatencyDevice]) {
+        self.streams -= 1;
+        for device in devices {
+            let index = self
+                .devices
+                .iter()
+                .position(|d| d.device == *device)
+                .expect("The stream should have been added on this device");
+            self.devices[index].streams -= 1;
+            if self.devices[index].streams == 0 {
+                self.devices.remove(index);
+            }
+        }
+        debug_assert!(self.streams > 0 || self.devices.is_empty());
+    }
+
+    fn device_latency(&self, device: &LatencyDevice) -> Option<u32> {
+        self.devices
+            .iter()
+            .find(|d| d.device == *device)
+            .map(|d| d.latency)
+    }
+}
+
+// SharedStorage<T> below looks generic but has evolved to be pretty tailored
+// the observed behavior of VoiceProcessingIO audio units on macOS 14.
+// Some key points are:
+// - Creating the first VoiceProcessingIO unit in a process takes a long time, often > 3s.
+// - Creating a second VoiceProcessingIO unit in a process is significantly faster, < 1s.
+// - Disposing of a VoiceProcessingIO unit when all other VoiceProcessingIO units are
+//   uninitialized will take significantly longer than disposing the remaining
+//   VoiceProcessingIO units, and will have other side effects: starting another
+//   VoiceProcessingIO unit after this is on par with creating the first one in the
+//   process, bluetooth devices will move away from the handsfree profile, etc.
+// The takeaway is that there is something internal to the VoiceProcessingIO audio unit
+// that is costly to create and dispose of and its creation is triggered by creation of
+// the first VoiceProcessingIO unit, and its disposal is triggered by the disposal of
+// the first VoiceProcessingIO unit when no other VoiceProcessingIO units are initialized.
+//
+// The intended behavior of SharedStorage<T> and SharedVoiceProcessingUnitManager is therefore:
+// - Retain ideally just one VoiceProcessingIO unit after stream destruction, so device
+//   switching is fast. The benefit of retaining more than one is unclear.
+// - Dispose of either all VoiceProcessingIO units, or none at all, such that the retained
+//   VoiceProcessingIO unit really helps speed up creating and starting the next. In practice
+//   this means we retain all VoiceProcessingIO units until they can all be disposed of.
 
 #[derive(Debug)]
 struct SharedStorageInternal<T> {
@@ -2190,12 +2835,13 @@ struct SharedStorageInternal<T> {
 #[derive(Debug)]
 struct SharedStorage<T> {
     queue: Queue,
-    idle_timeout: Duration,
+    // None to keep the idle elements until the storage is dropped.
+    idle_timeout: Option<Duration>,
     storage: Mutex<SharedStorageInternal<T>>,
 }
 
 impl<T: Send> SharedStorage<T> {
-    fn with_idle_timeout(queue: Queue, idle_timeout: Duration) -> Self {
+    fn with_idle_timeout(queue: Queue, idle_timeout: Option<Duration>) -> Self {
         Self {
             queue,
             idle_timeout,
@@ -2305,13 +2951,23 @@ impl<T: Send> SharedStorage<T> {
             );
             return;
         }
+        let idle_timeout = match storage.idle_timeout {
+            Some(timeout) => timeout,
+            None => {
+                cubeb_log!(
+                    "Keeping shared voiceprocessing unit storage at generation {} until the context is destroyed.",
+                    generation
+                );
+                return;
+            }
+        };
         cubeb_log!(
             "Clearing shared voiceprocessing unit storage in {}s if still at generation {}.",
-            storage.idle_timeout.as_secs_f32(),
+            idle_timeout.as_secs_f32(),
             generation
         );
         let storage = storage.clone();
-        queue.run_after(Instant::now() + storage.idle_timeout, move || {
+        queue.run_after(Instant::now() + idle_timeout, move || {
             let mut guard = storage.storage.lock().unwrap();
             if generation != guard.generation {
                 cubeb_log!(
@@ -2387,24 +3043,51 @@ impl Drop for VoiceProcessingUnit {
 
 unsafe impl Send for VoiceProcessingUnit {}
 
+// The vpio unit created ahead of the first voice stream, see
+// `AudioUnitContext::prewarm_voice_processing_unit`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VoiceProcessingPrewarmState {
+    // No unit is prewarmed, or it was taken by a stream, or released.
+    Idle,
+    // The unit is being created.
+    Pending,
+    // The unit is parked, ready for the next voice stream.
+    Ready,
+    // The unit could not be created.
+    Failed,
+}
+
+#[derive(Debug)]
+enum ParkedVoiceProcessingUnit {
+    None,
+    Pending,
+    Parked(OwningHandle<VoiceProcessingUnit>),
+    Failed,
+}
+
 #[derive(Debug)]
 struct SharedVoiceProcessingUnitManager {
     sync_storage: Mutex<Option<Arc<SharedStorage<VoiceProcessingUnit>>>>,
+    // Taken out of the storage, so that it is neither cleared on idle, nor shared until a stream
+    // takes it.
+    parked: Arc<Mutex<ParkedVoiceProcessingUnit>>,
     queue: Queue,
-    idle_timeout: Duration,
+    idle_timeout: Option<Duration>,
 }
 
 impl SharedVoiceProcessingUnitManager {
-    fn with_idle_timeout(queue: Queue, idle_timeout: Duration) -> Self {
+    fn with_idle_timeout(queue: Queue, idle_timeout: Option<Duration>) -> Self {
         Self {
             sync_storage: Mutex::new(None),
+            parked: Arc::new(Mutex::new(ParkedVoiceProcessingUnit::None)),
             queue,
             idle_timeout,
         }
     }
 
+    #[cfg(test)]
     fn new(queue: Queue) -> Self {
-        SharedVoiceProcessingUnitManager::with_idle_timeout(queue, VPIO_IDLE_TIMEOUT)
+        SharedVoiceProcessingUnitManager::with_idle_timeout(queue, Some(VPIO_IDLE_TIMEOUT))
     }
 
     fn ensure_storage_locked(
@@ -2434,15 +3117,87 @@ impl SharedVoiceProcessingUnitManager {
         res.map(|u| OwningHandle::new(Arc::downgrade(storage), u))
     }
 
-    // Take an already existing, shared, vpio unit, or create one if none are available.
+    // Take the parked vpio unit, or an already existing, shared, vpio unit, or create one if none
+    // are available.
     fn take_or_create(&mut self) -> Result<OwningHandle<VoiceProcessingUnit>> {
         debug_assert_running_serially();
+        if let Some(handle) = self.take_parked() {
+            cubeb_log!("Taking the prewarmed voiceprocessing unit.");
+            return Ok(handle);
+        }
         let mut guard = self.sync_storage.lock().unwrap();
         self.ensure_storage_locked(&mut guard);
         let storage = guard.as_mut().unwrap();
         let res = storage.take_or_create_with(create_voiceprocessing_audiounit);
         res.map(|u| OwningHandle::new(Arc::downgrade(storage), u))
     }
+
+    fn take_parked(&self) -> Option<OwningHandle<VoiceProcessingUnit>> {
+        let mut parked = self.parked.lock().unwrap();
+        match mem::replace(&mut *parked, ParkedVoiceProcessingUnit::None) {
+            ParkedVoiceProcessingUnit::Parked(handle) => Some(handle),
+            other => {
+                *parked = other;
+                None
+            }
+        }
+    }
+
+    // Create a vpio unit on the queue, and park it for the next `take_or_create`. Does nothing if
+    // one is already parked or being created.
+    fn prewarm(&self) {
+        {
+            let mut parked = self.parked.lock().unwrap();
+            if let ParkedVoiceProcessingUnit::Pending | ParkedVoiceProcessingUnit::Parked(_) =
+                *parked
+            {
+                return;
+            }
+            *parked = ParkedVoiceProcessingUnit::Pending;
+        }
+        let storage = {
+            let mut guard = self.sync_storage.lock().unwrap();
+            self.ensure_storage_locked(&mut guard);
+            guard.as_ref().unwrap().clone()
+        };
+        let parked = self.parked.clone();
+        self.queue.run_async(move || {
+            let handle = storage
+                .take_or_create_with(create_voiceprocessing_audiounit)
+                .map(|u| OwningHandle::new(Arc::downgrade(&storage), u));
+            let mut parked = parked.lock().unwrap();
+            if let ParkedVoiceProcessingUnit::Pending = *parked {
+                *parked = match handle {
+                    Ok(handle) => ParkedVoiceProcessingUnit::Parked(handle),
+                    Err(_) => ParkedVoiceProcessingUnit::Failed,
+                };
+                return;
+            }
+            // Released while being created. The unit goes back to the storage like the idle ones.
+            drop(parked);
+            drop(handle);
+        });
+    }
+
+    fn prewarm_state(&self) -> VoiceProcessingPrewarmState {
+        match *self.parked.lock().unwrap() {
+            ParkedVoiceProcessingUnit::None => VoiceProcessingPrewarmState::Idle,
+            ParkedVoiceProcessingUnit::Pending => VoiceProcessingPrewarmState::Pending,
+            ParkedVoiceProcessingUnit::Parked(_) => VoiceProcessingPrewarmState::Ready,
+            ParkedVoiceProcessingUnit::Failed => VoiceProcessingPrewarmState::Failed,
+        }
+    }
+
+    // Return the parked vpio unit to the storage, where it is cleared with the idle ones.
+    fn release_prewarmed(&self) {
+        let released = mem::replace(
+            &mut *self.parked.lock().unwrap(),
+            ParkedVoiceProcessingUnit::None,
+        );
+        if let ParkedVoiceProcessingUnit::Parked(_) = released {
+            cubeb_log!("Releasing the prewarmed voiceprocessing unit.");
+        }
+    }
 }
 
 unsafe impl Send for SharedVoiceProcessingUnitManager {}
@@ -2452,6 +3207,8 @@ impl Drop for SharedVoiceProcessingUnitManager {
     fn drop(&mut self) {
         debug_assert_not_running_serially();
         self.queue.run_final(|| {
+            // Back into the storage, to be cleared with it.
+            self.release_prewarmed();
             let mut guard = self.sync_storage.lock().unwrap();
             if guard.is_none() {
                 return;
@@ -2463,6 +3220,162 @@ impl Drop for SharedVoiceProcessingUnitManager {
 
 pub const OPS: Ops = capi_new!(AudioUnitContext, AudioUnitStream);
 
+// An aggregate device shared by the streams using the same input and output devices. The devices
+// are identified by UID, which unlike the AudioObjectIDs are stable across replugs.
+#[derive(Debug)]
+struct SharedAggregateDevice {
+    input_uid: String,
+    output_uid: String,
+    device: AggregateDevice,
+    users: usize,
+    // Set when the aggregate device is found dead. It's destroyed once its users are gone, but not
+    // handed out anymore.
+    stale: bool,
+}
+
+type SharedAggregateDevices = Arc<Mutex<Vec<SharedAggregateDevice>>>;
+
+// Creating an aggregate device takes seconds, waiting for the HAL to publish the blank device and
+// its sub-devices. The pool hands the aggregate device of a device pair to all the streams using
+// this pair, and destroys it when the last handle is dropped.
+#[derive(Debug, Default)]
+struct SharedAggregateDeviceManager {
+    devices: SharedAggregateDevices,
+}
+
+impl SharedAggregateDeviceManager {
+    fn take_or_create(
+        &self,
+        input_id: AudioObjectID,
+        output_id: AudioObjectID,
+        preferred_master_uid: Option<&str>,
+    ) -> Result<AggregateDeviceHandle> {
+        debug_assert_running_serially();
+        let uid = |id, devtype| {
+            get_device_uid(id, devtype)
+                .map(|uid| uid.into_string())
+                .map_err(|e| {
+                    cubeb_log!(
+                        "Cannot get the UID of {:?} device {}. Error: {}",
+                        devtype,
+                        id,
+                        e
+                    );
+                    Error::error()
+                })
+        };
+        let input_uid = uid(input_id, DeviceType::INPUT)?;
+        let output_uid = uid(output_id, DeviceType::OUTPUT)?;
+
+        let mut devices = self.devices.lock().unwrap();
+        for shared in devices
+            .iter_mut()
+            .filter(|d| !d.stale && d.input_uid == input_uid && d.output_uid == output_uid)
+        {
+            let device_id = shared.device.get_device_id();
+            if get_device_is_alive(device_id, DeviceType::INPUT | DeviceType::OUTPUT) != Ok(true) {
+                cubeb_log!(
+                    "Shared aggregate device {} is not alive anymore.",
+                    device_id
+                );
+                shared.stale = true;
+                continue;
+            }
+            shared.users += 1;
+            cubeb_log!(
+                "Reusing aggregate device {} for input {} and output {}. Nr of users now {}.",
+                device_id,
+                input_uid,
+                output_uid,
+                shared.users
+            );
+            return Ok(AggregateDeviceHandle::new(
+                Arc::downgrade(&self.devices),
+                device_id,
+            ));
+        }
+
+        let device =
+            AggregateDevice::new_with_master_preference(input_id, output_id, preferred_master_uid)
+                .map_err(|e| {
+                    cubeb_log!(
+                        "Cannot create an aggregate device for input {} and output {}. Error: {}",
+                        input_id,
+                        output_id,
+                        e
+                    );
+                    Error::error()
+                })?;
+        let device_id = device.get_device_id();
+        devices.push(SharedAggregateDevice {
+            input_uid,
+            output_uid,
+            device,
+            users: 1,
+            stale: false,
+        });
+        Ok(AggregateDeviceHandle::new(
+            Arc::downgrade(&self.devices),
+            device_id,
+        ))
+    }
+
+    fn clear(&self) {
+        debug_assert_running_serially();
+        let mut devices = self.devices.lock().unwrap();
+        if !devices.is_empty() {
+            cubeb_log!(
+                "Destroying {} shared aggregate devices still in use.",
+                devices.len()
+            );
+        }
+        devices.clear();
+    }
+}
+
+#[derive(Debug)]
+struct AggregateDeviceHandle {
+    devices: Weak<Mutex<Vec<SharedAggregateDevice>>>,
+    device_id: AudioObjectID,
+}
+
+impl AggregateDeviceHandle {
+    fn new(devices: Weak<Mutex<Vec<SharedAggregateDevice>>>, device_id: AudioObjectID) -> Self {
+        Self { devices, device_id }
+    }
+
+    fn get_device_id(&self) -> AudioObjectID {
+        self.device_id
+    }
+}
+
+impl Drop for AggregateDeviceHandle {
+    fn drop(&mut self) {
+        debug_assert_running_serially();
+        // The devices are gone if the context has been destroyed already.
+        let devices = match self.devices.upgrade() {
+            Some(devices) => devices,
+            None => return,
+        };
+        let mut devices = devices.lock().unwrap();
+        if let Some(index) = devices
+            .iter()
+            .position(|d| d.device.get_device_id() == self.device_id)
+        {
+            devices[index].users -= 1;
+            cubeb_log!(
+                "Releasing aggregate device {}. Nr of users now {}.",
+                self.device_id,
+                devices[index].users
+            );
+            if devices[index].users == 0 {
+                // Destroys the aggregate device.
+                devices.remove(index);
+            }
+        }
+    }
+}
+
 // The fisrt member of the Cubeb context must be a pointer to a Ops struct. The Ops struct is an
 // interface to link to all the Cubeb APIs, and the Cubeb interface use this assumption to operate
 // the Cubeb APIs on different implementation.
@@ -2472,16 +3385,26 @@ pub const OPS: Ops = capi_new!(AudioUnitContext, AudioUnitStream);
 pub struct AudioUnitContext {
     _ops: *const Ops,
     serial_queue: Queue,
+    settings: BackendSettings,
     latency_controller: Mutex<LatencyController>,
     devices: Mutex<SharedDevices>,
+    device_cache: Mutex<DeviceCache>,
+    // Incremented by the device cache listeners.
+    device_cache_generation: AtomicUsize,
     host_time_to_ns_ratio: (u32, u32),
     // Storage for a context-global vpio unit. Duplex streams that need one will take this
     // and return it when done.
     shared_voice_processing_unit: SharedVoiceProcessingUnitManager,
+    // Aggregate devices shared by the duplex streams using the same device pair.
+    shared_aggregate_devices: SharedAggregateDeviceManager,
 }
 
 impl AudioUnitContext {
     fn new() -> Self {
+        AudioUnitContext::with_settings(BackendSettings::default())
+    }
+
+    fn with_settings(settings: BackendSettings) -> Self {
         let queue_label = format!("{}.context", DISPATCH_QUEUE_LABEL);
         let serial_queue =
             Queue::new_with_target(queue_label.as_str(), get_serial_queue_singleton());
@@ -2499,26 +3422,156 @@ impl AudioUnitContext {
         Self {
             _ops: &OPS as *const _,
             serial_queue,
-            latency_controller: Mutex::new(LatencyController::default()),
+            latency_controller: Mutex::new(LatencyController::new(&settings)),
             devices: Mutex::new(SharedDevices::default()),
+            device_cache: Mutex::new(DeviceCache::default()),
+            device_cache_generation: AtomicUsize::new(0),
             host_time_to_ns_ratio,
-            shared_voice_processing_unit: SharedVoiceProcessingUnitManager::new(shared_vp_queue),
+            shared_voice_processing_unit: SharedVoiceProcessingUnitManager::with_idle_timeout(
+                shared_vp_queue,
+                settings.vpio_idle_timeout,
+            ),
+            shared_aggregate_devices: SharedAggregateDeviceManager::default(),
+            settings,
         }
     }
 
+    // Create a context with `settings`, unless overridden by the environment, instead of the
+    // defaults `ContextOps::init` uses.
+    pub fn init_with_settings(
+        _context_name: Option<&CStr>,
+        settings: BackendSettings,
+    ) -> Result<Context> {
+        let settings = settings.with_env_overrides();
+        if !settings.is_valid() {
+            cubeb_log!("Invalid backend settings: {:?}", settings);
+            return Err(Error::invalid_parameter());
+        }
+        run_serially(set_notification_runloop);
+        if settings.destroy_stale_aggregate_devices {
+            match run_serially(AggregateDevice::destroy_stale_devices) {
+                Ok(count) => cubeb_log!("Destroyed {} stale aggregate devices.", count),
+                Err(e) => cubeb_log!("Cannot destroy the stale aggregate devices. Error: {}", e),
+            }
+        }
+        let mut ctx = Box::new(AudioUnitContext::with_settings(settings));
+        let queue_label = format!("{}.context.{:p}", DISPATCH_QUEUE_LABEL, ctx.as_ref());
+        ctx.serial_queue =
+            Queue::new_with_target(queue_label.as_str(), get_serial_queue_singleton());
+        let shared_vp_queue = Queue::new_with_target(
+            format!("{}.shared_vpio", queue_label).as_str(),
+            &ctx.serial_queue,
+        );
+        ctx.shared_voice_processing_unit = SharedVoiceProcessingUnitManager::with_idle_timeout(
+            shared_vp_queue,
+            ctx.settings.vpio_idle_timeout,
+        );
+        Ok(unsafe { Context::from_ptr(Box::into_raw(ctx) as *mut _) })
+    }
+
     fn active_streams(&self) -> u32 {
         let controller = self.latency_controller.lock().unwrap();
         controller.streams
     }
 
-    fn update_latency_by_adding_stream(&self, latency_frames: u32) -> u32 {
+    fn update_latency_by_adding_stream(
+        &self,
+        devices: &[LatencyDevice],
+        latency_frames: u32,
+    ) -> Result<u32> {
         let mut controller = self.latency_controller.lock().unwrap();
-        controller.add_stream(latency_frames)
+        controller.add_stream(devices, latency_frames)
     }
 
-    fn update_latency_by_removing_stream(&self) {
+    fn update_latency_by_removing_stream(&self, devices: &[LatencyDevice]) {
         let mut controller = self.latency_controller.lock().unwrap();
-        controller.subtract_stream();
+        controller.subtract_stream(devices);
+    }
+
+    // Move a stream to other devices, e.g. after following the default device. The stream keeps
+    // its latency if it is alone on the new devices. The stream stays on `from` if it can't move.
+    fn update_latency_by_moving_stream(
+        &self,
+        from: &[LatencyDevice],
+        to: &[LatencyDevice],
+        latency_frames: u32,
+    ) -> Result<u32> {
+        let mut controller = self.latency_controller.lock().unwrap();
+        controller.subtract_stream(from);
+        controller.add_stream(to, latency_frames).inspect_err(|_| {
+            controller
+                .add_stream(from, latency_frames)
+                .expect("The stream was on these devices");
+        })
+    }
+
+    // The latency, in frames, negotiated for the streams using `devid` in `devtype`, or None if
+    // no stream of this context uses it.
+    pub fn device_latency(&self, devid: DeviceId, devtype: DeviceType) -> Result<Option<u32>> {
+        if devtype != DeviceType::INPUT && devtype != DeviceType::OUTPUT {
+            return Err(Error::invalid_parameter());
+        }
+        let id = self
+            .serial_queue
+            .run_sync(|| get_device_id(devid, devtype))
+            .unwrap()?;
+        let controller = self.latency_controller.lock().unwrap();
+        Ok(controller.device_latency(&(id, devtype)))
+    }
+
+    // Add a rule to the device quirks table, applied after the built-in rules and the rules added
+    // before it. The devices are shared by the whole process, so the rule applies to the streams of
+    // all the contexts, from the next time they set up their devices.
+    pub fn add_quirk_rule(&self, rule: QuirkRule) {
+        quirks::add_quirk_rule(rule);
+    }
+
+    // The quirks the stream setup applies to `devid` in `devtype`.
+    pub fn device_quirks(&self, devid: DeviceId, devtype: DeviceType) -> Result<Quirks> {
+        if devtype != DeviceType::INPUT && devtype != DeviceType::OUTPUT {
+            return Err(Error::invalid_parameter());
+        }
+        self.serial_queue
+            .run_sync(|| get_device_id(devid, devtype).map(|id| get_device_quirks(id, devtype)))
+            .unwrap()
+    }
+
+    // Create a VoiceProcessingIO unit in the background and park it for the first voice stream,
+    // which then doesn't wait seconds for it. The creation of the first unit in the process holds
+    // up the other work of the context until it is done. Does nothing if a unit is already parked
+    // or being created.
+    pub fn prewarm_voice_processing_unit(&self) -> Result<()> {
+        let blocked = self
+            .serial_queue
+            .run_sync(|| {
+                get_default_device(DeviceType::INPUT).is_some_and(|id| {
+                    get_device_quirks(id, DeviceType::INPUT).contains(Quirks::BLOCK_VPIO)
+                })
+            })
+            .unwrap();
+        if blocked {
+            cubeb_log!(
+                "({:p}) VoiceProcessingIO is blocked, not prewarming it.",
+                self as *const AudioUnitContext
+            );
+            return Err(Error::not_supported());
+        }
+        cubeb_log!(
+            "({:p}) Prewarming a VoiceProcessingIO unit.",
+            self as *const AudioUnitContext
+        );
+        self.shared_voice_processing_unit.prewarm();
+        Ok(())
+    }
+
+    pub fn voice_processing_prewarm_state(&self) -> VoiceProcessingPrewarmState {
+        self.shared_voice_processing_unit.prewarm_state()
+    }
+
+    // Give back the parked VoiceProcessingIO unit, or cancel its creation. It is then kept for the
+    // streams like the other idle units, until `BackendSettings::vpio_idle_timeout`.
+    pub fn release_prewarmed_voice_processing_unit(&self) {
+        self.shared_voice_processing_unit.release_prewarmed();
     }
 
     fn add_devices_changed_listener(
@@ -2568,7 +3621,7 @@ impl AudioUnitContext {
             devices.input.set(
                 collection_changed_callback,
                 user_ptr,
-                audiounit_get_devices_of_type(DeviceType::INPUT),
+                audiounit_get_device_infos_of_type(DeviceType::INPUT),
             );
         }
 
@@ -2578,7 +3631,7 @@ impl AudioUnitContext {
             devices.output.set(
                 collection_changed_callback,
                 user_ptr,
-                audiounit_get_devices_of_type(DeviceType::OUTPUT),
+                audiounit_get_device_infos_of_type(DeviceType::OUTPUT),
             );
         }
 
@@ -2601,47 +3654,223 @@ impl AudioUnitContext {
             devices.output.clear();
         }
 
-        if devices.input.changed_callback.is_some() || devices.output.changed_callback.is_some() {
-            return Ok(());
+        if devices.input.changed_callback.is_some() || devices.output.changed_callback.is_some() {
+            return Ok(());
+        }
+
+        let address = get_property_address(
+            Property::HardwareDevices,
+            DeviceType::INPUT | DeviceType::OUTPUT,
+        );
+        // Note: unregister a non registered cb is not a problem, not checking.
+        let r = audio_object_remove_property_listener(
+            kAudioObjectSystemObject,
+            &address,
+            audiounit_collection_changed_callback,
+            context_ptr,
+        );
+        if r == NO_ERR {
+            Ok(())
+        } else {
+            cubeb_log!(
+                "Cannot remove devices-changed listener for {:?}, Error: {}",
+                devtype,
+                r
+            );
+            Err(Error::error())
+        }
+    }
+
+    // The changes that triggered the latest device-collection-changed callback of `devtype`,
+    // either `DeviceType::INPUT` or `DeviceType::OUTPUT`. Meant to be called from that callback.
+    pub fn device_collection_changes(
+        &self,
+        devtype: DeviceType,
+    ) -> Result<DeviceCollectionChanges> {
+        let devices = self.devices.lock().unwrap();
+        match devtype {
+            DeviceType::INPUT => Ok(devices.input.changes.clone()),
+            DeviceType::OUTPUT => Ok(devices.output.changes.clone()),
+            _ => Err(Error::invalid_parameter()),
+        }
+    }
+
+    // Like `enumerate_devices` for both directions, with the input and output scopes of a device,
+    // or of devices sharing a group id, merged into a single entry.
+    pub fn enumerate_duplex_devices(&mut self) -> Result<Vec<DuplexDeviceInfo>> {
+        let inputs = self.cached_device_infos(DeviceType::INPUT);
+        let outputs = self.cached_device_infos(DeviceType::OUTPUT);
+        Ok(merge_duplex_devices(inputs, outputs))
+    }
+
+    // The infos of the devices in `devtype` direction. They are served from the cache without
+    // waiting for the other work on the serial queue, which is only used to refresh the cache.
+    fn cached_device_infos(&self, devtype: DeviceType) -> Vec<DeviceDirectionInfo> {
+        debug_assert_not_running_serially();
+        {
+            let cache = self.device_cache.lock().unwrap();
+            if let Some(infos) = cache.get_if_fresh(&self.device_cache_generation, devtype) {
+                return infos.to_vec();
+            }
+        }
+        self.serial_queue
+            .run_sync(|| {
+                let mut cache = self.device_cache.lock().unwrap();
+                cache.get(&self.device_cache_generation, devtype).to_vec()
+            })
+            .unwrap()
+    }
+
+    // The channel count of `devid` (or the default device of `devtype` if null) in the `devtype`
+    // direction.
+    pub fn device_max_channel_count(&self, devid: DeviceId, devtype: DeviceType) -> Result<u32> {
+        if devtype != DeviceType::INPUT && devtype != DeviceType::OUTPUT {
+            return Err(Error::invalid_parameter());
+        }
+        self.serial_queue
+            .run_sync(|| {
+                let device = get_device_id(devid, devtype)?;
+                get_channel_count(device, devtype).map_err(|e| {
+                    cubeb_log!(
+                        "Cannot get the channel count of device {} in {:?} scope. Error: {}",
+                        device,
+                        devtype,
+                        e
+                    );
+                    Error::error()
+                })
+            })
+            .unwrap()
+    }
+
+    // The smallest latency, in frames at the rate of `params` (or of the device if unset), a
+    // stream on `devid` (or the default device of `devtype` if null) can use.
+    pub fn device_min_latency(
+        &self,
+        devid: DeviceId,
+        devtype: DeviceType,
+        params: &StreamParams,
+    ) -> Result<u32> {
+        if devtype != DeviceType::INPUT && devtype != DeviceType::OUTPUT {
+            return Err(Error::invalid_parameter());
+        }
+        self.serial_queue
+            .run_sync(|| {
+                let device = get_device_id(devid, devtype)?;
+                let range = get_device_buffer_frame_size_range(device, devtype).map_err(|e| {
+                    cubeb_log!(
+                        "Could not get acceptable latency range of device {} in {:?} scope. Error: {}",
+                        device,
+                        devtype,
+                        e
+                    );
+                    Error::error()
+                })?;
+                let mut frames = range.mMinimum as u32;
+                if params.rate() > 0 {
+                    match get_device_sample_rate(device, devtype) {
+                        Ok(device_rate) => {
+                            frames = convert_frames_to_rate(frames, device_rate, params.rate());
+                        }
+                        Err(e) => {
+                            cubeb_log!(
+                                "Cannot get the sample rate of device {} in {:?} scope. Assuming {}Hz. Error: {}",
+                                device,
+                                devtype,
+                                params.rate(),
+                                e
+                            );
+                        }
+                    }
+                }
+                Ok(cmp::max(frames, self.settings.min_latency_frames))
+            })
+            .unwrap()
+    }
+
+    // The current sample rate of `devid` (or the default device of `devtype` if null).
+    pub fn device_preferred_sample_rate(
+        &self,
+        devid: DeviceId,
+        devtype: DeviceType,
+    ) -> Result<u32> {
+        if devtype != DeviceType::INPUT && devtype != DeviceType::OUTPUT {
+            return Err(Error::invalid_parameter());
+        }
+        self.serial_queue
+            .run_sync(|| {
+                let device = get_device_id(devid, devtype)?;
+                let rate = get_device_sample_rate(device, devtype).map_err(|e| {
+                    cubeb_log!(
+                        "Cannot get the sample rate of device {} in {:?} scope. Error: {}",
+                        device,
+                        devtype,
+                        e
+                    );
+                    Error::error()
+                })?;
+                Ok(rate as u32)
+            })
+            .unwrap()
+    }
+
+    // The data sources `devid` (or the default device of `devtype` if null) multiplexes in the
+    // `devtype` direction, e.g. the line-in and the internal microphone.
+    pub fn device_data_sources(
+        &self,
+        devid: DeviceId,
+        devtype: DeviceType,
+    ) -> Result<Vec<DataSource>> {
+        if devtype != DeviceType::INPUT && devtype != DeviceType::OUTPUT {
+            return Err(Error::invalid_parameter());
         }
+        self.serial_queue
+            .run_sync(|| {
+                let device = get_device_id(devid, devtype)?;
+                get_device_data_sources(device, devtype).map_err(|e| {
+                    cubeb_log!(
+                        "Cannot get the data sources for device {} in {:?} scope. Error: {}",
+                        device,
+                        devtype,
+                        e
+                    );
+                    Error::error()
+                })
+            })
+            .unwrap()
+    }
 
-        let address = get_property_address(
-            Property::HardwareDevices,
-            DeviceType::INPUT | DeviceType::OUTPUT,
-        );
-        // Note: unregister a non registered cb is not a problem, not checking.
-        let r = audio_object_remove_property_listener(
-            kAudioObjectSystemObject,
-            &address,
-            audiounit_collection_changed_callback,
-            context_ptr,
-        );
-        if r == NO_ERR {
-            Ok(())
-        } else {
-            cubeb_log!(
-                "Cannot remove devices-changed listener for {:?}, Error: {}",
-                devtype,
-                r
-            );
-            Err(Error::error())
+    // The data source currently selected on `devid` (or the default device of `devtype` if null).
+    pub fn device_data_source(
+        &mut self,
+        devid: DeviceId,
+        devtype: DeviceType,
+    ) -> Result<DataSource> {
+        if devtype != DeviceType::INPUT && devtype != DeviceType::OUTPUT {
+            return Err(Error::invalid_parameter());
         }
+        self.serial_queue
+            .run_sync(|| {
+                let device = get_device_id(devid, devtype)?;
+                get_device_source(device, devtype)
+                    .map(|source| create_data_source(device, devtype, source))
+                    .map_err(|e| {
+                        cubeb_log!(
+                            "Cannot get the data source for device {} in {:?} scope. Error: {}",
+                            device,
+                            devtype,
+                            e
+                        );
+                        Error::error()
+                    })
+            })
+            .unwrap()
     }
 }
 
 impl ContextOps for AudioUnitContext {
-    fn init(_context_name: Option<&CStr>) -> Result<Context> {
-        run_serially(set_notification_runloop);
-        let mut ctx = Box::new(AudioUnitContext::new());
-        let queue_label = format!("{}.context.{:p}", DISPATCH_QUEUE_LABEL, ctx.as_ref());
-        ctx.serial_queue =
-            Queue::new_with_target(queue_label.as_str(), get_serial_queue_singleton());
-        let shared_vp_queue = Queue::new_with_target(
-            format!("{}.shared_vpio", queue_label).as_str(),
-            &ctx.serial_queue,
-        );
-        ctx.shared_voice_processing_unit = SharedVoiceProcessingUnitManager::new(shared_vp_queue);
-        Ok(unsafe { Context::from_ptr(Box::into_raw(ctx) as *mut _) })
+    fn init(context_name: Option<&CStr>) -> Result<Context> {
+        AudioUnitContext::init_with_settings(context_name, BackendSettings::default())
     }
 
     fn backend_id(&mut self) -> &'static CStr {
@@ -2657,47 +3886,15 @@ impl ContextOps for AudioUnitContext {
     }
     #[cfg(not(target_os = "ios"))]
     fn max_channel_count(&mut self) -> Result<u32> {
-        self.serial_queue
-            .run_sync(|| {
-                let device = match get_default_device(DeviceType::OUTPUT) {
-                    None => {
-                        cubeb_log!("Could not get default output device");
-                        return Err(Error::error());
-                    }
-                    Some(id) => id,
-                };
-                get_channel_count(device, DeviceType::OUTPUT).map_err(|e| {
-                    cubeb_log!("Cannot get the channel count. Error: {}", e);
-                    Error::error()
-                })
-            })
-            .unwrap()
+        self.device_max_channel_count(ptr::null(), DeviceType::OUTPUT)
     }
     #[cfg(target_os = "ios")]
     fn min_latency(&mut self, _params: StreamParams) -> Result<u32> {
         Err(not_supported());
     }
     #[cfg(not(target_os = "ios"))]
-    fn min_latency(&mut self, _params: StreamParams) -> Result<u32> {
-        self.serial_queue
-            .run_sync(|| {
-                let device = match get_default_device(DeviceType::OUTPUT) {
-                    None => {
-                        cubeb_log!("Could not get default output device");
-                        return Err(Error::error());
-                    }
-                    Some(id) => id,
-                };
-
-                let range = get_device_buffer_frame_size_range(device, DeviceType::OUTPUT)
-                    .map_err(|e| {
-                        cubeb_log!("Could not get acceptable latency range. Error: {}", e);
-                        Error::error()
-                    })?;
-
-                Ok(cmp::max(range.mMinimum as u32, SAFE_MIN_LATENCY_FRAMES))
-            })
-            .unwrap()
+    fn min_latency(&mut self, params: StreamParams) -> Result<u32> {
+        self.device_min_latency(ptr::null(), DeviceType::OUTPUT, &params)
     }
     #[cfg(target_os = "ios")]
     fn preferred_sample_rate(&mut self) -> Result<u32> {
@@ -2705,27 +3902,20 @@ impl ContextOps for AudioUnitContext {
     }
     #[cfg(not(target_os = "ios"))]
     fn preferred_sample_rate(&mut self) -> Result<u32> {
-        self.serial_queue
-            .run_sync(|| {
-                let device = match get_default_device(DeviceType::OUTPUT) {
-                    None => {
-                        cubeb_log!("Could not get default output device");
-                        return Err(Error::error());
-                    }
-                    Some(id) => id,
-                };
-                let rate = get_device_sample_rate(device, DeviceType::OUTPUT).map_err(|e| {
-                    cubeb_log!(
-                        "Cannot get the sample rate of the default output device. Error: {}",
-                        e
-                    );
-                    Error::error()
-                })?;
-                Ok(rate as u32)
-            })
-            .unwrap()
+        self.device_preferred_sample_rate(ptr::null(), DeviceType::OUTPUT)
     }
     fn supported_input_processing_params(&mut self) -> Result<InputProcessingParams> {
+        // What a duplex stream on the default devices can apply, with VoiceProcessingIO, or in
+        // software when it is blocked for them. An input-only stream can only cancel echo with
+        // VoiceProcessingIO, see `AudioUnitStream::supported_input_processing_params` for what a
+        // given stream supports.
+        let has_input = self
+            .serial_queue
+            .run_sync(|| get_default_device(DeviceType::INPUT).is_some())
+            .unwrap();
+        if !has_input {
+            return Ok(InputProcessingParams::NONE);
+        }
         Ok(InputProcessingParams::ECHO_CANCELLATION
             | InputProcessingParams::NOISE_SUPPRESSION
             | InputProcessingParams::AUTOMATIC_GAIN_CONTROL)
@@ -2735,27 +3925,16 @@ impl ContextOps for AudioUnitContext {
         devtype: DeviceType,
         collection: &DeviceCollectionRef,
     ) -> Result<()> {
-        let device_infos = self
-            .serial_queue
-            .run_sync(|| {
-                let mut dev_types = vec![DeviceType::INPUT, DeviceType::OUTPUT];
-                dev_types.retain(|&dt| devtype.contains(dt));
-                let device_ids: Vec<(DeviceType, Vec<AudioObjectID>)> = dev_types
+        let mut dev_types = vec![DeviceType::INPUT, DeviceType::OUTPUT];
+        dev_types.retain(|&dt| devtype.contains(dt));
+        let mut device_infos = Vec::new();
+        for dt in dev_types {
+            device_infos.extend(
+                self.cached_device_infos(dt)
                     .iter()
-                    .map(|&dt| (dt, audiounit_get_devices_of_type(dt)))
-                    .collect();
-                let count = device_ids.iter().map(|(_dt, ids)| ids.len()).sum();
-                let mut device_infos = Vec::with_capacity(count);
-                for (dt, dev_ids) in device_ids {
-                    for dev_id in dev_ids {
-                        if let Ok(info) = create_cubeb_device_info(dev_id, dt) {
-                            device_infos.push(info);
-                        }
-                    }
-                }
-                device_infos
-            })
-            .unwrap();
+                    .map(|info| info.to_device_info()),
+            );
+        }
         let (ptr, len) = if device_infos.is_empty() {
             (ptr::null_mut(), 0)
         } else {
@@ -2850,13 +4029,23 @@ impl ContextOps for AudioUnitContext {
             None
         };
 
-        // Latency cannot change if another stream is operating in parallel. In this case
-        // latency is set to the other stream value.
-        let global_latency_frames = self.update_latency_by_adding_stream(latency_frames);
-        if global_latency_frames != latency_frames {
+        // Latency cannot change if another stream is operating in parallel on the same device. In
+        // this case latency is set to the other stream value.
+        let latency_devices: Vec<LatencyDevice> = in_stm_settings
+            .iter()
+            .map(|(_, device)| (device.id, DeviceType::INPUT))
+            .chain(
+                out_stm_settings
+                    .iter()
+                    .map(|(_, device)| (device.id, DeviceType::OUTPUT)),
+            )
+            .collect();
+        let device_latency_frames =
+            self.update_latency_by_adding_stream(&latency_devices, latency_frames)?;
+        if device_latency_frames != latency_frames {
             cubeb_log!(
-                "Use global latency {} instead of the requested latency {}.",
-                global_latency_frames,
+                "Use device latency {} instead of the requested latency {}.",
+                device_latency_frames,
                 latency_frames
             );
         }
@@ -2866,8 +4055,9 @@ impl ContextOps for AudioUnitContext {
             user_ptr,
             data_callback,
             state_callback,
-            global_latency_frames,
+            device_latency_frames,
         ));
+        boxed_stream.latency_devices = latency_devices;
 
         // Rename the task queue to be an unique label.
         let queue_label = format!(
@@ -2937,13 +4127,19 @@ impl Drop for AudioUnitContext {
             devices.input.changed_callback.is_none() && devices.output.changed_callback.is_none()
         });
 
-        self.shared_voice_processing_unit =
-            SharedVoiceProcessingUnitManager::new(self.serial_queue.clone());
+        self.shared_voice_processing_unit = SharedVoiceProcessingUnitManager::with_idle_timeout(
+            self.serial_queue.clone(),
+            self.settings.vpio_idle_timeout,
+        );
 
         // Make sure all the pending (device-collection-changed-callback) tasks
         // in queue are done, and cancel all the tasks appended after `drop` is executed.
         let queue = self.serial_queue.clone();
-        queue.run_final(|| {});
+        queue.run_final(|| {
+            let mut cache = self.device_cache.lock().unwrap();
+            cache.clear(&self.device_cache_generation);
+            self.shared_aggregate_devices.clear();
+        });
 
         {
             let controller = self.latency_controller.lock().unwrap();
@@ -3012,8 +4208,13 @@ impl fmt::Debug for InputCallbackLogger {
 #[derive(Debug)]
 struct CoreStreamData<'ctx> {
     stm_ptr: *const AudioUnitStream<'ctx>,
-    aggregate_device: Option<AggregateDevice>,
+    aggregate_device: Option<AggregateDeviceHandle>,
     mixer: Option<Mixer>,
+    // User-provided coefficients for the output mixer, used as long as they match the channel
+    // counts of the stream and of the output device.
+    output_mixing_matrix: Option<MixingMatrix>,
+    // The number of channels of the output hardware, as seen by the output unit.
+    output_hw_channel_count: u32,
     resampler: Resampler,
     // Stream creation parameters.
     input_stream_params: StreamParams,
@@ -3031,7 +4232,12 @@ struct CoreStreamData<'ctx> {
     output_device: device_info,
     input_processing_params: InputProcessingParams,
     input_mute: bool,
+    vpio_ducking: VpioDucking,
+    // Registered on the VoiceProcessingIO unit when it can report voice activity.
+    speech_activity_listener: Option<Box<SpeechActivityListenerBlock>>,
     input_buffer_manager: Option<BufferManager>,
+    // What the stream plays, for the echo canceller of the software input processing.
+    echo_reference: Option<EchoReferenceProducer>,
     units_running: bool,
     // Listeners indicating what system events are monitored.
     default_input_listener: Option<device_property_listener>,
@@ -3057,6 +4263,8 @@ impl Default for CoreStreamData<'_> {
             stm_ptr: ptr::null(),
             aggregate_device: None,
             mixer: None,
+            output_mixing_matrix: None,
+            output_hw_channel_count: 0,
             resampler: Resampler::default(),
             input_stream_params: StreamParams::from(ffi::cubeb_stream_params {
                 format: ffi::CUBEB_SAMPLE_FLOAT32NE,
@@ -3081,7 +4289,10 @@ impl Default for CoreStreamData<'_> {
             output_device: device_info::default(),
             input_processing_params: InputProcessingParams::NONE,
             input_mute: false,
+            vpio_ducking: VpioDucking::default(),
+            speech_activity_listener: None,
             input_buffer_manager: None,
+            echo_reference: None,
             units_running: false,
             default_input_listener: None,
             default_output_listener: None,
@@ -3125,6 +4336,8 @@ impl<'ctx> CoreStreamData<'ctx> {
             stm_ptr: stm,
             aggregate_device: None,
             mixer: None,
+            output_mixing_matrix: None,
+            output_hw_channel_count: 0,
             resampler: Resampler::default(),
             input_stream_params: in_stm_params,
             output_stream_params: out_stm_params,
@@ -3137,7 +4350,10 @@ impl<'ctx> CoreStreamData<'ctx> {
             output_device: out_dev,
             input_processing_params: InputProcessingParams::NONE,
             input_mute: false,
+            vpio_ducking: VpioDucking::default(),
+            speech_activity_listener: None,
             input_buffer_manager: None,
+            echo_reference: None,
             units_running: false,
             default_input_listener: None,
             default_output_listener: None,
@@ -3180,9 +4396,7 @@ impl<'ctx> CoreStreamData<'ctx> {
             // Always try to remember the applied input processing params. If they cannot
             // be applied in the new device pair, we notify the client of an error and it
             // will have to open a new stream.
-            if let Err(r) =
-                set_input_processing_params(self.input_unit, self.input_processing_params)
-            {
+            if let Err(r) = self.apply_voice_processing_params(self.input_processing_params) {
                 cubeb_log!(
                     "({:p}) Failed to set params of voiceprocessing. Error: {}",
                     self.stm_ptr,
@@ -3236,6 +4450,17 @@ impl<'ctx> CoreStreamData<'ctx> {
         self.output_stream_params.rate() > 0
     }
 
+    fn latency_devices(&self) -> Vec<LatencyDevice> {
+        let mut devices = Vec::new();
+        if self.has_input() {
+            devices.push((self.input_device.id, DeviceType::INPUT));
+        }
+        if self.has_output() {
+            devices.push((self.output_device.id, DeviceType::OUTPUT));
+        }
+        devices
+    }
+
     fn using_voice_processing_unit(&self) -> bool {
         self.voiceprocessing_unit_handle.is_some()
     }
@@ -3265,21 +4490,239 @@ impl<'ctx> CoreStreamData<'ctx> {
         input_domain == output_domain
     }
 
-    #[allow(non_upper_case_globals)]
-    fn should_force_vpio_for_input_device(id: AudioDeviceID) -> bool {
-        assert!(id != kAudioObjectUnknown);
-        debug_assert_running_serially();
-        match get_device_transport_type(id, DeviceType::INPUT) {
-            Ok(kAudioDeviceTransportTypeBuiltIn) => {
+    fn nested_devices(&self) -> bool {
+        self.debug_assert_is_on_stream_queue();
+        let leaves = |id| AggregateDevice::get_leaf_sub_devices(id).unwrap_or_else(|_| vec![id]);
+        let input_sub_devices = leaves(self.input_device.id);
+        let output_sub_devices = leaves(self.output_device.id);
+        let contains_all = |container: &[AudioObjectID], devices: &[AudioObjectID]| {
+            devices.iter().all(|device| container.contains(device))
+        };
+        contains_all(&input_sub_devices, &output_sub_devices)
+            || contains_all(&output_sub_devices, &input_sub_devices)
+    }
+
+    // The frames between the output callback rendering some output and the input callback getting
+    // its echo: the latencies and safety offsets of the devices, and one buffer each, less the one
+    // buffer the callbacks may run apart, which the echo canceller covers.
+    fn echo_path_delay_frames(&self) -> u32 {
+        self.debug_assert_is_on_stream_queue();
+        let device_latency = |id: AudioDeviceID, devtype: DeviceType| {
+            let safety_offset = get_device_safety_offset(id, devtype).unwrap_or_else(|e| {
+                cubeb_log!(
+                    "Cannot get the safety offset of device {} in {:?} scope. Error: {}",
+                    id,
+                    devtype,
+                    e
+                );
+                0
+            });
+            get_fixed_latency(id, devtype) + safety_offset
+        };
+        let stream = unsafe { &(*self.stm_ptr) };
+        device_latency(self.input_device.id, DeviceType::INPUT)
+            + device_latency(self.output_device.id, DeviceType::OUTPUT)
+            + stream.latency_frames
+    }
+
+    // Process the input in the input callback, when VoiceProcessingIO isn't there to do it, or can't
+    // do it on its own.
+    fn enable_software_input_processing(&mut self) {
+        self.debug_assert_is_on_stream_queue();
+        // The echo canceller needs the output at the rate of the input.
+        let rate = self.input_dev_desc.mSampleRate as u32;
+        let echo_reference = if self.has_output() && self.output_dev_desc.mSampleRate as u32 == rate
+        {
+            let (producer, consumer) = echo_reference(rate, self.echo_path_delay_frames());
+            self.echo_reference = Some(producer);
+            Some(consumer)
+        } else {
+            self.echo_reference = None;
+            None
+        };
+        self.input_buffer_manager
+            .as_mut()
+            .unwrap()
+            .enable_input_processing(rate, echo_reference);
+
+        if self.using_voice_processing_unit() {
+            // Applied along with VoiceProcessingIO when the units start.
+            return;
+        }
+
+        // Always try to remember the applied input processing params, like with VoiceProcessingIO.
+        let stream = unsafe { &(*self.stm_ptr) };
+        let supported = self.software_input_processing_params();
+        if !supported.contains(self.input_processing_params) {
+            cubeb_log!(
+                "({:p}) Input processing params {:?} can't all be applied in software, only {:?}.",
+                self.stm_ptr,
+                self.input_processing_params,
+                supported
+            );
+        }
+        stream
+            .software_input_processing_params
+            .store(self.input_processing_params & supported, Ordering::SeqCst);
+    }
+
+    fn software_input_processing_params(&self) -> InputProcessingParams {
+        let mut params = InputProcessingParams::NOISE_SUPPRESSION
+            | InputProcessingParams::AUTOMATIC_GAIN_CONTROL;
+        if self.echo_reference.is_some() {
+            params |= InputProcessingParams::ECHO_CANCELLATION;
+        }
+        params
+    }
+
+    fn set_software_input_processing_params(&self, params: InputProcessingParams) -> Result<()> {
+        self.debug_assert_is_on_stream_queue();
+        assert!(!self.using_voice_processing_unit());
+        let supported = self.software_input_processing_params();
+        if !supported.contains(params) {
+            cubeb_log!(
+                "({:p}) Input processing params {:?} can't be applied in software, only {:?}.",
+                self.stm_ptr,
+                params,
+                supported
+            );
+            return Err(Error::error());
+        }
+        let stream = unsafe { &(*self.stm_ptr) };
+        stream
+            .software_input_processing_params
+            .store(params, Ordering::SeqCst);
+        Ok(())
+    }
+
+    // The params to apply with VoiceProcessingIO, and the ones to apply in software, for `params`.
+    fn split_input_processing_params(
+        &self,
+        params: InputProcessingParams,
+    ) -> Result<(InputProcessingParams, InputProcessingParams)> {
+        assert!(self.using_voice_processing_unit());
+        let software = self.software_input_processing_params();
+        match split_voice_processing_params(params, software) {
+            Some(split) => Ok(split),
+            None => {
+                cubeb_log!(
+                    "({:p}) Input processing params {:?} can't be applied by voiceprocessing, nor in software, only {:?}.",
+                    self.stm_ptr,
+                    params,
+                    software
+                );
+                Err(Error::error())
+            }
+        }
+    }
+
+    fn apply_voice_processing_params(&self, params: InputProcessingParams) -> Result<()> {
+        self.debug_assert_is_on_stream_queue();
+        let (vpio_params, software_params) = self.split_input_processing_params(params)?;
+        set_input_processing_params(self.input_unit, vpio_params)?;
+        let stream = unsafe { &(*self.stm_ptr) };
+        stream
+            .software_input_processing_params
+            .store(software_params, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn install_speech_activity_listener(&mut self) {
+        self.debug_assert_is_on_stream_queue();
+        assert!(self.using_voice_processing_unit());
+        assert!(self.speech_activity_listener.is_none());
+        let listener = Box::new(SpeechActivityListenerBlock::new(
+            speech_activity_listener,
+            self.stm_ptr as *mut c_void,
+        ));
+        match set_speech_activity_listener(self.input_unit, Some(&*listener)) {
+            Ok(()) => self.speech_activity_listener = Some(listener),
+            Err(r) => cubeb_log!(
+                "({:p}) VoiceProcessingIO can't report voice activity, rv={}. Detect it in the input callback.",
+                self.stm_ptr,
+                r
+            ),
+        }
+    }
+
+    fn uninstall_speech_activity_listener(&mut self) {
+        self.debug_assert_is_on_stream_queue();
+        if self.speech_activity_listener.take().is_some() {
+            // The VoiceProcessingIO unit is shared, and outlives the stream.
+            if let Err(r) = set_speech_activity_listener(self.input_unit, None) {
                 cubeb_log!(
-                    "Input device {} is on the VPIO force list because it is built in, \
-                     and its volume is known to be very low without VPIO whenever VPIO \
-                     is hooked up to it elsewhere.",
-                    id
+                    "({:p}) Failed to remove the speech activity listener, rv={}",
+                    self.stm_ptr,
+                    r
                 );
-                true
             }
-            _ => false,
+        }
+    }
+
+    // Mute the input with the VoiceProcessingIO unit, or in the input callback when the stream
+    // detects voice activity itself, since the unit would silence the input before the detector.
+    fn apply_input_mute(&self, mute: bool) -> Result<()> {
+        self.debug_assert_is_on_stream_queue();
+        assert!(self.using_voice_processing_unit());
+        let stream = unsafe { &(*self.stm_ptr) };
+        let detect_voice_activity = self.speech_activity_listener.is_none()
+            && stream
+                .voice_activity_callback
+                .load(Ordering::SeqCst)
+                .is_some();
+        set_input_mute(self.input_unit, mute && !detect_voice_activity)?;
+        stream
+            .software_input_mute
+            .store(mute && detect_voice_activity, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn apply_vpio_ducking(&self) -> Result<()> {
+        self.debug_assert_is_on_stream_queue();
+        assert!(self.using_voice_processing_unit());
+        #[allow(non_upper_case_globals)]
+        let device = match self.output_device.id {
+            kAudioObjectUnknown => get_default_device(DeviceType::OUTPUT),
+            id => Some(id),
+        };
+        set_vpio_ducking(self.input_unit, device, self.vpio_ducking)
+    }
+
+    fn should_use_aggregate_device(&self) -> bool {
+        self.debug_assert_is_on_stream_queue();
+        assert!(self.has_input() && self.has_output());
+        let has_channels =
+            |id, devtype| matches!(get_channel_count(id, devtype), Ok(count) if count > 0);
+        let facts = DuplexDeviceFacts {
+            same_device: self.input_device.id == self.output_device.id,
+            nested_devices: self.nested_devices(),
+            same_clock_domain: self.same_clock_domain(),
+            input_has_output: has_channels(self.input_device.id, DeviceType::OUTPUT),
+            output_has_input: has_channels(self.output_device.id, DeviceType::INPUT),
+        };
+        let stream = unsafe { &(*self.stm_ptr) };
+        let policy = stream.context.settings.aggregate_device_policy;
+        let use_aggregate = should_use_aggregate_device(policy, &facts);
+        cubeb_log!(
+            "({:p}) Input device ID: {}, output device ID: {}, {:?}. Aggregate device policy {:?}: {}",
+            self.stm_ptr,
+            self.input_device.id,
+            self.output_device.id,
+            facts,
+            policy,
+            if use_aggregate { "use" } else { "don't use" }
+        );
+        use_aggregate
+    }
+
+    fn should_force_vpio_for_input_device(id: AudioDeviceID) -> bool {
+        assert!(id != kAudioObjectUnknown);
+        debug_assert_running_serially();
+        if get_device_quirks(id, DeviceType::INPUT).contains(Quirks::FORCE_VPIO) {
+            cubeb_log!("Input device {} is on the VPIO force list.", id);
+            true
+        } else {
+            false
         }
     }
 
@@ -3290,11 +4733,9 @@ impl<'ctx> CoreStreamData<'ctx> {
     ) -> bool {
         self.debug_assert_is_on_stream_queue();
         cubeb_log!("Evaluating device pair against VPIO block list");
-        let log_device_and_get_model_uid = |id, devtype| -> String {
-            let device_model_uid = get_device_model_uid(id, devtype)
-                .map(|s| s.into_string())
-                .unwrap_or_default();
-            cubeb_log!("{} uid=\"{}\", model_uid=\"{}\", transport_type={:?}, source={:?}, source_name=\"{}\", name=\"{}\", manufacturer=\"{}\"",
+        let log_device_and_get_quirks = |id, devtype| -> Quirks {
+            let quirks = get_device_quirks(id, devtype);
+            cubeb_log!("{} uid=\"{}\", model_uid=\"{}\", transport_type={:?}, source={:?}, source_name=\"{}\", name=\"{}\", manufacturer=\"{}\", quirks={:?}",
                 if devtype == DeviceType::INPUT {
                     "Input"
                 } else {
@@ -3302,13 +4743,14 @@ impl<'ctx> CoreStreamData<'ctx> {
                     "Output"
                 },
                 get_device_uid(id, devtype).map(|s| s.into_string()).unwrap_or_default(),
-                device_model_uid,
+                get_device_model_uid(id, devtype).map(|s| s.into_string()).unwrap_or_default(),
                 convert_uint32_into_string(get_device_transport_type(id, devtype).unwrap_or(0)),
                 convert_uint32_into_string(get_device_source(id, devtype).unwrap_or(0)),
                 get_device_source_name(id, devtype).map(|s| s.into_string()).unwrap_or_default(),
                 get_device_name(id, devtype).map(|s| s.into_string()).unwrap_or_default(),
-                get_device_manufacturer(id, devtype).map(|s| s.into_string()).unwrap_or_default());
-            device_model_uid
+                get_device_manufacturer(id, devtype).map(|s| s.into_string()).unwrap_or_default(),
+                quirks);
+            quirks
         };
 
         #[allow(non_upper_case_globals)]
@@ -3322,20 +4764,27 @@ impl<'ctx> CoreStreamData<'ctx> {
             id => Some(id),
         };
 
-        let (in_model_uid, out_model_uid) = (
+        let (in_quirks, out_quirks) = (
             in_id
-                .map(|id| log_device_and_get_model_uid(id, DeviceType::INPUT))
+                .map(|id| log_device_and_get_quirks(id, DeviceType::INPUT))
                 .unwrap_or_default(),
             out_id
                 .or_else(|| get_default_device(DeviceType::OUTPUT))
-                .map(|id| log_device_and_get_model_uid(id, DeviceType::OUTPUT))
+                .map(|id| log_device_and_get_quirks(id, DeviceType::OUTPUT))
                 .unwrap_or_default(),
         );
 
-        if in_model_uid.contains(APPLE_STUDIO_DISPLAY_USB_ID)
-            && out_model_uid.contains(APPLE_STUDIO_DISPLAY_USB_ID)
+        if in_quirks.contains(Quirks::BLOCK_VPIO) {
+            cubeb_log!("Input device is on the VPIO block list. BLOCKED");
+            return true;
+        }
+
+        if in_quirks.contains(Quirks::BLOCK_VPIO_FOR_PAIR)
+            && out_quirks.contains(Quirks::BLOCK_VPIO_FOR_PAIR)
         {
-            cubeb_log!("Both input and output device is an Apple Studio Display. BLOCKED");
+            cubeb_log!(
+                "Both input and output device are on the VPIO block list as a pair. BLOCKED"
+            );
             return true;
         }
 
@@ -3346,55 +4795,18 @@ impl<'ctx> CoreStreamData<'ctx> {
     fn create_audiounits(
         &mut self,
         shared_voice_processing_unit: &mut SharedVoiceProcessingUnitManager,
-    ) -> Result<(device_info, device_info)> {
-        self.debug_assert_is_on_stream_queue();
-        let should_use_voice_processing_unit = self.has_input()
-            && (self
-                .input_stream_params
-                .prefs()
-                .contains(StreamPrefs::VOICE)
-                || CoreStreamData::should_force_vpio_for_input_device(self.input_device.id))
-            && !self.should_block_vpio_for_device_pair(&self.input_device, &self.output_device)
-            && macos_kernel_major_version() != Ok(MACOS_KERNEL_MAJOR_VERSION_MONTEREY);
-
-        let should_use_aggregate_device = {
-            // It's impossible to create an aggregate device from an aggregate device, and it's
-            // unnecessary to create an aggregate device when opening the same device input/output. In
-            // all other cases, use an aggregate device.
-            let mut either_already_aggregate = false;
-            if self.has_input() {
-                let input_is_aggregate =
-                    get_device_transport_type(self.input_device.id, DeviceType::INPUT).unwrap_or(0)
-                        == kAudioDeviceTransportTypeAggregate;
-                if input_is_aggregate {
-                    either_already_aggregate = true;
-                }
-                cubeb_log!(
-                    "Input device ID: {} (aggregate: {:?})",
-                    self.input_device.id,
-                    input_is_aggregate
-                );
-            }
-            if self.has_output() {
-                let output_is_aggregate =
-                    get_device_transport_type(self.output_device.id, DeviceType::OUTPUT)
-                        .unwrap_or(0)
-                        == kAudioDeviceTransportTypeAggregate;
-                if output_is_aggregate {
-                    either_already_aggregate = true;
-                }
-                cubeb_log!(
-                    "Output device ID: {} (aggregate: {:?})",
-                    self.output_device.id,
-                    output_is_aggregate
-                );
-            }
-            // Only use an aggregate device when the device are different.
-            self.has_input()
-                && self.has_output()
-                && self.input_device.id != self.output_device.id
-                && !either_already_aggregate
-        };
+    ) -> Result<(device_info, device_info)> {
+        self.debug_assert_is_on_stream_queue();
+        let should_use_voice_processing_unit = self.has_input()
+            && (self
+                .input_stream_params
+                .prefs()
+                .contains(StreamPrefs::VOICE)
+                || CoreStreamData::should_force_vpio_for_input_device(self.input_device.id))
+            && !self.should_block_vpio_for_device_pair(&self.input_device, &self.output_device);
+
+        let should_use_aggregate_device =
+            self.has_input() && self.has_output() && self.should_use_aggregate_device();
 
         // Create an AudioUnit:
         // - If we're eligible to use voice processing, try creating a VoiceProcessingIO AudioUnit.
@@ -3421,7 +4833,16 @@ impl<'ctx> CoreStreamData<'ctx> {
         }
 
         if should_use_aggregate_device {
-            if let Ok(device) = AggregateDevice::new(self.input_device.id, self.output_device.id) {
+            let stream = unsafe { &(*self.stm_ptr) };
+            if let Ok(device) = stream.context.shared_aggregate_devices.take_or_create(
+                self.input_device.id,
+                self.output_device.id,
+                stream
+                    .context
+                    .settings
+                    .aggregate_master_device_uid
+                    .as_deref(),
+            ) {
                 let in_dev_info = {
                     device_info {
                         id: device.get_device_id(),
@@ -3707,13 +5128,21 @@ impl<'ctx> CoreStreamData<'ctx> {
             // the raw data taken from input callback.
             self.input_buffer_manager = Some(BufferManager::new(
                 self.input_stream_params.format(),
-                SAFE_MAX_LATENCY_FRAMES as usize,
+                stream.context.settings.max_latency_frames as usize,
+                stream.context.settings.input_buffer_callbacks as usize,
                 self.input_dev_desc.mChannelsPerFrame as usize,
                 self.input_dev_desc
                     .mChannelsPerFrame
                     .saturating_sub(device_channel_count) as usize,
                 self.input_stream_params.channels() as usize,
             ));
+            if using_voice_processing_unit {
+                // Only listens while the input is muted in software.
+                self.input_buffer_manager
+                    .as_mut()
+                    .unwrap()
+                    .enable_voice_activity_detection(self.input_dev_desc.mSampleRate as u32);
+            }
 
             let aurcbs_in = AURenderCallbackStruct {
                 inputProc: Some(audiounit_input_callback),
@@ -3827,10 +5256,28 @@ impl<'ctx> CoreStreamData<'ctx> {
                 return Err(Error::error());
             }
 
+            self.output_hw_channel_count = output_hw_desc.mChannelsPerFrame;
+
+            // The device might have changed since the mixing matrix was set.
+            let mixing_matrix = self.output_mixing_matrix.clone().filter(|m| {
+                let valid = m.input_channels() == self.output_stream_params.channels() as usize
+                    && m.output_channels() == output_hw_desc.mChannelsPerFrame as usize;
+                if !valid {
+                    cubeb_log!(
+                        "({:p}) Output mixing matrix {}x{} doesn't match the channels. Ignore it",
+                        self.stm_ptr,
+                        m.output_channels(),
+                        m.input_channels()
+                    );
+                }
+                valid
+            });
+
             // Simple case of stereo output, map to the stereo pair (that might not be the first
             // two channels). Fall back to regular mixing if this fails.
             let mut maybe_need_mixer = true;
-            if self.output_stream_params.channels() == 2
+            if mixing_matrix.is_none()
+                && self.output_stream_params.channels() == 2
                 && self.output_stream_params.layout() == ChannelLayout::STEREO
             {
                 let layout = AudioChannelLayout {
@@ -3923,14 +5370,26 @@ impl<'ctx> CoreStreamData<'ctx> {
                 // 1. using aggregate device whose input device has output channels
                 // 2. output device has more channels than we need, and stream isn't simply stereo
                 // 3. output device has different layout than the one we have
-                self.mixer = if self.output_dev_desc.mChannelsPerFrame
-                    != self.output_stream_params.channels()
-                    || device_layout != mixer::get_channel_order(self.output_stream_params.layout())
-                {
+                // 4. using a mixing matrix provided by the user
+                self.mixer = if let Some(matrix) = mixing_matrix {
+                    cubeb_log!("({:p}) Using the output mixing matrix", self.stm_ptr);
+                    Some(Mixer::with_matrix(
+                        self.output_stream_params.format(),
+                        self.output_dev_desc.mSampleRate as u32,
+                        matrix,
+                    ))
+                } else if mixer::get_output_channel_order(
+                    self.output_dev_desc.mChannelsPerFrame as usize,
+                    device_layout.clone(),
+                ) != mixer::get_input_channel_order(
+                    self.output_stream_params.layout(),
+                    self.output_stream_params.channels() as usize,
+                ) {
                     cubeb_log!("Incompatible channel layouts detected, setting up remixer");
                     // We will be remixing the data before it reaches the output device.
                     Some(Mixer::new(
                         self.output_stream_params.format(),
+                        self.output_dev_desc.mSampleRate as u32,
                         self.output_stream_params.channels() as usize,
                         self.output_stream_params.layout(),
                         self.output_dev_desc.mChannelsPerFrame as usize,
@@ -4012,6 +5471,10 @@ impl<'ctx> CoreStreamData<'ctx> {
             );
         }
 
+        if self.has_input() {
+            self.enable_software_input_processing();
+        }
+
         // We use a resampler because input AudioUnit operates
         // reliable only in the capture device sample rate.
         // Resampler will convert it to the user sample rate
@@ -4125,41 +5588,24 @@ impl<'ctx> CoreStreamData<'ctx> {
 
         if using_voice_processing_unit {
             // The VPIO AudioUnit automatically ducks other audio streams on the VPIO
-            // output device. Its ramp duration is 0.5s when ducking, so unduck similarly
-            // now.
+            // output device. Replace its ducking with the one of the stream now.
             // NOTE: On MacOS 14 the ducking happens on creation of the VPIO AudioUnit.
             //       On MacOS 10.15 it happens on both creation and initialization, which
-            //       is why we defer the unducking until now.
-            #[allow(non_upper_case_globals)]
-            let mut device = match self.output_device.id {
-                kAudioObjectUnknown => None,
-                id => Some(id),
-            };
-            device = device.or_else(|| get_default_device(DeviceType::OUTPUT));
-            match device {
-                None => {
-                    cubeb_log!(
-                        "({:p}) No output device to undo vpio ducking on",
-                        self.stm_ptr
-                    );
-                }
-                Some(id) => {
-                    let r = audio_device_duck(id, 1.0, ptr::null_mut(), 0.5);
-                    if r != NO_ERR {
-                        cubeb_log!(
-                            "({:p}) Failed to undo ducking of voiceprocessing on output device {}. Proceeding... Error: {}",
-                            self.stm_ptr,
-                            id,
-                            r
-                        );
-                    }
-                }
-            };
+            //       is why we defer this until now.
+            if self.apply_vpio_ducking().is_err() {
+                cubeb_log!(
+                    "({:p}) Failed to set ducking of voiceprocessing to {:?}. Proceeding...",
+                    self.stm_ptr,
+                    self.vpio_ducking
+                );
+            }
+
+            self.install_speech_activity_listener();
 
             // Always try to remember the applied input mute state. If it cannot be applied
             // to the new device pair, we notify the client of an error and it will have to
             // open a new stream.
-            if let Err(r) = set_input_mute(self.input_unit, self.input_mute) {
+            if let Err(r) = self.apply_input_mute(self.input_mute) {
                 cubeb_log!(
                     "({:p}) Failed to set mute state of voiceprocessing. Error: {}",
                     self.stm_ptr,
@@ -4209,6 +5655,7 @@ impl<'ctx> CoreStreamData<'ctx> {
 
     fn close(&mut self) {
         self.debug_assert_is_on_stream_queue();
+        self.uninstall_speech_activity_listener();
         if !self.input_unit.is_null() {
             audio_unit_uninitialize(self.input_unit);
             if self.using_voice_processing_unit() {
@@ -4269,6 +5716,7 @@ impl<'ctx> CoreStreamData<'ctx> {
 
         self.resampler.destroy();
         self.mixer = None;
+        self.echo_reference = None;
         self.aggregate_device = None;
 
         if self.uninstall_system_changed_callback().is_err() {
@@ -4554,7 +6002,8 @@ impl<'ctx> CoreStreamData<'ctx> {
         if self.using_voice_processing_unit() {
             return Ok(get_channel_order(ChannelLayout::MONO));
         }
-        get_channel_layout(self.output_unit)
+        let layout = get_channel_layout(self.output_unit)?;
+        Ok(get_output_channel_layout_quirk(self.output_device.id, &layout).unwrap_or(layout))
     }
 }
 
@@ -4578,7 +6027,7 @@ struct OutputCallbackTimingData {
 // #[repr(C)] is used to prevent any padding from being added in the beginning of the AudioUnitStream.
 #[repr(C)]
 #[derive(Debug)]
-struct AudioUnitStream<'ctx> {
+pub struct AudioUnitStream<'ctx> {
     context: &'ctx mut AudioUnitContext,
     user_ptr: *mut c_void,
     // Task queue for the stream.
@@ -4599,8 +6048,10 @@ struct AudioUnitStream<'ctx> {
     reinit_pending: AtomicBool,
     delayed_reinit: bool,
     destroy_pending: AtomicBool,
-    // Latency requested by the user.
+    // Latency requested by the user, as negotiated with the other streams on the same devices.
     latency_frames: u32,
+    // The devices this stream is accounted on in the context's latency controller.
+    latency_devices: Vec<LatencyDevice>,
     // Fixed latency, characteristic of the device.
     output_device_latency_frames: AtomicU32,
     input_device_latency_frames: AtomicU32,
@@ -4612,6 +6063,36 @@ struct AudioUnitStream<'ctx> {
     prev_position: u64,
     // This is true if a device change callback is currently running.
     switching_device: AtomicBool,
+    // Dither applied when audio is converted from float to 16-bit samples, in the input
+    // downmix or in the output mixer. Read on the callback threads.
+    dither_mode: Atomic<DitherMode>,
+    // Peak control applied by the output mixer after downmixing. This has no effect when there
+    // is no mixer in the output path.
+    limiter_mode: Atomic<LimiterMode>,
+    // The largest gain reduction applied by the limiter during the last output callback, in dB.
+    limiter_gain_reduction: Atomic<f32>,
+    // Volume set by the user, applied in software by `volume_gain`, which ramps towards it
+    // following `volume_ramp` over `volume_ramp_duration` seconds.
+    volume: Atomic<f32>,
+    volume_ramp: Atomic<VolumeRamp>,
+    volume_ramp_duration: Atomic<f32>,
+    // Only accessed from the output callback.
+    volume_gain: GainRamp,
+    // Fade-in on start and fade-out on stop and drain. Disabled when the duration, in seconds,
+    // is 0. The output callback applies the requested fade with `fader`, and sets
+    // `fade_out_done` once the output is silent, which `fade_out` polls for.
+    fade_duration: Atomic<f32>,
+    fade_request: Atomic<FadeRequest>,
+    fade_out_done: AtomicBool,
+    // Only accessed from the output callback.
+    fader: Fader,
+    // Input processing params applied by the input callback, when the stream doesn't use
+    // VoiceProcessingIO.
+    software_input_processing_params: Atomic<InputProcessingParams>,
+    voice_activity_callback: Atomic<Option<VoiceActivityCallback>>,
+    // Set when the input callback mutes the input instead of the VoiceProcessingIO unit, so the
+    // voice activity detector of the input buffer manager can hear the user.
+    software_input_mute: AtomicBool,
     core_stream_data: CoreStreamData<'ctx>,
 }
 
@@ -4648,6 +6129,7 @@ impl<'ctx> AudioUnitStream<'ctx> {
             delayed_reinit: false,
             destroy_pending: AtomicBool::new(false),
             latency_frames,
+            latency_devices: Vec::new(),
             output_device_latency_frames: AtomicU32::new(0),
             input_device_latency_frames: AtomicU32::new(0),
             total_output_latency_frames: AtomicU32::new(0),
@@ -4656,6 +6138,20 @@ impl<'ctx> AudioUnitStream<'ctx> {
             output_callback_timing_data_read,
             prev_position: 0,
             switching_device: AtomicBool::new(false),
+            dither_mode: Atomic::new(DitherMode::default()),
+            limiter_mode: Atomic::new(LimiterMode::default()),
+            limiter_gain_reduction: Atomic::new(0.0),
+            volume: Atomic::new(1.0),
+            volume_ramp: Atomic::new(VolumeRamp::default()),
+            volume_ramp_duration: Atomic::new(0.0),
+            volume_gain: GainRamp::new(1.0),
+            fade_duration: Atomic::new(0.0),
+            fade_request: Atomic::new(FadeRequest::None),
+            fade_out_done: AtomicBool::new(false),
+            fader: Fader::default(),
+            software_input_processing_params: Atomic::new(InputProcessingParams::NONE),
+            voice_activity_callback: Atomic::new(None),
+            software_input_mute: AtomicBool::new(false),
             core_stream_data: CoreStreamData::default(),
         }
     }
@@ -4680,6 +6176,14 @@ impl<'ctx> AudioUnitStream<'ctx> {
         )
     }
 
+    fn notify_voice_activity(&self, event: VoiceActivityEvent) {
+        if let Some(callback) = self.voice_activity_callback.load(Ordering::SeqCst) {
+            unsafe {
+                callback(self.user_ptr, event);
+            }
+        }
+    }
+
     fn notify_state_changed(&self, state: State) {
         if self.state_callback.is_none() {
             return;
@@ -4714,12 +6218,9 @@ impl<'ctx> AudioUnitStream<'ctx> {
             !self.core_stream_data.input_unit.is_null()
                 || !self.core_stream_data.output_unit.is_null()
         );
-        let vol_rv = if self.core_stream_data.output_unit.is_null() {
-            Err(Error::error())
-        } else {
-            get_volume(self.core_stream_data.output_unit)
-        };
-
+        // Hold the aggregate device until the stream is set up again, so the setup reuses it if
+        // the stream still uses the same devices.
+        let _aggregate_device = self.core_stream_data.aggregate_device.take();
         self.core_stream_data.close();
 
         // Use the new default device if this stream was set to follow the output device.
@@ -4760,6 +6261,26 @@ impl<'ctx> AudioUnitStream<'ctx> {
                 }
         }
 
+        // The new devices may already run at another latency.
+        let latency_devices = self.core_stream_data.latency_devices();
+        if latency_devices != self.latency_devices {
+            let latency_frames = self.context.update_latency_by_moving_stream(
+                &self.latency_devices,
+                &latency_devices,
+                self.latency_frames,
+            )?;
+            if latency_frames != self.latency_frames {
+                cubeb_log!(
+                    "({:p}) Use device latency {} instead of {} after switching devices.",
+                    self as *const AudioUnitStream,
+                    latency_frames,
+                    self.latency_frames
+                );
+            }
+            self.latency_frames = latency_frames;
+            self.latency_devices = latency_devices;
+        }
+
         cubeb_log!("Reinit: setup");
         self.core_stream_data
             .setup(&mut self.context.shared_voice_processing_unit)
@@ -4767,10 +6288,6 @@ impl<'ctx> AudioUnitStream<'ctx> {
                 cubeb_log!("({:p}) Setup failed.", self.core_stream_data.stm_ptr);
             })?;
 
-        if let Ok(volume) = vol_rv {
-            set_volume(self.core_stream_data.output_unit, volume);
-        }
-
         // If the stream was running, start it again.
         if !self.stopped.load(Ordering::SeqCst) {
             self.core_stream_data.start_audiounits().inspect_err(|_| {
@@ -4836,7 +6353,8 @@ impl<'ctx> AudioUnitStream<'ctx> {
         self.queue.debug_assert_is_current();
         self.core_stream_data.close();
         assert!(self.context.active_streams() >= 1);
-        self.context.update_latency_by_removing_stream();
+        self.context
+            .update_latency_by_removing_stream(&self.latency_devices);
     }
 
     fn destroy(&mut self) {
@@ -4881,6 +6399,370 @@ impl<'ctx> AudioUnitStream<'ctx> {
             self as *const AudioUnitStream
         );
     }
+
+    // Takes effect from the next callback, and is kept across reinits.
+    pub fn set_dither_mode(&self, mode: DitherMode) {
+        cubeb_log!(
+            "({:p}) Set dither mode to {:?}",
+            self as *const AudioUnitStream,
+            mode
+        );
+        self.dither_mode.store(mode, Ordering::SeqCst);
+    }
+
+    pub fn dither_mode(&self) -> DitherMode {
+        self.dither_mode.load(Ordering::SeqCst)
+    }
+
+    // Takes effect from the next callback, and is kept across reinits.
+    pub fn set_limiter_mode(&self, mode: LimiterMode) {
+        cubeb_log!(
+            "({:p}) Set limiter mode to {:?}",
+            self as *const AudioUnitStream,
+            mode
+        );
+        self.limiter_mode.store(mode, Ordering::SeqCst);
+    }
+
+    pub fn limiter_mode(&self) -> LimiterMode {
+        self.limiter_mode.load(Ordering::SeqCst)
+    }
+
+    // Gain reduction meter: the largest reduction applied by the limiter during the last output
+    // callback, in dB. 0 when the limiter is disabled, idle, or when no mixer is in use.
+    pub fn limiter_gain_reduction(&self) -> f32 {
+        self.limiter_gain_reduction.load(Ordering::SeqCst)
+    }
+
+    // How the output gain moves to a new volume passed to `set_volume`. A zero duration applies
+    // the new volume at the start of the next callback.
+    pub fn set_volume_ramp(&self, ramp: VolumeRamp, duration: Duration) {
+        cubeb_log!(
+            "({:p}) Set volume ramp to {:?} over {:?}",
+            self as *const AudioUnitStream,
+            ramp,
+            duration
+        );
+        self.volume_ramp.store(ramp, Ordering::SeqCst);
+        self.volume_ramp_duration
+            .store(duration.as_secs_f32(), Ordering::SeqCst);
+    }
+
+    pub fn volume(&self) -> f32 {
+        self.volume.load(Ordering::SeqCst)
+    }
+
+    // The data source currently selected on the device used by the stream in `devtype` direction.
+    pub fn data_source(&mut self, devtype: DeviceType) -> Result<DataSource> {
+        let device = self.data_source_device(devtype)?;
+        self.queue
+            .run_sync(|| {
+                get_device_source(device, devtype)
+                    .map(|source| create_data_source(device, devtype, source))
+                    .map_err(|e| {
+                        cubeb_log!(
+                            "({:p}) Cannot get the data source for device {} in {:?} scope. Error: {}",
+                            self as *const AudioUnitStream,
+                            device,
+                            devtype,
+                            e
+                        );
+                        Error::error()
+                    })
+            })
+            .unwrap()
+    }
+
+    // Select `source`, one of the ids of `AudioUnitContext::device_data_sources`, on the device
+    // used by the stream in `devtype` direction. This isn't scoped to the stream: the data source
+    // is a setting of the device, so the other streams and processes using the device switch to
+    // it too, and the device keeps it after the stream is destroyed. The stream reinitializes
+    // itself once the device reports the change.
+    pub fn set_data_source(&mut self, devtype: DeviceType, source: u32) -> Result<()> {
+        let device = self.data_source_device(devtype)?;
+        self.queue
+            .run_sync(|| {
+                let sources = get_device_sources(device, devtype).map_err(|e| {
+                    cubeb_log!(
+                        "({:p}) Cannot get the data sources for device {} in {:?} scope. Error: {}",
+                        self as *const AudioUnitStream,
+                        device,
+                        devtype,
+                        e
+                    );
+                    Error::error()
+                })?;
+                if !sources.contains(&source) {
+                    return Err(Error::invalid_parameter());
+                }
+                set_device_source(device, devtype, source).map_err(|e| {
+                    cubeb_log!(
+                        "({:p}) Cannot set data source {:?} for device {} in {:?} scope. Error: {}",
+                        self as *const AudioUnitStream,
+                        convert_uint32_into_string(source),
+                        device,
+                        devtype,
+                        e
+                    );
+                    Error::error()
+                })
+            })
+            .unwrap()?;
+
+        cubeb_log!(
+            "Cubeb stream ({:p}) set {:?} data source to {:?}.",
+            self as *const AudioUnitStream,
+            devtype,
+            convert_uint32_into_string(source)
+        );
+        Ok(())
+    }
+
+    // The master device and drift compensation of the aggregate device the stream uses, if any.
+    pub fn aggregate_clock_info(&self) -> Result<Option<AggregateClockInfo>> {
+        self.queue
+            .run_sync(|| match self.core_stream_data.aggregate_device {
+                Some(ref device) => AggregateDevice::get_clock_info(device.get_device_id())
+                    .map(Some)
+                    .map_err(|e| {
+                        cubeb_log!(
+                            "({:p}) Cannot get the clock info of aggregate device {}. Error: {}",
+                            self as *const AudioUnitStream,
+                            device.get_device_id(),
+                            e
+                        );
+                        Error::error()
+                    }),
+                None => Ok(None),
+            })
+            .unwrap()
+    }
+
+    fn data_source_device(&mut self, devtype: DeviceType) -> Result<AudioDeviceID> {
+        self.queue
+            .clone()
+            .run_sync(|| match devtype {
+                DeviceType::INPUT if self.core_stream_data.has_input() => {
+                    Ok(self.core_stream_data.input_device.id)
+                }
+                DeviceType::OUTPUT if self.core_stream_data.has_output() => {
+                    Ok(self.core_stream_data.output_device.id)
+                }
+                _ => Err(Error::invalid_parameter()),
+            })
+            .unwrap()
+    }
+
+    // The input processing params the stream can apply, for its current devices. Not all their
+    // combinations might be, see `supported_input_processing_param_combinations`.
+    pub fn supported_input_processing_params(&self) -> InputProcessingParams {
+        if self.core_stream_data.input_unit.is_null() {
+            return InputProcessingParams::NONE;
+        }
+        self.queue
+            .run_sync(|| {
+                if self.core_stream_data.using_voice_processing_unit() {
+                    InputProcessingParams::ECHO_CANCELLATION
+                        | InputProcessingParams::NOISE_SUPPRESSION
+                        | InputProcessingParams::AUTOMATIC_GAIN_CONTROL
+                } else {
+                    self.core_stream_data.software_input_processing_params()
+                }
+            })
+            .unwrap()
+    }
+
+    // The combinations of input processing params the stream can apply, for its current devices.
+    // VoiceProcessingIO cancels echo and suppresses noise together, so the other combinations are
+    // applied in software, with VoiceProcessingIO bypassed. Without it, they are all applied in
+    // software. Either way, echo cancellation in software needs a duplex stream playing at the rate
+    // of its input.
+    pub fn supported_input_processing_param_combinations(&self) -> Vec<InputProcessingParams> {
+        if self.core_stream_data.input_unit.is_null() {
+            return Vec::new();
+        }
+        self.queue
+            .run_sync(|| {
+                let software = self.core_stream_data.software_input_processing_params();
+                if self.core_stream_data.using_voice_processing_unit() {
+                    supported_param_combinations(|params| {
+                        split_voice_processing_params(params, software).is_some()
+                    })
+                } else {
+                    supported_param_combinations(|params| software.contains(params))
+                }
+            })
+            .unwrap()
+    }
+
+    // Call `callback` with the user pointer of the stream when the user starts or stops talking
+    // while its input is muted, e.g. to tell them they are muted, or stop calling with `None`. Only
+    // for streams using VoiceProcessingIO. Reported by VoiceProcessingIO where it can, and by a
+    // detector in the input callback otherwise, in which case the input gets muted there too.
+    pub fn register_voice_activity_callback(
+        &mut self,
+        callback: Option<VoiceActivityCallback>,
+    ) -> Result<()> {
+        if self.core_stream_data.input_unit.is_null() {
+            return Err(Error::invalid_parameter());
+        }
+
+        let queue = self.queue.clone();
+        queue
+            .run_sync(|| {
+                if !self.core_stream_data.using_voice_processing_unit() {
+                    return Err(Error::error());
+                }
+                self.voice_activity_callback
+                    .store(callback, Ordering::SeqCst);
+                self.core_stream_data
+                    .apply_input_mute(self.core_stream_data.input_mute)
+            })
+            .unwrap()?;
+
+        cubeb_log!(
+            "Cubeb stream ({:p}) {} voice activity callback.",
+            self as *const AudioUnitStream,
+            if callback.is_some() {
+                "registered"
+            } else {
+                "unregistered"
+            }
+        );
+        Ok(())
+    }
+
+    // How much other audio is ducked while the stream uses VoiceProcessingIO. Applied now if it
+    // does, and whenever the stream sets up a VoiceProcessingIO unit.
+    pub fn set_vpio_ducking(&mut self, ducking: VpioDucking) -> Result<()> {
+        let queue = self.queue.clone();
+        queue
+            .run_sync(|| {
+                self.core_stream_data.vpio_ducking = ducking;
+                if self.core_stream_data.using_voice_processing_unit() {
+                    self.core_stream_data.apply_vpio_ducking()
+                } else {
+                    Ok(())
+                }
+            })
+            .unwrap()?;
+
+        cubeb_log!(
+            "Cubeb stream ({:p}) set vpio ducking to {:?}.",
+            self as *const AudioUnitStream,
+            ducking
+        );
+        Ok(())
+    }
+
+    pub fn vpio_ducking(&self) -> VpioDucking {
+        self.queue
+            .run_sync(|| self.core_stream_data.vpio_ducking)
+            .unwrap()
+    }
+
+    // The latency, in frames, the stream runs at. It differs from the requested one when it is
+    // out of the safe range, or when other streams already use the devices of the stream.
+    pub fn negotiated_latency_frames(&self) -> u32 {
+        self.queue.run_sync(|| self.latency_frames).unwrap()
+    }
+
+    // Mix the output with `matrix` instead of the coefficients derived from the channel layouts,
+    // or go back to the default mixing with `None`. The matrix must have one row per channel of
+    // the output device and one column per channel of the stream. The stream is reinitialized,
+    // or will be on its next start if it's stopped, to apply it.
+    pub fn set_output_mixing_matrix(&mut self, matrix: Option<MixingMatrix>) -> Result<()> {
+        if !self.core_stream_data.has_output() {
+            return Err(Error::invalid_parameter());
+        }
+
+        let queue = self.queue.clone();
+        queue
+            .run_sync(|| -> Result<()> {
+                if let Some(m) = matrix.as_ref() {
+                    let stream_channels = self.core_stream_data.output_stream_params.channels();
+                    let device_channels = self.core_stream_data.output_hw_channel_count;
+                    if m.input_channels() != stream_channels as usize
+                        || m.output_channels() != device_channels as usize
+                    {
+                        cubeb_log!(
+                            "({:p}) Invalid output mixing matrix {}x{}, expected {}x{}",
+                            self as *const AudioUnitStream,
+                            m.output_channels(),
+                            m.input_channels(),
+                            device_channels,
+                            stream_channels
+                        );
+                        return Err(Error::invalid_parameter());
+                    }
+                }
+                self.core_stream_data.output_mixing_matrix = matrix;
+                let rv = self.reinit();
+                if rv.is_err() {
+                    self.close_on_error();
+                }
+                rv
+            })
+            .unwrap()?;
+
+        cubeb_log!(
+            "Cubeb stream ({:p}) set output mixing matrix.",
+            self as *const AudioUnitStream
+        );
+        Ok(())
+    }
+
+    // Duration of the fade-in when starting, and of the fade-out when stopping or draining. A
+    // zero duration disables fading. The fade-out of a drain is shorter when the last buffer the
+    // data callback returns is, as it only fades that buffer out.
+    pub fn set_fade_duration(&self, duration: Duration) {
+        cubeb_log!(
+            "({:p}) Set fade duration to {:?}",
+            self as *const AudioUnitStream,
+            duration
+        );
+        self.fade_duration
+            .store(duration.as_secs_f32(), Ordering::SeqCst);
+    }
+
+    // Fade the output out and wait until the device has played the fade, so the stream can be
+    // stopped without a click. Gives up if the callback doesn't render the fade in time.
+    fn fade_out(&self) {
+        let duration = self.fade_duration.load(Ordering::SeqCst);
+        if duration == 0.0
+            || !self.core_stream_data.has_output()
+            || self.stopped.load(Ordering::SeqCst)
+            || self.draining.load(Ordering::SeqCst)
+        {
+            return;
+        }
+        let fade = Duration::from_secs_f32(duration);
+        let start = Instant::now();
+        self.fade_out_done.store(false, Ordering::SeqCst);
+        self.fade_request.store(FadeRequest::Out, Ordering::SeqCst);
+        while !self.fade_out_done.load(Ordering::SeqCst) {
+            if start.elapsed() >= fade + FADE_OUT_TIMEOUT_MARGIN {
+                cubeb_log!(
+                    "({:p}) Timed out waiting for the fade-out",
+                    self as *const AudioUnitStream
+                );
+                return;
+            }
+            thread::sleep(FADE_OUT_POLL_INTERVAL);
+        }
+        // The fade ends within the buffer the callback has just rendered, which the device plays
+        // after its output latency. The callback may render ahead of the device, so the fade can't
+        // have been played before its whole duration has passed either.
+        let rate = self.core_stream_data.output_dev_desc.mSampleRate;
+        let frames = self.total_output_latency_frames.load(Ordering::SeqCst) + self.latency_frames;
+        let latency = if rate > 0.0 {
+            Duration::from_secs_f64(f64::from(frames) / rate)
+        } else {
+            Duration::ZERO
+        };
+        let end = cmp::max(start + fade, Instant::now()) + latency;
+        thread::sleep(end.saturating_duration_since(Instant::now()));
+    }
 }
 
 impl Drop for AudioUnitStream<'_> {
@@ -4895,6 +6777,9 @@ impl Drop for AudioUnitStream<'_> {
 
 impl StreamOps for AudioUnitStream<'_> {
     fn start(&mut self) -> Result<()> {
+        // Always ask for a fade-in, so the output recovers from a previous fade-out even if fading
+        // has been disabled since.
+        self.fade_request.store(FadeRequest::In, Ordering::SeqCst);
         let was_stopped = self.stopped.load(Ordering::SeqCst);
         let was_draining = self.draining.load(Ordering::SeqCst);
         self.stopped.store(false, Ordering::SeqCst);
@@ -4943,6 +6828,7 @@ impl StreamOps for AudioUnitStream<'_> {
         Ok(())
     }
     fn stop(&mut self) -> Result<()> {
+        self.fade_out();
         if !self.stopped.swap(true, Ordering::SeqCst) {
             // Execute stop in serial queue to avoid racing with destroy or reinit.
             self.queue
@@ -5024,13 +6910,14 @@ impl StreamOps for AudioUnitStream<'_> {
         }
     }
     fn set_volume(&mut self, volume: f32) -> Result<()> {
-        // Execute set_volume in serial queue to avoid racing with destroy or reinit.
-        let result = self
-            .queue
-            .run_sync(|| set_volume(self.core_stream_data.output_unit, volume))
-            .unwrap();
-
-        result?;
+        if !self.core_stream_data.has_output() {
+            return Err(Error::error());
+        }
+        if !volume.is_finite() || volume < 0.0 {
+            return Err(Error::invalid_parameter());
+        }
+        // The gain is applied in software by the output callback, so it survives reinits.
+        self.volume.store(volume, Ordering::SeqCst);
 
         cubeb_log!(
             "Cubeb stream ({:p}) set volume to {}.",
@@ -5059,7 +6946,7 @@ impl StreamOps for AudioUnitStream<'_> {
         let set = &mut result;
         let stream = &self;
         self.queue.run_sync(move || {
-            *set = set_input_mute(stream.core_stream_data.input_unit, mute);
+            *set = stream.core_stream_data.apply_input_mute(mute);
         });
 
         result?;
@@ -5079,36 +6966,20 @@ impl StreamOps for AudioUnitStream<'_> {
             return Err(Error::invalid_parameter());
         }
 
-        if self
-            .context
-            .supported_input_processing_params()
-            .unwrap()
-            .intersection(params)
-            != params
-        {
+        // Not checked against the params the context supports, which depend on the default
+        // input device rather than the one of this stream.
+        let backend_params = InputProcessingParams::ECHO_CANCELLATION
+            | InputProcessingParams::NOISE_SUPPRESSION
+            | InputProcessingParams::AUTOMATIC_GAIN_CONTROL;
+        if !backend_params.contains(params) {
             return Err(Error::invalid_parameter());
         }
 
-        // AEC and NS are active as soon as VPIO is not bypassed, therefore the only combinations
-        // of those we can explicitly support are {} and {aec, ns}.
-        let aec = params.contains(InputProcessingParams::ECHO_CANCELLATION);
-        let ns = params.contains(InputProcessingParams::NOISE_SUPPRESSION);
-        if aec != ns {
-            // No control to turn on AEC without NS or vice versa.
-            cubeb_log!(
-                "Cubeb stream ({:p}) couldn't set input processing params {:?}. AEC != NS.",
-                self as *const AudioUnitStream,
-                params
-            );
-            return Err(Error::error());
-        }
-
         // CUBEB_ERROR if params could not be applied
-        //   note: only works with VoiceProcessingIO
-        if !self.core_stream_data.using_voice_processing_unit() {
-            return Err(Error::error());
-        }
-
+        //   note: AEC and NS are active as soon as VPIO is not bypassed, therefore VPIO only
+        //   applies {} and {aec, ns}. The combinations without aec are applied in software, and
+        //   the ones with aec but not ns can't be applied. Without VPIO, all of them are applied in
+        //   software.
         // Execute set_input_processing_params in serial queue to avoid racing with destroy or reinit.
         let mut result = Err(Error::error());
         let result_ = &mut result;
@@ -5116,12 +6987,23 @@ impl StreamOps for AudioUnitStream<'_> {
         let deferred_ = &mut deferred;
         let stream = &self;
         self.queue.run_sync(move || {
-            if stream.core_stream_data.units_running {
+            if !stream.core_stream_data.using_voice_processing_unit() {
+                // Applied from the next input callback.
+                *deferred_ = false;
+                *result_ = stream
+                    .core_stream_data
+                    .set_software_input_processing_params(params);
+            } else if stream.core_stream_data.units_running {
                 *deferred_ = true;
-                *result_ = Ok(());
+                *result_ = stream
+                    .core_stream_data
+                    .split_input_processing_params(params)
+                    .map(|_| ());
             } else {
                 *deferred_ = false;
-                *result_ = set_input_processing_params(stream.core_stream_data.input_unit, params);
+                *result_ = stream
+                    .core_stream_data
+                    .apply_voice_processing_params(params);
             }
         });
 
@@ -0,0 +1,361 @@
+// Device-specific workarounds, described as a table of rules instead of checks spread over the
+// stream setup.
+//
+// Each rule matches some devices by their properties and the version of the OS, and sets or
+// clears some quirk flags for them. The rules are applied in order, built-in ones first, so a rule
+// added by the embedder can undo a built-in one for a device it knows better.
+
+use super::coreaudio_sys_utils::sys::kAudioDeviceTransportTypeBuiltIn;
+use super::mixer::Channel;
+use std::sync::{Mutex, OnceLock};
+
+// The Darwin major version of macOS 12.
+pub const MACOS_KERNEL_MAJOR_VERSION_MONTEREY: u32 = 21;
+
+const APPLE_STUDIO_DISPLAY_USB_ID: &str = "05AC:1114";
+// The Bluetooth vendor and product ids of the Bose QC35, mark 1 and 2.
+const BOSE_QC35_BLUETOOTH_IDS: [&str; 2] = ["9E:400C", "9E:4020"];
+// The model UID of the built-in devices of the Macs with a T2 chip, like the MacBook Pro 2018.
+const APPLE_T2_MODEL_UID: &str = "AppleT2";
+
+bitflags! {
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct Quirks: u32 {
+        // Use VoiceProcessingIO for this input device even if the stream doesn't ask for it.
+        const FORCE_VPIO = 0b0000_0001;
+        // Never use VoiceProcessingIO for this input device.
+        const BLOCK_VPIO = 0b0000_0010;
+        // Never use VoiceProcessingIO when both the input and the output device have this flag.
+        const BLOCK_VPIO_FOR_PAIR = 0b0000_0100;
+        // Run an aggregate device at the rate of the input device when both the input and the
+        // output device have this flag.
+        const AGGREGATE_INPUT_RATE_FOR_PAIR = 0b0000_1000;
+        // Play to a single channel output layout as mono, whatever channel it's mapped to.
+        const SINGLE_CHANNEL_OUTPUT_IS_MONO = 0b0001_0000;
+        // Play to a two undefined channels output layout as stereo.
+        const UNDEFINED_STEREO_OUTPUT_IS_STEREO = 0b0010_0000;
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum QuirkScope {
+    Input,
+    Output,
+    #[default]
+    Any,
+}
+
+// The properties of a device the rules can match on.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct QuirkDeviceFacts {
+    pub is_input: bool,
+    pub model_uid: String,
+    // The name of the data source of the device, or of the device if there is none.
+    pub label: String,
+    pub manufacturer: String,
+    pub transport_type: u32,
+    pub kernel_major_version: Option<u32>,
+}
+
+// A rule applies to the devices matching all of its conditions. The strings match when they are
+// part of the device property.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct QuirkRule {
+    pub scope: QuirkScope,
+    pub model_uid: Option<String>,
+    pub label: Option<String>,
+    pub manufacturer: Option<String>,
+    pub transport_type: Option<u32>,
+    pub min_kernel_major_version: Option<u32>,
+    pub max_kernel_major_version: Option<u32>,
+    pub set: Quirks,
+    pub clear: Quirks,
+}
+
+impl QuirkRule {
+    pub fn matches(&self, facts: &QuirkDeviceFacts) -> bool {
+        let contains = |pattern: &Option<String>, value: &str| {
+            pattern.as_ref().is_none_or(|p| value.contains(p.as_str()))
+        };
+        let kernel_in_range = match facts.kernel_major_version {
+            Some(version) => {
+                self.min_kernel_major_version
+                    .is_none_or(|min| version >= min)
+                    && self
+                        .max_kernel_major_version
+                        .is_none_or(|max| version <= max)
+            }
+            None => {
+                self.min_kernel_major_version.is_none() && self.max_kernel_major_version.is_none()
+            }
+        };
+        let in_scope = match self.scope {
+            QuirkScope::Input => facts.is_input,
+            QuirkScope::Output => !facts.is_input,
+            QuirkScope::Any => true,
+        };
+        in_scope
+            && contains(&self.model_uid, &facts.model_uid)
+            && contains(&self.label, &facts.label)
+            && contains(&self.manufacturer, &facts.manufacturer)
+            && self
+                .transport_type
+                .is_none_or(|transport_type| transport_type == facts.transport_type)
+            && kernel_in_range
+    }
+}
+
+pub fn builtin_quirk_rules() -> Vec<QuirkRule> {
+    let mut rules = vec![
+        // The volume of the built-in mic is known to be very low without VoiceProcessingIO
+        // whenever VoiceProcessingIO is hooked up to it elsewhere.
+        QuirkRule {
+            scope: QuirkScope::Input,
+            transport_type: Some(kAudioDeviceTransportTypeBuiltIn),
+            set: Quirks::FORCE_VPIO,
+            ..Default::default()
+        },
+        // VoiceProcessingIO has issues on macOS 12.
+        QuirkRule {
+            scope: QuirkScope::Input,
+            min_kernel_major_version: Some(MACOS_KERNEL_MAJOR_VERSION_MONTEREY),
+            max_kernel_major_version: Some(MACOS_KERNEL_MAJOR_VERSION_MONTEREY),
+            set: Quirks::BLOCK_VPIO,
+            ..Default::default()
+        },
+        // VoiceProcessingIO doesn't work with the mic and the speakers of an Apple Studio
+        // Display together.
+        QuirkRule {
+            model_uid: Some(String::from(APPLE_STUDIO_DISPLAY_USB_ID)),
+            set: Quirks::BLOCK_VPIO_FOR_PAIR,
+            ..Default::default()
+        },
+        // An aggregate device of the mic and the speakers of AirPods has to run at the rate of
+        // the mic.
+        QuirkRule {
+            label: Some(String::from("AirPods")),
+            set: Quirks::AGGREGATE_INPUT_RATE_FOR_PAIR,
+            ..Default::default()
+        },
+        // The builtin speaker on MacBook Pro 2018 maps a stereo layout to the undefined channels.
+        QuirkRule {
+            scope: QuirkScope::Output,
+            model_uid: Some(String::from(APPLE_T2_MODEL_UID)),
+            transport_type: Some(kAudioDeviceTransportTypeBuiltIn),
+            set: Quirks::UNDEFINED_STEREO_OUTPUT_IS_STEREO,
+            ..Default::default()
+        },
+    ];
+    // The Bose QC35, mark 1 and 2, expose a single channel mapped to the right for some reason.
+    rules.extend(BOSE_QC35_BLUETOOTH_IDS.iter().map(|id| QuirkRule {
+        scope: QuirkScope::Output,
+        model_uid: Some(String::from(*id)),
+        set: Quirks::SINGLE_CHANNEL_OUTPUT_IS_MONO,
+        ..Default::default()
+    }));
+    rules
+}
+
+// The rules added at runtime. Devices, and so their quirks, are shared by all the contexts.
+fn runtime_quirk_rules() -> &'static Mutex<Vec<QuirkRule>> {
+    static RULES: OnceLock<Mutex<Vec<QuirkRule>>> = OnceLock::new();
+    RULES.get_or_init(|| Mutex::new(Vec::new()))
+}
+
+pub fn add_quirk_rule(rule: QuirkRule) {
+    cubeb_log!("Add quirk rule {:?}", rule);
+    runtime_quirk_rules().lock().unwrap().push(rule);
+}
+
+pub fn apply_quirk_rules(rules: &[QuirkRule], facts: &QuirkDeviceFacts) -> Quirks {
+    rules
+        .iter()
+        .filter(|rule| rule.matches(facts))
+        .fold(Quirks::empty(), |quirks, rule| {
+            (quirks | rule.set) - rule.clear
+        })
+}
+
+pub fn get_quirks(facts: &QuirkDeviceFacts) -> Quirks {
+    let mut rules = builtin_quirk_rules();
+    rules.extend(runtime_quirk_rules().lock().unwrap().iter().cloned());
+    apply_quirk_rules(&rules, facts)
+}
+
+// The layout to use instead of `layout` for an output device with `quirks`, if any.
+pub fn fix_output_channel_layout(quirks: Quirks, layout: &[Channel]) -> Option<Vec<Channel>> {
+    if quirks.contains(Quirks::SINGLE_CHANNEL_OUTPUT_IS_MONO)
+        && layout.len() == 1
+        && layout[0] != Channel::FrontCenter
+    {
+        // A single channel is all the device can play, use it as mono.
+        Some(vec![Channel::FrontCenter])
+    } else if quirks.contains(Quirks::UNDEFINED_STEREO_OUTPUT_IS_STEREO)
+        && layout.len() == 2
+        && layout
+            .iter()
+            .all(|c| *c == Channel::Discrete || *c == Channel::Silence)
+    {
+        Some(vec![Channel::FrontLeft, Channel::FrontRight])
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+fn test_facts(is_input: bool) -> QuirkDeviceFacts {
+    QuirkDeviceFacts {
+        is_input,
+        kernel_major_version: Some(23),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_builtin_quirk_rules() {
+    let rules = builtin_quirk_rules();
+
+    let builtin_mic = QuirkDeviceFacts {
+        transport_type: kAudioDeviceTransportTypeBuiltIn,
+        ..test_facts(true)
+    };
+    assert_eq!(apply_quirk_rules(&rules, &builtin_mic), Quirks::FORCE_VPIO);
+    let monterey_builtin_mic = QuirkDeviceFacts {
+        kernel_major_version: Some(MACOS_KERNEL_MAJOR_VERSION_MONTEREY),
+        ..builtin_mic.clone()
+    };
+    assert_eq!(
+        apply_quirk_rules(&rules, &monterey_builtin_mic),
+        Quirks::FORCE_VPIO | Quirks::BLOCK_VPIO
+    );
+
+    let studio_display_mic = QuirkDeviceFacts {
+        model_uid: String::from("Studio Display:05AC:1114"),
+        ..test_facts(true)
+    };
+    assert_eq!(
+        apply_quirk_rules(&rules, &studio_display_mic),
+        Quirks::BLOCK_VPIO_FOR_PAIR
+    );
+
+    let airpods_speakers = QuirkDeviceFacts {
+        label: String::from("Someone's AirPods Pro"),
+        ..test_facts(false)
+    };
+    assert_eq!(
+        apply_quirk_rules(&rules, &airpods_speakers),
+        Quirks::AGGREGATE_INPUT_RATE_FOR_PAIR
+    );
+
+    for id in &BOSE_QC35_BLUETOOTH_IDS {
+        let bose_headphones = QuirkDeviceFacts {
+            model_uid: String::from(*id),
+            ..test_facts(false)
+        };
+        assert_eq!(
+            apply_quirk_rules(&rules, &bose_headphones),
+            Quirks::SINGLE_CHANNEL_OUTPUT_IS_MONO
+        );
+    }
+    let t2_speakers = QuirkDeviceFacts {
+        model_uid: String::from(APPLE_T2_MODEL_UID),
+        transport_type: kAudioDeviceTransportTypeBuiltIn,
+        ..test_facts(false)
+    };
+    assert_eq!(
+        apply_quirk_rules(&rules, &t2_speakers),
+        Quirks::UNDEFINED_STEREO_OUTPUT_IS_STEREO
+    );
+
+    // The layout of the other output devices is left alone.
+    let other_speakers = QuirkDeviceFacts {
+        model_uid: String::from("Acme Speakers"),
+        transport_type: kAudioDeviceTransportTypeBuiltIn,
+        ..test_facts(false)
+    };
+    assert_eq!(apply_quirk_rules(&rules, &other_speakers), Quirks::empty());
+
+    assert_eq!(
+        apply_quirk_rules(&rules, &test_facts(true)),
+        Quirks::empty()
+    );
+}
+
+#[test]
+fn test_quirk_rules_are_applied_in_order() {
+    let usb_mic = QuirkDeviceFacts {
+        manufacturer: String::from("Acme"),
+        transport_type: kAudioDeviceTransportTypeBuiltIn,
+        ..test_facts(true)
+    };
+    let mut rules = builtin_quirk_rules();
+    rules.push(QuirkRule {
+        manufacturer: Some(String::from("Acme")),
+        clear: Quirks::FORCE_VPIO,
+        ..Default::default()
+    });
+    assert_eq!(apply_quirk_rules(&rules, &usb_mic), Quirks::empty());
+
+    // A rule only applies to the devices matching all of its conditions.
+    let rule = QuirkRule {
+        scope: QuirkScope::Input,
+        manufacturer: Some(String::from("Acme")),
+        min_kernel_major_version: Some(24),
+        set: Quirks::BLOCK_VPIO,
+        ..Default::default()
+    };
+    assert!(!rule.matches(&usb_mic));
+    assert!(rule.matches(&QuirkDeviceFacts {
+        kernel_major_version: Some(24),
+        ..usb_mic.clone()
+    }));
+    assert!(!rule.matches(&QuirkDeviceFacts {
+        kernel_major_version: None,
+        ..usb_mic.clone()
+    }));
+    assert!(!rule.matches(&QuirkDeviceFacts {
+        is_input: false,
+        kernel_major_version: Some(24),
+        ..usb_mic
+    }));
+}
+
+#[test]
+fn test_add_quirk_rule() {
+    let device = QuirkDeviceFacts {
+        model_uid: String::from("test_add_quirk_rule"),
+        ..test_facts(true)
+    };
+    assert_eq!(get_quirks(&device), Quirks::empty());
+    add_quirk_rule(QuirkRule {
+        model_uid: Some(String::from("test_add_quirk_rule")),
+        set: Quirks::BLOCK_VPIO,
+        ..Default::default()
+    });
+    assert_eq!(get_quirks(&device), Quirks::BLOCK_VPIO);
+}
+
+#[test]
+fn test_fix_output_channel_layout() {
+    let all = Quirks::SINGLE_CHANNEL_OUTPUT_IS_MONO | Quirks::UNDEFINED_STEREO_OUTPUT_IS_STEREO;
+    assert_eq!(
+        fix_output_channel_layout(all, &[Channel::FrontRight]),
+        Some(vec![Channel::FrontCenter])
+    );
+    assert_eq!(
+        fix_output_channel_layout(all, &[Channel::Discrete, Channel::Silence]),
+        Some(vec![Channel::FrontLeft, Channel::FrontRight])
+    );
+    assert_eq!(
+        fix_output_channel_layout(all, &[Channel::FrontCenter]),
+        None
+    );
+    assert_eq!(
+        fix_output_channel_layout(all, &[Channel::FrontLeft, Channel::FrontRight]),
+        None
+    );
+    assert_eq!(
+        fix_output_channel_layout(Quirks::empty(), &[Channel::FrontRight]),
+        None
+    );
+}
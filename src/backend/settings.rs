@@ -0,0 +1,208 @@
+// Tunables of the backend, chosen when the context is created.
+//
+// Each setting can also be overridden by an environment variable, so the behavior can be changed
+// on a user's machine to debug an issue, without rebuilding the embedder.
+
+use super::aggregate_policy::AggregateDevicePolicy;
+use std::time::Duration;
+
+// Testing empirically, some headsets report a minimal latency that is very low,
+// but this does not work in practice. Lie and say the minimum is 128 frames.
+pub const SAFE_MIN_LATENCY_FRAMES: u32 = 128;
+pub const SAFE_MAX_LATENCY_FRAMES: u32 = 512;
+
+pub const VPIO_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// 8 times the expected callback size, to handle the input callback being called multiple times in
+// a row correctly.
+pub const INPUT_BUFFER_CALLBACKS: u32 = 8;
+
+const MIN_LATENCY_FRAMES_VAR: &str = "CUBEB_COREAUDIO_MIN_LATENCY_FRAMES";
+const MAX_LATENCY_FRAMES_VAR: &str = "CUBEB_COREAUDIO_MAX_LATENCY_FRAMES";
+// In milliseconds, or "never".
+const VPIO_IDLE_TIMEOUT_VAR: &str = "CUBEB_COREAUDIO_VPIO_IDLE_TIMEOUT_MS";
+const INPUT_BUFFER_CALLBACKS_VAR: &str = "CUBEB_COREAUDIO_INPUT_BUFFER_CALLBACKS";
+// 1 or 0.
+const DESTROY_STALE_AGGREGATE_DEVICES_VAR: &str = "CUBEB_COREAUDIO_DESTROY_STALE_AGGREGATE_DEVICES";
+// never, when-clock-domains-differ, input-only-plus-output-only or always.
+const AGGREGATE_DEVICE_POLICY_VAR: &str = "CUBEB_COREAUDIO_AGGREGATE_DEVICE_POLICY";
+const AGGREGATE_MASTER_DEVICE_UID_VAR: &str = "CUBEB_COREAUDIO_AGGREGATE_MASTER_DEVICE_UID";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BackendSettings {
+    // The range the latency of the first stream on a device is clamped to.
+    pub min_latency_frames: u32,
+    pub max_latency_frames: u32,
+    // How long an unused VoiceProcessingIO unit is kept to be reused by the next stream. None keeps
+    // it until the context is destroyed.
+    pub vpio_idle_timeout: Option<Duration>,
+    // The size of the input ring buffer, in callbacks of the maximum latency.
+    pub input_buffer_callbacks: u32,
+    // Destroy the aggregate devices leaked by crashed processes when the context is created.
+    pub destroy_stale_aggregate_devices: bool,
+    // When duplex streams on two devices use an aggregate device.
+    pub aggregate_device_policy: AggregateDevicePolicy,
+    // The UID of the device to use as the master clock of the aggregate devices it's part of,
+    // instead of the one picked from the device facts.
+    pub aggregate_master_device_uid: Option<String>,
+}
+
+impl Default for BackendSettings {
+    fn default() -> Self {
+        Self {
+            min_latency_frames: SAFE_MIN_LATENCY_FRAMES,
+            max_latency_frames: SAFE_MAX_LATENCY_FRAMES,
+            vpio_idle_timeout: Some(VPIO_IDLE_TIMEOUT),
+            input_buffer_callbacks: INPUT_BUFFER_CALLBACKS,
+            destroy_stale_aggregate_devices: false,
+            aggregate_device_policy: AggregateDevicePolicy::default(),
+            aggregate_master_device_uid: None,
+        }
+    }
+}
+
+impl BackendSettings {
+    pub fn is_valid(&self) -> bool {
+        self.min_latency_frames > 0
+            && self.min_latency_frames <= self.max_latency_frames
+            && self.input_buffer_callbacks > 0
+    }
+
+    pub fn with_env_overrides(self) -> Self {
+        self.with_overrides(|name| std::env::var(name).ok())
+    }
+
+    // Replace the settings whose variable `lookup` returns a valid value for. Invalid values are
+    // logged and ignored.
+    fn with_overrides<F>(mut self, lookup: F) -> Self
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        if let Some(frames) = parse_override(&lookup, MIN_LATENCY_FRAMES_VAR, parse_u32) {
+            self.min_latency_frames = frames;
+        }
+        if let Some(frames) = parse_override(&lookup, MAX_LATENCY_FRAMES_VAR, parse_u32) {
+            self.max_latency_frames = frames;
+        }
+        if let Some(timeout) = parse_override(&lookup, VPIO_IDLE_TIMEOUT_VAR, parse_timeout) {
+            self.vpio_idle_timeout = timeout;
+        }
+        if let Some(callbacks) = parse_override(&lookup, INPUT_BUFFER_CALLBACKS_VAR, parse_u32) {
+            self.input_buffer_callbacks = callbacks;
+        }
+        if let Some(destroy) =
+            parse_override(&lookup, DESTROY_STALE_AGGREGATE_DEVICES_VAR, parse_bool)
+        {
+            self.destroy_stale_aggregate_devices = destroy;
+        }
+        if let Some(policy) =
+            parse_override(&lookup, AGGREGATE_DEVICE_POLICY_VAR, |v| v.parse().ok())
+        {
+            self.aggregate_device_policy = policy;
+        }
+        if let Some(uid) = parse_override(&lookup, AGGREGATE_MASTER_DEVICE_UID_VAR, |v| {
+            Some(v).filter(|v| !v.is_empty()).map(String::from)
+        }) {
+            self.aggregate_master_device_uid = Some(uid);
+        }
+        self
+    }
+}
+
+fn parse_override<F, P, T>(lookup: &F, name: &str, parse: P) -> Option<T>
+where
+    F: Fn(&str) -> Option<String>,
+    P: Fn(&str) -> Option<T>,
+    T: std::fmt::Debug,
+{
+    let value = lookup(name)?;
+    let parsed = parse(value.trim());
+    match parsed {
+        Some(ref v) => cubeb_log!("Override {} with {:?} from the environment.", name, v),
+        None => cubeb_log!("Ignore invalid value {:?} of {}.", value, name),
+    }
+    parsed
+}
+
+fn parse_u32(value: &str) -> Option<u32> {
+    value.parse().ok()
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value {
+        "1" => Some(true),
+        "0" => Some(false),
+        _ => None,
+    }
+}
+
+fn parse_timeout(value: &str) -> Option<Option<Duration>> {
+    if value.eq_ignore_ascii_case("never") {
+        return Some(None);
+    }
+    value.parse().ok().map(|ms| Some(Duration::from_millis(ms)))
+}
+
+#[cfg(test)]
+fn test_lookup<'a>(vars: &'a [(&'a str, &'a str)]) -> impl Fn(&str) -> Option<String> + 'a {
+    move |name| {
+        vars.iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, v)| v.to_string())
+    }
+}
+
+#[test]
+fn test_settings_overrides() {
+    let settings = BackendSettings::default().with_overrides(test_lookup(&[]));
+    assert_eq!(settings, BackendSettings::default());
+    assert!(settings.is_valid());
+
+    let settings = BackendSettings::default().with_overrides(test_lookup(&[
+        (MIN_LATENCY_FRAMES_VAR, "32"),
+        (MAX_LATENCY_FRAMES_VAR, " 2048 "),
+        (VPIO_IDLE_TIMEOUT_VAR, "1500"),
+        (INPUT_BUFFER_CALLBACKS_VAR, "4"),
+        (DESTROY_STALE_AGGREGATE_DEVICES_VAR, "1"),
+        (AGGREGATE_DEVICE_POLICY_VAR, "never"),
+        (AGGREGATE_MASTER_DEVICE_UID_VAR, "AppleUSBAudioEngine:1"),
+    ]));
+    assert_eq!(
+        settings,
+        BackendSettings {
+            min_latency_frames: 32,
+            max_latency_frames: 2048,
+            vpio_idle_timeout: Some(Duration::from_millis(1500)),
+            input_buffer_callbacks: 4,
+            destroy_stale_aggregate_devices: true,
+            aggregate_device_policy: AggregateDevicePolicy::Never,
+            aggregate_master_device_uid: Some(String::from("AppleUSBAudioEngine:1")),
+        }
+    );
+
+    let settings =
+        BackendSettings::default().with_overrides(test_lookup(&[(VPIO_IDLE_TIMEOUT_VAR, "Never")]));
+    assert_eq!(settings.vpio_idle_timeout, None);
+}
+
+#[test]
+fn test_settings_invalid_overrides() {
+    // Values that don't parse are ignored.
+    let settings = BackendSettings::default().with_overrides(test_lookup(&[
+        (MIN_LATENCY_FRAMES_VAR, "-1"),
+        (VPIO_IDLE_TIMEOUT_VAR, "soon"),
+        (INPUT_BUFFER_CALLBACKS_VAR, ""),
+        (DESTROY_STALE_AGGREGATE_DEVICES_VAR, "yes"),
+        (AGGREGATE_DEVICE_POLICY_VAR, "sometimes"),
+        (AGGREGATE_MASTER_DEVICE_UID_VAR, " "),
+    ]));
+    assert_eq!(settings, BackendSettings::default());
+
+    // Values that parse are applied, and the result is checked as a whole.
+    let settings =
+        BackendSettings::default().with_overrides(test_lookup(&[(MIN_LATENCY_FRAMES_VAR, "1024")]));
+    assert!(!settings.is_valid());
+    let settings = BackendSettings::default()
+        .with_overrides(test_lookup(&[(INPUT_BUFFER_CALLBACKS_VAR, "0")]));
+    assert!(!settings.is_valid());
+}
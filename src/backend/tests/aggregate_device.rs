@@ -179,9 +179,115 @@ fn test_aggregate_create_blank_device() {
     let device = devices.into_iter().find(|dev| dev == &device).unwrap();
     let uid = run_serially(|| get_device_global_uid(device).unwrap().into_string());
     assert!(uid.contains(PRIVATE_AGGREGATE_DEVICE_NAME));
+    assert_eq!(
+        AggregateDevice::get_owner_pid(&uid),
+        Some(std::process::id())
+    );
     assert!(run_serially(|| AggregateDevice::destroy_device(plugin, device)).is_ok());
 }
 
+// AggregateDevice::get_owner_pid
+// ------------------------------------
+#[test]
+fn test_aggregate_get_owner_pid() {
+    assert_eq!(
+        AggregateDevice::get_owner_pid("org.mozilla.CubebAggregateDevice_123_456789"),
+        Some(123)
+    );
+    // Created before the pid was in the uid.
+    assert_eq!(
+        AggregateDevice::get_owner_pid("org.mozilla.CubebAggregateDevice_456789"),
+        None
+    );
+    // Not ours.
+    assert_eq!(
+        AggregateDevice::get_owner_pid("com.example.CubebAggregateDevice_123_456789"),
+        None
+    );
+    assert_eq!(
+        AggregateDevice::get_owner_pid("org.mozilla.VPAUAggregateAudioDevice_123_456789"),
+        None
+    );
+    assert_eq!(
+        AggregateDevice::get_owner_pid("org.mozilla.CubebAggregateDevice_123_456789_0"),
+        None
+    );
+    assert_eq!(
+        AggregateDevice::get_owner_pid("org.mozilla.CubebAggregateDevice_pid_456789"),
+        None
+    );
+}
+
+// AggregateDevice::destroy_stale_devices
+// ------------------------------------
+#[test]
+fn test_aggregate_destroy_stale_devices() {
+    // The devices of this process are never stale.
+    let plugin = run_serially(|| AggregateDevice::get_system_plugin_id()).unwrap();
+    let device = run_serially(|| AggregateDevice::create_blank_device_sync(plugin)).unwrap();
+    assert!(run_serially(AggregateDevice::destroy_stale_devices).is_ok());
+    let devices = test_get_all_devices(DeviceFilter::IncludeAll);
+    assert!(devices.contains(&device));
+    assert!(run_serially(|| AggregateDevice::destroy_device(plugin, device)).is_ok());
+}
+
+// Set in the environment of the child process `test_aggregate_destroy_stale_devices_of_dead_process`
+// runs to leak an aggregate device.
+const LEAK_AGGREGATE_DEVICE_ENV: &str = "CUBEB_TEST_LEAK_AGGREGATE_DEVICE";
+const LEAKED_AGGREGATE_DEVICE_PREFIX: &str = "Leaked aggregate device: ";
+
+#[test]
+#[ignore]
+fn test_aggregate_leak_device() {
+    if std::env::var_os(LEAK_AGGREGATE_DEVICE_ENV).is_none() {
+        return;
+    }
+    let plugin = run_serially(|| AggregateDevice::get_system_plugin_id()).unwrap();
+    let device = run_serially(|| AggregateDevice::create_blank_device_sync(plugin)).unwrap();
+    let uid = run_serially(|| get_device_global_uid(device))
+        .unwrap()
+        .into_string();
+    println!("{}{}", LEAKED_AGGREGATE_DEVICE_PREFIX, uid);
+    // Exit without destroying the device, like a crash would.
+    std::process::exit(0);
+}
+
+#[test]
+fn test_aggregate_destroy_stale_devices_of_dead_process() {
+    // Run this test binary again to create a device and exit without destroying it.
+    let output = std::process::Command::new(std::env::current_exe().unwrap())
+        .args([
+            "--exact",
+            "backend::tests::aggregate_device::test_aggregate_leak_device",
+            "--ignored",
+            "--nocapture",
+            "--test-threads=1",
+        ])
+        .env(LEAK_AGGREGATE_DEVICE_ENV, "1")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let uid = stdout
+        .lines()
+        .find_map(|line| line.strip_prefix(LEAKED_AGGREGATE_DEVICE_PREFIX))
+        .expect("The child process should have created a device")
+        .to_string();
+    assert!(AggregateDevice::get_owner_pid(&uid).is_some());
+    assert_ne!(
+        AggregateDevice::get_owner_pid(&uid),
+        Some(std::process::id())
+    );
+    // The device is private to the child, but it registered it.
+    assert!(AggregateDevice::get_registered_device_uids().contains(&uid));
+
+    // The HAL may already have removed the device with the child. Either way, it's gone after the
+    // sweep, and so is its registration.
+    assert!(run_serially(AggregateDevice::destroy_stale_devices).is_ok());
+    assert_eq!(run_serially(|| get_device_by_uid(&uid)), None);
+    assert!(!AggregateDevice::get_registered_device_uids().contains(&uid));
+}
+
 // AggregateDevice::get_sub_devices
 // ------------------------------------
 #[test]
@@ -221,15 +327,15 @@ fn test_aggregate_set_sub_devices() {
 
     let sub_devices = run_serially(|| AggregateDevice::get_sub_devices_or_self(device)).unwrap();
     let input_sub_devices =
-        run_serially(|| AggregateDevice::get_sub_devices_or_self(input_device)).unwrap();
+        run_serially(|| AggregateDevice::get_leaf_sub_devices(input_device)).unwrap();
     let output_sub_devices =
-        run_serially(|| AggregateDevice::get_sub_devices_or_self(output_device)).unwrap();
+        run_serially(|| AggregateDevice::get_leaf_sub_devices(output_device)).unwrap();
 
-    // TODO: There may be overlapping devices between input_sub_devices and output_sub_devices,
-    //       but now AggregateDevice::set_sub_devices will add them directly.
+    // The devices on both sides are added once.
     assert_eq!(
         sub_devices.len(),
-        input_sub_devices.len() + output_sub_devices.len()
+        AggregateDevice::merge_sub_devices(input_sub_devices.clone(), output_sub_devices.clone())
+            .len()
     );
     for dev in &input_sub_devices {
         assert!(sub_devices.contains(dev));
@@ -252,6 +358,60 @@ fn test_aggregate_set_sub_devices() {
     assert!(run_serially(|| AggregateDevice::destroy_device(plugin, device)).is_ok());
 }
 
+#[test]
+fn test_aggregate_set_sub_devices_with_a_nested_aggregate_device() {
+    let input_device = test_get_default_device(Scope::Input);
+    let output_device = test_get_default_device(Scope::Output);
+    if input_device.is_none() || output_device.is_none() || input_device == output_device {
+        println!("No input or output device to create an aggregate device.");
+        return;
+    }
+
+    let input_device = input_device.unwrap();
+    let output_device = output_device.unwrap();
+
+    run_serially_forward_panics(|| {
+        // An aggregate device of the input and output devices, like one made in Audio MIDI Setup,
+        // used as the input device of another aggregate device with the same output device.
+        let plugin = AggregateDevice::get_system_plugin_id().unwrap();
+        let inner = AggregateDevice::create_blank_device_sync(plugin).unwrap();
+        assert!(AggregateDevice::set_sub_devices_sync(inner, input_device, output_device).is_ok());
+        let outer = AggregateDevice::create_blank_device_sync(plugin).unwrap();
+        assert!(AggregateDevice::set_sub_devices_sync(outer, inner, output_device).is_ok());
+
+        let leaves = AggregateDevice::get_leaf_sub_devices(inner).unwrap();
+        assert_eq!(
+            AggregateDevice::get_leaf_sub_devices(outer).unwrap(),
+            leaves
+        );
+        let sub_devices = AggregateDevice::get_sub_devices(outer).unwrap();
+        assert!(!sub_devices.contains(&inner));
+        assert_eq!(sub_devices, leaves);
+
+        assert!(AggregateDevice::destroy_device(plugin, outer).is_ok());
+        assert!(AggregateDevice::destroy_device(plugin, inner).is_ok());
+    });
+}
+
+// AggregateDevice::merge_sub_devices
+// ------------------------------------
+#[test]
+fn test_aggregate_merge_sub_devices() {
+    assert_eq!(
+        AggregateDevice::merge_sub_devices(vec![1, 2], vec![3]),
+        vec![1, 2, 3]
+    );
+    // The input devices go first, and the devices on both sides are kept once.
+    assert_eq!(
+        AggregateDevice::merge_sub_devices(vec![3, 1, 2], vec![2, 4, 3]),
+        vec![3, 1, 2, 4]
+    );
+    assert_eq!(
+        AggregateDevice::merge_sub_devices(vec![1, 1], vec![]),
+        vec![1]
+    );
+}
+
 #[test]
 #[should_panic]
 fn test_aggregate_set_sub_devices_for_unknown_input_devices() {
@@ -523,10 +683,12 @@ fn test_aggregate_new() {
         let aggr = AggregateDevice::new(input_device, output_device).unwrap();
 
         // Check main device
-        let output_sub_devices = AggregateDevice::get_sub_devices_or_self(output_device).unwrap();
-        let first_output_sub_device_uid = get_device_uid(output_sub_devices[0]);
+        let master_device =
+            AggregateDevice::select_master_device(input_device, output_device, None);
+        let master_sub_devices = AggregateDevice::get_sub_devices_or_self(master_device).unwrap();
+        let first_master_sub_device_uid = get_device_uid(master_sub_devices[0]);
         let master_device_uid = test_get_master_device(aggr.get_device_id());
-        assert_eq!(first_output_sub_device_uid, master_device_uid);
+        assert_eq!(first_master_sub_device_uid, master_device_uid);
 
         // Check drift compensation
         let devices = test_get_all_onwed_devices(aggr.get_device_id());
@@ -549,3 +711,89 @@ fn test_aggregate_new() {
         }
     });
 }
+
+// AggregateDevice::new_with_master_preference
+// AggregateDevice::get_clock_info
+// ------------------------------------
+#[test]
+fn test_aggregate_new_with_master_preference() {
+    let input_device = test_get_default_device(Scope::Input);
+    let output_device = test_get_default_device(Scope::Output);
+    if input_device.is_none() || output_device.is_none() || input_device == output_device {
+        println!("No input or output device to create an aggregate device.");
+        return;
+    }
+
+    run_serially_forward_panics(|| {
+        let input_device = input_device.unwrap();
+        let output_device = output_device.unwrap();
+
+        for &preferred in &[input_device, output_device] {
+            let preferred_uid = get_device_uid(preferred);
+            assert_eq!(
+                AggregateDevice::select_master_device(
+                    input_device,
+                    output_device,
+                    Some(&preferred_uid)
+                ),
+                preferred
+            );
+
+            let aggr = AggregateDevice::new_with_master_preference(
+                input_device,
+                output_device,
+                Some(&preferred_uid),
+            )
+            .unwrap();
+            let preferred_sub_devices =
+                AggregateDevice::get_sub_devices_or_self(preferred).unwrap();
+            let info = AggregateDevice::get_clock_info(aggr.get_device_id()).unwrap();
+            assert_eq!(info.master_uid, get_device_uid(preferred_sub_devices[0]));
+            assert_eq!(
+                info.master_uid,
+                test_get_master_device(aggr.get_device_id())
+            );
+            // Drift compensation is on for all the other sub-devices.
+            assert!(!info.drift_compensation.is_empty());
+            for (uid, compensated) in &info.drift_compensation {
+                assert_ne!(uid, &info.master_uid);
+                assert!(*compensated, "No drift compensation for {}", uid);
+            }
+        }
+    });
+}
+
+// SharedAggregateDeviceManager::take_or_create
+// ------------------------------------
+#[test]
+fn test_shared_aggregate_devices() {
+    let input_device = test_get_default_device(Scope::Input);
+    let output_device = test_get_default_device(Scope::Output);
+    if input_device.is_none() || output_device.is_none() || input_device == output_device {
+        println!("No input or output device to create an aggregate device.");
+        return;
+    }
+
+    let manager = SharedAggregateDeviceManager::default();
+    run_serially_forward_panics(|| {
+        let input_device = input_device.unwrap();
+        let output_device = output_device.unwrap();
+
+        // The streams using the same device pair share an aggregate device.
+        let first = manager
+            .take_or_create(input_device, output_device, None)
+            .unwrap();
+        let second = manager
+            .take_or_create(input_device, output_device, None)
+            .unwrap();
+        assert_eq!(first.get_device_id(), second.get_device_id());
+        assert_eq!(manager.devices.lock().unwrap().len(), 1);
+        assert_eq!(manager.devices.lock().unwrap()[0].users, 2);
+
+        // It's destroyed with its last user.
+        drop(first);
+        assert_eq!(manager.devices.lock().unwrap()[0].users, 1);
+        drop(second);
+        assert!(manager.devices.lock().unwrap().is_empty());
+    });
+}
@@ -2,8 +2,9 @@ use super::utils::{
     test_audiounit_get_buffer_frame_size, test_audiounit_scope_is_enabled, test_create_audiounit,
     test_device_channels_in_scope, test_device_in_scope, test_get_all_devices,
     test_get_default_audiounit, test_get_default_device, test_get_default_raw_stream,
-    test_get_devices_in_scope, test_get_raw_context, ComponentSubType, DeviceFilter, PropertyScope,
-    Scope,
+    test_get_devices_in_scope, test_get_raw_context,
+    test_get_stream_with_default_data_callback_by_type, ComponentSubType, DeviceFilter,
+    PropertyScope, Scope, StreamType,
 };
 use super::*;
 
@@ -37,6 +38,7 @@ fn test_make_sized_audio_channel_layout_with_wrong_size() {
 fn test_increase_and_decrease_context_streams() {
     use std::thread;
     const STREAMS: u32 = 10;
+    const DEVICE: LatencyDevice = (1, DeviceType::OUTPUT);
 
     let context = AudioUnitContext::new();
     let context_ptr_value = &context as *const AudioUnitContext as usize;
@@ -46,7 +48,9 @@ fn test_increase_and_decrease_context_streams() {
         join_handles.push(thread::spawn(move || {
             let context = unsafe { &*(context_ptr_value as *const AudioUnitContext) };
 
-            context.update_latency_by_adding_stream(i)
+            context
+                .update_latency_by_adding_stream(&[DEVICE], i)
+                .unwrap()
         }));
     }
     let mut latencies = vec![];
@@ -56,7 +60,7 @@ fn test_increase_and_decrease_context_streams() {
     assert_eq!(context.active_streams(), STREAMS);
     check_streams(&context, STREAMS);
 
-    check_latency(&context, Some(latencies[0]));
+    check_latency(&context, DEVICE, Some(latencies[0]));
     for i in 0..latencies.len() - 1 {
         assert_eq!(latencies[i], latencies[i + 1]);
     }
@@ -65,7 +69,7 @@ fn test_increase_and_decrease_context_streams() {
     for _ in 0..STREAMS {
         join_handles.push(thread::spawn(move || {
             let context = unsafe { &*(context_ptr_value as *const AudioUnitContext) };
-            context.update_latency_by_removing_stream();
+            context.update_latency_by_removing_stream(&[DEVICE]);
         }));
     }
     for handle in join_handles {
@@ -73,7 +77,163 @@ fn test_increase_and_decrease_context_streams() {
     }
     check_streams(&context, 0);
 
-    check_latency(&context, None);
+    check_latency(&context, DEVICE, None);
+}
+
+#[test]
+fn test_latency_per_device() {
+    const INPUT: LatencyDevice = (1, DeviceType::INPUT);
+    const OUTPUT: LatencyDevice = (1, DeviceType::OUTPUT);
+    const OTHER_OUTPUT: LatencyDevice = (2, DeviceType::OUTPUT);
+    let first = SAFE_MIN_LATENCY_FRAMES + 1;
+    let second = SAFE_MIN_LATENCY_FRAMES + 2;
+    let third = SAFE_MIN_LATENCY_FRAMES + 3;
+
+    let context = AudioUnitContext::new();
+
+    // Streams on unrelated devices keep their own latency.
+    assert_eq!(
+        context.update_latency_by_adding_stream(&[OUTPUT], first),
+        Ok(first)
+    );
+    assert_eq!(
+        context.update_latency_by_adding_stream(&[INPUT], second),
+        Ok(second)
+    );
+    assert_eq!(
+        context.update_latency_by_adding_stream(&[OTHER_OUTPUT], third),
+        Ok(third)
+    );
+    check_latency(&context, OUTPUT, Some(first));
+    check_latency(&context, INPUT, Some(second));
+    check_latency(&context, OTHER_OUTPUT, Some(third));
+
+    // A duplex stream sharing a device gets its latency, on both of its devices.
+    const OTHER_INPUT: LatencyDevice = (2, DeviceType::INPUT);
+    assert_eq!(
+        context.update_latency_by_adding_stream(&[OTHER_INPUT, OUTPUT], third),
+        Ok(first)
+    );
+    check_latency(&context, OTHER_INPUT, Some(first));
+    check_latency(&context, OUTPUT, Some(first));
+
+    // Moving to a device used by nothing else keeps the latency.
+    assert_eq!(
+        context.update_latency_by_moving_stream(&[OTHER_OUTPUT], &[(3, DeviceType::OUTPUT)], third),
+        Ok(third)
+    );
+    check_latency(&context, OTHER_OUTPUT, None);
+
+    // The latency is released with the last stream on the device.
+    context.update_latency_by_removing_stream(&[OUTPUT]);
+    context.update_latency_by_removing_stream(&[OTHER_INPUT, OUTPUT]);
+    check_latency(&context, OUTPUT, None);
+    check_latency(&context, OTHER_INPUT, None);
+    check_latency(&context, INPUT, Some(second));
+    context.update_latency_by_removing_stream(&[INPUT]);
+    context.update_latency_by_removing_stream(&[(3, DeviceType::OUTPUT)]);
+    check_streams(&context, 0);
+    check_latency(&context, INPUT, None);
+
+    // The latency of the first stream is clamped within the safe range.
+    assert_eq!(
+        context.update_latency_by_adding_stream(&[OUTPUT], 0),
+        Ok(SAFE_MIN_LATENCY_FRAMES)
+    );
+    context.update_latency_by_removing_stream(&[OUTPUT]);
+}
+
+#[test]
+fn test_latency_kept_by_running_devices() {
+    const INPUT: LatencyDevice = (1, DeviceType::INPUT);
+    const OUTPUT: LatencyDevice = (2, DeviceType::OUTPUT);
+    let first = SAFE_MIN_LATENCY_FRAMES + 1;
+    let second = SAFE_MIN_LATENCY_FRAMES + 2;
+
+    let context = AudioUnitContext::new();
+
+    // Two streams requesting different latencies on the same device: the second one gets the
+    // latency of the first.
+    assert_eq!(
+        context.update_latency_by_adding_stream(&[OUTPUT], first),
+        Ok(first)
+    );
+    assert_eq!(
+        context.update_latency_by_adding_stream(&[OUTPUT], second),
+        Ok(first)
+    );
+    check_latency(&context, OUTPUT, Some(first));
+
+    // A duplex stream joining two devices running at different latencies would change the
+    // latency of the streams on one of them, so it is rejected and nothing changes.
+    assert_eq!(
+        context.update_latency_by_adding_stream(&[INPUT], second),
+        Ok(second)
+    );
+    assert_eq!(
+        context.update_latency_by_adding_stream(&[INPUT, OUTPUT], second),
+        Err(Error::error())
+    );
+    check_streams(&context, 3);
+    check_latency(&context, INPUT, Some(second));
+    check_latency(&context, OUTPUT, Some(first));
+
+    // Neither can a stream move there. It stays where it was.
+    const OTHER_OUTPUT: LatencyDevice = (3, DeviceType::OUTPUT);
+    assert_eq!(
+        context.update_latency_by_adding_stream(&[OTHER_OUTPUT], second),
+        Ok(second)
+    );
+    assert_eq!(
+        context.update_latency_by_moving_stream(&[OTHER_OUTPUT], &[INPUT, OUTPUT], second),
+        Err(Error::error())
+    );
+    check_streams(&context, 4);
+    check_latency(&context, OTHER_OUTPUT, Some(second));
+    check_latency(&context, INPUT, Some(second));
+    check_latency(&context, OUTPUT, Some(first));
+
+    context.update_latency_by_removing_stream(&[OTHER_OUTPUT]);
+    context.update_latency_by_removing_stream(&[INPUT]);
+    context.update_latency_by_removing_stream(&[OUTPUT]);
+    context.update_latency_by_removing_stream(&[OUTPUT]);
+    check_streams(&context, 0);
+}
+
+#[test]
+fn test_latency_clamped_by_settings() {
+    const OUTPUT: LatencyDevice = (1, DeviceType::OUTPUT);
+    let settings = BackendSettings {
+        min_latency_frames: 32,
+        max_latency_frames: 2048,
+        ..BackendSettings::default()
+    };
+    let context = AudioUnitContext::with_settings(settings);
+    assert_eq!(
+        context.update_latency_by_adding_stream(&[OUTPUT], 16),
+        Ok(32)
+    );
+    context.update_latency_by_removing_stream(&[OUTPUT]);
+    assert_eq!(
+        context.update_latency_by_adding_stream(&[OUTPUT], 64),
+        Ok(64)
+    );
+    context.update_latency_by_removing_stream(&[OUTPUT]);
+    assert_eq!(
+        context.update_latency_by_adding_stream(&[OUTPUT], 4096),
+        Ok(2048)
+    );
+    context.update_latency_by_removing_stream(&[OUTPUT]);
+
+    let settings = BackendSettings {
+        min_latency_frames: 1024,
+        max_latency_frames: 512,
+        ..BackendSettings::default()
+    };
+    assert_eq!(
+        AudioUnitContext::init_with_settings(None, settings).err(),
+        Some(Error::invalid_parameter())
+    );
 }
 
 fn check_streams(context: &AudioUnitContext, number: u32) {
@@ -81,9 +241,9 @@ fn check_streams(context: &AudioUnitContext, number: u32) {
     assert_eq!(guard.streams, number);
 }
 
-fn check_latency(context: &AudioUnitContext, latency: Option<u32>) {
+fn check_latency(context: &AudioUnitContext, device: LatencyDevice, latency: Option<u32>) {
     let guard = context.latency_controller.lock().unwrap();
-    assert_eq!(guard.latency, latency);
+    assert_eq!(guard.device_latency(&device), latency);
 }
 
 // make_silent
@@ -337,6 +497,33 @@ fn test_remove_listener_unknown_device() {
     });
 }
 
+// set_dither_mode
+// ------------------------------------
+#[test]
+fn test_set_dither_mode() {
+    test_get_default_raw_stream(|stream| {
+        assert_eq!(stream.dither_mode(), DitherMode::Disabled);
+        stream.set_dither_mode(DitherMode::TpdfNoiseShaped);
+        assert_eq!(stream.dither_mode(), DitherMode::TpdfNoiseShaped);
+        stream.set_dither_mode(DitherMode::Tpdf);
+        assert_eq!(stream.dither_mode(), DitherMode::Tpdf);
+    });
+}
+
+// set_limiter_mode
+// ------------------------------------
+#[test]
+fn test_set_limiter_mode() {
+    test_get_default_raw_stream(|stream| {
+        assert_eq!(stream.limiter_mode(), LimiterMode::Disabled);
+        assert_eq!(stream.limiter_gain_reduction(), 0.0);
+        stream.set_limiter_mode(LimiterMode::SoftClip);
+        assert_eq!(stream.limiter_mode(), LimiterMode::SoftClip);
+        stream.set_limiter_mode(LimiterMode::Limiter);
+        assert_eq!(stream.limiter_mode(), LimiterMode::Limiter);
+    });
+}
+
 // get_default_device_id
 // ------------------------------------
 #[test]
@@ -762,6 +949,37 @@ fn test_get_current_channel_layout_output() {
     }
 }
 
+// get_output_channel_layout_quirk
+// ------------------------------------
+#[test]
+fn test_get_output_channel_layout_quirk_of_unlisted_device() {
+    let device = match test_get_default_device(Scope::Output) {
+        Some(device) => device,
+        None => {
+            println!("No output device for test.");
+            return;
+        }
+    };
+    let layout_quirks =
+        Quirks::SINGLE_CHANNEL_OUTPUT_IS_MONO | Quirks::UNDEFINED_STEREO_OUTPUT_IS_STEREO;
+    let quirks = run_serially(|| get_device_quirks(device, DeviceType::OUTPUT));
+    if quirks.intersects(layout_quirks) {
+        println!("The default output device has a layout quirk, skipping the test.");
+        return;
+    }
+    // A device that isn't listed in the quirk rules keeps the layout it reports, whatever it is.
+    for layout in [
+        vec![mixer::Channel::FrontRight],
+        vec![mixer::Channel::Discrete, mixer::Channel::Discrete],
+        vec![mixer::Channel::FrontLeft, mixer::Channel::FrontRight],
+    ] {
+        assert_eq!(
+            run_serially(|| get_output_channel_layout_quirk(device, &layout)),
+            None
+        );
+    }
+}
+
 // create_stream_description
 // ------------------------------------
 #[test]
@@ -1005,19 +1223,257 @@ fn test_set_buffer_size_sync_by_scope_with_null_unit(scope: Scope) {
     );
 }
 
-// get_volume, set_volume
+// set_volume, set_volume_ramp
 // ------------------------------------
 #[test]
-fn test_stream_get_volume() {
-    if let Some(unit) = test_get_default_audiounit(Scope::Output) {
-        let expected_volume: f32 = 0.5;
-        run_serially(|| set_volume(unit.get_inner(), expected_volume));
+fn test_stream_set_volume() {
+    test_get_default_raw_stream(|stream| {
+        assert_eq!(stream.volume(), 1.0);
+        // There is no output in the default raw stream.
+        assert!(stream.set_volume(0.5).is_err());
+        assert_eq!(stream.volume(), 1.0);
+        stream.set_volume_ramp(VolumeRamp::Exponential, Duration::from_millis(50));
         assert_eq!(
-            expected_volume,
-            run_serially(|| get_volume(unit.get_inner()).unwrap())
+            stream.volume_ramp.load(Ordering::SeqCst),
+            VolumeRamp::Exponential
         );
-    } else {
-        println!("No output audiounit.");
+        assert_eq!(stream.volume_ramp_duration.load(Ordering::SeqCst), 0.05);
+    });
+}
+
+#[test]
+fn test_stream_set_volume_with_output() {
+    test_get_stream_with_default_data_callback_by_type(
+        "stream: set volume",
+        StreamType::OUTPUT,
+        None,
+        None,
+        state_callback,
+        ptr::null_mut(),
+        |stream| {
+            assert!(stream.set_volume(0.5).is_ok());
+            assert_eq!(stream.volume(), 0.5);
+            assert_eq!(
+                stream.set_volume(-1.0).unwrap_err(),
+                Error::invalid_parameter()
+            );
+            assert_eq!(stream.volume(), 0.5);
+        },
+    );
+
+    extern "C" fn state_callback(
+        _stream: *mut ffi::cubeb_stream,
+        _user_ptr: *mut c_void,
+        _state: ffi::cubeb_state,
+    ) {
+    }
+}
+
+// set_output_mixing_matrix
+// ------------------------------------
+#[test]
+fn test_stream_set_output_mixing_matrix() {
+    test_get_default_raw_stream(|stream| {
+        let matrix = MixingMatrix::new(2, 2, vec![1.0, 0.0, 0.0, 1.0]);
+        assert_eq!(
+            stream.set_output_mixing_matrix(matrix).unwrap_err(),
+            Error::invalid_parameter()
+        );
+    });
+
+    test_get_stream_with_default_data_callback_by_type(
+        "stream: set output mixing matrix",
+        StreamType::OUTPUT,
+        None,
+        None,
+        state_callback,
+        ptr::null_mut(),
+        |stream| {
+            let stream_channels = stream.core_stream_data.output_stream_params.channels() as usize;
+            let device_channels = stream.core_stream_data.output_hw_channel_count as usize;
+            assert_ne!(device_channels, 0);
+
+            // One row too many.
+            let matrix = MixingMatrix::new(
+                stream_channels,
+                device_channels + 1,
+                vec![0.5; stream_channels * (device_channels + 1)],
+            );
+            assert_eq!(
+                stream.set_output_mixing_matrix(matrix).unwrap_err(),
+                Error::invalid_parameter()
+            );
+
+            // Send every channel of the stream to every channel of the device.
+            let matrix = MixingMatrix::new(
+                stream_channels,
+                device_channels,
+                vec![0.5; stream_channels * device_channels],
+            );
+            assert!(stream.set_output_mixing_matrix(matrix).is_ok());
+            // The stream is stopped, the matrix is applied when starting.
+            assert!(stream.delayed_reinit);
+            assert!(stream.start().is_ok());
+            assert!(stream.core_stream_data.mixer.is_some());
+            assert!(stream.stop().is_ok());
+
+            assert!(stream.set_output_mixing_matrix(None).is_ok());
+        },
+    );
+
+    extern "C" fn state_callback(
+        _stream: *mut ffi::cubeb_stream,
+        _user_ptr: *mut c_void,
+        _state: ffi::cubeb_state,
+    ) {
+    }
+}
+
+// set_data_source
+// ------------------------------------
+#[test]
+fn test_stream_set_data_source() {
+    test_get_stream_with_default_data_callback_by_type(
+        "stream: set data source",
+        StreamType::OUTPUT,
+        None,
+        None,
+        state_callback,
+        ptr::null_mut(),
+        |stream| {
+            // The stream has no input.
+            assert_eq!(
+                stream.data_source(DeviceType::INPUT).unwrap_err(),
+                Error::invalid_parameter()
+            );
+            assert_eq!(
+                stream.set_data_source(DeviceType::INPUT, 0).unwrap_err(),
+                Error::invalid_parameter()
+            );
+
+            match stream.data_source(DeviceType::OUTPUT) {
+                Ok(source) => {
+                    println!("output data source: {:?}", source);
+                    // Selecting the current data source again is fine.
+                    assert!(stream
+                        .set_data_source(DeviceType::OUTPUT, source.id)
+                        .is_ok());
+                }
+                Err(e) => println!("No output data source. Error: {}", e),
+            }
+        },
+    );
+
+    extern "C" fn state_callback(
+        _stream: *mut ffi::cubeb_stream,
+        _user_ptr: *mut c_void,
+        _state: ffi::cubeb_state,
+    ) {
+    }
+}
+
+// aggregate_clock_info
+// ------------------------------------
+#[test]
+fn test_stream_aggregate_clock_info_without_aggregate_device() {
+    test_get_stream_with_default_data_callback_by_type(
+        "stream: aggregate clock info",
+        StreamType::OUTPUT,
+        None,
+        None,
+        state_callback,
+        ptr::null_mut(),
+        |stream| {
+            assert_eq!(stream.aggregate_clock_info().unwrap(), None);
+        },
+    );
+
+    extern "C" fn state_callback(
+        _stream: *mut ffi::cubeb_stream,
+        _user_ptr: *mut c_void,
+        _state: ffi::cubeb_state,
+    ) {
+    }
+}
+
+// negotiated_latency_frames
+// device_latency
+// ------------------------------------
+#[test]
+fn test_stream_negotiated_latency_frames() {
+    test_get_stream_with_default_data_callback_by_type(
+        "stream: negotiated latency",
+        StreamType::OUTPUT,
+        None,
+        None,
+        state_callback,
+        ptr::null_mut(),
+        |stream| {
+            let latency = stream.negotiated_latency_frames();
+            assert!(latency >= SAFE_MIN_LATENCY_FRAMES);
+            assert!(latency <= SAFE_MAX_LATENCY_FRAMES);
+            let device = stream.core_stream_data.output_device.id;
+            assert_eq!(
+                stream
+                    .context
+                    .device_latency(device as DeviceId, DeviceType::OUTPUT)
+                    .unwrap(),
+                Some(latency)
+            );
+            // The stream has no input, so its latency doesn't apply to the input side.
+            assert_eq!(
+                stream
+                    .context
+                    .device_latency(device as DeviceId, DeviceType::INPUT)
+                    .unwrap(),
+                None
+            );
+            assert_eq!(
+                stream
+                    .context
+                    .device_latency(ptr::null(), DeviceType::UNKNOWN)
+                    .unwrap_err(),
+                Error::invalid_parameter()
+            );
+        },
+    );
+
+    extern "C" fn state_callback(
+        _stream: *mut ffi::cubeb_stream,
+        _user_ptr: *mut c_void,
+        _state: ffi::cubeb_state,
+    ) {
+    }
+}
+
+// set_fade_duration
+// ------------------------------------
+#[test]
+fn test_stream_stop_waits_for_fade_out() {
+    test_get_stream_with_default_data_callback_by_type(
+        "stream: fade out on stop",
+        StreamType::OUTPUT,
+        None,
+        None,
+        state_callback,
+        ptr::null_mut(),
+        |stream| {
+            let fade = Duration::from_millis(100);
+            stream.set_fade_duration(fade);
+            assert!(stream.start().is_ok());
+            thread::sleep(Duration::from_millis(50));
+            let now = Instant::now();
+            assert!(stream.stop().is_ok());
+            assert!(now.elapsed() >= fade);
+            assert!(stream.fade_out_done.load(Ordering::SeqCst));
+        },
+    );
+
+    extern "C" fn state_callback(
+        _stream: *mut ffi::cubeb_stream,
+        _user_ptr: *mut c_void,
+        _state: ffi::cubeb_state,
+    ) {
     }
 }
 
@@ -1413,6 +1869,60 @@ fn test_get_device_label_by_unknown_device() {
     .is_err());
 }
 
+// AudioUnitContext::device_quirks
+// ------------------------------------
+#[test]
+fn test_context_device_quirks() {
+    let context = AudioUnitContext::new();
+    if let Some(device) = test_get_default_device(Scope::Output) {
+        let quirks = context
+            .device_quirks(device as DeviceId, DeviceType::OUTPUT)
+            .unwrap();
+        println!("output device quirks: {:?}", quirks);
+        assert!(!quirks.contains(Quirks::FORCE_VPIO));
+    } else {
+        println!("No output device.");
+    }
+
+    assert_eq!(
+        context.device_quirks(ptr::null(), DeviceType::INPUT | DeviceType::OUTPUT),
+        Err(Error::invalid_parameter())
+    );
+}
+
+// AudioUnitContext::prewarm_voice_processing_unit
+// ------------------------------------
+#[test]
+fn test_context_prewarm_voice_processing_unit() {
+    let context = AudioUnitContext::new();
+    assert_eq!(
+        context.voice_processing_prewarm_state(),
+        VoiceProcessingPrewarmState::Idle
+    );
+    if context.prewarm_voice_processing_unit().is_err() {
+        println!("VoiceProcessingIO is blocked.");
+        return;
+    }
+    assert_ne!(
+        context.voice_processing_prewarm_state(),
+        VoiceProcessingPrewarmState::Idle
+    );
+    context
+        .shared_voice_processing_unit
+        .queue
+        .run_sync(|| {})
+        .unwrap();
+    assert_eq!(
+        context.voice_processing_prewarm_state(),
+        VoiceProcessingPrewarmState::Ready
+    );
+    context.release_prewarmed_voice_processing_unit();
+    assert_eq!(
+        context.voice_processing_prewarm_state(),
+        VoiceProcessingPrewarmState::Idle
+    );
+}
+
 // get_device_global_uid
 // ------------------------------------
 #[test]
@@ -1511,8 +2021,11 @@ fn test_create_cubeb_device_info() {
         //       output/input scope ? The device may be a in-out device!
         assert_eq!(info.preferred, get_cubeb_device_pref(id, scope));
 
+        // The AudioUnit converts from and to all the formats.
         assert_eq!(info.format, ffi::CUBEB_DEVICE_FMT_ALL);
-        assert_eq!(info.default_format, ffi::CUBEB_DEVICE_FMT_F32NE);
+        // The default format is the native one.
+        assert_eq!(info.default_format.count_ones(), 1);
+        assert_ne!(info.format & info.default_format, 0);
         assert!(info.max_channels > 0);
         assert!(info.min_rate <= info.max_rate);
         assert!(info.min_rate <= info.default_rate);
@@ -1861,6 +2374,296 @@ fn get_devices_changed_callback(
     }
 }
 
+// device_collection_changes
+// ------------------------------------
+#[test]
+fn test_device_collection_changes() {
+    extern "C" fn callback(_: *mut ffi::cubeb, _: *mut c_void) {}
+
+    test_get_raw_context(|context| {
+        assert_eq!(
+            context
+                .device_collection_changes(DeviceType::INPUT | DeviceType::OUTPUT)
+                .unwrap_err(),
+            Error::invalid_parameter()
+        );
+
+        for devtype in &[DeviceType::INPUT, DeviceType::OUTPUT] {
+            assert!(run_serially(|| context.add_devices_changed_listener(
+                *devtype,
+                Some(callback),
+                ptr::null_mut()
+            ))
+            .is_ok());
+            // Nothing changed since the listener was added.
+            assert!(context
+                .device_collection_changes(*devtype)
+                .unwrap()
+                .is_empty());
+            assert!(run_serially(|| context.remove_devices_changed_listener(*devtype)).is_ok());
+        }
+    });
+}
+
+// enumerate_duplex_devices
+// ------------------------------------
+#[test]
+fn test_enumerate_duplex_devices() {
+    let input_devices =
+        run_serially_forward_panics(|| audiounit_get_devices_of_type(DeviceType::INPUT));
+    let output_devices =
+        run_serially_forward_panics(|| audiounit_get_devices_of_type(DeviceType::OUTPUT));
+
+    test_get_raw_context(|context| {
+        let devices = context.enumerate_duplex_devices().unwrap();
+        println!("duplex devices: {:?}", devices);
+        for device in &devices {
+            assert!(device.input.is_some() || device.output.is_some());
+            if let Some(input) = device.input.as_ref() {
+                assert!(input_devices.contains(&(input.devid as AudioObjectID)));
+                assert!(input.max_channels > 0);
+            }
+            if let Some(output) = device.output.as_ref() {
+                assert!(output_devices.contains(&(output.devid as AudioObjectID)));
+                assert!(output.max_channels > 0);
+            }
+        }
+        // A device with both input and output channels is reported as a single entry.
+        for id in input_devices
+            .iter()
+            .filter(|id| output_devices.contains(id))
+        {
+            let entries = devices
+                .iter()
+                .filter(|d| {
+                    d.input.as_ref().map(|i| i.devid as AudioObjectID) == Some(*id)
+                        || d.output.as_ref().map(|o| o.devid as AudioObjectID) == Some(*id)
+                })
+                .count();
+            assert!(entries <= 1);
+        }
+    });
+}
+
+// device cache
+// ------------------------------------
+#[test]
+fn test_device_cache() {
+    test_get_raw_context(|context| {
+        for devtype in &[DeviceType::INPUT, DeviceType::OUTPUT] {
+            let devices = run_serially(|| audiounit_get_devices_of_type(*devtype));
+            let cached = context.cached_device_infos(*devtype);
+            // Devices whose info can't be created aren't enumerated.
+            assert!(cached.len() <= devices.len());
+            for info in &cached {
+                assert!(devices.contains(&(info.devid as AudioObjectID)));
+                assert_eq!(info.device_type, devtype.bits());
+            }
+        }
+
+        // The cache is populated once, and refreshed after a notification.
+        let generation = context.device_cache.lock().unwrap().generation.unwrap();
+        assert!(generation <= context.device_cache_generation.load(Ordering::SeqCst));
+        assert!(!context.device_cache.lock().unwrap().listeners.is_empty());
+        context
+            .device_cache_generation
+            .fetch_add(1, Ordering::SeqCst);
+        assert!(context
+            .device_cache
+            .lock()
+            .unwrap()
+            .get_if_fresh(&context.device_cache_generation, DeviceType::OUTPUT)
+            .is_none());
+        let _ = context.cached_device_infos(DeviceType::OUTPUT);
+        assert!(context.device_cache.lock().unwrap().generation.unwrap() > generation);
+    });
+}
+
+#[test]
+fn test_device_cache_invalidated_by_supported_rates_and_latencies() {
+    test_get_raw_context(|context| {
+        let infos = context.cached_device_infos(DeviceType::OUTPUT);
+        if infos.is_empty() {
+            println!("No output device to test.");
+            return;
+        }
+        let id = infos[0].devid as AudioObjectID;
+        for property in &[
+            Property::DeviceSampleRates,
+            Property::DeviceBufferFrameSizeRange,
+        ] {
+            let address = get_property_address(*property, DeviceType::OUTPUT);
+            assert!(context
+                .device_cache
+                .lock()
+                .unwrap()
+                .listeners
+                .iter()
+                .any(|(i, a)| *i == id
+                    && a.mSelector == address.mSelector
+                    && a.mScope == address.mScope));
+
+            let _ = context.cached_device_infos(DeviceType::OUTPUT);
+            // What the HAL calls when the property changes.
+            let generation = &context.device_cache_generation as *const AtomicUsize;
+            assert_eq!(
+                audiounit_device_cache_listener(id, 1, &address, generation as *mut c_void),
+                NO_ERR
+            );
+            assert!(context
+                .device_cache
+                .lock()
+                .unwrap()
+                .get_if_fresh(&context.device_cache_generation, DeviceType::OUTPUT)
+                .is_none());
+        }
+    });
+}
+
+#[test]
+fn test_device_cache_hit_does_not_wait_for_queue() {
+    test_get_raw_context(|context| {
+        let expected = context.cached_device_infos(DeviceType::OUTPUT);
+        let generation = context.device_cache_generation.load(Ordering::SeqCst);
+        // Hold the queue until the cache has been read, or for a while if it needs a refresh.
+        let (tx, rx) = std::sync::mpsc::channel::<()>();
+        let released = Arc::new(AtomicBool::new(false));
+        let released_by_queue = released.clone();
+        context.serial_queue.run_async(move || {
+            let _ = rx.recv_timeout(Duration::from_secs(5));
+            released_by_queue.store(true, Ordering::SeqCst);
+        });
+        let infos = context.cached_device_infos(DeviceType::OUTPUT);
+        if context.device_cache_generation.load(Ordering::SeqCst) == generation {
+            assert!(!released.load(Ordering::SeqCst));
+            assert_eq!(infos, expected);
+        } else {
+            println!("A device notification came in, skipping the cache hit check.");
+        }
+        let _ = tx.send(());
+        context.serial_queue.run_sync(|| {});
+    });
+}
+
+// convert_frames_to_rate
+// ------------------------------------
+#[test]
+fn test_convert_frames_to_rate() {
+    assert_eq!(convert_frames_to_rate(480, 48000.0, 48000), 480);
+    assert_eq!(convert_frames_to_rate(480, 48000.0, 44100), 441);
+    assert_eq!(convert_frames_to_rate(480, 48000.0, 96000), 960);
+    // Rounded up.
+    assert_eq!(convert_frames_to_rate(15, 48000.0, 44100), 14);
+    assert_eq!(convert_frames_to_rate(1, 48000.0, 44100), 1);
+    // Unknown device rate.
+    assert_eq!(convert_frames_to_rate(480, 0.0, 44100), 480);
+}
+
+// device_max_channel_count, device_min_latency, device_preferred_sample_rate
+// ------------------------------------
+#[test]
+fn test_device_scoped_queries() {
+    test_get_raw_context(|context| {
+        assert_eq!(
+            context
+                .device_max_channel_count(ptr::null(), DeviceType::UNKNOWN)
+                .unwrap_err(),
+            Error::invalid_parameter()
+        );
+
+        for (devtype, scope) in &[
+            (DeviceType::INPUT, Scope::Input),
+            (DeviceType::OUTPUT, Scope::Output),
+        ] {
+            let device = match test_get_default_device(scope.clone()) {
+                Some(device) => device,
+                None => {
+                    assert!(context
+                        .device_max_channel_count(ptr::null(), *devtype)
+                        .is_err());
+                    continue;
+                }
+            };
+            // A null device id is the default device.
+            let channels = context
+                .device_max_channel_count(ptr::null(), *devtype)
+                .unwrap();
+            assert!(channels > 0);
+            assert_eq!(
+                context
+                    .device_max_channel_count(device as DeviceId, *devtype)
+                    .unwrap(),
+                channels
+            );
+
+            let rate = context
+                .device_preferred_sample_rate(device as DeviceId, *devtype)
+                .unwrap();
+            assert!(rate > 0);
+
+            // Without a rate, the latency is in frames at the device rate.
+            let params = StreamParams::from(ffi::cubeb_stream_params::default());
+            let latency = context
+                .device_min_latency(device as DeviceId, *devtype, &params)
+                .unwrap();
+            assert!(latency >= SAFE_MIN_LATENCY_FRAMES);
+            // Converting to twice the device rate never gives a shorter latency.
+            let params = StreamParams::from(ffi::cubeb_stream_params {
+                rate: rate * 2,
+                ..Default::default()
+            });
+            let doubled = context
+                .device_min_latency(device as DeviceId, *devtype, &params)
+                .unwrap();
+            assert!(doubled >= latency);
+            assert!(doubled >= SAFE_MIN_LATENCY_FRAMES);
+        }
+    });
+}
+
+// device_data_sources
+// ------------------------------------
+#[test]
+fn test_device_data_sources() {
+    test_get_raw_context(|context| {
+        assert_eq!(
+            context
+                .device_data_sources(ptr::null(), DeviceType::INPUT | DeviceType::OUTPUT)
+                .unwrap_err(),
+            Error::invalid_parameter()
+        );
+        assert_eq!(
+            context
+                .device_data_source(ptr::null(), DeviceType::UNKNOWN)
+                .unwrap_err(),
+            Error::invalid_parameter()
+        );
+
+        for (devtype, scope) in &[
+            (DeviceType::INPUT, Scope::Input),
+            (DeviceType::OUTPUT, Scope::Output),
+        ] {
+            if test_get_default_device(scope.clone()).is_none() {
+                println!("No device for {:?}.", scope);
+                continue;
+            }
+            // Some devices have no data source.
+            match (
+                context.device_data_sources(ptr::null(), *devtype),
+                context.device_data_source(ptr::null(), *devtype),
+            ) {
+                (Ok(sources), Ok(current)) => {
+                    println!("{:?} data sources: {:?}", scope, sources);
+                    assert!(sources.contains(&current));
+                }
+                (sources, current) => {
+                    println!("{:?} data sources: {:?}, {:?}", scope, sources, current);
+                }
+            }
+        }
+    });
+}
+
 // SharedVoiceProcessingUnitManager
 // ------------------------------------
 #[test]
@@ -1922,6 +2725,58 @@ fn test_shared_voice_processing_multiple_units() {
     assert!(r3.is_err());
 }
 
+#[test]
+fn test_shared_voice_processing_unit_prewarm() {
+    let queue = Queue::new_with_target(
+        "test_shared_voice_processing_unit_prewarm",
+        get_serial_queue_singleton(),
+    );
+    let mut shared = SharedVoiceProcessingUnitManager::new(queue.clone());
+    assert_eq!(shared.prewarm_state(), VoiceProcessingPrewarmState::Idle);
+    shared.prewarm();
+    // Already pending.
+    shared.prewarm();
+    queue.run_sync(|| {});
+    assert_eq!(shared.prewarm_state(), VoiceProcessingPrewarmState::Ready);
+
+    // The parked unit isn't shared, but goes to the next stream.
+    let r1 = queue.run_sync(|| shared.take()).unwrap();
+    assert!(r1.is_err());
+    let r2 = queue.run_sync(|| shared.take_or_create()).unwrap();
+    assert!(r2.is_ok());
+    assert_eq!(shared.prewarm_state(), VoiceProcessingPrewarmState::Idle);
+    {
+        let _handle = r2.unwrap();
+    }
+    let r3 = queue.run_sync(|| shared.take()).unwrap();
+    assert!(r3.is_ok());
+}
+
+#[test]
+fn test_shared_voice_processing_unit_release_prewarmed() {
+    let queue = Queue::new_with_target(
+        "test_shared_voice_processing_unit_release_prewarmed",
+        get_serial_queue_singleton(),
+    );
+    let mut shared = SharedVoiceProcessingUnitManager::with_idle_timeout(queue.clone(), None);
+    shared.prewarm();
+    queue.run_sync(|| {});
+    shared.release_prewarmed();
+    assert_eq!(shared.prewarm_state(), VoiceProcessingPrewarmState::Idle);
+    // Shared like the other idle units.
+    let r1 = queue.run_sync(|| shared.take()).unwrap();
+    assert!(r1.is_ok());
+    drop(r1);
+
+    // Released while being created.
+    shared.prewarm();
+    shared.release_prewarmed();
+    queue.run_sync(|| {});
+    assert_eq!(shared.prewarm_state(), VoiceProcessingPrewarmState::Idle);
+    let r2 = queue.run_sync(|| shared.take()).unwrap();
+    assert!(r2.is_ok());
+}
+
 #[test]
 fn test_shared_voice_processing_release_on_idle() {
     let queue = Queue::new_with_target(
@@ -1930,7 +2785,7 @@ fn test_shared_voice_processing_release_on_idle() {
     );
     let mut shared = SharedVoiceProcessingUnitManager::with_idle_timeout(
         queue.clone(),
-        Duration::from_millis(0),
+        Some(Duration::from_millis(0)),
     );
     let r = queue.run_sync(|| shared.take_or_create()).unwrap();
     assert!(r.is_ok());
@@ -1950,7 +2805,7 @@ fn test_shared_voice_processing_no_release_on_outstanding() {
     );
     let mut shared = SharedVoiceProcessingUnitManager::with_idle_timeout(
         queue.clone(),
-        Duration::from_millis(0),
+        Some(Duration::from_millis(0)),
     );
     let r1 = queue.run_sync(|| shared.take_or_create()).unwrap();
     assert!(r1.is_ok());
@@ -1972,7 +2827,7 @@ fn test_shared_voice_processing_release_on_idle_cancel_on_take() {
     );
     let mut shared = SharedVoiceProcessingUnitManager::with_idle_timeout(
         queue.clone(),
-        Duration::from_millis(0),
+        Some(Duration::from_millis(0)),
     );
     let r1 = queue.run_sync(|| shared.take_or_create()).unwrap();
     assert!(r1.is_ok());
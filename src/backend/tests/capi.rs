@@ -0,0 +1,837 @@
+use super::utils::{
+    test_get_default_device, test_get_default_raw_stream, test_get_raw_context, Scope,
+};
+use super::*;
+use crate::capi::*;
+use std::os::raw::c_int;
+
+fn as_context_ptr(context: &mut AudioUnitContext) -> *mut ffi::cubeb {
+    context as *mut AudioUnitContext as *mut ffi::cubeb
+}
+
+fn as_stream_ptr(stream: &mut AudioUnitStream) -> *mut ffi::cubeb_stream {
+    stream as *mut AudioUnitStream as *mut ffi::cubeb_stream
+}
+
+// audiounit_rust_stream_set_dither_mode
+// ------------------------------------
+#[test]
+fn test_capi_stream_set_dither_mode() {
+    test_get_default_raw_stream(|stream| {
+        let stm = as_stream_ptr(stream);
+        let mut mode: c_int = -1;
+        assert_eq!(
+            unsafe { audiounit_rust_stream_get_dither_mode(stm, &mut mode) },
+            ffi::CUBEB_OK
+        );
+        assert_eq!(mode, AUDIOUNIT_RUST_DITHER_MODE_DISABLED);
+        assert_eq!(
+            unsafe {
+                audiounit_rust_stream_set_dither_mode(
+                    stm,
+                    AUDIOUNIT_RUST_DITHER_MODE_TPDF_NOISE_SHAPED,
+                )
+            },
+            ffi::CUBEB_OK
+        );
+        assert_eq!(
+            unsafe { audiounit_rust_stream_get_dither_mode(stm, &mut mode) },
+            ffi::CUBEB_OK
+        );
+        assert_eq!(mode, AUDIOUNIT_RUST_DITHER_MODE_TPDF_NOISE_SHAPED);
+        assert_eq!(
+            unsafe { audiounit_rust_stream_set_dither_mode(stm, 3) },
+            ffi::CUBEB_ERROR_INVALID_PARAMETER
+        );
+        assert_eq!(stream.dither_mode(), DitherMode::TpdfNoiseShaped);
+    });
+}
+
+// audiounit_rust_stream_set_limiter_mode
+// ------------------------------------
+#[test]
+fn test_capi_stream_set_limiter_mode() {
+    test_get_default_raw_stream(|stream| {
+        let stm = as_stream_ptr(stream);
+        let mut mode: c_int = -1;
+        let mut reduction = -1.0;
+        assert_eq!(
+            unsafe { audiounit_rust_stream_get_limiter_mode(stm, &mut mode) },
+            ffi::CUBEB_OK
+        );
+        assert_eq!(mode, AUDIOUNIT_RUST_LIMITER_MODE_DISABLED);
+        assert_eq!(
+            unsafe { audiounit_rust_stream_get_limiter_gain_reduction(stm, &mut reduction) },
+            ffi::CUBEB_OK
+        );
+        assert_eq!(reduction, 0.0);
+        assert_eq!(
+            unsafe {
+                audiounit_rust_stream_set_limiter_mode(stm, AUDIOUNIT_RUST_LIMITER_MODE_SOFT_CLIP)
+            },
+            ffi::CUBEB_OK
+        );
+        assert_eq!(
+            unsafe { audiounit_rust_stream_get_limiter_mode(stm, &mut mode) },
+            ffi::CUBEB_OK
+        );
+        assert_eq!(mode, AUDIOUNIT_RUST_LIMITER_MODE_SOFT_CLIP);
+        assert_eq!(
+            unsafe { audiounit_rust_stream_set_limiter_mode(stm, -1) },
+            ffi::CUBEB_ERROR_INVALID_PARAMETER
+        );
+        assert_eq!(stream.limiter_mode(), LimiterMode::SoftClip);
+    });
+}
+
+// audiounit_rust_stream_set_volume_ramp
+// ------------------------------------
+#[test]
+fn test_capi_stream_set_volume_ramp() {
+    test_get_default_raw_stream(|stream| {
+        let stm = as_stream_ptr(stream);
+        assert_eq!(
+            unsafe {
+                audiounit_rust_stream_set_volume_ramp(
+                    stm,
+                    AUDIOUNIT_RUST_VOLUME_RAMP_EXPONENTIAL,
+                    50,
+                )
+            },
+            ffi::CUBEB_OK
+        );
+        assert_eq!(
+            unsafe { audiounit_rust_stream_set_volume_ramp(stm, 2, 50) },
+            ffi::CUBEB_ERROR_INVALID_PARAMETER
+        );
+        assert_eq!(
+            stream.volume_ramp.load(Ordering::SeqCst),
+            VolumeRamp::Exponential
+        );
+        assert_eq!(stream.volume_ramp_duration.load(Ordering::SeqCst), 0.05);
+        let mut volume = -1.0;
+        assert_eq!(
+            unsafe { audiounit_rust_stream_get_volume(stm, &mut volume) },
+            ffi::CUBEB_OK
+        );
+        assert_eq!(volume, 1.0);
+    });
+}
+
+// audiounit_rust_stream_set_fade_duration
+// ------------------------------------
+#[test]
+fn test_capi_stream_set_fade_duration() {
+    test_get_default_raw_stream(|stream| {
+        let stm = as_stream_ptr(stream);
+        assert_eq!(
+            unsafe { audiounit_rust_stream_set_fade_duration(stm, 20) },
+            ffi::CUBEB_OK
+        );
+        assert_eq!(stream.fade_duration.load(Ordering::SeqCst), 0.02);
+        assert_eq!(
+            unsafe { audiounit_rust_stream_set_fade_duration(stm, 0) },
+            ffi::CUBEB_OK
+        );
+        assert_eq!(stream.fade_duration.load(Ordering::SeqCst), 0.0);
+    });
+}
+
+// audiounit_rust_stream_set_output_mixing_matrix
+// ------------------------------------
+#[test]
+fn test_capi_stream_set_output_mixing_matrix() {
+    test_get_default_raw_stream(|stream| {
+        let stm = as_stream_ptr(stream);
+        let coefficients = [1.0, 0.0, 0.0, 1.0];
+        // There is no output in the default raw stream.
+        assert_eq!(
+            unsafe {
+                audiounit_rust_stream_set_output_mixing_matrix(stm, 2, 2, coefficients.as_ptr())
+            },
+            ffi::CUBEB_ERROR_INVALID_PARAMETER
+        );
+        assert_eq!(
+            unsafe {
+                audiounit_rust_stream_set_output_mixing_matrix(stm, 0, 2, coefficients.as_ptr())
+            },
+            ffi::CUBEB_ERROR_INVALID_PARAMETER
+        );
+        assert_eq!(
+            unsafe { audiounit_rust_stream_set_output_mixing_matrix(stm, 0, 0, ptr::null()) },
+            ffi::CUBEB_ERROR_INVALID_PARAMETER
+        );
+    });
+}
+
+// audiounit_rust_get_device_collection_changes
+// ------------------------------------
+#[test]
+fn test_capi_get_device_collection_changes() {
+    test_get_raw_context(|context| {
+        let ctx = as_context_ptr(context);
+        let mut changes = audiounit_rust_device_collection_changes {
+            added: ptr::null_mut(),
+            added_count: 0,
+            removed: ptr::null_mut(),
+            removed_count: 0,
+            changed: ptr::null_mut(),
+            changed_count: 0,
+        };
+        assert_eq!(
+            unsafe {
+                audiounit_rust_get_device_collection_changes(
+                    ctx,
+                    ffi::CUBEB_DEVICE_TYPE_UNKNOWN,
+                    &mut changes,
+                )
+            },
+            ffi::CUBEB_ERROR_INVALID_PARAMETER
+        );
+        // No callback has been called yet.
+        assert_eq!(
+            unsafe {
+                audiounit_rust_get_device_collection_changes(
+                    ctx,
+                    ffi::CUBEB_DEVICE_TYPE_INPUT,
+                    &mut changes,
+                )
+            },
+            ffi::CUBEB_OK
+        );
+        assert!(changes.added.is_null());
+        assert_eq!(changes.added_count, 0);
+        assert!(changes.removed.is_null());
+        assert_eq!(changes.removed_count, 0);
+        assert!(changes.changed.is_null());
+        assert_eq!(changes.changed_count, 0);
+        assert_eq!(
+            unsafe { audiounit_rust_device_collection_changes_destroy(&mut changes) },
+            ffi::CUBEB_OK
+        );
+    });
+}
+
+// audiounit_rust_enumerate_duplex_devices
+// ------------------------------------
+#[test]
+fn test_capi_enumerate_duplex_devices() {
+    test_get_raw_context(|context| {
+        let expected = context.enumerate_duplex_devices().unwrap();
+        let ctx = as_context_ptr(context);
+        let mut collection = audiounit_rust_duplex_device_collection {
+            device: ptr::null_mut(),
+            count: 0,
+        };
+        assert_eq!(
+            unsafe { audiounit_rust_enumerate_duplex_devices(ctx, &mut collection) },
+            ffi::CUBEB_OK
+        );
+        assert_eq!(collection.count, expected.len());
+        if collection.count > 0 {
+            let devices = unsafe { slice::from_raw_parts(collection.device, collection.count) };
+            for (device, expected) in devices.iter().zip(expected.iter()) {
+                assert_eq!(
+                    device.input.devid,
+                    expected.input.as_ref().map_or(ptr::null(), |i| i.devid)
+                );
+                assert_eq!(
+                    device.output.devid,
+                    expected.output.as_ref().map_or(ptr::null(), |o| o.devid)
+                );
+                if let Some(output) = expected.output.as_ref() {
+                    assert_eq!(DeviceDirectionInfo::new(&device.output), *output);
+                }
+            }
+        }
+        assert_eq!(
+            unsafe { audiounit_rust_duplex_device_collection_destroy(&mut collection) },
+            ffi::CUBEB_OK
+        );
+        assert!(collection.device.is_null());
+        assert_eq!(collection.count, 0);
+    });
+}
+
+// audiounit_rust_get_device_data_sources
+// ------------------------------------
+#[test]
+fn test_capi_get_device_data_sources() {
+    test_get_raw_context(|context| {
+        for devtype in &[DeviceType::INPUT, DeviceType::OUTPUT] {
+            // Some devices have no data source.
+            let expected = context.device_data_sources(ptr::null(), *devtype);
+            let ctx = as_context_ptr(context);
+            let mut collection = audiounit_rust_data_source_collection {
+                source: ptr::null_mut(),
+                count: 0,
+            };
+            let r = unsafe {
+                audiounit_rust_get_device_data_sources(
+                    ctx,
+                    ptr::null(),
+                    devtype.bits(),
+                    &mut collection,
+                )
+            };
+            let expected = match expected {
+                Ok(sources) => sources,
+                Err(e) => {
+                    assert_eq!(r, e.raw_code());
+                    continue;
+                }
+            };
+            assert_eq!(r, ffi::CUBEB_OK);
+            assert_eq!(collection.count, expected.len());
+            if collection.count > 0 {
+                let sources = unsafe { slice::from_raw_parts(collection.source, collection.count) };
+                for (source, expected) in sources.iter().zip(expected.iter()) {
+                    assert_eq!(source.id, expected.id);
+                    let name = unsafe { CStr::from_ptr(source.name) };
+                    assert_eq!(name.to_str().unwrap(), expected.name);
+                }
+            }
+            assert_eq!(
+                unsafe { audiounit_rust_data_source_collection_destroy(&mut collection) },
+                ffi::CUBEB_OK
+            );
+            assert!(collection.source.is_null());
+
+            let mut current = audiounit_rust_data_source {
+                id: 0,
+                name: ptr::null_mut(),
+            };
+            assert_eq!(
+                unsafe {
+                    audiounit_rust_get_device_data_source(
+                        ctx,
+                        ptr::null(),
+                        devtype.bits(),
+                        &mut current,
+                    )
+                },
+                ffi::CUBEB_OK
+            );
+            assert!(expected.iter().any(|source| source.id == current.id));
+            assert_eq!(
+                unsafe { audiounit_rust_data_source_destroy(&mut current) },
+                ffi::CUBEB_OK
+            );
+            assert!(current.name.is_null());
+        }
+    });
+}
+
+// audiounit_rust_get_device_latency
+// ------------------------------------
+#[test]
+fn test_capi_get_device_latency() {
+    test_get_raw_context(|context| {
+        let ctx = as_context_ptr(context);
+        let mut latency_frames = u32::MAX;
+        assert_eq!(
+            unsafe {
+                audiounit_rust_get_device_latency(
+                    ctx,
+                    ptr::null(),
+                    ffi::CUBEB_DEVICE_TYPE_UNKNOWN,
+                    &mut latency_frames,
+                )
+            },
+            ffi::CUBEB_ERROR_INVALID_PARAMETER
+        );
+        if test_get_default_device(Scope::Output).is_none() {
+            println!("No output device.");
+            return;
+        }
+        // No stream uses the device.
+        assert_eq!(
+            unsafe {
+                audiounit_rust_get_device_latency(
+                    ctx,
+                    ptr::null(),
+                    ffi::CUBEB_DEVICE_TYPE_OUTPUT,
+                    &mut latency_frames,
+                )
+            },
+            ffi::CUBEB_OK
+        );
+        assert_eq!(latency_frames, 0);
+    });
+}
+
+// audiounit_rust_get_device_max_channel_count, audiounit_rust_get_device_min_latency,
+// audiounit_rust_get_device_preferred_sample_rate
+// ------------------------------------
+#[test]
+fn test_capi_get_device_scoped_queries() {
+    test_get_raw_context(|context| {
+        let ctx = as_context_ptr(context);
+        let params = ffi::cubeb_stream_params::default();
+        let mut value = u32::MAX;
+        assert_eq!(
+            unsafe {
+                audiounit_rust_get_device_max_channel_count(
+                    ctx,
+                    ptr::null(),
+                    ffi::CUBEB_DEVICE_TYPE_UNKNOWN,
+                    &mut value,
+                )
+            },
+            ffi::CUBEB_ERROR_INVALID_PARAMETER
+        );
+        assert_eq!(
+            unsafe {
+                audiounit_rust_get_device_min_latency(
+                    ctx,
+                    ptr::null(),
+                    ffi::CUBEB_DEVICE_TYPE_UNKNOWN,
+                    params,
+                    &mut value,
+                )
+            },
+            ffi::CUBEB_ERROR_INVALID_PARAMETER
+        );
+        assert_eq!(
+            unsafe {
+                audiounit_rust_get_device_preferred_sample_rate(
+                    ctx,
+                    ptr::null(),
+                    ffi::CUBEB_DEVICE_TYPE_UNKNOWN,
+                    &mut value,
+                )
+            },
+            ffi::CUBEB_ERROR_INVALID_PARAMETER
+        );
+
+        for (devtype, scope) in &[
+            (DeviceType::INPUT, Scope::Input),
+            (DeviceType::OUTPUT, Scope::Output),
+        ] {
+            if test_get_default_device(scope.clone()).is_none() {
+                println!("No {:?} device.", scope);
+                continue;
+            }
+            let mut channels = 0;
+            assert_eq!(
+                unsafe {
+                    audiounit_rust_get_device_max_channel_count(
+                        ctx,
+                        ptr::null(),
+                        devtype.bits(),
+                        &mut channels,
+                    )
+                },
+                ffi::CUBEB_OK
+            );
+            assert_eq!(
+                channels,
+                context
+                    .device_max_channel_count(ptr::null(), *devtype)
+                    .unwrap()
+            );
+
+            let mut latency = 0;
+            assert_eq!(
+                unsafe {
+                    audiounit_rust_get_device_min_latency(
+                        ctx,
+                        ptr::null(),
+                        devtype.bits(),
+                        params,
+                        &mut latency,
+                    )
+                },
+                ffi::CUBEB_OK
+            );
+            assert_eq!(
+                latency,
+                context
+                    .device_min_latency(ptr::null(), *devtype, &StreamParams::from(params))
+                    .unwrap()
+            );
+
+            let mut rate = 0;
+            assert_eq!(
+                unsafe {
+                    audiounit_rust_get_device_preferred_sample_rate(
+                        ctx,
+                        ptr::null(),
+                        devtype.bits(),
+                        &mut rate,
+                    )
+                },
+                ffi::CUBEB_OK
+            );
+            assert_eq!(
+                rate,
+                context
+                    .device_preferred_sample_rate(ptr::null(), *devtype)
+                    .unwrap()
+            );
+        }
+    });
+}
+
+// audiounit_rust_stream_get_negotiated_latency
+// ------------------------------------
+#[test]
+fn test_capi_stream_get_negotiated_latency() {
+    test_get_default_raw_stream(|stream| {
+        let stm = as_stream_ptr(stream);
+        let mut latency_frames = u32::MAX;
+        assert_eq!(
+            unsafe { audiounit_rust_stream_get_negotiated_latency(stm, &mut latency_frames) },
+            ffi::CUBEB_OK
+        );
+        assert_eq!(latency_frames, stream.latency_frames);
+    });
+}
+
+// audiounit_rust_init_with_settings
+// ------------------------------------
+#[test]
+fn test_capi_init_with_settings() {
+    let mut settings: audiounit_rust_settings = unsafe { std::mem::zeroed() };
+    assert_eq!(
+        unsafe { audiounit_rust_get_default_settings(&mut settings) },
+        ffi::CUBEB_OK
+    );
+    let defaults = BackendSettings::default();
+    assert_eq!(settings.min_latency_frames, defaults.min_latency_frames);
+    assert_eq!(settings.max_latency_frames, defaults.max_latency_frames);
+    assert_eq!(
+        settings.input_buffer_callbacks,
+        defaults.input_buffer_callbacks
+    );
+    assert_eq!(
+        settings.destroy_stale_aggregate_devices,
+        defaults.destroy_stale_aggregate_devices
+    );
+    assert!(settings.aggregate_master_device_uid.is_null());
+
+    let mut context: *mut ffi::cubeb = ptr::null_mut();
+    assert_eq!(
+        unsafe { audiounit_rust_init_with_settings(&mut context, ptr::null(), &settings) },
+        ffi::CUBEB_OK
+    );
+    assert!(!context.is_null());
+    unsafe { OPS.destroy.unwrap()(context) };
+}
+
+#[test]
+fn test_capi_init_with_invalid_settings() {
+    let mut settings: audiounit_rust_settings = unsafe { std::mem::zeroed() };
+    assert_eq!(
+        unsafe { audiounit_rust_get_default_settings(&mut settings) },
+        ffi::CUBEB_OK
+    );
+    let mut context: *mut ffi::cubeb = ptr::null_mut();
+
+    settings.min_latency_frames = settings.max_latency_frames + 1;
+    assert_eq!(
+        unsafe { audiounit_rust_init_with_settings(&mut context, ptr::null(), &settings) },
+        ffi::CUBEB_ERROR_INVALID_PARAMETER
+    );
+    assert!(context.is_null());
+
+    settings.min_latency_frames = 1;
+    settings.aggregate_device_policy = 4;
+    assert_eq!(
+        unsafe { audiounit_rust_init_with_settings(&mut context, ptr::null(), &settings) },
+        ffi::CUBEB_ERROR_INVALID_PARAMETER
+    );
+    assert!(context.is_null());
+}
+
+// audiounit_rust_stream_get_aggregate_clock_info
+// ------------------------------------
+#[test]
+fn test_capi_stream_get_aggregate_clock_info_without_aggregate_device() {
+    test_get_default_raw_stream(|stream| {
+        let stm = as_stream_ptr(stream);
+        let mut info = audiounit_rust_aggregate_clock_info {
+            master_uid: ptr::null_mut(),
+            sub_device: ptr::null_mut(),
+            count: 0,
+        };
+        assert_eq!(
+            unsafe { audiounit_rust_stream_get_aggregate_clock_info(stm, &mut info) },
+            ffi::CUBEB_OK
+        );
+        assert!(info.master_uid.is_null());
+        assert!(info.sub_device.is_null());
+        assert_eq!(info.count, 0);
+        assert_eq!(
+            unsafe { audiounit_rust_aggregate_clock_info_destroy(&mut info) },
+            ffi::CUBEB_OK
+        );
+    });
+}
+
+#[test]
+fn test_capi_aggregate_clock_info_destroy() {
+    let mut info = audiounit_rust_aggregate_clock_info::from(AggregateClockInfo {
+        master_uid: "master".to_string(),
+        drift_compensation: vec![("sub".to_string(), true)],
+    });
+    assert_eq!(
+        unsafe { CStr::from_ptr(info.master_uid) }.to_str().unwrap(),
+        "master"
+    );
+    assert_eq!(info.count, 1);
+    let sub_device = unsafe { &*info.sub_device };
+    assert_eq!(
+        unsafe { CStr::from_ptr(sub_device.uid) }.to_str().unwrap(),
+        "sub"
+    );
+    assert!(sub_device.drift_compensation);
+    assert_eq!(
+        unsafe { audiounit_rust_aggregate_clock_info_destroy(&mut info) },
+        ffi::CUBEB_OK
+    );
+    assert!(info.master_uid.is_null());
+    assert!(info.sub_device.is_null());
+    assert_eq!(info.count, 0);
+}
+
+// audiounit_rust_add_quirk_rule
+// ------------------------------------
+#[test]
+fn test_capi_add_quirk_rule() {
+    test_get_raw_context(|context| {
+        let ctx = as_context_ptr(context);
+        // The rules stay for the whole process, so this one must not match any real device.
+        let model_uid = CString::new("audiounit-rust-test-no-such-device").unwrap();
+        let mut rule = audiounit_rust_quirk_rule {
+            scope: AUDIOUNIT_RUST_QUIRK_SCOPE_INPUT,
+            model_uid: model_uid.as_ptr(),
+            label: ptr::null(),
+            manufacturer: ptr::null(),
+            transport_type: 0,
+            min_kernel_major_version: 0,
+            max_kernel_major_version: 0,
+            set: AUDIOUNIT_RUST_QUIRK_BLOCK_VPIO,
+            clear: 0,
+        };
+        assert_eq!(
+            unsafe { audiounit_rust_add_quirk_rule(ctx, &rule) },
+            ffi::CUBEB_OK
+        );
+
+        rule.scope = 3;
+        assert_eq!(
+            unsafe { audiounit_rust_add_quirk_rule(ctx, &rule) },
+            ffi::CUBEB_ERROR_INVALID_PARAMETER
+        );
+        rule.scope = AUDIOUNIT_RUST_QUIRK_SCOPE_ANY;
+        rule.set = 1 << 31;
+        assert_eq!(
+            unsafe { audiounit_rust_add_quirk_rule(ctx, &rule) },
+            ffi::CUBEB_ERROR_INVALID_PARAMETER
+        );
+    });
+}
+
+// audiounit_rust_get_device_quirks
+// ------------------------------------
+#[test]
+fn test_capi_get_device_quirks() {
+    if test_get_default_device(Scope::Output).is_none() {
+        println!("No output device to get the quirks of.");
+        return;
+    }
+    test_get_raw_context(|context| {
+        let ctx = as_context_ptr(context);
+        let mut quirks = 0;
+        assert_eq!(
+            unsafe {
+                audiounit_rust_get_device_quirks(
+                    ctx,
+                    ptr::null(),
+                    ffi::CUBEB_DEVICE_TYPE_OUTPUT,
+                    &mut quirks,
+                )
+            },
+            ffi::CUBEB_OK
+        );
+        assert_eq!(
+            Some(quirks),
+            context
+                .device_quirks(ptr::null(), DeviceType::OUTPUT)
+                .ok()
+                .map(|q| q.bits())
+        );
+        assert_eq!(
+            unsafe {
+                audiounit_rust_get_device_quirks(
+                    ctx,
+                    ptr::null(),
+                    ffi::CUBEB_DEVICE_TYPE_INPUT | ffi::CUBEB_DEVICE_TYPE_OUTPUT,
+                    &mut quirks,
+                )
+            },
+            ffi::CUBEB_ERROR_INVALID_PARAMETER
+        );
+    });
+}
+
+// audiounit_rust_stream_set_vpio_ducking
+// ------------------------------------
+#[test]
+fn test_capi_stream_set_vpio_ducking() {
+    test_get_default_raw_stream(|stream| {
+        let stm = as_stream_ptr(stream);
+        let mut level: c_int = -1;
+        let mut ramp_duration_ms = 0;
+        assert_eq!(
+            unsafe {
+                audiounit_rust_stream_get_vpio_ducking(stm, &mut level, &mut ramp_duration_ms)
+            },
+            ffi::CUBEB_OK
+        );
+        assert_eq!(level, AUDIOUNIT_RUST_DUCKING_LEVEL_NONE);
+        assert_eq!(
+            u128::from(ramp_duration_ms),
+            VpioDucking::default().ramp_duration.as_millis()
+        );
+
+        // The stream doesn't use VoiceProcessingIO, so this is only applied to its next unit.
+        assert_eq!(
+            unsafe {
+                audiounit_rust_stream_set_vpio_ducking(stm, AUDIOUNIT_RUST_DUCKING_LEVEL_MAX, 100)
+            },
+            ffi::CUBEB_OK
+        );
+        assert_eq!(
+            unsafe {
+                audiounit_rust_stream_get_vpio_ducking(stm, &mut level, &mut ramp_duration_ms)
+            },
+            ffi::CUBEB_OK
+        );
+        assert_eq!(level, AUDIOUNIT_RUST_DUCKING_LEVEL_MAX);
+        assert_eq!(ramp_duration_ms, 100);
+
+        assert_eq!(
+            unsafe { audiounit_rust_stream_set_vpio_ducking(stm, 4, 100) },
+            ffi::CUBEB_ERROR_INVALID_PARAMETER
+        );
+        assert_eq!(stream.vpio_ducking().level, DuckingLevel::Max);
+    });
+}
+
+// audiounit_rust_stream_register_voice_activity_callback
+// ------------------------------------
+#[test]
+fn test_capi_stream_register_voice_activity_callback_without_input() {
+    unsafe extern "C" fn voice_activity_callback(
+        _user_ptr: *mut c_void,
+        _event: VoiceActivityEvent,
+    ) {
+    }
+
+    assert_eq!(AUDIOUNIT_RUST_VOICE_ACTIVITY_SPEECH_STARTED, 0);
+    assert_eq!(AUDIOUNIT_RUST_VOICE_ACTIVITY_SPEECH_ENDED, 1);
+    test_get_default_raw_stream(|stream| {
+        let stm = as_stream_ptr(stream);
+        assert!(stream.core_stream_data.input_unit.is_null());
+        assert_eq!(
+            unsafe {
+                audiounit_rust_stream_register_voice_activity_callback(
+                    stm,
+                    Some(voice_activity_callback),
+                )
+            },
+            ffi::CUBEB_ERROR_INVALID_PARAMETER
+        );
+        assert_eq!(
+            unsafe { audiounit_rust_stream_register_voice_activity_callback(stm, None) },
+            ffi::CUBEB_ERROR_INVALID_PARAMETER
+        );
+    });
+}
+
+// audiounit_rust_stream_get_supported_input_processing_params
+// ------------------------------------
+#[test]
+fn test_capi_stream_get_supported_input_processing_params_without_input() {
+    test_get_default_raw_stream(|stream| {
+        let stm = as_stream_ptr(stream);
+        let mut params = ffi::CUBEB_INPUT_PROCESSING_PARAM_ECHO_CANCELLATION;
+        assert_eq!(
+            unsafe {
+                audiounit_rust_stream_get_supported_input_processing_params(stm, &mut params)
+            },
+            ffi::CUBEB_OK
+        );
+        assert_eq!(params, ffi::CUBEB_INPUT_PROCESSING_PARAM_NONE);
+    });
+}
+
+// audiounit_rust_stream_get_supported_input_processing_param_combinations
+// ------------------------------------
+#[test]
+fn test_capi_stream_get_supported_input_processing_param_combinations_without_input() {
+    test_get_default_raw_stream(|stream| {
+        let stm = as_stream_ptr(stream);
+        let mut combinations = audiounit_rust_input_processing_param_combinations {
+            params: ptr::null_mut(),
+            count: 0,
+        };
+        assert_eq!(
+            unsafe {
+                audiounit_rust_stream_get_supported_input_processing_param_combinations(
+                    stm,
+                    &mut combinations,
+                )
+            },
+            ffi::CUBEB_OK
+        );
+        assert!(combinations.params.is_null());
+        assert_eq!(combinations.count, 0);
+        assert_eq!(
+            unsafe {
+                audiounit_rust_input_processing_param_combinations_destroy(&mut combinations)
+            },
+            ffi::CUBEB_OK
+        );
+    });
+}
+
+// audiounit_rust_prewarm_voice_processing_unit
+// ------------------------------------
+#[test]
+fn test_capi_prewarm_voice_processing_unit() {
+    test_get_raw_context(|context| {
+        let ctx = as_context_ptr(context);
+        let mut state: c_int = -1;
+        assert_eq!(
+            unsafe { audiounit_rust_get_voice_processing_prewarm_state(ctx, &mut state) },
+            ffi::CUBEB_OK
+        );
+        assert_eq!(state, AUDIOUNIT_RUST_VOICE_PROCESSING_PREWARM_STATE_IDLE);
+        if unsafe { audiounit_rust_prewarm_voice_processing_unit(ctx) } != ffi::CUBEB_OK {
+            println!("VoiceProcessingIO is blocked.");
+            return;
+        }
+        context
+            .shared_voice_processing_unit
+            .queue
+            .run_sync(|| {})
+            .unwrap();
+        assert_eq!(
+            unsafe { audiounit_rust_get_voice_processing_prewarm_state(ctx, &mut state) },
+            ffi::CUBEB_OK
+        );
+        assert_eq!(state, AUDIOUNIT_RUST_VOICE_PROCESSING_PREWARM_STATE_READY);
+        assert_eq!(
+            unsafe { audiounit_rust_release_prewarmed_voice_processing_unit(ctx) },
+            ffi::CUBEB_OK
+        );
+        assert_eq!(
+            unsafe { audiounit_rust_get_voice_processing_prewarm_state(ctx, &mut state) },
+            ffi::CUBEB_OK
+        );
+        assert_eq!(state, AUDIOUNIT_RUST_VOICE_PROCESSING_PREWARM_STATE_IDLE);
+    });
+}
@@ -30,6 +30,25 @@ fn test_get_device_uid_by_unknwon_device() {
     );
 }
 
+// get_device_by_uid
+// ------------------------------------
+#[test]
+fn test_get_device_by_uid() {
+    if let Some(output) = test_get_default_device(Scope::Output) {
+        let uid = run_serially(|| get_device_uid(output, DeviceType::OUTPUT))
+            .unwrap()
+            .into_string();
+        assert_eq!(run_serially(|| get_device_by_uid(&uid)), Some(output));
+    } else {
+        println!("No output device.");
+    }
+
+    assert_eq!(
+        run_serially(|| get_device_by_uid("org.mozilla.NoSuchDevice")),
+        None
+    );
+}
+
 // get_device_model_uid
 // ------------------------------------
 // Some devices (e.g., AirPods) fail to get model uid.
@@ -147,6 +166,39 @@ fn test_get_device_source_by_unknown_device() {
     .is_err());
 }
 
+// get_device_sources
+// ------------------------------------
+#[test]
+fn test_get_device_sources() {
+    if let Some(device) = test_get_default_device(Scope::Input) {
+        match run_serially(|| get_device_sources(device, DeviceType::INPUT)) {
+            Ok(sources) => println!("input sources: {:?}", sources),
+            Err(e) => println!("No input sources. Error: {}", e),
+        }
+    } else {
+        println!("No input device.");
+    }
+
+    if let Some(device) = test_get_default_device(Scope::Output) {
+        match run_serially(|| get_device_sources(device, DeviceType::OUTPUT)) {
+            Ok(sources) => println!("output sources: {:?}", sources),
+            Err(e) => println!("No output sources. Error: {}", e),
+        }
+    } else {
+        println!("No output device.");
+    }
+}
+
+#[test]
+#[should_panic]
+fn test_get_device_sources_by_unknown_device() {
+    assert!(run_serially_forward_panics(|| get_device_sources(
+        kAudioObjectUnknown,
+        DeviceType::INPUT
+    ))
+    .is_err());
+}
+
 // get_device_source_name
 // ------------------------------------
 #[test]
@@ -315,6 +367,124 @@ fn test_get_device_latency_by_unknown_device() {
     .is_err());
 }
 
+// get_device_safety_offset
+// ------------------------------------
+#[test]
+fn test_get_device_safety_offset() {
+    if let Some(device) = test_get_default_device(Scope::Input) {
+        let offset = run_serially(|| get_device_safety_offset(device, DeviceType::INPUT)).unwrap();
+        println!("safety offset of input device: {}", offset);
+    } else {
+        println!("No input device.");
+    }
+
+    if let Some(device) = test_get_default_device(Scope::Output) {
+        let offset = run_serially(|| get_device_safety_offset(device, DeviceType::OUTPUT)).unwrap();
+        println!("safety offset of output device: {}", offset);
+    } else {
+        println!("No output device.");
+    }
+}
+
+#[test]
+#[should_panic]
+fn test_get_device_safety_offset_by_unknown_device() {
+    assert!(run_serially_forward_panics(|| get_device_safety_offset(
+        kAudioObjectUnknown,
+        DeviceType::INPUT
+    ))
+    .is_err());
+}
+
+// get_device_is_alive
+// ------------------------------------
+#[test]
+fn test_get_device_is_alive() {
+    if let Some(device) = test_get_default_device(Scope::Input) {
+        let r = run_serially(|| get_device_is_alive(device, DeviceType::INPUT));
+        println!("get_device_is_alive for input device: {:?}", r);
+        assert_eq!(r, Ok(true));
+    } else {
+        println!("No input device.");
+    }
+
+    if let Some(device) = test_get_default_device(Scope::Output) {
+        let r = run_serially(|| get_device_is_alive(device, DeviceType::OUTPUT));
+        println!("get_device_is_alive for output device: {:?}", r);
+        assert_eq!(r, Ok(true));
+    } else {
+        println!("No output device.");
+    }
+}
+
+#[test]
+#[should_panic]
+fn test_get_device_is_alive_by_unknown_device() {
+    assert!(run_serially_forward_panics(|| get_device_is_alive(
+        kAudioObjectUnknown,
+        DeviceType::INPUT
+    ))
+    .is_err());
+}
+
+// get_device_is_hidden
+// ------------------------------------
+#[test]
+fn test_get_device_is_hidden() {
+    if let Some(device) = test_get_default_device(Scope::Input) {
+        let r = run_serially(|| get_device_is_hidden(device, DeviceType::INPUT));
+        println!("get_device_is_hidden for input device: {:?}", r);
+    } else {
+        println!("No input device.");
+    }
+
+    if let Some(device) = test_get_default_device(Scope::Output) {
+        let r = run_serially(|| get_device_is_hidden(device, DeviceType::OUTPUT));
+        println!("get_device_is_hidden for output device: {:?}", r);
+    } else {
+        println!("No output device.");
+    }
+}
+
+#[test]
+#[should_panic]
+fn test_get_device_is_hidden_by_unknown_device() {
+    assert!(run_serially_forward_panics(|| get_device_is_hidden(
+        kAudioObjectUnknown,
+        DeviceType::INPUT
+    ))
+    .is_err());
+}
+
+// get_device_jack_is_connected
+// ------------------------------------
+#[test]
+fn test_get_device_jack_is_connected() {
+    if let Some(device) = test_get_default_device(Scope::Input) {
+        let r = run_serially(|| get_device_jack_is_connected(device, DeviceType::INPUT));
+        println!("get_device_jack_is_connected for input device: {:?}", r);
+    } else {
+        println!("No input device.");
+    }
+
+    if let Some(device) = test_get_default_device(Scope::Output) {
+        let r = run_serially(|| get_device_jack_is_connected(device, DeviceType::OUTPUT));
+        println!("get_device_jack_is_connected for output device: {:?}", r);
+    } else {
+        println!("No output device.");
+    }
+}
+
+#[test]
+#[should_panic]
+fn test_get_device_jack_is_connected_by_unknown_device() {
+    assert!(run_serially_forward_panics(|| get_device_jack_is_connected(
+        kAudioObjectUnknown,
+        DeviceType::INPUT
+    ))
+    .is_err());
+}
+
 // get_device_streams
 // ------------------------------------
 #[test]
@@ -88,6 +88,10 @@ fn test_ops_context_supported_input_processing_params() {
                 OPS.get_supported_input_processing_params.unwrap()(context_ptr, &mut params)
             };
             assert_eq!(r, ffi::CUBEB_OK);
+            if test_get_default_device(Scope::Input).is_none() {
+                assert_eq!(params, ffi::CUBEB_INPUT_PROCESSING_PARAM_NONE);
+                return;
+            }
             assert_eq!(
                 params,
                 ffi::CUBEB_INPUT_PROCESSING_PARAM_ECHO_CANCELLATION
@@ -1763,6 +1767,166 @@ fn test_ops_duplex_voice_stream_set_input_mute_after_start() {
     });
 }
 
+#[test]
+fn test_ops_duplex_voice_stream_set_vpio_ducking() {
+    if macos_kernel_major_version().unwrap() == MACOS_KERNEL_MAJOR_VERSION_MONTEREY {
+        // We disable VPIO on Monterey.
+        return;
+    }
+    // The ducking configuration of the VPIO unit of the stream, or None if the OS doesn't have
+    // it, in which case AudioDeviceDuck is used instead.
+    fn vpio_ducking_configuration(
+        stm: &AudioUnitStream,
+    ) -> Option<VpioOtherAudioDuckingConfiguration> {
+        let queue = stm.queue.clone();
+        queue
+            .run_sync(|| {
+                let mut config = VpioOtherAudioDuckingConfiguration::default();
+                let r = audio_unit_get_property(
+                    stm.core_stream_data.input_unit,
+                    VPIO_PROPERTY_OTHER_AUDIO_DUCKING_CONFIGURATION,
+                    kAudioUnitScope_Global,
+                    AU_OUT_BUS,
+                    &mut config,
+                    &mut mem::size_of::<VpioOtherAudioDuckingConfiguration>(),
+                );
+                if r == NO_ERR {
+                    Some(config)
+                } else {
+                    None
+                }
+            })
+            .unwrap()
+    }
+
+    test_default_duplex_voice_stream_operation("duplex voice stream: ducking", |stream| {
+        let stm = unsafe { &mut *(stream as *mut AudioUnitStream) };
+        assert!(stm.core_stream_data.using_voice_processing_unit());
+        assert_eq!(stm.vpio_ducking(), VpioDucking::default());
+
+        let ducking = VpioDucking {
+            level: DuckingLevel::Mid,
+            ramp_duration: Duration::from_millis(100),
+        };
+        assert!(stm.set_vpio_ducking(ducking).is_ok());
+        assert_eq!(unsafe { OPS.stream_start.unwrap()(stream) }, ffi::CUBEB_OK);
+        let config = vpio_ducking_configuration(stm);
+        if let Some(config) = config {
+            assert_eq!(config.ducking_level, VPIO_OTHER_AUDIO_DUCKING_LEVEL_MID);
+        }
+
+        // The ducking is applied again to the new VPIO unit. Wait for the reinit on the queue
+        // before checking the unit.
+        stm.reinit_async();
+        let queue = stm.queue.clone();
+        queue.run_sync(|| {});
+        assert!(stm.core_stream_data.using_voice_processing_unit());
+        assert_eq!(stm.vpio_ducking(), ducking);
+        if config.is_some() {
+            assert_eq!(
+                vpio_ducking_configuration(stm).map(|c| c.ducking_level),
+                Some(VPIO_OTHER_AUDIO_DUCKING_LEVEL_MID)
+            );
+        } else {
+            println!("No VPIO ducking configuration, AudioDeviceDuck is used instead.");
+        }
+
+        assert!(stm.set_vpio_ducking(VpioDucking::default()).is_ok());
+    });
+}
+
+#[test]
+fn test_ops_duplex_voice_stream_register_voice_activity_callback() {
+    if macos_kernel_major_version().unwrap() == MACOS_KERNEL_MAJOR_VERSION_MONTEREY {
+        // We disable VPIO on Monterey.
+        return;
+    }
+
+    unsafe extern "C" fn voice_activity_callback(
+        _user_ptr: *mut c_void,
+        _event: VoiceActivityEvent,
+    ) {
+    }
+
+    fn vpio_input_mute(stm: &AudioUnitStream) -> bool {
+        let queue = stm.queue.clone();
+        let mut mute: u32 = 0;
+        queue.run_sync(|| {
+            let r = audio_unit_get_property(
+                stm.core_stream_data.input_unit,
+                kAUVoiceIOProperty_MuteOutput,
+                kAudioUnitScope_Global,
+                AU_IN_BUS,
+                &mut mute,
+                &mut mem::size_of::<u32>(),
+            );
+            assert_eq!(r, NO_ERR);
+        });
+        mute == 1
+    }
+
+    test_default_duplex_voice_stream_operation("duplex voice stream: voice activity", |stream| {
+        let stm = unsafe { &mut *(stream as *mut AudioUnitStream) };
+        assert!(stm.core_stream_data.using_voice_processing_unit());
+        assert!(stm
+            .register_voice_activity_callback(Some(voice_activity_callback))
+            .is_ok());
+        assert_eq!(
+            unsafe { OPS.stream_set_input_mute.unwrap()(stream, 1) },
+            ffi::CUBEB_OK
+        );
+        assert_eq!(unsafe { OPS.stream_start.unwrap()(stream) }, ffi::CUBEB_OK);
+
+        // The input is muted by VoiceProcessingIO if it reports voice activity, and in the input
+        // callback otherwise.
+        let software_mute = stm.software_input_mute.load(Ordering::SeqCst);
+        assert_ne!(vpio_input_mute(stm), software_mute);
+
+        // Without a callback, VoiceProcessingIO mutes the input again.
+        assert!(stm.register_voice_activity_callback(None).is_ok());
+        assert!(vpio_input_mute(stm));
+        assert!(!stm.software_input_mute.load(Ordering::SeqCst));
+    });
+}
+
+#[test]
+fn test_ops_duplex_stream_set_input_processing_params_in_software() {
+    test_default_duplex_stream_operation("duplex stream: software processing", |stream| {
+        let stm = unsafe { &mut *(stream as *mut AudioUnitStream) };
+        if stm.core_stream_data.using_voice_processing_unit() {
+            // VoiceProcessingIO is forced for some input devices.
+            return;
+        }
+        assert!(stm.supported_input_processing_params().contains(
+            InputProcessingParams::NOISE_SUPPRESSION
+                | InputProcessingParams::AUTOMATIC_GAIN_CONTROL
+        ));
+
+        // Noise suppression doesn't need echo cancellation in software.
+        let params: ffi::cubeb_input_processing_params =
+            ffi::CUBEB_INPUT_PROCESSING_PARAM_NOISE_SUPPRESSION
+                | ffi::CUBEB_INPUT_PROCESSING_PARAM_AUTOMATIC_GAIN_CONTROL;
+        assert_eq!(
+            unsafe { OPS.stream_set_input_processing_params.unwrap()(stream, params) },
+            ffi::CUBEB_OK
+        );
+        assert_eq!(
+            stm.software_input_processing_params.load(Ordering::SeqCst),
+            InputProcessingParams::from_bits_truncate(params)
+        );
+        assert_eq!(unsafe { OPS.stream_start.unwrap()(stream) }, ffi::CUBEB_OK);
+
+        // Kept across reinits.
+        stm.reinit_async();
+        let queue = stm.queue.clone();
+        queue.run_sync(|| {});
+        assert_eq!(
+            stm.software_input_processing_params.load(Ordering::SeqCst),
+            InputProcessingParams::from_bits_truncate(params)
+        );
+    });
+}
+
 #[test]
 fn test_ops_duplex_voice_stream_set_input_processing_params() {
     if macos_kernel_major_version().unwrap() == MACOS_KERNEL_MAJOR_VERSION_MONTEREY {
@@ -1783,6 +1947,145 @@ fn test_ops_duplex_voice_stream_set_input_processing_params() {
     });
 }
 
+#[test]
+fn test_ops_duplex_voice_stream_set_split_input_processing_params() {
+    if macos_kernel_major_version().unwrap() == MACOS_KERNEL_MAJOR_VERSION_MONTEREY {
+        // We disable VPIO on Monterey.
+        return;
+    }
+    test_default_duplex_voice_stream_operation("duplex voice stream: split processing", |stream| {
+        let stm = unsafe { &mut *(stream as *mut AudioUnitStream) };
+        assert!(stm.core_stream_data.using_voice_processing_unit());
+        // The software echo canceller needs the output at the rate of the input.
+        let software_aec = stm.core_stream_data.echo_reference.is_some();
+        // All the combinations, some of them in software. Echo cancellation without noise
+        // suppression is only done in software.
+        let combinations = stm.supported_input_processing_param_combinations();
+        assert_eq!(combinations.len(), if software_aec { 8 } else { 6 });
+        assert_eq!(
+            combinations.contains(&InputProcessingParams::ECHO_CANCELLATION),
+            software_aec
+        );
+
+        // VPIO can't cancel echo without suppressing noise, so the software echo canceller does
+        // it instead.
+        assert_eq!(
+            unsafe {
+                OPS.stream_set_input_processing_params.unwrap()(
+                    stream,
+                    ffi::CUBEB_INPUT_PROCESSING_PARAM_ECHO_CANCELLATION,
+                )
+            },
+            if software_aec {
+                ffi::CUBEB_OK
+            } else {
+                ffi::CUBEB_ERROR
+            }
+        );
+
+        assert_eq!(
+            unsafe {
+                OPS.stream_set_input_processing_params.unwrap()(
+                    stream,
+                    ffi::CUBEB_INPUT_PROCESSING_PARAM_NOISE_SUPPRESSION,
+                )
+            },
+            ffi::CUBEB_OK
+        );
+        assert_eq!(unsafe { OPS.stream_start.unwrap()(stream) }, ffi::CUBEB_OK);
+
+        // VPIO is bypassed, and the noise is suppressed in software.
+        let queue = stm.queue.clone();
+        let mut bypass: u32 = 0;
+        let r = queue
+            .run_sync(|| {
+                audio_unit_get_property(
+                    stm.core_stream_data.input_unit,
+                    kAUVoiceIOProperty_BypassVoiceProcessing,
+                    kAudioUnitScope_Global,
+                    AU_IN_BUS,
+                    &mut bypass,
+                    &mut mem::size_of::<u32>(),
+                )
+            })
+            .unwrap();
+        assert_eq!(r, NO_ERR);
+        assert_eq!(bypass, 1);
+        assert_eq!(
+            stm.software_input_processing_params.load(Ordering::SeqCst),
+            InputProcessingParams::NOISE_SUPPRESSION
+        );
+
+        if !software_aec {
+            println!("The output doesn't play at the rate of the input, no echo cancellation.");
+            return;
+        }
+        // So is the echo, with VPIO still bypassed.
+        assert_eq!(
+            unsafe {
+                OPS.stream_set_input_processing_params.unwrap()(
+                    stream,
+                    ffi::CUBEB_INPUT_PROCESSING_PARAM_ECHO_CANCELLATION,
+                )
+            },
+            ffi::CUBEB_OK
+        );
+        let r = queue
+            .run_sync(|| {
+                audio_unit_get_property(
+                    stm.core_stream_data.input_unit,
+                    kAUVoiceIOProperty_BypassVoiceProcessing,
+                    kAudioUnitScope_Global,
+                    AU_IN_BUS,
+                    &mut bypass,
+                    &mut mem::size_of::<u32>(),
+                )
+            })
+            .unwrap();
+        assert_eq!(r, NO_ERR);
+        assert_eq!(bypass, 1);
+        assert_eq!(
+            stm.software_input_processing_params.load(Ordering::SeqCst),
+            InputProcessingParams::ECHO_CANCELLATION
+        );
+    });
+}
+
+#[test]
+fn test_ops_input_voice_stream_set_split_input_processing_params() {
+    if macos_kernel_major_version().unwrap() == MACOS_KERNEL_MAJOR_VERSION_MONTEREY {
+        // We disable VPIO on Monterey.
+        return;
+    }
+    test_default_input_voice_stream_operation("input voice stream: split processing", |stream| {
+        let stm = unsafe { &mut *(stream as *mut AudioUnitStream) };
+        assert!(stm.core_stream_data.using_voice_processing_unit());
+        // VPIO can't cancel echo without suppressing noise, and there is no output to cancel it in
+        // software instead.
+        assert_eq!(
+            unsafe {
+                OPS.stream_set_input_processing_params.unwrap()(
+                    stream,
+                    ffi::CUBEB_INPUT_PROCESSING_PARAM_ECHO_CANCELLATION,
+                )
+            },
+            ffi::CUBEB_ERROR
+        );
+        assert_eq!(
+            unsafe {
+                OPS.stream_set_input_processing_params.unwrap()(
+                    stream,
+                    ffi::CUBEB_INPUT_PROCESSING_PARAM_NOISE_SUPPRESSION,
+                )
+            },
+            ffi::CUBEB_OK
+        );
+        assert!(!stm
+            .supported_input_processing_param_combinations()
+            .contains(&InputProcessingParams::ECHO_CANCELLATION));
+    });
+}
+
 #[test]
 fn test_ops_duplex_voice_stream_set_input_processing_params_before_start() {
     if macos_kernel_major_version().unwrap() == MACOS_KERNEL_MAJOR_VERSION_MONTEREY {
@@ -1323,7 +1323,9 @@ fn test_get_raw_stream<F>(
 
     // Add a stream to the context since we are about to create one.
     // AudioUnitStream::drop() will check the context has at least one stream.
-    let global_latency_frames = context.update_latency_by_adding_stream(latency_frames);
+    let global_latency_frames = context
+        .update_latency_by_adding_stream(&[], latency_frames)
+        .unwrap();
 
     let mut stream = AudioUnitStream::new(
         &mut context,
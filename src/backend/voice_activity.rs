@@ -0,0 +1,178 @@
+// Detection of the user talking into a muted mic, from the energy of the input, for when
+// VoiceProcessingIO can't report it.
+//
+// The input is cut in blocks of 10ms. A block is loud when its power is above a threshold well
+// above the noise floor of a mic in a quiet room. Speech starts after a few loud blocks in a row, so
+// clicks and bumps don't count, and ends after half a second without any, so the pauses between
+// words don't end it.
+
+const BLOCK_DURATION_MS: u32 = 10;
+const THRESHOLD_DBFS: f32 = -45.0;
+const ONSET_BLOCKS: u32 = 5;
+const HANGOVER_BLOCKS: u32 = 50;
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VoiceActivityEvent {
+    SpeechStarted,
+    SpeechEnded,
+}
+
+#[derive(Debug)]
+pub struct VoiceActivityDetector {
+    block_samples: usize,
+    block_len: usize,
+    block_power: f32,
+    // Mean square of the samples of a loud block.
+    threshold: f32,
+    speaking: bool,
+    loud_blocks: u32,
+    quiet_blocks: u32,
+    event: Option<VoiceActivityEvent>,
+}
+
+impl VoiceActivityDetector {
+    pub fn new(rate: u32, channels: usize) -> Self {
+        assert!(channels > 0);
+        let block_frames = (rate * BLOCK_DURATION_MS / 1000).max(1) as usize;
+        Self {
+            block_samples: block_frames * channels,
+            block_len: 0,
+            block_power: 0.0,
+            threshold: 10.0_f32.powf(THRESHOLD_DBFS / 10.0),
+            speaking: false,
+            loud_blocks: 0,
+            quiet_blocks: 0,
+            event: None,
+        }
+    }
+
+    // `samples` are interleaved, in [-1.0, 1.0].
+    pub fn process<I: IntoIterator<Item = f32>>(&mut self, samples: I) {
+        for sample in samples {
+            self.block_power += sample * sample;
+            self.block_len += 1;
+            if self.block_len == self.block_samples {
+                let loud = self.block_power / self.block_samples as f32 > self.threshold;
+                self.end_block(loud);
+                self.block_len = 0;
+                self.block_power = 0.0;
+            }
+        }
+    }
+
+    fn end_block(&mut self, loud: bool) {
+        if loud {
+            self.loud_blocks += 1;
+            self.quiet_blocks = 0;
+        } else {
+            self.quiet_blocks += 1;
+            // A quiet block interrupts the onset, but not the speech.
+            if !self.speaking {
+                self.loud_blocks = 0;
+            }
+        }
+        if !self.speaking && self.loud_blocks >= ONSET_BLOCKS {
+            self.set_speaking(true);
+        } else if self.speaking && self.quiet_blocks >= HANGOVER_BLOCKS {
+            self.set_speaking(false);
+        }
+    }
+
+    fn set_speaking(&mut self, speaking: bool) {
+        self.speaking = speaking;
+        self.loud_blocks = 0;
+        self.quiet_blocks = 0;
+        // An event that wasn't taken yet is cancelled by the opposite one.
+        self.event = match self.event.take() {
+            Some(_) => None,
+            None if speaking => Some(VoiceActivityEvent::SpeechStarted),
+            None => Some(VoiceActivityEvent::SpeechEnded),
+        };
+    }
+
+    // The speech started or ended since the last call, if it did.
+    pub fn take_event(&mut self) -> Option<VoiceActivityEvent> {
+        self.event.take()
+    }
+
+    // Start over, e.g. when the input gets unmuted. Ends the current speech, if any.
+    pub fn reset(&mut self) {
+        if self.speaking {
+            self.set_speaking(false);
+        }
+        self.loud_blocks = 0;
+        self.quiet_blocks = 0;
+        self.block_len = 0;
+        self.block_power = 0.0;
+    }
+}
+
+#[cfg(test)]
+fn test_feed(
+    detector: &mut VoiceActivityDetector,
+    channels: u32,
+    amplitude: f32,
+    duration_ms: u32,
+) {
+    // A square wave at 48kHz.
+    let samples = (48 * duration_ms * channels) as usize;
+    detector.process((0..samples).map(|i| if i % 4 < 2 { amplitude } else { -amplitude }));
+}
+
+#[test]
+fn test_voice_activity_detector() {
+    let mut detector = VoiceActivityDetector::new(48000, 2);
+    test_feed(&mut detector, 2, 0.0, 1000);
+    assert_eq!(detector.take_event(), None);
+
+    // The noise floor of a quiet room.
+    test_feed(&mut detector, 2, 0.001, 1000);
+    assert_eq!(detector.take_event(), None);
+
+    // Talking.
+    test_feed(&mut detector, 2, 0.1, 100);
+    assert_eq!(
+        detector.take_event(),
+        Some(VoiceActivityEvent::SpeechStarted)
+    );
+    assert_eq!(detector.take_event(), None);
+
+    // A pause between words.
+    test_feed(&mut detector, 2, 0.0, 200);
+    test_feed(&mut detector, 2, 0.1, 100);
+    assert_eq!(detector.take_event(), None);
+
+    // Done talking.
+    test_feed(&mut detector, 2, 0.0, 600);
+    assert_eq!(detector.take_event(), Some(VoiceActivityEvent::SpeechEnded));
+}
+
+#[test]
+fn test_voice_activity_detector_ignores_clicks() {
+    let mut detector = VoiceActivityDetector::new(48000, 1);
+    for _ in 0..10 {
+        test_feed(&mut detector, 1, 0.5, 20);
+        test_feed(&mut detector, 1, 0.0, 100);
+    }
+    assert_eq!(detector.take_event(), None);
+}
+
+#[test]
+fn test_voice_activity_detector_reset() {
+    let mut detector = VoiceActivityDetector::new(48000, 1);
+    test_feed(&mut detector, 1, 0.1, 100);
+    assert_eq!(
+        detector.take_event(),
+        Some(VoiceActivityEvent::SpeechStarted)
+    );
+    detector.reset();
+    assert_eq!(detector.take_event(), Some(VoiceActivityEvent::SpeechEnded));
+    detector.reset();
+    assert_eq!(detector.take_event(), None);
+
+    // A start and an end that weren't taken cancel out.
+    test_feed(&mut detector, 1, 0.1, 100);
+    detector.reset();
+    assert_eq!(detector.take_event(), None);
+}
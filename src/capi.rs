@@ -3,9 +3,306 @@
 // This program is made available under an ISC-style license.  See the
 // accompanying file LICENSE for details.
 
-use crate::backend::AudioUnitContext;
-use cubeb_backend::{capi, ffi};
+use crate::backend::{
+    release_device_info, AggregateClockInfo, AggregateDevicePolicy, AudioUnitContext,
+    AudioUnitStream, BackendSettings, DataSource, DeviceCollectionChanges, DitherMode,
+    DuckingLevel, DuplexDeviceInfo, LimiterMode, MixingMatrix, QuirkRule, QuirkScope, Quirks,
+    VoiceActivityCallback, VoiceActivityEvent, VoiceProcessingPrewarmState, VolumeRamp,
+    VpioDucking,
+};
+use cubeb_backend::{capi, ffi, DeviceType, StreamParams};
+use std::ffi::{CStr, CString};
+use std::mem;
 use std::os::raw::{c_char, c_int};
+use std::ptr;
+use std::slice;
+use std::time::Duration;
+
+// Helper macro for unwrapping `Result` values from the backend calls, returning early with the
+// C error code if the value of the expression is `Err`.
+macro_rules! _try(
+    ($e:expr) => (match $e {
+        Ok(e) => e,
+        Err(e) => return e.raw_code()
+    })
+);
+
+// The values of the enums passed to the backend-specific functions below.
+pub const AUDIOUNIT_RUST_DITHER_MODE_DISABLED: c_int = 0;
+pub const AUDIOUNIT_RUST_DITHER_MODE_TPDF: c_int = 1;
+pub const AUDIOUNIT_RUST_DITHER_MODE_TPDF_NOISE_SHAPED: c_int = 2;
+pub const AUDIOUNIT_RUST_LIMITER_MODE_DISABLED: c_int = 0;
+pub const AUDIOUNIT_RUST_LIMITER_MODE_LIMITER: c_int = 1;
+pub const AUDIOUNIT_RUST_LIMITER_MODE_SOFT_CLIP: c_int = 2;
+pub const AUDIOUNIT_RUST_VOLUME_RAMP_LINEAR: c_int = 0;
+pub const AUDIOUNIT_RUST_VOLUME_RAMP_EXPONENTIAL: c_int = 1;
+pub const AUDIOUNIT_RUST_AGGREGATE_DEVICE_POLICY_NEVER: c_int = 0;
+pub const AUDIOUNIT_RUST_AGGREGATE_DEVICE_POLICY_WHEN_CLOCK_DOMAINS_DIFFER: c_int = 1;
+pub const AUDIOUNIT_RUST_AGGREGATE_DEVICE_POLICY_INPUT_ONLY_PLUS_OUTPUT_ONLY: c_int = 2;
+pub const AUDIOUNIT_RUST_AGGREGATE_DEVICE_POLICY_ALWAYS: c_int = 3;
+pub const AUDIOUNIT_RUST_QUIRK_SCOPE_INPUT: c_int = 0;
+pub const AUDIOUNIT_RUST_QUIRK_SCOPE_OUTPUT: c_int = 1;
+pub const AUDIOUNIT_RUST_QUIRK_SCOPE_ANY: c_int = 2;
+pub const AUDIOUNIT_RUST_QUIRK_FORCE_VPIO: u32 = Quirks::FORCE_VPIO.bits();
+pub const AUDIOUNIT_RUST_QUIRK_BLOCK_VPIO: u32 = Quirks::BLOCK_VPIO.bits();
+pub const AUDIOUNIT_RUST_QUIRK_BLOCK_VPIO_FOR_PAIR: u32 = Quirks::BLOCK_VPIO_FOR_PAIR.bits();
+pub const AUDIOUNIT_RUST_QUIRK_AGGREGATE_INPUT_RATE_FOR_PAIR: u32 =
+    Quirks::AGGREGATE_INPUT_RATE_FOR_PAIR.bits();
+pub const AUDIOUNIT_RUST_QUIRK_SINGLE_CHANNEL_OUTPUT_IS_MONO: u32 =
+    Quirks::SINGLE_CHANNEL_OUTPUT_IS_MONO.bits();
+pub const AUDIOUNIT_RUST_QUIRK_UNDEFINED_STEREO_OUTPUT_IS_STEREO: u32 =
+    Quirks::UNDEFINED_STEREO_OUTPUT_IS_STEREO.bits();
+pub const AUDIOUNIT_RUST_DUCKING_LEVEL_NONE: c_int = 0;
+pub const AUDIOUNIT_RUST_DUCKING_LEVEL_MIN: c_int = 1;
+pub const AUDIOUNIT_RUST_DUCKING_LEVEL_MID: c_int = 2;
+pub const AUDIOUNIT_RUST_DUCKING_LEVEL_MAX: c_int = 3;
+// The events passed to the voice activity callback.
+pub const AUDIOUNIT_RUST_VOICE_ACTIVITY_SPEECH_STARTED: c_int =
+    VoiceActivityEvent::SpeechStarted as c_int;
+pub const AUDIOUNIT_RUST_VOICE_ACTIVITY_SPEECH_ENDED: c_int =
+    VoiceActivityEvent::SpeechEnded as c_int;
+pub const AUDIOUNIT_RUST_VOICE_PROCESSING_PREWARM_STATE_IDLE: c_int = 0;
+pub const AUDIOUNIT_RUST_VOICE_PROCESSING_PREWARM_STATE_PENDING: c_int = 1;
+pub const AUDIOUNIT_RUST_VOICE_PROCESSING_PREWARM_STATE_READY: c_int = 2;
+pub const AUDIOUNIT_RUST_VOICE_PROCESSING_PREWARM_STATE_FAILED: c_int = 3;
+// The `vpio_idle_timeout_ms` keeping the unused VoiceProcessingIO units until the context is
+// destroyed.
+pub const AUDIOUNIT_RUST_VPIO_IDLE_TIMEOUT_NEVER: u32 = u32::MAX;
+
+// The tunables of a context, see `BackendSettings`. Filled with the defaults by
+// `audiounit_rust_get_default_settings`.
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Debug)]
+pub struct audiounit_rust_settings {
+    pub min_latency_frames: u32,
+    pub max_latency_frames: u32,
+    // Or AUDIOUNIT_RUST_VPIO_IDLE_TIMEOUT_NEVER.
+    pub vpio_idle_timeout_ms: u32,
+    pub input_buffer_callbacks: u32,
+    pub destroy_stale_aggregate_devices: bool,
+    // One of the AUDIOUNIT_RUST_AGGREGATE_DEVICE_POLICY_* values.
+    pub aggregate_device_policy: c_int,
+    // Null to pick the master device from the device facts.
+    pub aggregate_master_device_uid: *const c_char,
+}
+
+unsafe fn settings_from_raw(raw: &audiounit_rust_settings) -> Option<BackendSettings> {
+    let aggregate_device_policy = match raw.aggregate_device_policy {
+        AUDIOUNIT_RUST_AGGREGATE_DEVICE_POLICY_NEVER => AggregateDevicePolicy::Never,
+        AUDIOUNIT_RUST_AGGREGATE_DEVICE_POLICY_WHEN_CLOCK_DOMAINS_DIFFER => {
+            AggregateDevicePolicy::WhenClockDomainsDiffer
+        }
+        AUDIOUNIT_RUST_AGGREGATE_DEVICE_POLICY_INPUT_ONLY_PLUS_OUTPUT_ONLY => {
+            AggregateDevicePolicy::InputOnlyPlusOutputOnly
+        }
+        AUDIOUNIT_RUST_AGGREGATE_DEVICE_POLICY_ALWAYS => AggregateDevicePolicy::Always,
+        _ => return None,
+    };
+    let aggregate_master_device_uid = if raw.aggregate_master_device_uid.is_null() {
+        None
+    } else {
+        Some(
+            CStr::from_ptr(raw.aggregate_master_device_uid)
+                .to_string_lossy()
+                .into_owned(),
+        )
+    };
+    Some(BackendSettings {
+        min_latency_frames: raw.min_latency_frames,
+        max_latency_frames: raw.max_latency_frames,
+        vpio_idle_timeout: match raw.vpio_idle_timeout_ms {
+            AUDIOUNIT_RUST_VPIO_IDLE_TIMEOUT_NEVER => None,
+            ms => Some(Duration::from_millis(u64::from(ms))),
+        },
+        input_buffer_callbacks: raw.input_buffer_callbacks,
+        destroy_stale_aggregate_devices: raw.destroy_stale_aggregate_devices,
+        aggregate_device_policy,
+        aggregate_master_device_uid,
+    })
+}
+
+// A rule of the device quirks table, see `QuirkRule`. The null strings, and the zero transport
+// type and kernel versions, match any device.
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Debug)]
+pub struct audiounit_rust_quirk_rule {
+    // One of the AUDIOUNIT_RUST_QUIRK_SCOPE_* values.
+    pub scope: c_int,
+    pub model_uid: *const c_char,
+    pub label: *const c_char,
+    pub manufacturer: *const c_char,
+    pub transport_type: u32,
+    pub min_kernel_major_version: u32,
+    pub max_kernel_major_version: u32,
+    // AUDIOUNIT_RUST_QUIRK_* flags.
+    pub set: u32,
+    pub clear: u32,
+}
+
+unsafe fn quirk_rule_from_raw(raw: &audiounit_rust_quirk_rule) -> Option<QuirkRule> {
+    let scope = match raw.scope {
+        AUDIOUNIT_RUST_QUIRK_SCOPE_INPUT => QuirkScope::Input,
+        AUDIOUNIT_RUST_QUIRK_SCOPE_OUTPUT => QuirkScope::Output,
+        AUDIOUNIT_RUST_QUIRK_SCOPE_ANY => QuirkScope::Any,
+        _ => return None,
+    };
+    let string = |p: *const c_char| {
+        if p.is_null() {
+            None
+        } else {
+            Some(CStr::from_ptr(p).to_string_lossy().into_owned())
+        }
+    };
+    let nonzero = |v: u32| if v == 0 { None } else { Some(v) };
+    Some(QuirkRule {
+        scope,
+        model_uid: string(raw.model_uid),
+        label: string(raw.label),
+        manufacturer: string(raw.manufacturer),
+        transport_type: nonzero(raw.transport_type),
+        min_kernel_major_version: nonzero(raw.min_kernel_major_version),
+        max_kernel_major_version: nonzero(raw.max_kernel_major_version),
+        set: Quirks::from_bits(raw.set)?,
+        clear: Quirks::from_bits(raw.clear)?,
+    })
+}
+
+// The devices that triggered a device-collection-changed callback. Filled by
+// `audiounit_rust_get_device_collection_changes`, and released by
+// `audiounit_rust_device_collection_changes_destroy`.
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Debug)]
+pub struct audiounit_rust_device_collection_changes {
+    pub added: *mut ffi::cubeb_devid,
+    pub added_count: usize,
+    pub removed: *mut ffi::cubeb_devid,
+    pub removed_count: usize,
+    pub changed: *mut ffi::cubeb_devid,
+    pub changed_count: usize,
+}
+
+// A device with its input and output directions. The `devid` of a direction is null if the device
+// doesn't have it.
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Debug)]
+pub struct audiounit_rust_duplex_device_info {
+    pub input: ffi::cubeb_device_info,
+    pub output: ffi::cubeb_device_info,
+}
+
+// Filled by `audiounit_rust_enumerate_duplex_devices`, and released by
+// `audiounit_rust_duplex_device_collection_destroy`.
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Debug)]
+pub struct audiounit_rust_duplex_device_collection {
+    pub device: *mut audiounit_rust_duplex_device_info,
+    pub count: usize,
+}
+
+// A data source of a device. Released by `audiounit_rust_data_source_destroy`, unless it is part of
+// a collection.
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Debug)]
+pub struct audiounit_rust_data_source {
+    pub id: u32,
+    pub name: *mut c_char,
+}
+
+impl From<DataSource> for audiounit_rust_data_source {
+    fn from(source: DataSource) -> Self {
+        Self {
+            id: source.id,
+            // The names come from C strings, so they have no interior nul.
+            name: CString::new(source.name).unwrap_or_default().into_raw(),
+        }
+    }
+}
+
+// Filled by `audiounit_rust_get_device_data_sources`, and released by
+// `audiounit_rust_data_source_collection_destroy`.
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Debug)]
+pub struct audiounit_rust_data_source_collection {
+    pub source: *mut audiounit_rust_data_source,
+    pub count: usize,
+}
+
+// A sub-device of an aggregate device, other than the one driving the clock.
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Debug)]
+pub struct audiounit_rust_aggregate_sub_device {
+    pub uid: *mut c_char,
+    pub drift_compensation: bool,
+}
+
+// Filled by `audiounit_rust_stream_get_aggregate_clock_info`, and released by
+// `audiounit_rust_aggregate_clock_info_destroy`. `master_uid` is null when the stream doesn't use
+// an aggregate device.
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Debug)]
+pub struct audiounit_rust_aggregate_clock_info {
+    pub master_uid: *mut c_char,
+    pub sub_device: *mut audiounit_rust_aggregate_sub_device,
+    pub count: usize,
+}
+
+impl From<AggregateClockInfo> for audiounit_rust_aggregate_clock_info {
+    fn from(info: AggregateClockInfo) -> Self {
+        // The UIDs come from CFStrings, so they have no interior nul.
+        let sub_devices = info
+            .drift_compensation
+            .into_iter()
+            .map(
+                |(uid, drift_compensation)| audiounit_rust_aggregate_sub_device {
+                    uid: CString::new(uid).unwrap_or_default().into_raw(),
+                    drift_compensation,
+                },
+            )
+            .collect::<Vec<_>>();
+        let (sub_device, count) = into_raw_array(sub_devices);
+        Self {
+            master_uid: CString::new(info.master_uid).unwrap_or_default().into_raw(),
+            sub_device,
+            count,
+        }
+    }
+}
+
+// Filled by `audiounit_rust_stream_get_supported_input_processing_param_combinations`, and
+// released by `audiounit_rust_input_processing_param_combinations_destroy`.
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Debug)]
+pub struct audiounit_rust_input_processing_param_combinations {
+    pub params: *mut ffi::cubeb_input_processing_params,
+    pub count: usize,
+}
+
+// Hand the elements of `v` over to C, as a pointer and a count, until `drop_raw_array` takes them
+// back.
+fn into_raw_array<T>(v: Vec<T>) -> (*mut T, usize) {
+    if v.is_empty() {
+        return (ptr::null_mut(), 0);
+    }
+    let count = v.len();
+    let ptr = Box::into_raw(v.into_boxed_slice()) as *mut T;
+    (ptr, count)
+}
+
+unsafe fn drop_raw_array<T>(ptr: *mut T, count: usize) {
+    if !ptr.is_null() {
+        drop(Box::from_raw(slice::from_raw_parts_mut(ptr, count)));
+    }
+}
 
 /// # Safety
 ///
@@ -17,3 +314,882 @@ pub unsafe extern "C" fn audiounit_rust_init(
 ) -> c_int {
     capi::capi_init::<AudioUnitContext>(c, context_name)
 }
+
+/// # Safety
+///
+/// Entry point from C code.
+///
+/// This function is unsafe because it dereferences the given `settings` pointer.
+/// The caller should ensure that pointer is valid.
+#[no_mangle]
+pub unsafe extern "C" fn audiounit_rust_get_default_settings(
+    settings: *mut audiounit_rust_settings,
+) -> c_int {
+    let defaults = BackendSettings::default();
+    *settings = audiounit_rust_settings {
+        min_latency_frames: defaults.min_latency_frames,
+        max_latency_frames: defaults.max_latency_frames,
+        vpio_idle_timeout_ms: defaults
+            .vpio_idle_timeout
+            .map_or(AUDIOUNIT_RUST_VPIO_IDLE_TIMEOUT_NEVER, |t| {
+                t.as_millis() as u32
+            }),
+        input_buffer_callbacks: defaults.input_buffer_callbacks,
+        destroy_stale_aggregate_devices: defaults.destroy_stale_aggregate_devices,
+        aggregate_device_policy: match defaults.aggregate_device_policy {
+            AggregateDevicePolicy::Never => AUDIOUNIT_RUST_AGGREGATE_DEVICE_POLICY_NEVER,
+            AggregateDevicePolicy::WhenClockDomainsDiffer => {
+                AUDIOUNIT_RUST_AGGREGATE_DEVICE_POLICY_WHEN_CLOCK_DOMAINS_DIFFER
+            }
+            AggregateDevicePolicy::InputOnlyPlusOutputOnly => {
+                AUDIOUNIT_RUST_AGGREGATE_DEVICE_POLICY_INPUT_ONLY_PLUS_OUTPUT_ONLY
+            }
+            AggregateDevicePolicy::Always => AUDIOUNIT_RUST_AGGREGATE_DEVICE_POLICY_ALWAYS,
+        },
+        aggregate_master_device_uid: ptr::null(),
+    };
+    ffi::CUBEB_OK
+}
+
+/// Like `audiounit_rust_init`, with `settings` instead of the defaults. The environment variables
+/// still override them.
+///
+/// # Safety
+///
+/// This function should only be called once per process, and dereferences the given `c`,
+/// `context_name` and `settings` pointers. The caller should ensure those pointers are valid.
+#[no_mangle]
+pub unsafe extern "C" fn audiounit_rust_init_with_settings(
+    c: *mut *mut ffi::cubeb,
+    context_name: *const c_char,
+    settings: *const audiounit_rust_settings,
+) -> c_int {
+    let settings = match settings_from_raw(&*settings) {
+        Some(settings) => settings,
+        None => return ffi::CUBEB_ERROR_INVALID_PARAMETER,
+    };
+    let context_name = if context_name.is_null() {
+        None
+    } else {
+        Some(CStr::from_ptr(context_name))
+    };
+    let context = _try!(AudioUnitContext::init_with_settings(context_name, settings));
+    *c = context.as_ptr();
+    // Leaking pointer across C FFI
+    mem::forget(context);
+    ffi::CUBEB_OK
+}
+
+/// # Safety
+///
+/// Entry point from C code.
+///
+/// This function is unsafe because it dereferences the given `s` pointer.
+/// The caller should ensure it is a valid stream of this backend.
+#[no_mangle]
+pub unsafe extern "C" fn audiounit_rust_stream_set_dither_mode(
+    s: *mut ffi::cubeb_stream,
+    mode: c_int,
+) -> c_int {
+    let stm = &*(s as *mut AudioUnitStream);
+    let mode = match mode {
+        AUDIOUNIT_RUST_DITHER_MODE_DISABLED => DitherMode::Disabled,
+        AUDIOUNIT_RUST_DITHER_MODE_TPDF => DitherMode::Tpdf,
+        AUDIOUNIT_RUST_DITHER_MODE_TPDF_NOISE_SHAPED => DitherMode::TpdfNoiseShaped,
+        _ => return ffi::CUBEB_ERROR_INVALID_PARAMETER,
+    };
+    stm.set_dither_mode(mode);
+    ffi::CUBEB_OK
+}
+
+/// # Safety
+///
+/// Entry point from C code.
+///
+/// This function is unsafe because it dereferences the given `s` and `mode` pointers.
+/// The caller should ensure those pointers are valid.
+#[no_mangle]
+pub unsafe extern "C" fn audiounit_rust_stream_get_dither_mode(
+    s: *mut ffi::cubeb_stream,
+    mode: *mut c_int,
+) -> c_int {
+    let stm = &*(s as *mut AudioUnitStream);
+    *mode = match stm.dither_mode() {
+        DitherMode::Disabled => AUDIOUNIT_RUST_DITHER_MODE_DISABLED,
+        DitherMode::Tpdf => AUDIOUNIT_RUST_DITHER_MODE_TPDF,
+        DitherMode::TpdfNoiseShaped => AUDIOUNIT_RUST_DITHER_MODE_TPDF_NOISE_SHAPED,
+    };
+    ffi::CUBEB_OK
+}
+
+/// # Safety
+///
+/// Entry point from C code.
+///
+/// This function is unsafe because it dereferences the given `s` pointer.
+/// The caller should ensure it is a valid stream of this backend.
+#[no_mangle]
+pub unsafe extern "C" fn audiounit_rust_stream_set_limiter_mode(
+    s: *mut ffi::cubeb_stream,
+    mode: c_int,
+) -> c_int {
+    let stm = &*(s as *mut AudioUnitStream);
+    let mode = match mode {
+        AUDIOUNIT_RUST_LIMITER_MODE_DISABLED => LimiterMode::Disabled,
+        AUDIOUNIT_RUST_LIMITER_MODE_LIMITER => LimiterMode::Limiter,
+        AUDIOUNIT_RUST_LIMITER_MODE_SOFT_CLIP => LimiterMode::SoftClip,
+        _ => return ffi::CUBEB_ERROR_INVALID_PARAMETER,
+    };
+    stm.set_limiter_mode(mode);
+    ffi::CUBEB_OK
+}
+
+/// # Safety
+///
+/// Entry point from C code.
+///
+/// This function is unsafe because it dereferences the given `s` and `mode` pointers.
+/// The caller should ensure those pointers are valid.
+#[no_mangle]
+pub unsafe extern "C" fn audiounit_rust_stream_get_limiter_mode(
+    s: *mut ffi::cubeb_stream,
+    mode: *mut c_int,
+) -> c_int {
+    let stm = &*(s as *mut AudioUnitStream);
+    *mode = match stm.limiter_mode() {
+        LimiterMode::Disabled => AUDIOUNIT_RUST_LIMITER_MODE_DISABLED,
+        LimiterMode::Limiter => AUDIOUNIT_RUST_LIMITER_MODE_LIMITER,
+        LimiterMode::SoftClip => AUDIOUNIT_RUST_LIMITER_MODE_SOFT_CLIP,
+    };
+    ffi::CUBEB_OK
+}
+
+/// # Safety
+///
+/// Entry point from C code.
+///
+/// This function is unsafe because it dereferences the given `s` and `reduction` pointers.
+/// The caller should ensure those pointers are valid.
+#[no_mangle]
+pub unsafe extern "C" fn audiounit_rust_stream_get_limiter_gain_reduction(
+    s: *mut ffi::cubeb_stream,
+    reduction: *mut f32,
+) -> c_int {
+    let stm = &*(s as *mut AudioUnitStream);
+    *reduction = stm.limiter_gain_reduction();
+    ffi::CUBEB_OK
+}
+
+/// # Safety
+///
+/// Entry point from C code.
+///
+/// This function is unsafe because it dereferences the given `s` pointer.
+/// The caller should ensure it is a valid stream of this backend.
+#[no_mangle]
+pub unsafe extern "C" fn audiounit_rust_stream_set_volume_ramp(
+    s: *mut ffi::cubeb_stream,
+    ramp: c_int,
+    duration_ms: u32,
+) -> c_int {
+    let stm = &*(s as *mut AudioUnitStream);
+    let ramp = match ramp {
+        AUDIOUNIT_RUST_VOLUME_RAMP_LINEAR => VolumeRamp::Linear,
+        AUDIOUNIT_RUST_VOLUME_RAMP_EXPONENTIAL => VolumeRamp::Exponential,
+        _ => return ffi::CUBEB_ERROR_INVALID_PARAMETER,
+    };
+    stm.set_volume_ramp(ramp, Duration::from_millis(u64::from(duration_ms)));
+    ffi::CUBEB_OK
+}
+
+/// # Safety
+///
+/// Entry point from C code.
+///
+/// This function is unsafe because it dereferences the given `s` and `volume` pointers.
+/// The caller should ensure those pointers are valid.
+#[no_mangle]
+pub unsafe extern "C" fn audiounit_rust_stream_get_volume(
+    s: *mut ffi::cubeb_stream,
+    volume: *mut f32,
+) -> c_int {
+    let stm = &*(s as *mut AudioUnitStream);
+    *volume = stm.volume();
+    ffi::CUBEB_OK
+}
+
+/// # Safety
+///
+/// Entry point from C code.
+///
+/// This function is unsafe because it dereferences the given `s` pointer.
+/// The caller should ensure it is a valid stream of this backend.
+#[no_mangle]
+pub unsafe extern "C" fn audiounit_rust_stream_set_fade_duration(
+    s: *mut ffi::cubeb_stream,
+    duration_ms: u32,
+) -> c_int {
+    let stm = &*(s as *mut AudioUnitStream);
+    stm.set_fade_duration(Duration::from_millis(u64::from(duration_ms)));
+    ffi::CUBEB_OK
+}
+
+/// # Safety
+///
+/// Entry point from C code.
+///
+/// This function is unsafe because it dereferences the given `s` and `coefficients` pointers.
+/// The caller should ensure `s` is valid, and `coefficients` is either null, to go back to the
+/// default mixing, or points to `output_channels` rows of `input_channels` coefficients.
+#[no_mangle]
+pub unsafe extern "C" fn audiounit_rust_stream_set_output_mixing_matrix(
+    s: *mut ffi::cubeb_stream,
+    input_channels: u32,
+    output_channels: u32,
+    coefficients: *const f32,
+) -> c_int {
+    let stm = &mut *(s as *mut AudioUnitStream);
+    let matrix = if coefficients.is_null() {
+        None
+    } else {
+        let len = input_channels as usize * output_channels as usize;
+        let coefficients = slice::from_raw_parts(coefficients, len).to_vec();
+        match MixingMatrix::new(
+            input_channels as usize,
+            output_channels as usize,
+            coefficients,
+        ) {
+            Some(matrix) => Some(matrix),
+            None => return ffi::CUBEB_ERROR_INVALID_PARAMETER,
+        }
+    };
+    _try!(stm.set_output_mixing_matrix(matrix));
+    ffi::CUBEB_OK
+}
+
+/// # Safety
+///
+/// Entry point from C code.
+///
+/// This function is unsafe because it dereferences the given `c` and `changes` pointers.
+/// The caller should ensure those pointers are valid, and release `changes` with
+/// `audiounit_rust_device_collection_changes_destroy`.
+#[no_mangle]
+pub unsafe extern "C" fn audiounit_rust_get_device_collection_changes(
+    c: *mut ffi::cubeb,
+    devtype: ffi::cubeb_device_type,
+    changes: *mut audiounit_rust_device_collection_changes,
+) -> c_int {
+    let ctx = &*(c as *mut AudioUnitContext);
+    let devtype = DeviceType::from_bits_truncate(devtype);
+    let DeviceCollectionChanges {
+        added,
+        removed,
+        changed,
+    } = _try!(ctx.device_collection_changes(devtype));
+    let changes = &mut *changes;
+    (changes.added, changes.added_count) = into_raw_array(added);
+    (changes.removed, changes.removed_count) = into_raw_array(removed);
+    (changes.changed, changes.changed_count) = into_raw_array(changed);
+    ffi::CUBEB_OK
+}
+
+/// # Safety
+///
+/// Entry point from C code.
+///
+/// This function is unsafe because it dereferences the given `changes` pointer.
+/// The caller should ensure it was filled by `audiounit_rust_get_device_collection_changes`.
+#[no_mangle]
+pub unsafe extern "C" fn audiounit_rust_device_collection_changes_destroy(
+    changes: *mut audiounit_rust_device_collection_changes,
+) -> c_int {
+    let changes = &mut *changes;
+    drop_raw_array(changes.added, changes.added_count);
+    drop_raw_array(changes.removed, changes.removed_count);
+    drop_raw_array(changes.changed, changes.changed_count);
+    (changes.added, changes.added_count) = (ptr::null_mut(), 0);
+    (changes.removed, changes.removed_count) = (ptr::null_mut(), 0);
+    (changes.changed, changes.changed_count) = (ptr::null_mut(), 0);
+    ffi::CUBEB_OK
+}
+
+/// # Safety
+///
+/// Entry point from C code.
+///
+/// This function is unsafe because it dereferences the given `c` and `collection` pointers.
+/// The caller should ensure those pointers are valid, and release `collection` with
+/// `audiounit_rust_duplex_device_collection_destroy`.
+#[no_mangle]
+pub unsafe extern "C" fn audiounit_rust_enumerate_duplex_devices(
+    c: *mut ffi::cubeb,
+    collection: *mut audiounit_rust_duplex_device_collection,
+) -> c_int {
+    let ctx = &mut *(c as *mut AudioUnitContext);
+    let devices = _try!(ctx.enumerate_duplex_devices())
+        .iter()
+        .map(
+            |DuplexDeviceInfo { input, output }| audiounit_rust_duplex_device_info {
+                input: input
+                    .as_ref()
+                    .map(|i| i.to_device_info())
+                    .unwrap_or_default(),
+                output: output
+                    .as_ref()
+                    .map(|o| o.to_device_info())
+                    .unwrap_or_default(),
+            },
+        )
+        .collect::<Vec<_>>();
+    let collection = &mut *collection;
+    (collection.device, collection.count) = into_raw_array(devices);
+    ffi::CUBEB_OK
+}
+
+/// # Safety
+///
+/// Entry point from C code.
+///
+/// This function is unsafe because it dereferences the given `collection` pointer.
+/// The caller should ensure it was filled by `audiounit_rust_enumerate_duplex_devices`.
+#[no_mangle]
+pub unsafe extern "C" fn audiounit_rust_duplex_device_collection_destroy(
+    collection: *mut audiounit_rust_duplex_device_collection,
+) -> c_int {
+    let collection = &mut *collection;
+    if !collection.device.is_null() {
+        for device in slice::from_raw_parts_mut(collection.device, collection.count) {
+            release_device_info(&mut device.input);
+            release_device_info(&mut device.output);
+        }
+    }
+    drop_raw_array(collection.device, collection.count);
+    (collection.device, collection.count) = (ptr::null_mut(), 0);
+    ffi::CUBEB_OK
+}
+
+/// # Safety
+///
+/// Entry point from C code.
+///
+/// This function is unsafe because it dereferences the given `c` and `collection` pointers.
+/// The caller should ensure those pointers are valid, and release `collection` with
+/// `audiounit_rust_data_source_collection_destroy`.
+#[no_mangle]
+pub unsafe extern "C" fn audiounit_rust_get_device_data_sources(
+    c: *mut ffi::cubeb,
+    devid: ffi::cubeb_devid,
+    devtype: ffi::cubeb_device_type,
+    collection: *mut audiounit_rust_data_source_collection,
+) -> c_int {
+    let ctx = &mut *(c as *mut AudioUnitContext);
+    let devtype = DeviceType::from_bits_truncate(devtype);
+    let sources = _try!(ctx.device_data_sources(devid, devtype))
+        .into_iter()
+        .map(audiounit_rust_data_source::from)
+        .collect::<Vec<_>>();
+    let collection = &mut *collection;
+    (collection.source, collection.count) = into_raw_array(sources);
+    ffi::CUBEB_OK
+}
+
+/// # Safety
+///
+/// Entry point from C code.
+///
+/// This function is unsafe because it dereferences the given `collection` pointer.
+/// The caller should ensure it was filled by `audiounit_rust_get_device_data_sources`.
+#[no_mangle]
+pub unsafe extern "C" fn audiounit_rust_data_source_collection_destroy(
+    collection: *mut audiounit_rust_data_source_collection,
+) -> c_int {
+    let collection = &mut *collection;
+    if !collection.source.is_null() {
+        for source in slice::from_raw_parts_mut(collection.source, collection.count) {
+            audiounit_rust_data_source_destroy(source);
+        }
+    }
+    drop_raw_array(collection.source, collection.count);
+    (collection.source, collection.count) = (ptr::null_mut(), 0);
+    ffi::CUBEB_OK
+}
+
+/// # Safety
+///
+/// Entry point from C code.
+///
+/// This function is unsafe because it dereferences the given `c` and `source` pointers.
+/// The caller should ensure those pointers are valid, and release `source` with
+/// `audiounit_rust_data_source_destroy`.
+#[no_mangle]
+pub unsafe extern "C" fn audiounit_rust_get_device_data_source(
+    c: *mut ffi::cubeb,
+    devid: ffi::cubeb_devid,
+    devtype: ffi::cubeb_device_type,
+    source: *mut audiounit_rust_data_source,
+) -> c_int {
+    let ctx = &mut *(c as *mut AudioUnitContext);
+    let devtype = DeviceType::from_bits_truncate(devtype);
+    *source = _try!(ctx.device_data_source(devid, devtype)).into();
+    ffi::CUBEB_OK
+}
+
+/// # Safety
+///
+/// Entry point from C code.
+///
+/// This function is unsafe because it dereferences the given `source` pointer.
+/// The caller should ensure it was filled by `audiounit_rust_get_device_data_source` or
+/// `audiounit_rust_stream_get_data_source`.
+#[no_mangle]
+pub unsafe extern "C" fn audiounit_rust_data_source_destroy(
+    source: *mut audiounit_rust_data_source,
+) -> c_int {
+    let source = &mut *source;
+    if !source.name.is_null() {
+        drop(CString::from_raw(source.name));
+        source.name = ptr::null_mut();
+    }
+    ffi::CUBEB_OK
+}
+
+/// # Safety
+///
+/// Entry point from C code.
+///
+/// This function is unsafe because it dereferences the given `s` and `source` pointers.
+/// The caller should ensure those pointers are valid, and release `source` with
+/// `audiounit_rust_data_source_destroy`.
+#[no_mangle]
+pub unsafe extern "C" fn audiounit_rust_stream_get_data_source(
+    s: *mut ffi::cubeb_stream,
+    devtype: ffi::cubeb_device_type,
+    source: *mut audiounit_rust_data_source,
+) -> c_int {
+    let stm = &mut *(s as *mut AudioUnitStream);
+    let devtype = DeviceType::from_bits_truncate(devtype);
+    *source = _try!(stm.data_source(devtype)).into();
+    ffi::CUBEB_OK
+}
+
+/// Select the data source `id` on the device the stream uses in `devtype` direction. This is a
+/// setting of the device, not of the stream: the other streams and processes using the device
+/// switch to the new source too, and the device keeps it after the stream is destroyed.
+///
+/// # Safety
+///
+/// Entry point from C code.
+///
+/// This function is unsafe because it dereferences the given `s` pointer.
+/// The caller should ensure it is a valid stream of this backend.
+#[no_mangle]
+pub unsafe extern "C" fn audiounit_rust_stream_set_data_source(
+    s: *mut ffi::cubeb_stream,
+    devtype: ffi::cubeb_device_type,
+    id: u32,
+) -> c_int {
+    let stm = &mut *(s as *mut AudioUnitStream);
+    let devtype = DeviceType::from_bits_truncate(devtype);
+    _try!(stm.set_data_source(devtype, id));
+    ffi::CUBEB_OK
+}
+
+/// # Safety
+///
+/// Entry point from C code.
+///
+/// This function is unsafe because it dereferences the given `s` and `latency_frames` pointers.
+/// The caller should ensure those pointers are valid.
+#[no_mangle]
+pub unsafe extern "C" fn audiounit_rust_stream_get_negotiated_latency(
+    s: *mut ffi::cubeb_stream,
+    latency_frames: *mut u32,
+) -> c_int {
+    let stm = &*(s as *mut AudioUnitStream);
+    *latency_frames = stm.negotiated_latency_frames();
+    ffi::CUBEB_OK
+}
+
+/// Get the latency negotiated for the streams of the context using `devid` (or the default device
+/// of `devtype` if null) in `devtype` direction. `latency_frames` is set to 0 if no stream uses
+/// the device.
+///
+/// # Safety
+///
+/// Entry point from C code.
+///
+/// This function is unsafe because it dereferences the given `c` and `latency_frames` pointers.
+/// The caller should ensure those pointers are valid.
+#[no_mangle]
+pub unsafe extern "C" fn audiounit_rust_get_device_latency(
+    c: *mut ffi::cubeb,
+    devid: ffi::cubeb_devid,
+    devtype: ffi::cubeb_device_type,
+    latency_frames: *mut u32,
+) -> c_int {
+    let ctx = &*(c as *mut AudioUnitContext);
+    let devtype = DeviceType::from_bits_truncate(devtype);
+    *latency_frames = _try!(ctx.device_latency(devid, devtype)).unwrap_or(0);
+    ffi::CUBEB_OK
+}
+
+/// Get the channel count of `devid` (or the default device of `devtype` if null) in `devtype`
+/// direction.
+///
+/// # Safety
+///
+/// Entry point from C code.
+///
+/// This function is unsafe because it dereferences the given `c` and `max_channels` pointers.
+/// The caller should ensure those pointers are valid.
+#[no_mangle]
+pub unsafe extern "C" fn audiounit_rust_get_device_max_channel_count(
+    c: *mut ffi::cubeb,
+    devid: ffi::cubeb_devid,
+    devtype: ffi::cubeb_device_type,
+    max_channels: *mut u32,
+) -> c_int {
+    let ctx = &*(c as *mut AudioUnitContext);
+    let devtype = DeviceType::from_bits_truncate(devtype);
+    *max_channels = _try!(ctx.device_max_channel_count(devid, devtype));
+    ffi::CUBEB_OK
+}
+
+/// Get the smallest latency, in frames at the rate of `params` (or of the device if 0), a stream
+/// on `devid` (or the default device of `devtype` if null) can use.
+///
+/// # Safety
+///
+/// Entry point from C code.
+///
+/// This function is unsafe because it dereferences the given `c` and `latency_frames` pointers.
+/// The caller should ensure those pointers are valid.
+#[no_mangle]
+pub unsafe extern "C" fn audiounit_rust_get_device_min_latency(
+    c: *mut ffi::cubeb,
+    devid: ffi::cubeb_devid,
+    devtype: ffi::cubeb_device_type,
+    params: ffi::cubeb_stream_params,
+    latency_frames: *mut u32,
+) -> c_int {
+    let ctx = &*(c as *mut AudioUnitContext);
+    let devtype = DeviceType::from_bits_truncate(devtype);
+    let params = StreamParams::from(params);
+    *latency_frames = _try!(ctx.device_min_latency(devid, devtype, &params));
+    ffi::CUBEB_OK
+}
+
+/// Get the current sample rate of `devid` (or the default device of `devtype` if null) in
+/// `devtype` direction.
+///
+/// # Safety
+///
+/// Entry point from C code.
+///
+/// This function is unsafe because it dereferences the given `c` and `rate` pointers.
+/// The caller should ensure those pointers are valid.
+#[no_mangle]
+pub unsafe extern "C" fn audiounit_rust_get_device_preferred_sample_rate(
+    c: *mut ffi::cubeb,
+    devid: ffi::cubeb_devid,
+    devtype: ffi::cubeb_device_type,
+    rate: *mut u32,
+) -> c_int {
+    let ctx = &*(c as *mut AudioUnitContext);
+    let devtype = DeviceType::from_bits_truncate(devtype);
+    *rate = _try!(ctx.device_preferred_sample_rate(devid, devtype));
+    ffi::CUBEB_OK
+}
+
+/// # Safety
+///
+/// Entry point from C code.
+///
+/// This function is unsafe because it dereferences the given `s` and `info` pointers.
+/// The caller should ensure those pointers are valid, and release `info` with
+/// `audiounit_rust_aggregate_clock_info_destroy`.
+#[no_mangle]
+pub unsafe extern "C" fn audiounit_rust_stream_get_aggregate_clock_info(
+    s: *mut ffi::cubeb_stream,
+    info: *mut audiounit_rust_aggregate_clock_info,
+) -> c_int {
+    let stm = &*(s as *mut AudioUnitStream);
+    *info = match _try!(stm.aggregate_clock_info()) {
+        Some(clock_info) => clock_info.into(),
+        None => audiounit_rust_aggregate_clock_info {
+            master_uid: ptr::null_mut(),
+            sub_device: ptr::null_mut(),
+            count: 0,
+        },
+    };
+    ffi::CUBEB_OK
+}
+
+/// # Safety
+///
+/// Entry point from C code.
+///
+/// This function is unsafe because it dereferences the given `info` pointer.
+/// The caller should ensure it was filled by `audiounit_rust_stream_get_aggregate_clock_info`.
+#[no_mangle]
+pub unsafe extern "C" fn audiounit_rust_aggregate_clock_info_destroy(
+    info: *mut audiounit_rust_aggregate_clock_info,
+) -> c_int {
+    let info = &mut *info;
+    if !info.master_uid.is_null() {
+        drop(CString::from_raw(info.master_uid));
+        info.master_uid = ptr::null_mut();
+    }
+    if !info.sub_device.is_null() {
+        for sub_device in slice::from_raw_parts_mut(info.sub_device, info.count) {
+            drop(CString::from_raw(sub_device.uid));
+            sub_device.uid = ptr::null_mut();
+        }
+    }
+    drop_raw_array(info.sub_device, info.count);
+    (info.sub_device, info.count) = (ptr::null_mut(), 0);
+    ffi::CUBEB_OK
+}
+
+/// Add a rule to the device quirks table of the process. It applies to the streams of all the
+/// contexts, from the next time they set up their devices.
+///
+/// # Safety
+///
+/// Entry point from C code.
+///
+/// This function is unsafe because it dereferences the given `c` and `rule` pointers.
+/// The caller should ensure those pointers are valid.
+#[no_mangle]
+pub unsafe extern "C" fn audiounit_rust_add_quirk_rule(
+    c: *mut ffi::cubeb,
+    rule: *const audiounit_rust_quirk_rule,
+) -> c_int {
+    let ctx = &*(c as *mut AudioUnitContext);
+    match quirk_rule_from_raw(&*rule) {
+        Some(rule) => {
+            ctx.add_quirk_rule(rule);
+            ffi::CUBEB_OK
+        }
+        None => ffi::CUBEB_ERROR_INVALID_PARAMETER,
+    }
+}
+
+/// Get the AUDIOUNIT_RUST_QUIRK_* flags applied to `devid` (or the default device of `devtype` if
+/// null) in `devtype` direction.
+///
+/// # Safety
+///
+/// Entry point from C code.
+///
+/// This function is unsafe because it dereferences the given `c` and `quirks` pointers.
+/// The caller should ensure those pointers are valid.
+#[no_mangle]
+pub unsafe extern "C" fn audiounit_rust_get_device_quirks(
+    c: *mut ffi::cubeb,
+    devid: ffi::cubeb_devid,
+    devtype: ffi::cubeb_device_type,
+    quirks: *mut u32,
+) -> c_int {
+    let ctx = &*(c as *mut AudioUnitContext);
+    let devtype = DeviceType::from_bits_truncate(devtype);
+    *quirks = _try!(ctx.device_quirks(devid, devtype)).bits();
+    ffi::CUBEB_OK
+}
+
+/// Set how much the other audio is ducked while the stream uses VoiceProcessingIO.
+/// `ramp_duration_ms` only applies when the OS has no VoiceProcessingIO ducking configuration.
+///
+/// # Safety
+///
+/// Entry point from C code.
+///
+/// This function is unsafe because it dereferences the given `s` pointer.
+/// The caller should ensure it is a valid stream of this backend.
+#[no_mangle]
+pub unsafe extern "C" fn audiounit_rust_stream_set_vpio_ducking(
+    s: *mut ffi::cubeb_stream,
+    level: c_int,
+    ramp_duration_ms: u32,
+) -> c_int {
+    let stm = &mut *(s as *mut AudioUnitStream);
+    let level = match level {
+        AUDIOUNIT_RUST_DUCKING_LEVEL_NONE => DuckingLevel::None,
+        AUDIOUNIT_RUST_DUCKING_LEVEL_MIN => DuckingLevel::Min,
+        AUDIOUNIT_RUST_DUCKING_LEVEL_MID => DuckingLevel::Mid,
+        AUDIOUNIT_RUST_DUCKING_LEVEL_MAX => DuckingLevel::Max,
+        _ => return ffi::CUBEB_ERROR_INVALID_PARAMETER,
+    };
+    _try!(stm.set_vpio_ducking(VpioDucking {
+        level,
+        ramp_duration: Duration::from_millis(u64::from(ramp_duration_ms)),
+    }));
+    ffi::CUBEB_OK
+}
+
+/// # Safety
+///
+/// Entry point from C code.
+///
+/// This function is unsafe because it dereferences the given `s`, `level` and `ramp_duration_ms`
+/// pointers. The caller should ensure those pointers are valid.
+#[no_mangle]
+pub unsafe extern "C" fn audiounit_rust_stream_get_vpio_ducking(
+    s: *mut ffi::cubeb_stream,
+    level: *mut c_int,
+    ramp_duration_ms: *mut u32,
+) -> c_int {
+    let stm = &*(s as *mut AudioUnitStream);
+    let ducking = stm.vpio_ducking();
+    *level = match ducking.level {
+        DuckingLevel::None => AUDIOUNIT_RUST_DUCKING_LEVEL_NONE,
+        DuckingLevel::Min => AUDIOUNIT_RUST_DUCKING_LEVEL_MIN,
+        DuckingLevel::Mid => AUDIOUNIT_RUST_DUCKING_LEVEL_MID,
+        DuckingLevel::Max => AUDIOUNIT_RUST_DUCKING_LEVEL_MAX,
+    };
+    *ramp_duration_ms = ducking.ramp_duration.as_millis() as u32;
+    ffi::CUBEB_OK
+}
+
+/// Call `callback` with the user pointer of the stream and one of the
+/// AUDIOUNIT_RUST_VOICE_ACTIVITY_* events when the user starts or stops talking while the input of
+/// the stream is muted. A null `callback` unregisters it. Only for streams using
+/// VoiceProcessingIO.
+///
+/// # Safety
+///
+/// Entry point from C code.
+///
+/// This function is unsafe because it dereferences the given `s` pointer.
+/// The caller should ensure it is a valid stream of this backend.
+#[no_mangle]
+pub unsafe extern "C" fn audiounit_rust_stream_register_voice_activity_callback(
+    s: *mut ffi::cubeb_stream,
+    callback: Option<VoiceActivityCallback>,
+) -> c_int {
+    let stm = &mut *(s as *mut AudioUnitStream);
+    _try!(stm.register_voice_activity_callback(callback));
+    ffi::CUBEB_OK
+}
+
+/// Get the input processing params the stream can apply for its current devices, as
+/// `cubeb_input_processing_params` flags. Not all their combinations might be.
+///
+/// # Safety
+///
+/// Entry point from C code.
+///
+/// This function is unsafe because it dereferences the given `s` and `params` pointers.
+/// The caller should ensure those pointers are valid.
+#[no_mangle]
+pub unsafe extern "C" fn audiounit_rust_stream_get_supported_input_processing_params(
+    s: *mut ffi::cubeb_stream,
+    params: *mut ffi::cubeb_input_processing_params,
+) -> c_int {
+    let stm = &*(s as *mut AudioUnitStream);
+    *params = stm.supported_input_processing_params().bits();
+    ffi::CUBEB_OK
+}
+
+/// Get the combinations of `cubeb_input_processing_params` flags the stream can apply for its
+/// current devices. With VoiceProcessingIO, echo cancellation only comes with noise suppression.
+///
+/// # Safety
+///
+/// Entry point from C code.
+///
+/// This function is unsafe because it dereferences the given `s` and `combinations` pointers.
+/// The caller should ensure those pointers are valid, and release `combinations` with
+/// `audiounit_rust_input_processing_param_combinations_destroy`.
+#[no_mangle]
+pub unsafe extern "C" fn audiounit_rust_stream_get_supported_input_processing_param_combinations(
+    s: *mut ffi::cubeb_stream,
+    combinations: *mut audiounit_rust_input_processing_param_combinations,
+) -> c_int {
+    let stm = &*(s as *mut AudioUnitStream);
+    let params = stm
+        .supported_input_processing_param_combinations()
+        .into_iter()
+        .map(|params| params.bits())
+        .collect::<Vec<_>>();
+    let combinations = &mut *combinations;
+    (combinations.params, combinations.count) = into_raw_array(params);
+    ffi::CUBEB_OK
+}
+
+/// # Safety
+///
+/// Entry point from C code.
+///
+/// This function is unsafe because it dereferences the given `combinations` pointer.
+/// The caller should ensure it was filled by
+/// `audiounit_rust_stream_get_supported_input_processing_param_combinations`.
+#[no_mangle]
+pub unsafe extern "C" fn audiounit_rust_input_processing_param_combinations_destroy(
+    combinations: *mut audiounit_rust_input_processing_param_combinations,
+) -> c_int {
+    let combinations = &mut *combinations;
+    drop_raw_array(combinations.params, combinations.count);
+    (combinations.params, combinations.count) = (ptr::null_mut(), 0);
+    ffi::CUBEB_OK
+}
+
+/// Create a VoiceProcessingIO unit in the background, and park it for the first voice stream of
+/// the context. Fails if VoiceProcessingIO is blocked for the default input device.
+///
+/// # Safety
+///
+/// Entry point from C code.
+///
+/// This function is unsafe because it dereferences the given `c` pointer.
+/// The caller should ensure it is a valid context of this backend.
+#[no_mangle]
+pub unsafe extern "C" fn audiounit_rust_prewarm_voice_processing_unit(c: *mut ffi::cubeb) -> c_int {
+    let ctx = &*(c as *mut AudioUnitContext);
+    _try!(ctx.prewarm_voice_processing_unit());
+    ffi::CUBEB_OK
+}
+
+/// Get the AUDIOUNIT_RUST_VOICE_PROCESSING_PREWARM_STATE_* state of the prewarmed
+/// VoiceProcessingIO unit.
+///
+/// # Safety
+///
+/// Entry point from C code.
+///
+/// This function is unsafe because it dereferences the given `c` and `state` pointers.
+/// The caller should ensure those pointers are valid.
+#[no_mangle]
+pub unsafe extern "C" fn audiounit_rust_get_voice_processing_prewarm_state(
+    c: *mut ffi::cubeb,
+    state: *mut c_int,
+) -> c_int {
+    let ctx = &*(c as *mut AudioUnitContext);
+    *state = match ctx.voice_processing_prewarm_state() {
+        VoiceProcessingPrewarmState::Idle => AUDIOUNIT_RUST_VOICE_PROCESSING_PREWARM_STATE_IDLE,
+        VoiceProcessingPrewarmState::Pending => {
+            AUDIOUNIT_RUST_VOICE_PROCESSING_PREWARM_STATE_PENDING
+        }
+        VoiceProcessingPrewarmState::Ready => AUDIOUNIT_RUST_VOICE_PROCESSING_PREWARM_STATE_READY,
+        VoiceProcessingPrewarmState::Failed => AUDIOUNIT_RUST_VOICE_PROCESSING_PREWARM_STATE_FAILED,
+    };
+    ffi::CUBEB_OK
+}
+
+/// Give back the prewarmed VoiceProcessingIO unit, or cancel its creation.
+///
+/// # Safety
+///
+/// Entry point from C code.
+///
+/// This function is unsafe because it dereferences the given `c` pointer.
+/// The caller should ensure it is a valid context of this backend.
+#[no_mangle]
+pub unsafe extern "C" fn audiounit_rust_release_prewarmed_voice_processing_unit(
+    c: *mut ffi::cubeb,
+) -> c_int {
+    let ctx = &*(c as *mut AudioUnitContext);
+    ctx.release_prewarmed_voice_processing_unit();
+    ffi::CUBEB_OK
+}
@@ -17,4 +17,4 @@ extern crate num;
 mod backend;
 mod capi;
 
-pub use crate::capi::audiounit_rust_init;
+pub use crate::capi::*;